@@ -23,6 +23,11 @@ lazy_static::lazy_static! {
         "insert_block_headers_multiple_times",
         "insert_300_blocks",
         "get_metrics",
+        "pre_upgrade_with_1000_unstable_blocks",
+        "pre_upgrade_with_3000_unstable_blocks",
+        "post_upgrade_with_1000_unstable_blocks",
+        "post_upgrade_with_3000_unstable_blocks",
+        "post_upgrade_migrating_version_1",
     ];
 }
 
@@ -1,13 +1,28 @@
 use bitcoin::consensus::Decodable;
-use bitcoin::{consensus::Encodable, Block as BitcoinBlock, BlockHeader};
+use bitcoin::{
+    consensus::Encodable, Block as BitcoinBlock, BlockHash, BlockHeader, OutPoint, Script,
+    Transaction, TxIn, TxMerkleNode, TxOut, Txid, Witness,
+};
 use candid::CandidType;
-use ic_btc_canister::{types::BlockHeaderBlob, with_state_mut};
+use ciborium::value::Value;
+use ic_btc_canister::{
+    memory::{self, get_upgrades_memory, Reader, Writer},
+    types::BlockHeaderBlob,
+    with_state_mut,
+};
 use ic_btc_interface::{Config, Network};
 use ic_btc_types::Block;
 use ic_cdk_macros::{init, query};
 use maplit::btreemap;
-use serde::Deserialize;
-use std::{cell::RefCell, collections::BTreeMap};
+use serde::{
+    ser::{SerializeMap, SerializeSeq},
+    Deserialize, Serialize, Serializer,
+};
+use std::{
+    cell::RefCell,
+    collections::BTreeMap,
+    io::{BufWriter, Read, Write},
+};
 
 thread_local! {
     static TESTNET_BLOCKS: RefCell<Vec<Block>> =  RefCell::new(vec![]);
@@ -152,6 +167,225 @@ fn insert_block_headers_multiple_times() -> BenchResult {
     })
 }
 
+// Benchmarks the pre-upgrade hook at different numbers of unstable blocks, to
+// verify that its cost doesn't grow with the size of the unstable blocks.
+#[query]
+fn pre_upgrade_with_1000_unstable_blocks() -> BenchResult {
+    bench_pre_upgrade(1000)
+}
+
+#[query]
+fn pre_upgrade_with_3000_unstable_blocks() -> BenchResult {
+    bench_pre_upgrade(3000)
+}
+
+// Benchmarks the post-upgrade hook at different numbers of unstable blocks.
+#[query]
+fn post_upgrade_with_1000_unstable_blocks() -> BenchResult {
+    bench_post_upgrade(1000)
+}
+
+#[query]
+fn post_upgrade_with_3000_unstable_blocks() -> BenchResult {
+    bench_post_upgrade(3000)
+}
+
+fn bench_pre_upgrade(num_blocks: usize) -> BenchResult {
+    init_with_unstable_blocks(num_blocks);
+
+    benchmark(|| {
+        ic_btc_canister::pre_upgrade();
+    })
+}
+
+fn bench_post_upgrade(num_blocks: usize) -> BenchResult {
+    init_with_unstable_blocks(num_blocks);
+    ic_btc_canister::pre_upgrade();
+
+    benchmark(|| {
+        ic_btc_canister::post_upgrade();
+    })
+}
+
+// Initializes the canister with the given number of testnet blocks, all of which are unstable.
+fn init_with_unstable_blocks(num_blocks: usize) {
+    ic_btc_canister::init(Config {
+        network: Network::Testnet,
+        stability_threshold: num_blocks as u128,
+        ..Config::default()
+    });
+
+    with_state_mut(|s| {
+        for i in 0..num_blocks {
+            ic_btc_canister::state::insert_block(s, TESTNET_BLOCKS.with(|b| b.borrow()[i].clone()))
+                .unwrap();
+        }
+    });
+}
+
+// Benchmarks the post-upgrade hook migrating a state of version 1, which kept the
+// unstable blocks on the heap in their serde representation, with a day's worth of
+// mainnet-sized unstable blocks.
+#[query]
+fn post_upgrade_migrating_version_1() -> BenchResult {
+    ic_btc_canister::init(Config {
+        network: Network::Regtest,
+        stability_threshold: 144,
+        ..Config::default()
+    });
+    write_state_v1(144);
+
+    benchmark(|| {
+        ic_btc_canister::post_upgrade();
+    })
+}
+
+// The number of transactions in the blocks of a state of version 1, which makes them
+// about 1MB in size.
+const NUM_TRANSACTIONS_PER_BLOCK: u32 = 4_500;
+
+// Writes the current state into the upgrades memory in the layout of version 1, with
+// an unstable block tree that's a chain of `num_blocks` blocks.
+fn write_state_v1(num_blocks: u32) {
+    ic_btc_canister::pre_upgrade();
+
+    // Read the state that was written in the current version.
+    let memory = get_upgrades_memory();
+    let mut len = [0; 4];
+    Reader::new(&memory, 0, 4).read_exact(&mut len).unwrap();
+    let state: Value =
+        ciborium::de::from_reader(Reader::new(&memory, 12, u32::from_le_bytes(len) as u64))
+            .unwrap();
+
+    // Version 1 has no version header, so the state directly follows its length.
+    let mut writer = BufWriter::with_capacity(1024 * 1024, Writer::new(&memory, 4));
+    ciborium::ser::into_writer(&StateV1 { state, num_blocks }, &mut writer).unwrap();
+    writer.flush().unwrap();
+    let len = writer.get_ref().offset() - 4;
+    memory::write(&memory, 0, &(len as u32).to_le_bytes());
+}
+
+// A state in the encoding of version 1, with the fields of `state` other than its
+// unstable blocks.
+struct StateV1 {
+    state: Value,
+    num_blocks: u32,
+}
+
+impl Serialize for StateV1 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let fields = match &self.state {
+            Value::Map(fields) => fields,
+            other => panic!("expected a state, found {:?}", other),
+        };
+
+        let mut map = serializer.serialize_map(Some(fields.len()))?;
+        for (key, value) in fields {
+            if *key == Value::Text("unstable_blocks".to_string()) {
+                map.serialize_entry(
+                    key,
+                    &UnstableBlocksV1 {
+                        unstable_blocks: value,
+                        num_blocks: self.num_blocks,
+                    },
+                )?;
+            } else {
+                map.serialize_entry(key, value)?;
+            }
+        }
+        map.end()
+    }
+}
+
+// Unstable blocks in the encoding of version 1, with an empty outpoints cache.
+struct UnstableBlocksV1<'a> {
+    unstable_blocks: &'a Value,
+    num_blocks: u32,
+}
+
+impl<'a> Serialize for UnstableBlocksV1<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let field = |name: &str| match self.unstable_blocks {
+            Value::Map(fields) => fields
+                .iter()
+                .find(|(key, _)| *key == Value::Text(name.to_string()))
+                .map(|(_, value)| value)
+                .unwrap(),
+            other => panic!("expected unstable blocks, found {:?}", other),
+        };
+        let empty_map = Value::Map(vec![]);
+
+        let mut map = serializer.serialize_map(Some(5))?;
+        map.serialize_entry("stability_threshold", field("stability_threshold"))?;
+        map.serialize_entry("tree", &ChainV1(self.num_blocks))?;
+        map.serialize_entry(
+            "outpoints_cache",
+            &btreemap! {
+                "tx_outs" => &empty_map,
+                "added_outpoints" => &empty_map,
+                "removed_outpoints" => &empty_map,
+            },
+        )?;
+        map.serialize_entry("network", field("network"))?;
+        map.serialize_entry("next_block_headers", field("next_block_headers"))?;
+        map.end()
+    }
+}
+
+// A flattened tree in the encoding of version 1 that's a chain of the given number of
+// blocks. The blocks are built as they're serialized, so they're never held in full.
+struct ChainV1(u32);
+
+impl Serialize for ChainV1 {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0 as usize))?;
+        let mut prev_blockhash = BlockHash::default();
+        for i in 0..self.0 {
+            let block = Block::new(build_block(prev_blockhash, i));
+            prev_blockhash = block.header().block_hash();
+
+            let num_children = if i + 1 < self.0 { 1 } else { 0 };
+            seq.serialize_element(&(&block, num_children))?;
+        }
+        seq.end()
+    }
+}
+
+// Builds a block with `NUM_TRANSACTIONS_PER_BLOCK` segwit transactions of a typical size.
+fn build_block(prev_blockhash: BlockHash, i: u32) -> BitcoinBlock {
+    let txdata = (0..NUM_TRANSACTIONS_PER_BLOCK)
+        .map(|j| Transaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: OutPoint::new(Txid::default(), i * NUM_TRANSACTIONS_PER_BLOCK + j),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Witness::from_vec(vec![vec![1; 72], vec![2; 33]]),
+            }],
+            output: vec![
+                TxOut {
+                    value: 10_000,
+                    script_pubkey: Script::from(vec![3; 22]),
+                };
+                2
+            ],
+        })
+        .collect();
+
+    BitcoinBlock {
+        header: BlockHeader {
+            version: 1,
+            prev_blockhash,
+            merkle_root: TxMerkleNode::default(),
+            time: i,
+            bits: 0x207fffff,
+            nonce: 0,
+        },
+        txdata,
+    }
+}
+
 #[derive(Debug, PartialEq, Deserialize, CandidType)]
 pub struct BenchResult {
     measurements: BTreeMap<String, u64>,
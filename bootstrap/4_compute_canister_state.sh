@@ -1,11 +1,10 @@
 #!/usr/bin/env bash
 #
-# A script to build the canister's state given a UTXO dump file.
+# A script to build the canister's state from the chainstate database of bitcoind.
 set -euo pipefail
 
 CANISTER_STATE_DIR=canister_state
 CANISTER_STATE_FILE=canister_state.bin
-UTXO_FILE=utxodump_shuffled.csv
 UNSTABLE_BLOCKS_FILE=unstable_blocks
 BLOCK_HEADERS_FILE=block_headers
 
@@ -19,23 +18,20 @@ if ! [[ "$NETWORK" == "mainnet" || "$NETWORK" == "testnet" ]]; then
     false
 fi
 
-mkdir $CANISTER_STATE_DIR
-
-echo "Computing balances..."
-cargo run --release --bin build-balances -- \
-   --output $CANISTER_STATE_DIR/balances --network "$NETWORK" --utxos-dump-path $UTXO_FILE
-
-echo "Computing address UTXOs..."
-cargo run --release --bin build-address-utxos -- \
-   --output $CANISTER_STATE_DIR/address_utxos --network "$NETWORK" --utxos-dump-path $UTXO_FILE
+if [[ "$NETWORK" == "mainnet" ]]; then
+    CHAIN_STATE_DIR=./data/chainstate
+else
+    CHAIN_STATE_DIR=./data/testnet3/chainstate
+fi
 
-echo "Computing UTXOs..."
-cargo run --release --bin build-utxos -- \
-   --output $CANISTER_STATE_DIR --network "$NETWORK" --utxos-dump-path $UTXO_FILE
+mkdir $CANISTER_STATE_DIR
 
-echo "Combining the state into $CANISTER_STATE_FILE"
-cargo run --release --bin combine-state -- \
-   --output $CANISTER_STATE_FILE --canister-state-dir $CANISTER_STATE_DIR
+echo "Computing the UTXO set into $CANISTER_STATE_FILE..."
+cargo run --release --bin compute-canister-state -- \
+   --chainstate-dir "$CHAIN_STATE_DIR" \
+   --network "$NETWORK" \
+   --canister-state-dir $CANISTER_STATE_DIR \
+   --output $CANISTER_STATE_FILE
 
 echo "Building state struct.."
 pushd main-state-builder
@@ -7,7 +7,7 @@
 //!   --canister-state-dir ./canister_state \
 //!   --network mainnet --stability-threshold 30 --stable-height 9999 \
 //!   --unstable-blocks ./unstable_blocks
-use bitcoin::{consensus::Decodable, Block as BitcoinBlock};
+use bitcoin::{consensus::Decodable, util::uint::Uint256, Block as BitcoinBlock, BlockHeader};
 use clap::Parser;
 use ic_btc_canister::{
     pre_upgrade,
@@ -107,22 +107,6 @@ fn main() {
     );
     println!("Next block hash: {:?}", next_block.block_hash().to_string());
 
-    println!("Ingesting unstable blocks..");
-    with_state_mut(|s| {
-        s.utxos.utxos.large_utxos = large_utxos;
-
-        s.utxos.next_height = args.anchor_height;
-
-        // Ingest the blocks.
-        s.unstable_blocks = UnstableBlocks::new(
-            &s.utxos,
-            args.stability_threshold as u32,
-            anchor_block,
-            args.network,
-        );
-        unstable_blocks::push(&mut s.unstable_blocks, &s.utxos, next_block).unwrap();
-    });
-
     println!("Inserting block headers...");
     let block_headers_file = BufReader::new(File::open(&args.block_headers).unwrap());
 
@@ -139,13 +123,38 @@ fn main() {
         )
     });
 
+    // The chainwork of the stable blocks, which is counted from the genesis block.
+    let mut chainwork = Uint256::default();
     with_state_mut(|s| {
         for (height, (block_hash, block_header)) in block_headers.enumerate() {
+            chainwork = chainwork
+                + BlockHeader::consensus_decode(block_header.as_slice())
+                    .unwrap()
+                    .work();
             s.stable_block_headers
-                .insert(block_hash, block_header, height as u32);
+                .insert(block_hash, block_header, height as u32, chainwork);
         }
     });
 
+    println!("Ingesting unstable blocks..");
+    with_state_mut(|s| {
+        s.utxos.utxos.large_utxos = large_utxos;
+
+        s.utxos.next_height = args.anchor_height;
+
+        // Ingest the blocks.
+        let anchor_block_work = anchor_block.work();
+        s.unstable_blocks = UnstableBlocks::new(
+            &s.utxos,
+            args.stability_threshold as u32,
+            anchor_block,
+            args.network,
+        );
+        s.unstable_blocks
+            .set_anchor_chainwork(chainwork + anchor_block_work);
+        unstable_blocks::push(&mut s.unstable_blocks, &s.utxos, next_block).unwrap();
+    });
+
     println!(
         "# Small UTXOs: {}",
         with_state(|s| s.utxos.utxos.small_utxos.len())
@@ -0,0 +1,225 @@
+//! Reading raw blocks and ordering them into a chain.
+//!
+//! Blocks can be read from either the `blk*.dat` files of `bitcoind`, where every block
+//! is prefixed with the network's magic and its size, or from a plain concatenation
+//! of serialized blocks. All the blocks are held in memory, so this is only meant for
+//! small chains, such as regtest or signet fixtures.
+use bitcoin::{
+    consensus::{deserialize, Decodable},
+    Block, BlockHash,
+};
+use ic_btc_canister::types::into_bitcoin_network;
+use ic_btc_interface::{Height, Network};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Cursor,
+    path::{Path, PathBuf},
+};
+
+/// Reads the blocks in the given file, or in the `blk*.dat` files of the given directory.
+pub fn read_blocks(path: &Path, network: Network) -> Vec<Block> {
+    let magic = into_bitcoin_network(network).magic().to_le_bytes();
+
+    if !path.is_dir() {
+        return parse_blocks(&fs::read(path).unwrap(), magic);
+    }
+
+    // Since version 28.0, `bitcoind` obfuscates its block files with the key in `xor.dat`.
+    let xor_key = fs::read(path.join("xor.dat")).unwrap_or_default();
+
+    let mut files: Vec<PathBuf> = fs::read_dir(path)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| {
+            let name = path.file_name().unwrap().to_string_lossy();
+            name.starts_with("blk") && name.ends_with(".dat")
+        })
+        .collect();
+    files.sort();
+
+    let mut blocks = vec![];
+    for file in files {
+        println!("Reading {}...", file.display());
+        let mut bytes = fs::read(file).unwrap();
+        if !xor_key.is_empty() {
+            for (i, byte) in bytes.iter_mut().enumerate() {
+                *byte ^= xor_key[i % xor_key.len()];
+            }
+        }
+        blocks.extend(parse_blocks(&bytes, magic));
+    }
+    blocks
+}
+
+// Parses the blocks in the given bytes, which are either framed with the network's
+// magic and the size of every block, or a plain concatenation of blocks.
+fn parse_blocks(bytes: &[u8], magic: [u8; 4]) -> Vec<Block> {
+    let mut blocks = vec![];
+
+    if !bytes.starts_with(&magic) {
+        let mut cursor = Cursor::new(bytes);
+        while (cursor.position() as usize) < bytes.len() {
+            blocks.push(Block::consensus_decode(&mut cursor).expect("block must be valid"));
+        }
+        return blocks;
+    }
+
+    let mut offset = 0;
+    while offset + 8 <= bytes.len() {
+        // Block files are preallocated, so the end of the blocks is padded with zeros.
+        if bytes[offset..offset + 4] == [0; 4] {
+            break;
+        }
+        assert_eq!(
+            bytes[offset..offset + 4],
+            magic,
+            "unexpected magic at offset {}",
+            offset
+        );
+
+        let size = u32::from_le_bytes(bytes[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        offset += 8;
+        blocks.push(deserialize(&bytes[offset..offset + size]).expect("block must be valid"));
+        offset += size;
+    }
+    blocks
+}
+
+/// Returns the blocks of the longest chain that extends the given genesis block, in order
+/// and excluding the genesis block itself.
+///
+/// If a height is given, the chain stops at that height. Ties between chains of the same
+/// length are broken by the order the blocks were read in.
+pub fn main_chain(
+    blocks: Vec<Block>,
+    genesis_hash: BlockHash,
+    height: Option<Height>,
+) -> Vec<Block> {
+    // Index the children of every block, keeping the order the blocks were read in.
+    let mut children: HashMap<BlockHash, Vec<BlockHash>> = HashMap::new();
+    let mut blocks_by_hash: HashMap<BlockHash, Block> = HashMap::new();
+    for block in blocks {
+        let block_hash = block.block_hash();
+        if block_hash == genesis_hash || blocks_by_hash.contains_key(&block_hash) {
+            continue;
+        }
+        children
+            .entry(block.header.prev_blockhash)
+            .or_default()
+            .push(block_hash);
+        blocks_by_hash.insert(block_hash, block);
+    }
+
+    // Compute the height of every block that's connected to the genesis block, and
+    // find the highest tip.
+    let mut parents: HashMap<BlockHash, BlockHash> = HashMap::new();
+    let mut tip = (genesis_hash, 0);
+    let mut stack = vec![(genesis_hash, 0)];
+    while let Some((block_hash, block_height)) = stack.pop() {
+        if block_height > tip.1 {
+            tip = (block_hash, block_height);
+        }
+
+        // Children are pushed in reverse so that earlier blocks are visited first.
+        for child in children.get(&block_hash).into_iter().flatten().rev() {
+            parents.insert(*child, block_hash);
+            stack.push((*child, block_height + 1));
+        }
+    }
+
+    let (mut block_hash, mut tip_height) = tip;
+    if let Some(height) = height {
+        assert!(
+            height <= tip_height,
+            "cannot build a chain of height {}, the blocks only reach height {}",
+            height,
+            tip_height
+        );
+        while tip_height > height {
+            block_hash = parents[&block_hash];
+            tip_height -= 1;
+        }
+    }
+
+    // Walk back from the tip to the genesis block.
+    let mut chain = vec![];
+    while block_hash != genesis_hash {
+        chain.push(blocks_by_hash.remove(&block_hash).unwrap());
+        block_hash = parents[&block_hash];
+    }
+    chain.reverse();
+    chain
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::consensus::serialize;
+    use ic_btc_test_utils::BlockBuilder;
+
+    // Builds a chain of the given length on top of the given block.
+    fn build_chain(prev: &Block, len: usize) -> Vec<Block> {
+        let mut chain: Vec<Block> = vec![];
+        for _ in 0..len {
+            let prev_header = chain.last().unwrap_or(prev).header;
+            chain.push(BlockBuilder::with_prev_header(prev_header).build());
+        }
+        chain
+    }
+
+    fn hashes(blocks: &[Block]) -> Vec<BlockHash> {
+        blocks.iter().map(|b| b.block_hash()).collect()
+    }
+
+    #[test]
+    fn parses_framed_and_plain_blocks() {
+        let genesis = BlockBuilder::genesis().build();
+        let blocks = build_chain(&genesis, 3);
+        let magic = into_bitcoin_network(Network::Regtest).magic().to_le_bytes();
+
+        let mut plain = vec![];
+        let mut framed = vec![];
+        for block in &blocks {
+            let bytes = serialize(block);
+            plain.extend(&bytes);
+            framed.extend(magic);
+            framed.extend((bytes.len() as u32).to_le_bytes());
+            framed.extend(bytes);
+        }
+        // Block files are padded with zeros.
+        framed.extend([0; 16]);
+
+        assert_eq!(hashes(&parse_blocks(&plain, magic)), hashes(&blocks));
+        assert_eq!(hashes(&parse_blocks(&framed, magic)), hashes(&blocks));
+    }
+
+    #[test]
+    fn orders_the_longest_chain() {
+        let genesis = BlockBuilder::genesis().build();
+        let chain = build_chain(&genesis, 5);
+        let fork = build_chain(&chain[1], 2);
+
+        // Shuffle the blocks and add the fork and the genesis block.
+        let mut blocks = vec![chain[3].clone(), genesis.clone()];
+        blocks.extend(fork.clone());
+        blocks.extend([chain[4].clone(), chain[0].clone(), chain[2].clone()]);
+        blocks.push(chain[1].clone());
+
+        assert_eq!(
+            hashes(&main_chain(blocks.clone(), genesis.block_hash(), None)),
+            hashes(&chain)
+        );
+        assert_eq!(
+            hashes(&main_chain(blocks, genesis.block_hash(), Some(2))),
+            hashes(&chain[..2])
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "cannot build a chain of height 3, the blocks only reach height 2")]
+    fn panics_if_the_chain_is_too_short() {
+        let genesis = BlockBuilder::genesis().build();
+        main_chain(build_chain(&genesis, 2), genesis.block_hash(), Some(3));
+    }
+}
@@ -0,0 +1,101 @@
+//! A script for building the Bitcoin canister's state from raw blocks, without `bitcoind`.
+//!
+//! The blocks are read from a directory of `blk*.dat` files or from a file containing
+//! a plain concatenation of serialized blocks. They are ordered into a chain by
+//! following their parents, and inserted into the canister up to the given height.
+//!
+//! The output is the canister's memory, which can be loaded as a `FileMemory` in the
+//! same way as the state produced by `main-state-builder`.
+//!
+//! Example run:
+//!
+//! cargo run --release --bin build-state-from-blocks -- \
+//!   --blocks-path ./regtest/blocks \
+//!   --network regtest \
+//!   --stability-threshold 6 \
+//!   --height 1000 \
+//!   --output canister_state.bin
+use clap::Parser;
+use ic_btc_canister::{pre_upgrade, state, types::into_bitcoin_network, with_state_mut};
+use ic_btc_interface::{Config, Flag, Height, Network};
+use ic_btc_types::Block;
+use state_builder::blocks::{main_chain, read_blocks};
+use std::{fs::File, io::Write, path::PathBuf};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// A directory of `blk*.dat` files, or a file of concatenated blocks.
+    #[clap(long, value_hint = clap::ValueHint::AnyPath)]
+    blocks_path: PathBuf,
+
+    /// The bitcoin network.
+    #[clap(long)]
+    network: Network,
+
+    /// The stability threshold to use.
+    #[clap(long)]
+    stability_threshold: u128,
+
+    /// The height to build the chain up to. Defaults to the tip of the longest chain.
+    #[clap(long)]
+    height: Option<Height>,
+
+    /// The path to store the canister's memory in.
+    #[clap(long, value_hint = clap::ValueHint::FilePath)]
+    output: PathBuf,
+}
+
+fn main() {
+    let args = Args::parse();
+
+    println!("Reading blocks...");
+    let blocks = read_blocks(&args.blocks_path, args.network);
+    println!("Read {} blocks", blocks.len());
+
+    let genesis_hash =
+        bitcoin::blockdata::constants::genesis_block(into_bitcoin_network(args.network))
+            .block_hash();
+    let chain = main_chain(blocks, genesis_hash, args.height);
+    println!("Building a chain of height {}...", chain.len());
+
+    ic_btc_canister::init(Config {
+        stability_threshold: args.stability_threshold,
+        network: args.network,
+        api_access: Flag::Disabled,
+        ..Config::default()
+    });
+
+    with_state_mut(|s| {
+        for (i, block) in chain.into_iter().enumerate() {
+            let block_hash = block.block_hash();
+            state::insert_block(s, Block::new(block))
+                .unwrap_or_else(|err| panic!("cannot insert block {}: {:?}", block_hash, err));
+
+            // Ingest the blocks that became stable into the UTXO set.
+            while state::ingest_stable_blocks_into_utxoset(s) {}
+
+            if (i + 1) % 1_000 == 0 {
+                println!("Height: {}", i + 1);
+            }
+        }
+
+        println!(
+            "Main chain height: {}, stable height: {}",
+            state::main_chain_height(s),
+            s.stable_height()
+        );
+    });
+
+    // Run the pre-upgrade hook to save all the state into the memory.
+    pre_upgrade();
+
+    let mut file = match File::create(&args.output) {
+        Err(err) => panic!("couldn't create {}: {}", args.output.display(), err),
+        Ok(file) => file,
+    };
+
+    match file.write_all(&ic_btc_canister::get_memory().borrow()) {
+        Err(err) => panic!("couldn't write to {}: {}", args.output.display(), err),
+        Ok(_) => println!("successfully wrote state to {}", args.output.display()),
+    };
+}
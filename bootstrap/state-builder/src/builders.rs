@@ -0,0 +1,228 @@
+//! Building the canister's stable structures from the UTXO set.
+//!
+//! Every structure is built in its own memory and written into a file in the canister
+//! state directory. The files are then combined into the memory of the canister.
+use crate::Utxo;
+use ic_btc_canister::{
+    types::{Address, AddressUtxo, TxOut},
+    with_state, with_state_mut,
+};
+use ic_btc_interface::{Config, Flag, Network};
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager},
+    storable::Blob,
+    BoundedStorable, DefaultMemoryImpl, FileMemory, Memory, StableBTreeMap, Storable,
+};
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use std::{
+    collections::BTreeMap,
+    fs::{create_dir_all, File},
+    io::Write,
+    path::Path,
+};
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+// The amount of data to read from a file in a single read request.
+const CHUNK_SIZE: u64 = 1024 * WASM_PAGE_SIZE;
+
+/// Builds the balances of the addresses into the output file.
+pub fn build_balances(utxos: impl Iterator<Item = Utxo>, output: &Path) {
+    // Compute the balances. We use a standard BTreeMap here for speed.
+    let mut balances: BTreeMap<Address, u64> = BTreeMap::new();
+    for (i, utxo) in utxos.enumerate() {
+        if i % 100_000 == 0 {
+            println!("Processed {} UTXOs", i);
+        }
+
+        if let Some(address) = utxo.address {
+            // Update the balance of the address.
+            if utxo.value != 0 {
+                balances
+                    .entry(address)
+                    .and_modify(|curr| *curr += utxo.value)
+                    .or_insert(utxo.value);
+            }
+        }
+    }
+
+    // Shuffle the balances. Based on anecdotal evidence, inserting the elements in a random
+    // order is ~40% more space efficient than inserting the elements in sorted order.
+    println!("Shuffling...");
+    let mut balances: Vec<_> = balances.into_iter().collect();
+    let mut rng = ChaCha8Rng::seed_from_u64(1);
+    balances.shuffle(&mut rng);
+
+    println!("Writing to stable structure...");
+    let memory = DefaultMemoryImpl::default();
+    let mut stable_balances: StableBTreeMap<Address, u64, _> = StableBTreeMap::init(memory.clone());
+
+    // Write the balances into a stable btreemap.
+    for (address, amount) in balances.into_iter() {
+        stable_balances.insert(address, amount).unwrap();
+    }
+
+    println!("Writing stable structure to file...");
+    write_file(output, &memory.borrow());
+}
+
+/// Builds the index of the UTXOs of every address into the output file.
+pub fn build_address_utxos(utxos: impl Iterator<Item = Utxo>, output: &Path) {
+    let memory = DefaultMemoryImpl::default();
+    let mut address_utxos: StableBTreeMap<Blob<{ AddressUtxo::MAX_SIZE as usize }>, (), _> =
+        StableBTreeMap::init(memory.clone());
+
+    for (i, utxo) in utxos.enumerate() {
+        if i % 100_000 == 0 {
+            println!("Processed {} UTXOs", i);
+        }
+
+        if let Some(address) = utxo.address {
+            address_utxos
+                .insert(
+                    Blob::try_from(
+                        AddressUtxo {
+                            address,
+                            height: utxo.height,
+                            outpoint: utxo.outpoint,
+                        }
+                        .to_bytes()
+                        .as_ref(),
+                    )
+                    .unwrap(),
+                    (),
+                )
+                .unwrap();
+        }
+    }
+
+    println!("Writing stable structure to file...");
+    write_file(output, &memory.borrow());
+}
+
+/// Builds the UTXOs into the output directory.
+///
+/// The small and medium UTXOs are stable structures, and are written as-is into the
+/// `small_utxos` and `medium_utxos` files. The large UTXOs are serialized into `large_utxos`.
+pub fn build_utxos(utxos: impl Iterator<Item = Utxo>, network: Network, output_dir: &Path) {
+    // Create the output directory if it doesn't already exist.
+    create_dir_all(output_dir).unwrap();
+
+    ic_btc_canister::init(Config {
+        network,
+        api_access: Flag::Disabled,
+        ..Config::default()
+    });
+
+    with_state_mut(|s| {
+        for (i, utxo) in utxos.enumerate() {
+            if i % 100_000 == 0 {
+                println!("Processed {} UTXOs", i);
+            }
+
+            if !bitcoin::Script::from(utxo.script.clone()).is_provably_unspendable() {
+                let txout = TxOut {
+                    value: utxo.value,
+                    script_pubkey: utxo.script,
+                };
+
+                let found = s.utxos.utxos.insert(utxo.outpoint, (txout, utxo.height));
+                assert!(!found); // A UTXO cannot be seen more than once.
+            }
+        }
+    });
+
+    // Write the memories corresponding to the small and medium UTXOs.
+    // These are stable structures so we write the memory as-is.
+    println!("Writing small UTXOs...");
+    write_canister_memory_to_file(&output_dir.join("small_utxos"), MemoryId::new(2));
+
+    println!("Writing medium UTXOs...");
+    write_canister_memory_to_file(&output_dir.join("medium_utxos"), MemoryId::new(3));
+
+    // Write the large UTXOs, which is a standard BTreeMap so it needs to
+    // be serialized.
+    println!("Writing large UTXOs...");
+    with_state(|s| {
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&s.utxos.utxos.large_utxos, &mut bytes)
+            .expect("failed to encode large utxos");
+        write_file(&output_dir.join("large_utxos"), &bytes);
+    });
+}
+
+/// Combines the stable structures in the canister state directory into the memory
+/// of the canister.
+pub fn combine_state(canister_state_dir: &Path, output: &Path) {
+    // Create the file memory of the whole canister.
+    let memory = FileMemory::new(File::create(output).expect("Cannot create output file."));
+    let memory_manager = MemoryManager::init(memory);
+
+    // Add the various memories.
+    write_memory(
+        &memory_manager,
+        1,
+        &canister_state_dir.join("address_utxos"),
+    );
+    write_memory(&memory_manager, 2, &canister_state_dir.join("small_utxos"));
+    write_memory(&memory_manager, 3, &canister_state_dir.join("medium_utxos"));
+    write_memory(&memory_manager, 4, &canister_state_dir.join("balances"));
+}
+
+fn write_memory(memory_manager: &MemoryManager<FileMemory>, memory_id: u8, memory: &Path) {
+    println!("Writing memory {}..", memory_id);
+    let dst = memory_manager.get(MemoryId::new(memory_id));
+
+    let src = FileMemory::new(File::open(memory).unwrap());
+    dst.grow(src.size());
+
+    let src_size_in_bytes = src.size() * WASM_PAGE_SIZE;
+    println!("Memory size: {}", src_size_in_bytes);
+
+    // Read the file in small chunks.
+    let mut bytes_read = 0;
+    let mut buf = vec![0; CHUNK_SIZE as usize];
+    while bytes_read + CHUNK_SIZE <= src_size_in_bytes {
+        src.read(bytes_read, &mut buf);
+        dst.write(bytes_read, &buf);
+
+        bytes_read += buf.len() as u64;
+        println!("Wrote {} bytes", bytes_read);
+    }
+
+    if src_size_in_bytes - bytes_read != 0 {
+        // Read remaining bytes.
+        let mut buf = vec![0; (src_size_in_bytes - bytes_read) as usize];
+        src.read(bytes_read, &mut buf);
+        dst.write(bytes_read, &buf);
+        bytes_read += buf.len() as u64;
+        assert_eq!(bytes_read, src_size_in_bytes);
+        println!("Wrote {} bytes", bytes_read);
+    }
+}
+
+fn write_canister_memory_to_file(path: &Path, memory_id: MemoryId) {
+    let canister_mem = ic_btc_canister::get_memory();
+    let memory_manager = MemoryManager::init(canister_mem);
+
+    let memory = memory_manager.get(memory_id);
+
+    let mut memory_vec = vec![0; (memory.size() * WASM_PAGE_SIZE).try_into().unwrap()];
+
+    memory.read(0, &mut memory_vec);
+
+    write_file(path, &memory_vec);
+}
+
+fn write_file(path: &Path, bytes: &[u8]) {
+    let mut file = match File::create(path) {
+        Err(err) => panic!("couldn't create {}: {}", path.display(), err),
+        Ok(file) => file,
+    };
+
+    match file.write_all(bytes) {
+        Err(err) => panic!("couldn't write to {}: {}", path.display(), err),
+        Ok(_) => println!("successfully wrote to {}", path.display()),
+    };
+}
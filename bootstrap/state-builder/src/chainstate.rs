@@ -0,0 +1,321 @@
+//! Reading the UTXO set directly from the chainstate database of `bitcoind`.
+//!
+//! Every UTXO is stored under the key `C || txid || VARINT(vout)`, and its value is
+//! `VARINT(height * 2 + is_coinbase) || VARINT(compressed amount) || compressed script`,
+//! XOR-ed with the database's obfuscation key. See `coins.h` and `compressor.h` in
+//! Bitcoin Core for the details of the encoding.
+use crate::Utxo;
+use bitcoin::{
+    blockdata::{opcodes::all::OP_CHECKSIG, script::Builder},
+    hashes::Hash,
+    secp256k1, PubkeyHash, PublicKey, Script, ScriptHash,
+};
+use ic_btc_canister::types::Address;
+use ic_btc_interface::Network;
+use ic_btc_types::{OutPoint, Txid};
+use rusty_leveldb::{DBIterator, LdbIterator, Options, DB};
+use std::path::Path;
+
+// The prefix of the keys of UTXOs.
+const COIN_KEY_PREFIX: u8 = b'C';
+
+// The key of the obfuscation key, which is serialized with its length as a prefix.
+const OBFUSCATE_KEY_KEY: &[u8] = b"\x0e\x00obfuscate_key";
+
+// The number of script types that are compressed into a special encoding.
+const NUM_SPECIAL_SCRIPTS: u64 = 6;
+
+/// An iterator over the UTXOs in a chainstate database.
+pub struct ChainstateReader {
+    iter: DBIterator,
+    obfuscate_key: Vec<u8>,
+    network: Network,
+    _db: DB,
+}
+
+impl ChainstateReader {
+    /// Opens the chainstate database at the given path.
+    ///
+    /// NOTE: `bitcoind` must not be running while the database is being read.
+    pub fn open(path: &Path, network: Network) -> Self {
+        assert!(
+            path.exists(),
+            "chainstate directory {} doesn't exist",
+            path.display()
+        );
+
+        let mut db = DB::open(path, Options::default()).expect("cannot open chainstate");
+
+        // The obfuscation key is serialized as a vector, i.e. prefixed with its length.
+        // Databases that were created before obfuscation was introduced have no key.
+        let obfuscate_key = db
+            .get(OBFUSCATE_KEY_KEY)
+            .map(|key| key[1..].to_vec())
+            .unwrap_or_default();
+
+        let iter = db.new_iter().expect("cannot iterate over chainstate");
+
+        Self {
+            iter,
+            obfuscate_key,
+            network,
+            _db: db,
+        }
+    }
+}
+
+impl Iterator for ChainstateReader {
+    type Item = Utxo;
+
+    fn next(&mut self) -> Option<Utxo> {
+        while self.iter.advance() {
+            let (mut key, mut value) = (vec![], vec![]);
+            self.iter.current(&mut key, &mut value);
+
+            if key.first() != Some(&COIN_KEY_PREFIX) {
+                // Skip the entries that aren't UTXOs (e.g. the best block).
+                continue;
+            }
+
+            deobfuscate(&mut value, &self.obfuscate_key);
+            return Some(decode_utxo(&key[1..], &value, self.network));
+        }
+
+        None
+    }
+}
+
+fn deobfuscate(value: &mut [u8], obfuscate_key: &[u8]) {
+    if obfuscate_key.is_empty() {
+        return;
+    }
+
+    for (i, byte) in value.iter_mut().enumerate() {
+        *byte ^= obfuscate_key[i % obfuscate_key.len()];
+    }
+}
+
+// Decodes a UTXO given its key (without the prefix) and its deobfuscated value.
+fn decode_utxo(mut key: &[u8], mut value: &[u8], network: Network) -> Utxo {
+    let txid = Txid::from(take(&mut key, 32).to_vec());
+    let vout = read_varint(&mut key) as u32;
+
+    // The lowest bit of the code denotes whether or not the output is a coinbase.
+    let code = read_varint(&mut value);
+    let height = (code >> 1) as u32;
+    let value_sats = decompress_amount(read_varint(&mut value));
+    let script = decompress_script(&mut value);
+
+    let address = Address::from_script(&Script::from(script.clone()), network).ok();
+
+    Utxo {
+        outpoint: OutPoint { txid, vout },
+        height,
+        value: value_sats,
+        script,
+        address,
+    }
+}
+
+// Reads Bitcoin Core's VARINT, which is an MSB base-128 encoding where every byte but
+// the last one is offset by one to make the encoding of every number unique.
+fn read_varint(bytes: &mut &[u8]) -> u64 {
+    let mut n = 0;
+    loop {
+        let byte = take(bytes, 1)[0];
+        n = (n << 7) | (byte & 0x7F) as u64;
+        if byte & 0x80 == 0 {
+            return n;
+        }
+        n += 1;
+    }
+}
+
+// Decompresses an amount that was compressed with Bitcoin Core's `CompressAmount`.
+fn decompress_amount(x: u64) -> u64 {
+    if x == 0 {
+        return 0;
+    }
+
+    // x = 1 + 10 * (9 * n + d - 1) + e, or x = 1 + 10 * (n - 1) + 9.
+    let mut x = x - 1;
+    let mut e = x % 10;
+    x /= 10;
+    let mut n = if e < 9 {
+        let d = (x % 9) + 1;
+        x /= 9;
+        x * 10 + d
+    } else {
+        x + 1
+    };
+
+    while e > 0 {
+        n *= 10;
+        e -= 1;
+    }
+    n
+}
+
+// Decompresses a script that was compressed with Bitcoin Core's `ScriptCompression`.
+fn decompress_script(bytes: &mut &[u8]) -> Vec<u8> {
+    let size = read_varint(bytes);
+    let script = match size {
+        0 => Script::new_p2pkh(&PubkeyHash::from_slice(take(bytes, 20)).unwrap()),
+        1 => Script::new_p2sh(&ScriptHash::from_slice(take(bytes, 20)).unwrap()),
+        2 | 3 => {
+            // Compressed public keys are stored as-is. They aren't necessarily points on
+            // the curve, so the script is rebuilt without parsing them.
+            let mut key = vec![size as u8];
+            key.extend_from_slice(take(bytes, 32));
+            Builder::new()
+                .push_slice(&key)
+                .push_opcode(OP_CHECKSIG)
+                .into_script()
+        }
+        4 | 5 => {
+            // Uncompressed public keys are stored compressed, with the parity of `y`
+            // encoded in the size.
+            let mut key = vec![(size - 2) as u8];
+            key.extend_from_slice(take(bytes, 32));
+            let key = secp256k1::PublicKey::from_slice(&key).expect("public key must be valid");
+            Script::new_p2pk(&PublicKey::new_uncompressed(key))
+        }
+        size => Script::from(take(bytes, (size - NUM_SPECIAL_SCRIPTS) as usize).to_vec()),
+    };
+
+    script.to_bytes()
+}
+
+// Takes the given number of bytes from the front of the slice.
+fn take<'a>(bytes: &mut &'a [u8], len: usize) -> &'a [u8] {
+    assert!(bytes.len() >= len, "unexpected end of chainstate entry");
+    let (front, rest) = bytes.split_at(len);
+    *bytes = rest;
+    front
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // The x coordinate of secp256k1's generator, whose y coordinate is even.
+    const G_X: &str = "79be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798";
+    const G_Y: &str = "483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+
+    // An x coordinate that isn't on secp256k1, as 5^3 + 7 isn't a square modulo p.
+    const NOT_ON_CURVE_X: &str = "0000000000000000000000000000000000000000000000000000000000000005";
+
+    #[test]
+    fn reads_varints() {
+        for (bytes, n) in [
+            (vec![0x00], 0),
+            (vec![0x7f], 127),
+            (vec![0x80, 0x00], 128),
+            (vec![0x80, 0x7f], 255),
+            (vec![0xfe, 0x7f], 16383),
+            (vec![0xff, 0x00], 16384),
+        ] {
+            assert_eq!(read_varint(&mut bytes.as_slice()), n);
+        }
+    }
+
+    #[test]
+    fn decompresses_amounts() {
+        // Test vectors from Bitcoin Core's `compress_tests.cpp`.
+        const CENT: u64 = 1_000_000;
+        const COIN: u64 = 100_000_000;
+        for (compressed, amount) in [
+            (0x0, 0),
+            (0x1, 1),
+            (0x7, CENT),
+            (0x9, COIN),
+            (0x32, 50 * COIN),
+            (0x1406f40, 21_000_000 * COIN),
+        ] {
+            assert_eq!(decompress_amount(compressed), amount);
+        }
+    }
+
+    #[test]
+    fn decompresses_scripts() {
+        let hash = [7; 20];
+        let mut p2pkh = vec![0x00];
+        p2pkh.extend(hash);
+        assert_eq!(
+            decompress_script(&mut p2pkh.as_slice()),
+            Script::new_p2pkh(&PubkeyHash::from_slice(&hash).unwrap()).to_bytes()
+        );
+
+        let mut p2sh = vec![0x01];
+        p2sh.extend(hash);
+        assert_eq!(
+            decompress_script(&mut p2sh.as_slice()),
+            Script::new_p2sh(&ScriptHash::from_slice(&hash).unwrap()).to_bytes()
+        );
+
+        let mut compressed_p2pk = vec![0x02];
+        compressed_p2pk.extend(hex::decode(G_X).unwrap());
+        assert_eq!(
+            decompress_script(&mut compressed_p2pk.as_slice()),
+            hex::decode(format!("2102{}ac", G_X)).unwrap()
+        );
+
+        // Compressed public keys are restored even if they aren't on the curve.
+        let mut invalid_compressed_p2pk = vec![0x03];
+        invalid_compressed_p2pk.extend(hex::decode(NOT_ON_CURVE_X).unwrap());
+        assert_eq!(
+            decompress_script(&mut invalid_compressed_p2pk.as_slice()),
+            hex::decode(format!("2103{}ac", NOT_ON_CURVE_X)).unwrap()
+        );
+
+        let mut uncompressed_p2pk = vec![0x04];
+        uncompressed_p2pk.extend(hex::decode(G_X).unwrap());
+        assert_eq!(
+            decompress_script(&mut uncompressed_p2pk.as_slice()),
+            hex::decode(format!("4104{}{}ac", G_X, G_Y)).unwrap()
+        );
+
+        // Other scripts are stored as-is, with their size offset by the special scripts.
+        let op_true = vec![0x07, 0x51];
+        assert_eq!(decompress_script(&mut op_true.as_slice()), vec![0x51]);
+    }
+
+    #[test]
+    fn deobfuscates_values() {
+        let mut value = vec![0x00, 0xff, 0x0f, 0x00];
+        deobfuscate(&mut value, &[0xaa, 0xbb, 0xcc]);
+        assert_eq!(value, vec![0xaa, 0x44, 0xc3, 0xaa]);
+    }
+
+    #[test]
+    fn decodes_utxos() {
+        let txid = [1; 32];
+        let mut key = txid.to_vec();
+        key.push(0x80); // vout 128
+        key.push(0x00);
+
+        // A coinbase output at height 100 with 50 BTC paying to a P2PKH.
+        let mut value = vec![0x80, 0x49, 0x32, 0x00];
+        value.extend([7; 20]);
+
+        let utxo = decode_utxo(&key, &value, Network::Mainnet);
+        assert_eq!(
+            utxo.outpoint,
+            OutPoint {
+                txid: Txid::from(txid.to_vec()),
+                vout: 128
+            }
+        );
+        assert_eq!(utxo.height, 100);
+        assert_eq!(utxo.value, 5_000_000_000);
+        let pubkey_hash = PubkeyHash::from_slice(&[7; 20]).unwrap();
+        assert_eq!(utxo.script, Script::new_p2pkh(&pubkey_hash).to_bytes());
+        assert_eq!(
+            utxo.address,
+            Some(Address::from(bitcoin::Address {
+                network: bitcoin::Network::Bitcoin,
+                payload: bitcoin::util::address::Payload::PubkeyHash(pubkey_hash),
+            }))
+        );
+    }
+}
@@ -0,0 +1,76 @@
+//! A script for computing the Bitcoin canister's UTXO set from the chainstate database
+//! of `bitcoind`.
+//!
+//! The UTXOs are read from the chainstate, shuffled, built into the canister's stable
+//! structures, and combined into a single file.
+//!
+//! Example run:
+//!
+//! cargo run --release --bin compute-canister-state -- \
+//!   --chainstate-dir ./data/chainstate \
+//!   --network mainnet \
+//!   --canister-state-dir ./canister_state \
+//!   --output canister_state.bin
+use clap::Parser;
+use ic_btc_interface::Network;
+use state_builder::{
+    builders::{build_address_utxos, build_balances, build_utxos, combine_state},
+    chainstate::ChainstateReader,
+    shuffle::{read_utxos, shuffle},
+};
+use std::{fs::create_dir_all, path::PathBuf};
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// The chainstate directory of `bitcoind`.
+    #[clap(long, value_hint = clap::ValueHint::DirPath)]
+    chainstate_dir: PathBuf,
+
+    /// The bitcoin network.
+    #[clap(long)]
+    network: Network,
+
+    /// The directory to store the intermediate state in.
+    #[clap(long, value_hint = clap::ValueHint::DirPath)]
+    canister_state_dir: PathBuf,
+
+    /// The path to store the canister's memory in.
+    #[clap(long, value_hint = clap::ValueHint::FilePath)]
+    output: PathBuf,
+}
+
+fn main() {
+    let args = Args::parse();
+    create_dir_all(&args.canister_state_dir).unwrap();
+    let utxos_path = args.canister_state_dir.join("utxos_shuffled");
+
+    println!("Reading and shuffling the UTXOs...");
+    let (num_utxos, checksum) = shuffle(
+        ChainstateReader::open(&args.chainstate_dir, args.network),
+        &args.canister_state_dir.join("shuffle"),
+        &utxos_path,
+    );
+    println!("Shuffled {} UTXOs. Checksum: {}", num_utxos, checksum);
+
+    println!("Computing balances...");
+    build_balances(
+        read_utxos(&utxos_path),
+        &args.canister_state_dir.join("balances"),
+    );
+
+    println!("Computing address UTXOs...");
+    build_address_utxos(
+        read_utxos(&utxos_path),
+        &args.canister_state_dir.join("address_utxos"),
+    );
+
+    println!("Computing UTXOs...");
+    build_utxos(
+        read_utxos(&utxos_path),
+        args.network,
+        &args.canister_state_dir,
+    );
+
+    println!("Combining the state into {}...", args.output.display());
+    combine_state(&args.canister_state_dir, &args.output);
+}
@@ -0,0 +1,12 @@
+//! Building the Bitcoin canister's state offline.
+//!
+//! The UTXO set is either read from the chainstate database of `bitcoind`, shuffled, and
+//! written into the canister's stable structures, or the state is built by replaying
+//! raw blocks through the canister.
+pub mod blocks;
+pub mod builders;
+pub mod chainstate;
+pub mod shuffle;
+mod utxo;
+
+pub use utxo::Utxo;
@@ -0,0 +1,81 @@
+//! Shuffling the UTXO set deterministically.
+//!
+//! Based on anecdotal evidence, inserting the UTXOs into the canister's stable structures
+//! in a random order is more space efficient than inserting them in sorted order, which
+//! is the order of the chainstate database.
+//!
+//! The UTXO set doesn't fit in memory, so the UTXOs are first spread randomly over a
+//! number of bucket files, and then every bucket is shuffled in memory.
+use crate::Utxo;
+use bitcoin::hashes::{sha256, Hash, HashEngine};
+use rand::prelude::*;
+use rand_chacha::ChaCha8Rng;
+use std::{
+    fs::{create_dir_all, remove_dir_all, File},
+    io::{BufReader, BufWriter, Write},
+    path::Path,
+};
+
+// The number of buckets to spread the UTXOs over. With ~200M UTXOs in mainnet, every
+// bucket holds less than 1M UTXOs.
+const NUM_BUCKETS: usize = 256;
+
+// The seed of the shuffle, which must be fixed to build the same state on every run.
+const SEED: u64 = 0;
+
+/// Shuffles the given UTXOs into the output file, using the given directory for
+/// intermediate files.
+///
+/// Returns the number of UTXOs and the SHA-256 checksum of the output file.
+pub fn shuffle(utxos: impl Iterator<Item = Utxo>, tmp_dir: &Path, output: &Path) -> (u64, String) {
+    create_dir_all(tmp_dir).unwrap();
+    let bucket_path = |i: usize| tmp_dir.join(format!("bucket_{}", i));
+
+    let mut rng = ChaCha8Rng::seed_from_u64(SEED);
+
+    // Spread the UTXOs over the buckets.
+    let mut buckets: Vec<_> = (0..NUM_BUCKETS)
+        .map(|i| BufWriter::new(File::create(bucket_path(i)).unwrap()))
+        .collect();
+    for (i, utxo) in utxos.enumerate() {
+        if i % 1_000_000 == 0 {
+            println!("Read {} UTXOs", i);
+        }
+
+        utxo.write(&mut buckets[rng.gen_range(0..NUM_BUCKETS)])
+            .unwrap();
+    }
+    for mut bucket in buckets {
+        bucket.flush().unwrap();
+    }
+
+    // Shuffle every bucket and append it to the output.
+    let mut output = BufWriter::new(File::create(output).unwrap());
+    let mut engine = sha256::Hash::engine();
+    let mut num_utxos = 0;
+    for i in 0..NUM_BUCKETS {
+        let mut bucket: Vec<_> = read_utxos(&bucket_path(i)).collect();
+        bucket.shuffle(&mut rng);
+
+        for utxo in bucket {
+            let mut bytes = vec![];
+            utxo.write(&mut bytes).unwrap();
+            engine.input(&bytes);
+            output.write_all(&bytes).unwrap();
+            num_utxos += 1;
+        }
+    }
+    output.flush().unwrap();
+    remove_dir_all(tmp_dir).unwrap();
+
+    (
+        num_utxos,
+        hex::encode(sha256::Hash::from_engine(engine).into_inner()),
+    )
+}
+
+/// Reads the UTXOs of a file written by `shuffle`.
+pub fn read_utxos(path: &Path) -> impl Iterator<Item = Utxo> {
+    let mut reader = BufReader::new(File::open(path).unwrap());
+    std::iter::from_fn(move || Utxo::read(&mut reader).expect("UTXOs file must be valid"))
+}
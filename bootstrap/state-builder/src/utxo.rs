@@ -0,0 +1,75 @@
+use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
+use ic_btc_canister::types::Address;
+use ic_btc_types::{OutPoint, Txid};
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    str::FromStr,
+};
+
+/// An unspent output, as read from the chainstate database.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Utxo {
+    pub outpoint: OutPoint,
+    pub height: u32,
+    pub value: u64,
+    pub script: Vec<u8>,
+    /// The address the output pays to, if the script has one.
+    pub address: Option<Address>,
+}
+
+impl Utxo {
+    /// Writes the UTXO in the format of the shuffled UTXOs file.
+    pub fn write(&self, writer: &mut impl Write) -> io::Result<()> {
+        writer.write_all(self.outpoint.txid.as_bytes())?;
+        writer.write_u32::<LittleEndian>(self.outpoint.vout)?;
+        writer.write_u32::<LittleEndian>(self.height)?;
+        writer.write_u64::<LittleEndian>(self.value)?;
+        writer.write_u32::<LittleEndian>(self.script.len() as u32)?;
+        writer.write_all(&self.script)?;
+
+        // Addresses are never empty, so an empty address denotes the lack of one.
+        let address = self
+            .address
+            .as_ref()
+            .map(|a| a.to_string())
+            .unwrap_or_default();
+        writer.write_u8(address.len() as u8)?;
+        writer.write_all(address.as_bytes())
+    }
+
+    /// Reads a UTXO written with `write`. Returns `None` at the end of the input.
+    pub fn read(reader: &mut impl Read) -> io::Result<Option<Self>> {
+        let mut txid = vec![0; 32];
+        match reader.read_exact(&mut txid) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+            result => result?,
+        };
+
+        let vout = reader.read_u32::<LittleEndian>()?;
+        let height = reader.read_u32::<LittleEndian>()?;
+        let value = reader.read_u64::<LittleEndian>()?;
+
+        let mut script = vec![0; reader.read_u32::<LittleEndian>()? as usize];
+        reader.read_exact(&mut script)?;
+
+        let mut address = vec![0; reader.read_u8()? as usize];
+        reader.read_exact(&mut address)?;
+        let address = if address.is_empty() {
+            None
+        } else {
+            let address = String::from_utf8(address).expect("address must be valid UTF-8");
+            Some(Address::from_str(&address).expect("address must be valid"))
+        };
+
+        Ok(Some(Self {
+            outpoint: OutPoint {
+                txid: Txid::from(txid),
+                vout,
+            },
+            height,
+            value,
+            script,
+            address,
+        }))
+    }
+}
@@ -0,0 +1,152 @@
+//! A script for downloading a snapshot of the Bitcoin canister's stable memory.
+//!
+//! The snapshot must first be started by a controller by calling `start_state_snapshot`
+//! on the Bitcoin canister, and the identity used here must be one of its controllers.
+//! The chunks are verified against their hashes and written into a file that can be
+//! loaded as a `FileMemory` by the canister library, or uploaded with the `upload` script.
+//!
+//! Example run:
+//!
+//! cargo run --example download -- \
+//!     --canister-id ghsi2-tqaaa-aaaan-aaaca-cai \
+//!     --identity ~/.config/dfx/identity/default/identity.pem \
+//!     --output ./canister_state.bin \
+//!     --chunk-hashes ./chunk_hashes.txt
+use candid::{Decode, Encode};
+use clap::Parser;
+use ic_agent::{export::Principal, identity::Secp256k1Identity, Agent};
+use ic_btc_interface::{StateSnapshot, StateSnapshotChunk};
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    path::PathBuf,
+};
+use uploader::*;
+use url::Url;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// A path to write the state to.
+    #[clap(long, value_hint = clap::ValueHint::DirPath)]
+    output: PathBuf,
+
+    /// An optional path to write the hashes of the chunks to, in the format expected
+    /// by the `uploader` canister.
+    #[clap(long, value_hint = clap::ValueHint::DirPath)]
+    chunk_hashes: Option<PathBuf>,
+
+    /// The PEM file of a controller of the canister.
+    #[clap(long, value_hint = clap::ValueHint::DirPath)]
+    identity: PathBuf,
+
+    /// Url of the IC network to connect to.
+    #[clap(long, default_value_t = Url::parse("https://ic0.app").unwrap())]
+    ic_network: Url,
+
+    /// Whether or not to fetch the root key. Should be true for testnets, false otherwise.
+    #[clap(long, default_value_t = false)]
+    fetch_root_key: bool,
+
+    /// The canister to download the state from.
+    #[clap(long)]
+    canister_id: Principal,
+}
+
+// Helper method for fetching the snapshot that is in progress.
+async fn get_snapshot(agent: &Agent, canister_id: &Principal) -> StateSnapshot {
+    let response = agent
+        .query(canister_id, "get_state_snapshot")
+        .with_arg(Encode!().unwrap())
+        .call()
+        .await
+        .expect("fetching the snapshot failed");
+    Decode!(&response, Option<StateSnapshot>)
+        .unwrap()
+        .expect("no snapshot is in progress. Call `start_state_snapshot` first.")
+}
+
+// Helper method for downloading a chunk.
+async fn download(agent: &Agent, canister_id: &Principal, index: u64) -> StateSnapshotChunk {
+    let response = agent
+        .query(canister_id, "get_state_snapshot_chunk")
+        .with_arg(Encode!(&index).unwrap())
+        .call()
+        .await
+        .expect("downloading chunk failed");
+    Decode!(&response, StateSnapshotChunk).unwrap()
+}
+
+#[async_std::main]
+async fn main() {
+    let args = Args::parse();
+
+    let identity =
+        Secp256k1Identity::from_pem_file(&args.identity).expect("identity must be valid");
+
+    // Connect to the given network.
+    #[allow(deprecated)]
+    let agent = Agent::builder()
+        .with_url(args.ic_network.to_string())
+        .with_identity(identity)
+        .build()
+        .expect("agent creation must succeed");
+
+    // Fetch root key if needed.
+    if args.fetch_root_key {
+        agent
+            .fetch_root_key()
+            .await
+            .expect("fetch root key must succeed");
+    }
+
+    let snapshot = get_snapshot(&agent, &args.canister_id).await;
+    assert_eq!(
+        snapshot.chunk_size_in_pages, CHUNK_SIZE_IN_PAGES,
+        "the chunks of the snapshot must have the same size as the uploader's"
+    );
+    println!(
+        "Downloading a snapshot at stable height {} ({} chunks)",
+        snapshot.stable_height, snapshot.num_chunks
+    );
+
+    let mut writer = BufWriter::new(File::create(&args.output).expect("cannot create output file"));
+    let mut hashes = vec![];
+    for index in 0..snapshot.num_chunks {
+        let chunk = download(&agent, &args.canister_id, index).await;
+        assert_eq!(chunk.index, index);
+
+        // Verify the chunk's bytes against its hash.
+        let actual_hash = sha256::digest(&*chunk.bytes);
+        if actual_hash != chunk.sha256 {
+            panic!(
+                "Expected digest {} for chunk {} but found {}",
+                chunk.sha256, index, actual_hash
+            );
+        }
+
+        writer.write_all(&chunk.bytes).expect("write must succeed");
+        hashes.push(chunk.sha256);
+        println!("Downloaded chunk {}/{}", index + 1, snapshot.num_chunks);
+    }
+    writer.flush().expect("flush must succeed");
+
+    // An upgrade or a new snapshot while downloading would leave the chunks inconsistent.
+    // The snapshot may have been extended in the meantime, which doesn't change its chunks.
+    assert_eq!(
+        StateSnapshot {
+            expires_at: snapshot.expires_at,
+            ..get_snapshot(&agent, &args.canister_id).await
+        },
+        snapshot,
+        "the snapshot changed while it was being downloaded"
+    );
+
+    if let Some(path) = args.chunk_hashes {
+        let mut file = File::create(path).expect("cannot create chunk hashes file");
+        for hash in hashes {
+            writeln!(file, "{}", hash).expect("write must succeed");
+        }
+    }
+
+    println!("Done. Call `finish_state_snapshot` to resume syncing.");
+}
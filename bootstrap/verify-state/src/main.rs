@@ -0,0 +1,546 @@
+//! A script for verifying a bootstrapped state of the Bitcoin canister.
+//!
+//! The state is loaded through the canister library as it would be after an upgrade, and
+//! a number of consistency checks are run against it. A JSON report of the checks is
+//! printed to stdout, and the script exits with a non-zero code if any of them fails.
+//!
+//! Optionally, the UTXO set can be compared with the output of bitcoind's
+//! `gettxoutsetinfo hash_serialized_2` at the block preceding the anchor block, including
+//! the hash of the serialized UTXO set. `hash_serialized_2` is available in bitcoind
+//! versions up to 25.
+//!
+//! Example run:
+//!
+//! cargo run --release -- \
+//!   --state ./canister_state.bin \
+//!   --next-height 800000 \
+//!   --txoutsetinfo ./txoutsetinfo.json
+use bitcoin::{
+    blockdata::constants::genesis_block,
+    consensus::serialize,
+    hashes::{sha256d, Hash, HashEngine},
+};
+use clap::Parser;
+use ic_btc_canister::{
+    post_upgrade,
+    state::State,
+    types::{into_bitcoin_network, Address, TxOut},
+    unstable_blocks, with_state, with_state_mut,
+};
+use ic_btc_interface::{Height, Network};
+use ic_btc_types::{BlockHash, OutPoint, Txid};
+use ic_stable_structures::FileMemory;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::{collections::BTreeMap, fs::File, path::PathBuf};
+
+// The subsidy of the first blocks, before any halving.
+const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+
+// Bitcoin Core doesn't track outputs with scripts larger than this, as they're unspendable.
+const MAX_SCRIPT_SIZE: usize = 10_000;
+
+#[derive(Parser, Debug)]
+struct Args {
+    /// The canister's state, as computed by the bootstrap scripts.
+    #[clap(long, value_hint = clap::ValueHint::FilePath)]
+    state: PathBuf,
+
+    /// The expected height of the next block to ingest into the UTXO set.
+    #[clap(long)]
+    next_height: Option<Height>,
+
+    /// The output of `bitcoin-cli gettxoutsetinfo hash_serialized_2` at height `next_height - 1`.
+    #[clap(long, value_hint = clap::ValueHint::FilePath)]
+    txoutsetinfo: Option<PathBuf>,
+}
+
+// The fields of bitcoind's `gettxoutsetinfo` that are compared with the UTXO set.
+#[derive(Deserialize, Debug)]
+struct TxOutSetInfo {
+    height: Height,
+    bestblock: String,
+    txouts: u64,
+    bogosize: u64,
+    hash_serialized_2: String,
+    total_amount: f64,
+}
+
+#[derive(Serialize, Debug)]
+struct Check {
+    name: &'static str,
+    passed: bool,
+    expected: Value,
+    actual: Value,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    details: Option<String>,
+}
+
+impl Check {
+    fn new(name: &'static str, expected: Value, actual: Value) -> Self {
+        Self {
+            name,
+            passed: expected == actual,
+            expected,
+            actual,
+            details: None,
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+struct Report {
+    network: Network,
+    next_height: Height,
+    passed: bool,
+    checks: Vec<Check>,
+}
+
+// Statistics of the UTXO set, computed in a single pass over all the UTXOs.
+#[derive(Default)]
+struct UtxoStats {
+    total_supply: u64,
+    num_address_utxos: u64,
+    // The statistics below are computed the way bitcoind computes them.
+    txouts: u64,
+    total_amount: u64,
+    bogosize: u64,
+    // The hash of the tip, i.e. the latest stable block.
+    tip: Option<String>,
+    hash_serialized_2: Option<String>,
+}
+
+// Computes the `hash_serialized_2` of a UTXO set the way bitcoind computes it.
+//
+// The hash is computed over the hash of the tip, followed by the outputs of every
+// transaction, ordered by txid and vout.
+struct HashSerialized2 {
+    engine: sha256d::HashEngine,
+    // The transaction whose outputs are being collected, along with its outputs.
+    tx: Option<(Txid, BTreeMap<u32, Vec<u8>>)>,
+}
+
+impl HashSerialized2 {
+    fn new(tip: &BlockHash) -> Self {
+        let mut engine = sha256d::Hash::engine();
+        engine.input(&tip.clone().to_vec());
+        Self { engine, tx: None }
+    }
+
+    // Adds an output. Outputs must be added in the order of their txids.
+    fn add(&mut self, outpoint: &OutPoint, txout: &TxOut) {
+        if self.tx.as_ref().map(|(txid, _)| txid) != Some(&outpoint.txid) {
+            self.flush_tx();
+            self.tx = Some((outpoint.txid.clone(), BTreeMap::new()));
+        }
+
+        let mut output = vec![];
+        output.extend(varint(outpoint.vout as u64 + 1));
+        output.extend(serialize(&bitcoin::Script::from(
+            txout.script_pubkey.clone(),
+        )));
+        output.extend(varint(txout.value));
+        self.tx
+            .as_mut()
+            .expect("transaction must be set")
+            .1
+            .insert(outpoint.vout, output);
+    }
+
+    fn flush_tx(&mut self) {
+        if let Some((txid, outputs)) = self.tx.take() {
+            self.engine.input(txid.as_bytes());
+            // Bitcoind intends to write the height and coinbase flag of the transaction,
+            // but due to an operator precedence bug writes 1 for every transaction
+            // other than a non-coinbase one at height 0, which can't exist.
+            self.engine.input(&varint(1));
+            for output in outputs.values() {
+                self.engine.input(output);
+            }
+            self.engine.input(&varint(0));
+        }
+    }
+
+    fn finish(mut self) -> String {
+        self.flush_tx();
+        sha256d::Hash::from_engine(self.engine).to_string()
+    }
+}
+
+// Encodes an integer in bitcoind's `VARINT` format, a base-128 encoding where every byte
+// but the last has its most significant bit set.
+fn varint(mut n: u64) -> Vec<u8> {
+    let mut bytes = vec![];
+    loop {
+        bytes.push((n & 0x7f) as u8 | if bytes.is_empty() { 0x00 } else { 0x80 });
+        if n <= 0x7f {
+            break;
+        }
+        n = (n >> 7) - 1;
+    }
+    bytes.reverse();
+    bytes
+}
+
+fn compute_utxo_stats(state: &State) -> UtxoStats {
+    // Bitcoind doesn't include the output of the genesis block, as it can't be spent.
+    let genesis_outpoint = OutPoint::new(
+        Txid::from(
+            genesis_block(into_bitcoin_network(state.network())).txdata[0]
+                .txid()
+                .to_vec(),
+        ),
+        0,
+    );
+
+    let tip = state
+        .stable_height()
+        .checked_sub(1)
+        .and_then(|height| state.stable_block_headers.block_heights.get(&height));
+    let mut hash_serialized_2 = tip.as_ref().map(HashSerialized2::new);
+
+    let mut stats = UtxoStats::default();
+    for (i, (outpoint, (txout, _))) in state.utxos.utxos.iter_by_txid().enumerate() {
+        if i % 1_000_000 == 0 {
+            eprintln!("Processed {} UTXOs", i);
+        }
+
+        stats.total_supply += txout.value;
+
+        let script = bitcoin::Script::from(txout.script_pubkey.clone());
+        if Address::from_script(&script, state.network()).is_ok() {
+            stats.num_address_utxos += 1;
+        }
+
+        if outpoint != genesis_outpoint && script.len() <= MAX_SCRIPT_SIZE {
+            stats.txouts += 1;
+            stats.total_amount += txout.value;
+            // The size of the outpoint, the height, the value and the script, as
+            // estimated by bitcoind.
+            stats.bogosize += 32 + 4 + 4 + 8 + 2 + script.len() as u64;
+            if let Some(hash_serialized_2) = hash_serialized_2.as_mut() {
+                hash_serialized_2.add(&outpoint, &txout);
+            }
+        }
+    }
+    stats.tip = tip.map(|tip| tip.to_string());
+    stats.hash_serialized_2 = hash_serialized_2.map(HashSerialized2::finish);
+    stats
+}
+
+// Returns the sum of the subsidies of the blocks below the given height.
+fn max_supply(network: Network, height: Height) -> u64 {
+    let halving_interval = match network {
+        Network::Mainnet | Network::Testnet => 210_000,
+        Network::Regtest => 150,
+    };
+
+    (0..height)
+        .map(|h| {
+            let halvings = h / halving_interval;
+            if halvings >= 64 {
+                0
+            } else {
+                INITIAL_SUBSIDY >> halvings
+            }
+        })
+        .sum()
+}
+
+// Checks that the stable headers form a chain that starts at the genesis block and
+// that the anchor block extends it.
+fn check_header_chain(state: &State) -> Check {
+    let network = into_bitcoin_network(state.network());
+    let headers = &state.stable_block_headers;
+    let mut prev_hash = None;
+    let mut error = None;
+
+    for height in 0..state.stable_height() {
+        let block_hash = match headers.block_heights.get(&height) {
+            Some(block_hash) => block_hash,
+            None => {
+                error = Some(format!("missing the header at height {}", height));
+                break;
+            }
+        };
+        let header = match headers.get_with_block_hash(&block_hash) {
+            Some(header) => header,
+            None => {
+                error = Some(format!(
+                    "missing the header of block {}",
+                    block_hash.to_string()
+                ));
+                break;
+            }
+        };
+
+        if BlockHash::from(header.block_hash()) != block_hash {
+            error = Some(format!(
+                "the header at height {} doesn't hash to {}",
+                height,
+                block_hash.to_string()
+            ));
+            break;
+        }
+
+        let expected_prev_hash = match prev_hash {
+            Some(prev_hash) => prev_hash,
+            None => {
+                if header != genesis_block(network).header {
+                    error = Some(String::from(
+                        "the header at height 0 isn't the genesis block",
+                    ));
+                    break;
+                }
+                header.prev_blockhash
+            }
+        };
+        if header.prev_blockhash != expected_prev_hash {
+            error = Some(format!(
+                "the header at height {} doesn't extend the header at height {}",
+                height,
+                height - 1
+            ));
+            break;
+        }
+        prev_hash = Some(header.block_hash());
+    }
+
+    if error.is_none() {
+        let anchor = unstable_blocks::get_main_chain(&state.unstable_blocks).first();
+        if let Some(prev_hash) = prev_hash {
+            if anchor.header().prev_blockhash != prev_hash {
+                error = Some(String::from(
+                    "the anchor block doesn't extend the last stable header",
+                ));
+            }
+        }
+    }
+
+    let mut check = Check::new("header_chain", json!(true), json!(error.is_none()));
+    check.details = error;
+    check
+}
+
+fn check_utxo_set_audit() -> Check {
+    eprintln!("Auditing the UTXO set...");
+    let report = with_state_mut(|s| {
+        // An audit that was in progress when the state was taken only covers part of it.
+        s.utxo_set_audit.cancel();
+        s.utxo_set_audit.start(&s.utxos, 0);
+        while !s.utxo_set_audit.audit_continue(&mut s.utxos, 0) {}
+        s.utxo_set_audit
+            .last_completed()
+            .cloned()
+            .expect("audit must be completed")
+    });
+
+    let mut check = Check::new(
+        "utxo_set_audit_mismatches",
+        json!(0),
+        json!(report.num_mismatches),
+    );
+    if !report.mismatches.is_empty() {
+        check.details = Some(
+            report
+                .mismatches
+                .iter()
+                .map(|m| format!("{}: {}", m.address, m.message))
+                .collect::<Vec<_>>()
+                .join("; "),
+        );
+    }
+    check
+}
+
+fn check_txoutsetinfo(info: &TxOutSetInfo, next_height: Height, stats: &UtxoStats) -> Vec<Check> {
+    vec![
+        Check::new(
+            "bitcoind_next_height",
+            json!(info.height + 1),
+            json!(next_height),
+        ),
+        Check::new(
+            "bitcoind_bestblock",
+            json!(info.bestblock),
+            json!(stats.tip),
+        ),
+        Check::new("bitcoind_txouts", json!(info.txouts), json!(stats.txouts)),
+        Check::new(
+            "bitcoind_total_amount",
+            json!((info.total_amount * 100_000_000.0).round() as u64),
+            json!(stats.total_amount),
+        ),
+        Check::new(
+            "bitcoind_bogosize",
+            json!(info.bogosize),
+            json!(stats.bogosize),
+        ),
+        Check::new(
+            "bitcoind_hash_serialized_2",
+            json!(info.hash_serialized_2),
+            json!(stats.hash_serialized_2),
+        ),
+    ]
+}
+
+fn main() {
+    let args = Args::parse();
+
+    // The state is only read, so the file is opened as read-only.
+    ic_btc_canister::memory::set_memory(FileMemory::new(
+        File::open(&args.state).expect("canister state file must be available"),
+    ));
+
+    eprintln!("Loading the state...");
+    post_upgrade();
+
+    let (network, next_height) = with_state(|s| (s.network(), s.stable_height()));
+    let mut checks = vec![];
+
+    if let Some(expected) = args.next_height {
+        checks.push(Check::new(
+            "next_height",
+            json!(expected),
+            json!(next_height),
+        ));
+    }
+
+    checks.push(Check::new(
+        "num_headers",
+        json!(next_height),
+        json!(with_state(|s| s.stable_block_headers.block_heights.len())),
+    ));
+
+    eprintln!("Verifying the block headers...");
+    checks.push(with_state(check_header_chain));
+
+    eprintln!("Computing the statistics of the UTXO set...");
+    let stats = with_state(compute_utxo_stats);
+
+    checks.push(Check::new(
+        "num_address_utxos",
+        json!(stats.num_address_utxos),
+        json!(with_state(|s| s.utxos.address_utxos_len())),
+    ));
+
+    checks.push(check_utxo_set_audit());
+
+    let max_supply = max_supply(network, next_height);
+    checks.push(Check {
+        name: "total_supply",
+        passed: stats.total_supply <= max_supply,
+        expected: json!(format!("<= {}", max_supply)),
+        actual: json!(stats.total_supply),
+        details: None,
+    });
+
+    if let Some(path) = args.txoutsetinfo {
+        let info: TxOutSetInfo =
+            serde_json::from_reader(File::open(path).expect("txoutsetinfo file must be available"))
+                .expect("txoutsetinfo must be valid");
+        checks.extend(check_txoutsetinfo(&info, next_height, &stats));
+    }
+
+    let report = Report {
+        network,
+        next_height,
+        passed: checks.iter().all(|check| check.passed),
+        checks,
+    };
+
+    println!("{}", serde_json::to_string_pretty(&report).unwrap());
+
+    if !report.passed {
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn max_supply_follows_the_subsidy_schedule() {
+        assert_eq!(max_supply(Network::Mainnet, 0), 0);
+        assert_eq!(max_supply(Network::Mainnet, 1), INITIAL_SUBSIDY);
+        assert_eq!(
+            max_supply(Network::Mainnet, 210_001),
+            210_000 * INITIAL_SUBSIDY + INITIAL_SUBSIDY / 2
+        );
+        assert_eq!(
+            max_supply(Network::Regtest, 300),
+            150 * INITIAL_SUBSIDY + 150 * INITIAL_SUBSIDY / 2
+        );
+    }
+
+    #[test]
+    fn compares_with_txoutsetinfo() {
+        let info = TxOutSetInfo {
+            height: 99,
+            bestblock: String::from("ab"),
+            txouts: 2,
+            bogosize: 150,
+            hash_serialized_2: String::from("cd"),
+            total_amount: 0.1,
+        };
+        let stats = UtxoStats {
+            txouts: 2,
+            total_amount: 10_000_000,
+            bogosize: 150,
+            tip: Some(String::from("ab")),
+            hash_serialized_2: Some(String::from("cd")),
+            ..UtxoStats::default()
+        };
+        assert!(check_txoutsetinfo(&info, 100, &stats)
+            .iter()
+            .all(|check| check.passed));
+
+        let checks = check_txoutsetinfo(&info, 101, &stats);
+        assert!(!checks[0].passed);
+        assert!(checks[1..].iter().all(|check| check.passed));
+    }
+
+    #[test]
+    fn encodes_varints_like_bitcoind() {
+        // The examples in bitcoind's `serialize.h`.
+        assert_eq!(varint(0), vec![0x00]);
+        assert_eq!(varint(1), vec![0x01]);
+        assert_eq!(varint(127), vec![0x7f]);
+        assert_eq!(varint(128), vec![0x80, 0x00]);
+        assert_eq!(varint(255), vec![0x80, 0x7f]);
+        assert_eq!(varint(256), vec![0x81, 0x00]);
+        assert_eq!(varint(16383), vec![0xfe, 0x7f]);
+        assert_eq!(varint(16384), vec![0xff, 0x00]);
+        assert_eq!(varint(16511), vec![0xff, 0x7f]);
+        assert_eq!(varint(65535), vec![0x82, 0xfe, 0x7f]);
+        assert_eq!(varint(1 << 32), vec![0x8e, 0xfe, 0xfe, 0xff, 0x00]);
+    }
+
+    #[test]
+    fn hashes_the_outputs_of_a_transaction_in_order() {
+        let tip = BlockHash::from(vec![1; 32]);
+        let txid = Txid::from(vec![2; 32]);
+        let txout = |value| TxOut {
+            value,
+            script_pubkey: vec![0x51],
+        };
+
+        // Outputs are hashed by vout, regardless of the order in which they're added.
+        let mut hash = HashSerialized2::new(&tip);
+        hash.add(&OutPoint::new(txid.clone(), 256), &txout(2));
+        hash.add(&OutPoint::new(txid.clone(), 1), &txout(1));
+
+        let mut expected = sha256d::Hash::engine();
+        expected.input(&[1; 32]);
+        expected.input(&[2; 32]);
+        expected.input(&[0x01]); // The height and coinbase flag.
+        expected.input(&[0x02, 0x01, 0x51, 0x01]); // vout 1, script, value 1.
+        expected.input(&[0x81, 0x01, 0x01, 0x51, 0x02]); // vout 256, script, value 2.
+        expected.input(&[0x00]);
+        assert_eq!(
+            hash.finish(),
+            sha256d::Hash::from_engine(expected).to_string()
+        );
+    }
+}
@@ -4,7 +4,7 @@ use crate::{
     unstable_blocks::UnstableBlocks,
     UtxoSet,
 };
-use ic_btc_types::{Block, OutPoint};
+use ic_btc_types::{BlockHash, OutPoint};
 use std::{collections::BTreeSet, sync::Arc};
 
 /// A struct that tracks the UTXO set of a given address.
@@ -44,21 +44,23 @@ impl<'a> AddressUtxoSet<'a> {
         }
     }
 
-    pub fn apply_block(&mut self, block: &Block) {
+    /// Applies the changes that the unstable block with the given hash made to the
+    /// UTXOs of the address.
+    pub fn apply_block(&mut self, block_hash: &BlockHash) {
         for outpoint in self
             .unstable_blocks
-            .get_removed_outpoints(&block.block_hash(), &self.address)
+            .get_removed_outpoints(block_hash, &self.address)
         {
-            self.removed_outpoints.insert(outpoint.clone());
+            self.removed_outpoints.insert(outpoint);
         }
 
         for outpoint in self
             .unstable_blocks
-            .get_added_outpoints(&block.block_hash(), &self.address)
+            .get_added_outpoints(block_hash, &self.address)
         {
             let (txout, height) = self
                 .unstable_blocks
-                .get_tx_out(outpoint)
+                .get_tx_out(&outpoint)
                 .unwrap_or_else(|| {
                     panic!(
                         "tx out for outpoint {:?} must exist in added outpoints",
@@ -66,7 +68,7 @@ impl<'a> AddressUtxoSet<'a> {
                     );
                 });
             self.added_utxos.insert(Utxo {
-                outpoint: outpoint.clone(),
+                outpoint,
                 value: txout.value,
                 height,
             });
@@ -130,7 +132,7 @@ mod test {
         let utxo_set = UtxoSet::new(network);
 
         // Create a genesis block where 1000 satoshis are given to address 1.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .build();
 
@@ -142,7 +144,7 @@ mod test {
 
         let mut address_utxo_set = AddressUtxoSet::new(address_1, &utxo_set, &unstable_blocks);
 
-        address_utxo_set.apply_block(&block_0);
+        address_utxo_set.apply_block(&block_0.block_hash());
 
         // Address should have that data.
         assert_eq!(
@@ -168,7 +170,7 @@ mod test {
         let utxo_set = UtxoSet::new(network);
 
         // Create a genesis block where 1000 satoshis are given to address 1.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .build();
         let block_0 = BlockBuilder::genesis()
@@ -188,14 +190,14 @@ mod test {
         unstable_blocks::push(&mut unstable_blocks, &utxo_set, block_1.clone()).unwrap();
 
         let mut address_utxo_set = AddressUtxoSet::new(address_1, &utxo_set, &unstable_blocks);
-        address_utxo_set.apply_block(&block_0);
-        address_utxo_set.apply_block(&block_1);
+        address_utxo_set.apply_block(&block_0.block_hash());
+        address_utxo_set.apply_block(&block_1.block_hash());
 
         assert_eq!(address_utxo_set.into_iter(None).collect::<Vec<_>>(), vec![]);
 
         let mut address_2_utxo_set = AddressUtxoSet::new(address_2, &utxo_set, &unstable_blocks);
-        address_2_utxo_set.apply_block(&block_0);
-        address_2_utxo_set.apply_block(&block_1);
+        address_2_utxo_set.apply_block(&block_0.block_hash());
+        address_2_utxo_set.apply_block(&block_1.block_hash());
 
         assert_eq!(
             address_2_utxo_set.into_iter(None).collect::<Vec<_>>(),
@@ -220,7 +222,7 @@ mod test {
 
         // Create a genesis block where 2000 satoshis are given to address 1
         // in two different outputs.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .with_output(&address_1, 1000)
             .build();
@@ -245,12 +247,12 @@ mod test {
         unstable_blocks::push(&mut unstable_blocks, &utxo_set, block_1.clone()).unwrap();
 
         let mut address_1_utxo_set = AddressUtxoSet::new(address_1, &utxo_set, &unstable_blocks);
-        address_1_utxo_set.apply_block(&block_0);
-        address_1_utxo_set.apply_block(&block_1);
+        address_1_utxo_set.apply_block(&block_0.block_hash());
+        address_1_utxo_set.apply_block(&block_1.block_hash());
 
         let mut address_2_utxo_set = AddressUtxoSet::new(address_2, &utxo_set, &unstable_blocks);
-        address_2_utxo_set.apply_block(&block_0);
-        address_2_utxo_set.apply_block(&block_1);
+        address_2_utxo_set.apply_block(&block_0.block_hash());
+        address_2_utxo_set.apply_block(&block_1.block_hash());
 
         // Address 1 should have one UTXO corresponding to the remaining amount
         // it gave back to itself.
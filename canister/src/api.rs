@@ -1,14 +1,29 @@
 mod fee_percentiles;
 mod get_balance;
 mod get_utxos;
+mod logs;
 mod metrics;
 mod send_transaction;
 mod set_config;
+mod state_snapshot;
+mod unstable_block_tree;
+mod utxo_set_audit;
 pub use fee_percentiles::get_current_fee_percentiles;
 pub use get_balance::get_balance;
 pub use get_balance::get_balance_query;
 pub use get_utxos::get_utxos;
 pub use get_utxos::get_utxos_query;
+pub use logs::get_logs;
+pub use logs::get_logs_http;
 pub use metrics::get_metrics;
 pub use send_transaction::send_transaction;
-pub use set_config::set_config;
+pub use set_config::{set_config, SetConfigError};
+pub use state_snapshot::{
+    extend_state_snapshot, finish_state_snapshot, get_state_snapshot, get_state_snapshot_chunk,
+    start_state_snapshot, StateSnapshotError,
+};
+pub use unstable_block_tree::get_unstable_block_tree;
+pub use unstable_block_tree::get_unstable_block_tree_dot;
+pub use unstable_block_tree::get_unstable_block_tree_json;
+pub use utxo_set_audit::get_utxo_set_audit_status;
+pub use utxo_set_audit::start_utxo_set_audit;
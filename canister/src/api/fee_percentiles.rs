@@ -1,20 +1,24 @@
 use crate::{
-    charge_cycles,
-    runtime::{performance_counter, print},
+    blocktree::BlockMetadata,
+    charge_cycles, logs,
+    metrics::Endpoint,
+    runtime::performance_counter,
     state::{FeePercentilesCache, State},
     unstable_blocks::{self, UnstableBlocks},
-    verify_has_enough_cycles, with_state, with_state_mut,
+    with_state, with_state_mut,
 };
-use ic_btc_interface::MillisatoshiPerByte;
-use ic_btc_types::{Block, Transaction};
+use ic_btc_interface::{LogComponent, MillisatoshiPerByte};
+use ic_btc_types::Transaction;
 
 /// The number of transactions to include in the percentiles calculation.
 const NUM_TRANSACTIONS: u32 = 10_000;
 
 /// Returns the 100 fee percentiles of the chain's 10,000 most recent transactions.
 pub fn get_current_fee_percentiles() -> Vec<MillisatoshiPerByte> {
-    verify_has_enough_cycles(with_state(|s| s.fees.get_current_fee_percentiles_maximum));
-    charge_cycles(with_state(|s| s.fees.get_current_fee_percentiles));
+    charge_cycles(
+        Endpoint::GetCurrentFeePercentiles,
+        with_state(|s| s.fees.get_current_fee_percentiles),
+    );
 
     let res = with_state_mut(|s| get_current_fee_percentiles_internal(s, NUM_TRANSACTIONS));
 
@@ -23,12 +27,18 @@ pub fn get_current_fee_percentiles() -> Vec<MillisatoshiPerByte> {
     with_state_mut(|s| {
         s.metrics
             .get_current_fee_percentiles_total
-            .observe(ins_total)
+            .observe(ins_total);
+        s.metrics
+            .get_current_fee_percentiles_total_fine
+            .observe(ins_total);
     });
-    print(&format!(
-        "[INSTRUCTION COUNT] get_current_fee_percentiles: {}",
-        ins_total
-    ));
+    logs::debug(
+        LogComponent::Api,
+        &format!(
+            "[INSTRUCTION COUNT] get_current_fee_percentiles: {}",
+            ins_total
+        ),
+    );
     res
 }
 
@@ -65,7 +75,7 @@ fn get_current_fee_percentiles_internal(
 /// Fees are returned in a reversed order, starting with the most recent ones, followed by the older ones.
 /// Eg. for transactions [..., Tn-2, Tn-1, Tn] fees would be [Fn, Fn-1, Fn-2, ...].
 fn get_fees_per_byte(
-    main_chain: Vec<&Block>,
+    main_chain: Vec<&BlockMetadata>,
     unstable_blocks: &UnstableBlocks,
     number_of_transactions: u32,
 ) -> Vec<MillisatoshiPerByte> {
@@ -75,6 +85,10 @@ fn get_fees_per_byte(
         if tx_i >= number_of_transactions {
             break;
         }
+        // Blocks are loaded from stable memory only once their transactions are needed.
+        let block = unstable_blocks
+            .get_block(&block.block_hash())
+            .expect("blocks of the main chain must be stored");
         for tx in block.txdata() {
             if tx_i >= number_of_transactions {
                 break;
@@ -154,7 +168,7 @@ mod test {
     };
     use bitcoin::Witness;
     use ic_btc_interface::{Config, Fees, Network, Satoshi};
-    use ic_btc_types::OutPoint;
+    use ic_btc_types::{Block, OutPoint};
     use std::iter::FromIterator;
 
     /// Covers an inclusive range of `[0, 100]` percentiles.
@@ -234,7 +248,7 @@ mod test {
         let address_1 = random_p2pkh_address(network);
         let address_2 = random_p2pkh_address(network);
 
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(1)
             .with_output(&address_1, initial_balance)
             .build();
         let block_0 = BlockBuilder::with_prev_header(genesis_block(network).header())
@@ -336,7 +350,7 @@ mod test {
         let fee = 1;
         let fee_in_millisatoshi = fee * 1000;
 
-        let tx_1 = TransactionBuilder::coinbase()
+        let tx_1 = TransactionBuilder::coinbase(1)
             .with_output(&random_p2pkh_address(Network::Regtest), balance)
             .build();
         let tx_2 = TransactionBuilder::new()
@@ -526,7 +540,7 @@ mod test {
         let fee = 1;
         let fee_in_millisatoshi = 1000;
 
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(1)
             .with_output(&random_p2pkh_address(Network::Regtest), balance)
             .build();
 
@@ -550,12 +564,16 @@ mod test {
         assert_ne!(tx.vsize(), tx.size());
         assert_eq!(tx_without_witness.vsize(), tx_without_witness.size());
 
-        let blocks = vec![
-            BlockBuilder::with_prev_header(genesis_block(Network::Regtest).header())
-                .with_transaction(coinbase_tx)
-                .with_transaction(tx.clone())
-                .build(),
-        ];
+        // The witness commitment changes the coinbase of the block with the witness, so the
+        // transaction spends the coinbase of the previous block.
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(Network::Regtest).header())
+            .with_transaction(coinbase_tx)
+            .build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header())
+            .with_transaction(TransactionBuilder::coinbase(2).build())
+            .with_transaction(tx.clone())
+            .build();
+        let blocks = vec![block_1, block_2];
 
         let stability_threshold = blocks.len() as u128;
         init_state(blocks, stability_threshold);
@@ -1,10 +1,11 @@
 use crate::{
-    charge_cycles,
-    runtime::{performance_counter, print},
+    charge_cycles, logs,
+    metrics::Endpoint,
+    runtime::performance_counter,
     types::{Address, GetBalanceRequest},
-    unstable_blocks, verify_has_enough_cycles, with_state, with_state_mut,
+    unstable_blocks, with_state, with_state_mut,
 };
-use ic_btc_interface::{GetBalanceError, Satoshi};
+use ic_btc_interface::{GetBalanceError, LogComponent, Satoshi};
 use std::str::FromStr;
 
 // Various profiling stats for tracking the performance of `get_balance`.
@@ -19,8 +20,7 @@ struct Stats {
 
 /// Retrieves the balance of the given Bitcoin address.
 pub fn get_balance(request: GetBalanceRequest) -> Result<Satoshi, GetBalanceError> {
-    verify_has_enough_cycles(with_state(|s| s.fees.get_balance_maximum));
-    charge_cycles(with_state(|s| s.fees.get_balance));
+    charge_cycles(Endpoint::GetBalance, with_state(|s| s.fees.get_balance));
 
     get_balance_private(request)
 }
@@ -68,7 +68,7 @@ fn get_balance_private(request: GetBalanceRequest) -> Result<Satoshi, GetBalance
                 .unstable_blocks
                 .get_added_outpoints(&block.block_hash(), &address)
             {
-                let (txout, _) = state.unstable_blocks.get_tx_out(outpoint).unwrap();
+                let (txout, _) = state.unstable_blocks.get_tx_out(&outpoint).unwrap();
                 balance += txout.value;
             }
 
@@ -76,7 +76,7 @@ fn get_balance_private(request: GetBalanceRequest) -> Result<Satoshi, GetBalance
                 .unstable_blocks
                 .get_removed_outpoints(&block.block_hash(), &address)
             {
-                let (txout, _) = state.unstable_blocks.get_tx_out(outpoint).unwrap();
+                let (txout, _) = state.unstable_blocks.get_tx_out(&outpoint).unwrap();
                 balance -= txout.value;
             }
         }
@@ -92,13 +92,17 @@ fn get_balance_private(request: GetBalanceRequest) -> Result<Satoshi, GetBalance
     // Observe metrics
     with_state_mut(|s| {
         s.metrics.get_balance_total.observe(stats.ins_total);
+        s.metrics.get_balance_total_fine.observe(stats.ins_total);
         s.metrics
             .get_balance_apply_unstable_blocks
             .observe(stats.ins_apply_unstable_blocks);
     });
 
     // Print the number of instructions it took to process this request.
-    print(&format!("[INSTRUCTION COUNT] {:?}: {:?}", request, stats));
+    logs::debug(
+        LogComponent::Api,
+        &format!("[INSTRUCTION COUNT] {:?}: {:?}", request, stats),
+    );
 
     Ok(balance)
 }
@@ -159,7 +163,7 @@ mod test {
 
         // Create a block where 1000 satoshis are given to an address.
         let address = random_p2pkh_address(network);
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(1)
             .with_output(&address, 1000)
             .build();
         let block = BlockBuilder::with_prev_header(genesis_block(network).header())
@@ -242,7 +246,7 @@ mod test {
 
         // Create a chain where 1000 satoshis are given to the address_1, then
         // address_1 gives 1000 satoshis to address_2.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(1)
             .with_output(&address_1, 1000)
             .build();
         let block_0 = genesis_block(network);
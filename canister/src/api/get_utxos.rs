@@ -1,12 +1,15 @@
 use crate::{
-    blocktree::BlockChain,
-    charge_cycles,
-    runtime::{performance_counter, print},
+    blocktree::{BlockChain, BlockMetadata},
+    charge_cycles, charge_more_cycles, logs,
+    metrics::Endpoint,
+    runtime::performance_counter,
     types::{Address, GetUtxosRequest, Page, Utxo},
-    unstable_blocks, verify_has_enough_cycles, with_state, with_state_mut, State,
+    unstable_blocks, with_state, with_state_mut, State,
 };
-use ic_btc_interface::{GetUtxosError, GetUtxosResponse, Utxo as PublicUtxo, UtxosFilter};
-use ic_btc_types::{Block, BlockHash, OutPoint, Txid};
+use ic_btc_interface::{
+    GetUtxosError, GetUtxosResponse, LogComponent, Utxo as PublicUtxo, UtxosFilter,
+};
+use ic_btc_types::{BlockHash, OutPoint, Txid};
 use serde_bytes::ByteBuf;
 use std::str::FromStr;
 
@@ -39,11 +42,15 @@ fn get_utxos_private(
     request: GetUtxosRequest,
     charge_fees: bool,
 ) -> Result<GetUtxosResponse, GetUtxosError> {
-    if charge_fees {
-        verify_has_enough_cycles(with_state(|s| s.fees.get_utxos_maximum));
+    let charge = if charge_fees {
         // Charge the base fee.
-        charge_cycles(with_state(|s| s.fees.get_utxos_base));
-    }
+        Some(charge_cycles(
+            Endpoint::GetUtxos,
+            with_state(|s| s.fees.get_utxos_base),
+        ))
+    } else {
+        None
+    };
     let (res, stats) = with_state(|state| {
         match &request.filter {
             None => {
@@ -73,6 +80,7 @@ fn get_utxos_private(
     // Observe metrics
     with_state_mut(|s| {
         s.metrics.get_utxos_total.observe(stats.ins_total);
+        s.metrics.get_utxos_total_fine.observe(stats.ins_total);
         s.metrics
             .get_utxos_apply_unstable_blocks
             .observe(stats.ins_apply_unstable_blocks);
@@ -82,18 +90,21 @@ fn get_utxos_private(
     });
 
     // Charge the fee based on the number of the instructions.
-    with_state(|s| {
-        let fee = std::cmp::min(
+    let fee = with_state(|s| {
+        std::cmp::min(
             (stats.ins_total / 10) as u128 * s.fees.get_utxos_cycles_per_ten_instructions,
             s.fees.get_utxos_maximum - s.fees.get_utxos_base,
-        );
-        if charge_fees {
-            charge_cycles(fee);
-        }
+        )
     });
+    if let Some(charge) = charge {
+        charge_more_cycles(Endpoint::GetUtxos, fee, charge);
+    }
 
     // Print the number of instructions it took to process this request.
-    print(&format!("[INSTRUCTION COUNT] {:?}: {:?}", request, stats));
+    logs::debug(
+        LogComponent::Api,
+        &format!("[INSTRUCTION COUNT] {:?}: {:?}", request, stats),
+    );
     Ok(res)
 }
 
@@ -175,7 +186,7 @@ fn get_utxos_internal(
 //    stability_count(b) = d(b) if |D(b)| = 0 and d(b) - max_{b' ∈ D(b)} d(b') otherwise.
 // ```
 fn get_stability_count(
-    blocks_with_depths_on_the_same_height: &[(&Block, u32)],
+    blocks_with_depths_on_the_same_height: &[(&BlockMetadata, u32)],
     target_block: BlockHash,
 ) -> i32 {
     let mut max_depth_of_the_other_blocks = 0;
@@ -228,7 +239,7 @@ fn get_utxos_from_chain(
         }
         tip_block_hash = block.block_hash();
         tip_block_height = state.utxos.next_height() + (i as u32);
-        address_utxos.apply_block(block);
+        address_utxos.apply_block(&block.block_hash());
     }
     stats.ins_apply_unstable_blocks = performance_counter() - ins_start;
 
@@ -367,7 +378,7 @@ mod test {
         let address = random_p2pkh_address(network);
 
         // Create a block where 1000 satoshis are given to the address.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(1)
             .with_output(&address, 1000)
             .build();
         let block = BlockBuilder::with_prev_header(genesis_block(network).header())
@@ -423,12 +434,13 @@ mod test {
         let mut transactions = vec![];
         let mut blocks = vec![];
         for i in 0..num_blocks {
+            let height = (i + 1) as u32;
             let tx = if i % 2 == 0 {
-                TransactionBuilder::coinbase()
+                TransactionBuilder::coinbase(height)
                     .with_output(&address_1, i + 1)
                     .build()
             } else {
-                TransactionBuilder::coinbase()
+                TransactionBuilder::coinbase(height)
                     .with_output(&address_2, i + 1)
                     .build()
             };
@@ -523,7 +535,7 @@ mod test {
         });
 
         // Create a genesis block where 1000 satoshis are given to the address.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(1)
             .with_output(&address, 1000)
             .build();
 
@@ -576,7 +588,7 @@ mod test {
 
         // Create a block where 1000 satoshis are given to the address_1, followed
         // by a block where address_1 gives 1000 satoshis to address_2.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(1)
             .with_output(&address_1, 1000)
             .build();
         let block_0 = BlockBuilder::with_prev_header(genesis_block(network).header())
@@ -721,7 +733,7 @@ mod test {
         let address_4 = random_p2pkh_address(network);
 
         // Create a genesis block where 1000 satoshis are given to address 1.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(1)
             .with_output(&address_1, 1000)
             .build();
 
@@ -826,8 +838,8 @@ mod test {
             state::insert_block(state, block_1_prime.clone()).unwrap();
         });
 
-        // Because block 1 and block 1' contest with each other, neither of them are included
-        // in the UTXOs. Only the UTXOs of block 0 are returned.
+        // Block 1 and block 1' have the same chainwork, and block 1 remains the tip of
+        // the main chain since it was received first.
         assert_eq!(
             get_utxos(GetUtxosRequest {
                 address: address_2.to_string(),
@@ -835,9 +847,16 @@ mod test {
             })
             .unwrap(),
             GetUtxosResponse {
-                utxos: vec![],
-                tip_block_hash: block_0.block_hash().to_vec(),
-                tip_height: 1,
+                utxos: vec![Utxo {
+                    outpoint: OutPoint {
+                        txid: block_1.txdata()[1].txid().into(),
+                        vout: 0,
+                    },
+                    value: 1000,
+                    height: 2,
+                }],
+                tip_block_hash: block_1.block_hash().to_vec(),
+                tip_height: 2,
                 next_page: None,
             }
         );
@@ -849,8 +868,8 @@ mod test {
             .unwrap(),
             GetUtxosResponse {
                 utxos: vec![],
-                tip_block_hash: block_0.block_hash().to_vec(),
-                tip_height: 1,
+                tip_block_hash: block_1.block_hash().to_vec(),
+                tip_height: 2,
                 next_page: None,
             }
         );
@@ -860,7 +879,12 @@ mod test {
                 filter: None,
             })
             .unwrap(),
-            block_0_utxos
+            GetUtxosResponse {
+                utxos: vec![],
+                tip_block_hash: block_1.block_hash().to_vec(),
+                tip_height: 2,
+                next_page: None,
+            }
         );
 
         // Now extend block 1' with another block that transfers the funds to address 4.
@@ -946,7 +970,7 @@ mod test {
         let address_1 = random_p2pkh_address(network);
 
         // Create a block where 1000 satoshis are given to the address_1.
-        let tx = TransactionBuilder::coinbase()
+        let tx = TransactionBuilder::coinbase(1)
             .with_output(&address_1, 1000)
             .build();
         let block_0 = BlockBuilder::with_prev_header(genesis_block(network).header())
@@ -1020,7 +1044,7 @@ mod test {
 
         // Create a genesis block where 1000 satoshis are given to the address_1, followed
         // by a block where address_1 gives 1000 satoshis to address_2.
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .build();
         let block_0 = BlockBuilder::genesis()
@@ -1067,7 +1091,7 @@ mod test {
             let mut transactions = vec![];
             for i in 0..num_transactions {
                 transactions.push(
-                    TransactionBuilder::coinbase()
+                    TransactionBuilder::coinbase(0)
                         .with_output(&address, (i + 1) * 10)
                         .build(),
                 );
@@ -1167,21 +1191,15 @@ mod test {
                     None => BlockBuilder::genesis(),
                 };
 
-                let mut transactions = vec![];
+                // A block has a single coinbase, so the UTXOs are its outputs.
+                let mut coinbase = TransactionBuilder::coinbase(block_idx as u32);
                 for _ in 0..(num_transactions + block_idx) {
-                    transactions.push(
-                        TransactionBuilder::coinbase()
-                            .with_output(&address, value)
-                            .build()
-                    );
-                    // Vary the value of the transaction to ensure that
-                    // we get unique outpoints in the blockchain.
+                    coinbase = coinbase.with_output(&address, value);
+                    // Vary the value of the outputs to ensure that
+                    // we get unique transactions in the blockchain.
                     value += 1;
                 }
-
-                for transaction in transactions.iter() {
-                    block_builder = block_builder.with_transaction(transaction.clone());
-                }
+                block_builder = block_builder.with_transaction(coinbase.build());
 
                 let block = block_builder.build();
                 blocks.push(block.clone());
@@ -1297,8 +1315,8 @@ mod test {
 
     #[test]
     fn test_get_stability_count_single_block_on_height() {
-        let block = BlockBuilder::genesis().build();
-        let blocks_with_depths: Vec<(&Block, u32)> = vec![(&block, 1)];
+        let block = BlockMetadata::from(BlockBuilder::genesis().build());
+        let blocks_with_depths = vec![(&block, 1)];
         // Stability count should be 1.
         assert_eq!(
             get_stability_count(&blocks_with_depths, block.block_hash()),
@@ -1308,11 +1326,11 @@ mod test {
 
     #[test]
     fn test_get_stability_count_multiple_blocks_on_height() {
-        let block1 = BlockBuilder::genesis().build();
-        let block2 = BlockBuilder::genesis().build();
-        let block3 = BlockBuilder::genesis().build();
+        let block1 = BlockMetadata::from(BlockBuilder::genesis().build());
+        let block2 = BlockMetadata::from(BlockBuilder::genesis().build());
+        let block3 = BlockMetadata::from(BlockBuilder::genesis().build());
 
-        let blocks_with_depths: Vec<(&Block, u32)> = vec![(&block1, 5), (&block2, 7), (&block3, 3)];
+        let blocks_with_depths = vec![(&block1, 5), (&block2, 7), (&block3, 3)];
         // The stability_count of block1 should be 5 - 7 = -2.
         assert_eq!(
             get_stability_count(&blocks_with_depths, block1.block_hash()),
@@ -1355,9 +1373,9 @@ mod test {
             }
         });
 
-        // Because the forks are of equal length, `A`, the root of the fork,
-        // is considered the tip at zero confirmations.
-        assert_tip_at_confirmations(0, chain[0].block_hash());
+        // Because the forks have the same chainwork, `F`, the tip of the fork that was
+        // received first, is considered the tip at zero confirmations.
+        assert_tip_at_confirmations(0, chain[5].block_hash());
 
         // Extend the first fork by one block.
         let chain_6 = BlockBuilder::with_prev_header(chain[5].header()).build();
@@ -0,0 +1,103 @@
+use crate::{logs, types::HttpResponse};
+use ic_btc_interface::{GetLogsRequest, LogEntry, LogLevel};
+use serde_bytes::ByteBuf;
+use std::str::FromStr;
+
+/// Returns the canister's log entries matching the given request.
+pub fn get_logs(request: GetLogsRequest) -> Vec<LogEntry> {
+    logs::get_logs(request.level, request.since)
+}
+
+/// Returns the canister's log entries as JSON.
+///
+/// The entries can be filtered using the `level` and `since` query parameters,
+/// e.g. `/logs?level=warning&since=1690000000`.
+pub fn get_logs_http(query: &str) -> HttpResponse {
+    let request = match parse_query(query) {
+        Ok(request) => request,
+        Err(err) => {
+            return HttpResponse {
+                status_code: 400,
+                headers: vec![],
+                body: ByteBuf::from(err),
+            }
+        }
+    };
+
+    match serde_json::to_vec(&get_logs(request)) {
+        Ok(body) => HttpResponse {
+            status_code: 200,
+            headers: vec![
+                ("Content-Type".to_string(), "application/json".to_string()),
+                ("Content-Length".to_string(), body.len().to_string()),
+            ],
+            body: ByteBuf::from(body),
+        },
+        Err(err) => HttpResponse {
+            status_code: 500,
+            headers: vec![],
+            body: ByteBuf::from(format!("Failed to encode logs: {}", err)),
+        },
+    }
+}
+
+fn parse_query(query: &str) -> Result<GetLogsRequest, String> {
+    let mut request = GetLogsRequest::default();
+    for param in query.split('&').filter(|p| !p.is_empty()) {
+        let (key, value) = param.split_once('=').unwrap_or((param, ""));
+        match key {
+            "level" => {
+                request.level = Some(
+                    LogLevel::from_str(value)
+                        .map_err(|_| format!("Invalid log level: {}", value))?,
+                )
+            }
+            "since" => {
+                request.since = Some(
+                    value
+                        .parse()
+                        .map_err(|_| format!("Invalid timestamp: {}", value))?,
+                )
+            }
+            _ => return Err(format!("Unknown query parameter: {}", key)),
+        }
+    }
+    Ok(request)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_btc_interface::LogComponent;
+
+    #[test]
+    fn parses_query() {
+        assert_eq!(parse_query("").unwrap().level, None);
+        assert_eq!(parse_query("").unwrap().since, None);
+
+        let request = parse_query("level=warning&since=12").unwrap();
+        assert_eq!(request.level, Some(LogLevel::Warning));
+        assert_eq!(request.since, Some(12));
+
+        assert!(parse_query("level=verbose").is_err());
+        assert!(parse_query("since=yesterday").is_err());
+        assert!(parse_query("foo=bar").is_err());
+    }
+
+    #[test]
+    fn returns_logs_as_json() {
+        logs::clear();
+        logs::info(LogComponent::Heartbeat, "info");
+        logs::error(LogComponent::Api, "error");
+
+        let response = get_logs_http("level=error");
+        assert_eq!(response.status_code, 200);
+
+        let entries: Vec<LogEntry> = serde_json::from_slice(&response.body).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].message, "error");
+        assert_eq!(entries[0].component, LogComponent::Api);
+
+        assert_eq!(get_logs_http("level=verbose").status_code, 400);
+    }
+}
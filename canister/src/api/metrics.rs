@@ -1,9 +1,15 @@
-use crate::{metrics::InstructionHistogram, state, types::HttpResponse, with_state};
-use ic_btc_interface::Flag;
+use crate::{
+    metrics::{BlocksSourceCounters, Endpoint, EndpointCounters, InstructionHistogram},
+    state,
+    types::HttpResponse,
+    with_state,
+};
+use candid::Principal;
+use ic_btc_interface::{ApiAccessMode, Flag};
 use ic_cdk::api::time;
 use ic_metrics_encoder::MetricsEncoder;
 use serde_bytes::ByteBuf;
-use std::io;
+use std::{collections::BTreeMap, io};
 
 const WASM_PAGE_SIZE: u64 = 65536;
 
@@ -88,6 +94,11 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             state.unstable_blocks.blocks_difficulty_based_depth() as f64,
             "The difficulty-based depth of the unstable blocks.",
         )?;
+        w.encode_counter(
+            "num_pruned_unstable_blocks",
+            state.unstable_blocks.num_pruned_blocks() as f64,
+            "The number of unstable blocks that were pruned along with stale forks.",
+        )?;
 
         // Memory
         w.encode_gauge(
@@ -107,6 +118,12 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             state.syncing_state.num_get_successors_rejects as f64,
             "The number of rejects received when calling GetSuccessors.",
         )?;
+        encode_blocks_source_counters(w, &state.metrics.blocks_sources)?;
+        w.encode_gauge(
+            "blocks_source_index",
+            state.syncing_state.blocks_source_index as f64,
+            "The index of the blocks source from which blocks are currently retrieved.",
+        )?;
         w.encode_counter(
             "num_block_deserialize_errors",
             state.syncing_state.num_block_deserialize_errors as f64,
@@ -117,6 +134,11 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             state.syncing_state.num_insert_block_errors as f64,
             "The number of errors occurred when inserting a block.",
         )?;
+        w.encode_counter(
+            "num_checkpoint_rejections",
+            state.syncing_state.num_checkpoint_rejections as f64,
+            "The number of blocks and block headers rejected for conflicting with a checkpoint.",
+        )?;
 
         // Profiling
         encode_instruction_histogram(w, &state.metrics.get_utxos_total)?;
@@ -126,6 +148,9 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
         encode_instruction_histogram(w, &state.metrics.get_balance_apply_unstable_blocks)?;
         encode_instruction_histogram(w, &state.metrics.get_current_fee_percentiles_total)?;
         encode_instruction_histogram(w, &state.metrics.block_insertion)?;
+        encode_instruction_histogram(w, &state.metrics.get_utxos_total_fine)?;
+        encode_instruction_histogram(w, &state.metrics.get_balance_total_fine)?;
+        encode_instruction_histogram(w, &state.metrics.get_current_fee_percentiles_total_fine)?;
 
         w.encode_gauge(
             "send_transaction_count",
@@ -139,6 +164,13 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "The cycles balance of the canister.",
         )?;
 
+        encode_endpoint_counters(w, &state.metrics.endpoints)?;
+        w.encode_counter(
+            "throttled_calls_total",
+            state.metrics.num_throttled_calls as f64,
+            "The number of requests rejected for exceeding the rate limit.",
+        )?;
+
         encode_labeled_gauge(
             w,
             "block_ingestion_stats",
@@ -152,6 +184,67 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
             "Is the canister synced with the network?",
         )?;
 
+        // Upgrades
+        w.encode_gauge(
+            "state_version",
+            crate::upgrade::STATE_VERSION as f64,
+            "The version of the state's schema.",
+        )?;
+        if let Some(report) = &state.metrics.state_migration {
+            w.encode_gauge(
+                "state_migrated_from_version",
+                report.from_version as f64,
+                "The version of the state's schema that was migrated in the most recent upgrade.",
+            )?;
+            w.encode_gauge(
+                "state_migrations_applied",
+                report.migrations.len() as f64,
+                "The number of migrations of the state that ran in the most recent upgrade.",
+            )?;
+        }
+
+        // UTXO set audit
+        let audit = &state.utxo_set_audit;
+        w.encode_gauge(
+            "utxo_set_audit_in_progress",
+            if audit.current().is_some() { 1.0 } else { 0.0 },
+            "Is an audit of the UTXO set's indexes in progress?",
+        )?;
+        if let Some(report) = audit.current() {
+            w.encode_gauge(
+                "utxo_set_audit_entries_checked",
+                (report.address_utxos_checked + report.balances_checked) as f64,
+                "The number of address UTXOs and balances checked by the audit in progress.",
+            )?;
+        }
+        w.encode_counter(
+            "utxo_set_audit_runs_completed",
+            audit.runs_completed() as f64,
+            "The number of audits of the UTXO set's indexes that have completed.",
+        )?;
+        if let Some(report) = audit.last_completed() {
+            w.encode_gauge(
+                "utxo_set_audit_last_completed_timestamp",
+                report.completed_at.unwrap_or_default() as f64,
+                "The time the last audit of the UTXO set's indexes completed, in seconds since the UNIX epoch.",
+            )?;
+            w.encode_gauge(
+                "utxo_set_audit_last_mismatches",
+                report.num_mismatches as f64,
+                "The number of mismatches found by the last completed audit of the UTXO set's indexes.",
+            )?;
+        }
+
+        w.encode_gauge(
+            "state_snapshot_in_progress",
+            if state.is_state_snapshot_in_progress() {
+                1.0
+            } else {
+                0.0
+            },
+            "Is a snapshot of stable memory being exported?",
+        )?;
+
         let (enabled, disabled) = match state.api_access {
             Flag::Enabled => (1.0, 0.0),
             Flag::Disabled => (0.0, 1.0),
@@ -163,6 +256,16 @@ fn encode_metrics(w: &mut MetricsEncoder<Vec<u8>>) -> std::io::Result<()> {
         .value(&[("flag", "enabled")], enabled)?
         .value(&[("flag", "disabled")], disabled)?;
 
+        w.encode_gauge(
+            "api_access_restricted",
+            if state.api_access_mode == ApiAccessMode::Restricted {
+                1.0
+            } else {
+                0.0
+            },
+            "Is access to the APIs restricted to the principals in the allow-list?",
+        )?;
+
         Ok(())
     })
 }
@@ -174,6 +277,94 @@ fn encode_instruction_histogram(
     metrics_encoder.encode_histogram(&h.name, h.buckets(), h.sum, &h.help)
 }
 
+fn encode_endpoint_counters(
+    metrics_encoder: &mut MetricsEncoder<Vec<u8>>,
+    endpoints: &BTreeMap<Endpoint, EndpointCounters>,
+) -> io::Result<()> {
+    let mut requests = metrics_encoder.counter_vec(
+        "requests_total",
+        "The total number of requests received by each endpoint.",
+    )?;
+    for (endpoint, counters) in endpoints {
+        requests = requests.value(&[("endpoint", endpoint.name())], counters.requests as f64)?;
+    }
+
+    let mut errors = metrics_encoder.counter_vec(
+        "request_errors_total",
+        "The number of failed requests of each endpoint, by error.",
+    )?;
+    for (endpoint, counters) in endpoints {
+        for (error, count) in &counters.errors {
+            errors = errors.value(
+                &[("endpoint", endpoint.name()), ("error", error.as_str())],
+                *count as f64,
+            )?;
+        }
+    }
+
+    let mut cycles = metrics_encoder.counter_vec(
+        "cycles_charged_total",
+        "The total amount of cycles charged by each endpoint.",
+    )?;
+    for (endpoint, counters) in endpoints {
+        cycles = cycles.value(
+            &[("endpoint", endpoint.name())],
+            counters.cycles_charged as f64,
+        )?;
+    }
+
+    let mut free_calls = metrics_encoder.counter_vec(
+        "free_calls_total",
+        "The number of requests to each endpoint that were free under the fee policy.",
+    )?;
+    for (endpoint, counters) in endpoints {
+        free_calls =
+            free_calls.value(&[("endpoint", endpoint.name())], counters.free_calls as f64)?;
+    }
+
+    Ok(())
+}
+
+fn encode_blocks_source_counters(
+    metrics_encoder: &mut MetricsEncoder<Vec<u8>>,
+    blocks_sources: &BTreeMap<Principal, BlocksSourceCounters>,
+) -> io::Result<()> {
+    let mut successes = metrics_encoder.counter_vec(
+        "blocks_source_successes_total",
+        "The number of requests for blocks that each blocks source responded to with progress.",
+    )?;
+    for (source, counters) in blocks_sources {
+        successes = successes.value(
+            &[("source", source.to_text().as_str())],
+            counters.successes as f64,
+        )?;
+    }
+
+    let mut rejects = metrics_encoder.counter_vec(
+        "blocks_source_rejects_total",
+        "The number of requests for blocks that each blocks source rejected.",
+    )?;
+    for (source, counters) in blocks_sources {
+        rejects = rejects.value(
+            &[("source", source.to_text().as_str())],
+            counters.rejects as f64,
+        )?;
+    }
+
+    let mut stalls = metrics_encoder.counter_vec(
+        "blocks_source_stalls_total",
+        "The number of requests for blocks that each blocks source stalled on.",
+    )?;
+    for (source, counters) in blocks_sources {
+        stalls = stalls.value(
+            &[("source", source.to_text().as_str())],
+            counters.stalls as f64,
+        )?;
+    }
+
+    Ok(())
+}
+
 fn encode_labeled_gauge(
     metrics_encoder: &mut MetricsEncoder<Vec<u8>>,
     name: &str,
@@ -1,33 +1,45 @@
 use crate::{
-    charge_cycles, runtime, types::SendTransactionInternalRequest, verify_api_access,
-    verify_network, with_state, with_state_mut,
+    charge_cycles, logs, metrics::Endpoint, runtime, types::SendTransactionInternalRequest,
+    with_state, with_state_mut,
 };
 use bitcoin::{consensus::Decodable, Transaction};
-use ic_btc_interface::{SendTransactionError, SendTransactionRequest};
+use ic_btc_interface::{LogComponent, SendTransactionError, SendTransactionRequest};
 
 pub async fn send_transaction(request: SendTransactionRequest) -> Result<(), SendTransactionError> {
-    verify_api_access();
-    verify_network(request.network.into());
-
-    charge_cycles(with_state(|s| {
-        s.fees.send_transaction_base
-            + s.fees.send_transaction_per_byte * request.transaction.len() as u128
-    }));
+    charge_cycles(
+        Endpoint::SendTransaction,
+        with_state(|s| {
+            s.fees.send_transaction_base
+                + s.fees.send_transaction_per_byte * request.transaction.len() as u128
+        }),
+    );
 
     // Decode the transaction as a sanity check that it's valid.
-    let tx = Transaction::consensus_decode(request.transaction.as_slice())
-        .map_err(|_| SendTransactionError::MalformedTransaction)?;
+    let tx = match Transaction::consensus_decode(request.transaction.as_slice()) {
+        Ok(tx) => tx,
+        Err(_) => return Err(SendTransactionError::MalformedTransaction),
+    };
 
-    runtime::print(&format!("[send_transaction] Tx ID: {}", tx.txid()));
+    logs::info(
+        LogComponent::Api,
+        &format!("[send_transaction] Tx ID: {}", tx.txid()),
+    );
 
     // Bump the counter for the number of (valid) requests received.
     with_state_mut(|s| {
         s.metrics.send_transaction_count += 1;
     });
 
+    // In the regtest developer mode, the transaction is included in the next mined block.
+    #[cfg(feature = "regtest_mining")]
+    if crate::mining::is_enabled() {
+        crate::mining::add_pending_transaction(tx);
+        return Ok(());
+    }
+
     // Use the internal endpoint to send the transaction to the bitcoin network.
     runtime::call_send_transaction_internal(
-        with_state(|s| s.blocks_source),
+        with_state(|s| s.current_blocks_source()),
         SendTransactionInternalRequest {
             network: request.network.into(),
             transaction: request.transaction,
@@ -41,6 +53,7 @@ pub async fn send_transaction(request: SendTransactionRequest) -> Result<(), Sen
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::types::{RejectionReason, RequestError};
     use ic_btc_interface::{Config, Fees, Flag, Network, NetworkInRequest};
 
     fn empty_transaction() -> Vec<u8> {
@@ -114,7 +127,6 @@ mod test {
     }
 
     #[async_std::test]
-    #[should_panic(expected = "Bitcoin API is disabled")]
     async fn send_transaction_access_disabled() {
         crate::init(Config {
             fees: Fees {
@@ -127,11 +139,13 @@ mod test {
             ..Default::default()
         });
 
-        send_transaction(SendTransactionRequest {
-            network: NetworkInRequest::Mainnet,
-            transaction: vec![1, 2, 3], // Invalid transaction
-        })
-        .await
-        .unwrap();
+        assert_eq!(
+            crate::send_transaction(SendTransactionRequest {
+                network: NetworkInRequest::Mainnet,
+                transaction: vec![1, 2, 3], // Invalid transaction
+            })
+            .await,
+            Err(RequestError::Rejected(RejectionReason::ApiDisabled))
+        );
     }
 }
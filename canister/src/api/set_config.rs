@@ -1,14 +1,41 @@
-use ic_btc_interface::SetConfigRequest;
+use crate::metrics::Endpoint;
+use ic_btc_interface::{Flag, SetConfigRequest};
+use ic_btc_types::BlockHash;
 use std::convert::TryInto;
 
-pub async fn set_config(request: SetConfigRequest) {
+#[derive(Debug, PartialEq, Eq)]
+pub enum SetConfigError {
+    /// The caller is neither a controller nor the watchdog canister.
+    NotAuthorized,
+}
+
+impl std::fmt::Display for SetConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotAuthorized => write!(f, "Only controllers can call set_config"),
+        }
+    }
+}
+
+/// Updates the config of the canister.
+///
+/// Rather than trapping, unauthorized calls return an error, so that they are
+/// recorded in the metrics.
+pub async fn set_config(request: SetConfigRequest) -> Result<(), SetConfigError> {
+    crate::with_state_mut(|s| s.metrics.observe_request(Endpoint::SetConfig));
+
     if is_watchdog_caller() {
-        // The watchdog canister can only set the API access flag.
+        // The watchdog canister can only set the API access flag and mode.
         set_api_access(request);
-    } else {
-        verify_caller().await;
-        set_config_no_verification(request);
+        return Ok(());
     }
+
+    if let Err(err) = verify_caller().await {
+        crate::with_state_mut(|s| s.metrics.observe_error(Endpoint::SetConfig, &err));
+        return Err(err);
+    }
+    set_config_no_verification(request);
+    Ok(())
 }
 
 fn is_watchdog_caller() -> bool {
@@ -28,6 +55,9 @@ fn set_api_access(request: SetConfigRequest) {
         if let Some(api_access) = request.api_access {
             s.api_access = api_access;
         }
+        if let Some(api_access_mode) = request.api_access_mode {
+            s.api_access_mode = api_access_mode;
+        }
     });
 }
 
@@ -52,16 +82,56 @@ fn set_config_no_verification(request: SetConfigRequest) {
         if let Some(api_access) = request.api_access {
             s.api_access = api_access;
         }
+        if let Some(api_access_mode) = request.api_access_mode {
+            s.api_access_mode = api_access_mode;
+        }
+        if let Some(api_access_allow_list) = request.api_access_allow_list {
+            s.api_access_allow_list = api_access_allow_list;
+        }
         if let Some(disable_api_if_not_fully_synced) = request.disable_api_if_not_fully_synced {
             s.disable_api_if_not_fully_synced = disable_api_if_not_fully_synced;
         }
         if let Some(watchdog_canister) = request.watchdog_canister {
             s.watchdog_canister = watchdog_canister;
         }
+        if let Some(checkpoints) = request.checkpoints {
+            s.checkpoints = checkpoints
+                .into_iter()
+                .map(|checkpoint| (checkpoint.height, BlockHash::from(checkpoint.block_hash)))
+                .collect();
+        }
+        if let Some(sync_interval_ms) = request.sync_interval_ms {
+            s.syncing_state.sync_interval_ms = sync_interval_ms;
+        }
+        if let Some(max_sync_interval_ms) = request.max_sync_interval_ms {
+            s.syncing_state.max_sync_interval_ms = max_sync_interval_ms;
+        }
+        if request.blocks_source.is_some() || request.fallback_blocks_sources.is_some() {
+            let blocks_source = request.blocks_source.unwrap_or(s.blocks_source);
+            let fallback_blocks_sources = request
+                .fallback_blocks_sources
+                .unwrap_or_else(|| s.fallback_blocks_sources.clone());
+            s.set_blocks_sources(blocks_source, fallback_blocks_sources);
+        }
+        if let Some(threshold) = request.blocks_source_failover_threshold {
+            s.syncing_state.blocks_source_failover_threshold = threshold;
+        }
+        if let Some(fee_policy) = request.fee_policy {
+            s.fee_policy = fee_policy;
+        }
+        if let Some(rate_limit) = request.rate_limit {
+            s.rate_limit = rate_limit;
+        }
+        if let Some(pruning_policy) = request.pruning_policy {
+            s.unstable_blocks.set_pruning_policy(pruning_policy);
+        }
+        if let Some(print_logs) = request.print_logs {
+            s.set_print_logs(print_logs);
+        }
     });
 }
 
-async fn verify_caller() {
+async fn verify_caller() -> Result<(), SetConfigError> {
     #[cfg(target_arch = "wasm32")]
     {
         use ic_cdk::api::management_canister::main::CanisterIdRecord;
@@ -78,17 +148,21 @@ async fn verify_caller() {
             .controllers;
 
         if !controllers.contains(&caller) {
-            panic!("Only controllers can call set_config");
+            return Err(SetConfigError::NotAuthorized);
         }
     }
+
+    Ok(())
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::{init, with_state};
+    use crate::{init, with_state, with_state_mut};
     use candid::Principal;
-    use ic_btc_interface::{Config, Fees, Flag};
+    use ic_btc_interface::{
+        ApiAccessMode, Checkpoint, Config, FeePolicy, FeeTier, Fees, PruningPolicy, RateLimit,
+    };
     use proptest::prelude::*;
 
     #[test]
@@ -107,6 +181,22 @@ mod test {
         assert_eq!(with_state(|s| s.api_access), Flag::Disabled);
     }
 
+    #[test]
+    fn test_set_api_access_updates_mode_but_not_allow_list() {
+        init(Config::default());
+
+        set_api_access(SetConfigRequest {
+            api_access_mode: Some(ApiAccessMode::Restricted),
+            api_access_allow_list: Some(vec![Principal::from_slice(&[1])]),
+            ..Default::default()
+        });
+
+        with_state(|s| {
+            assert_eq!(s.api_access_mode, ApiAccessMode::Restricted);
+            assert!(s.api_access_allow_list.is_empty());
+        });
+    }
+
     #[test]
     fn test_set_api_access_does_not_update_state() {
         // Arrange
@@ -244,4 +334,195 @@ mod test {
             assert_eq!(with_state(|s| s.watchdog_canister), watchdog_canister);
         }
     }
+
+    #[test]
+    fn test_set_checkpoints() {
+        init(Config::default());
+
+        let checkpoints = vec![
+            Checkpoint {
+                height: 10,
+                block_hash: vec![1; 32],
+            },
+            Checkpoint {
+                height: 20,
+                block_hash: vec![2; 32],
+            },
+        ];
+        set_config_no_verification(SetConfigRequest {
+            checkpoints: Some(checkpoints),
+            ..Default::default()
+        });
+        assert_eq!(
+            with_state(|s| s.checkpoints.clone()),
+            vec![
+                (10, BlockHash::from(vec![1; 32])),
+                (20, BlockHash::from(vec![2; 32]))
+            ]
+        );
+
+        // Checkpoints that are set replace the previous ones.
+        set_config_no_verification(SetConfigRequest {
+            checkpoints: Some(vec![]),
+            ..Default::default()
+        });
+        assert_eq!(with_state(|s| s.checkpoints.clone()), vec![]);
+    }
+
+    #[test]
+    fn test_set_sync_intervals() {
+        init(Config::default());
+
+        proptest!(|(
+            sync_interval_ms in 0..1_000_000u64,
+            max_sync_interval_ms in 0..1_000_000u64,
+        )| {
+            set_config_no_verification(SetConfigRequest {
+                sync_interval_ms: Some(sync_interval_ms),
+                max_sync_interval_ms: Some(max_sync_interval_ms),
+                ..Default::default()
+            });
+
+            with_state(|s| {
+                assert_eq!(s.syncing_state.sync_interval_ms, sync_interval_ms);
+                assert_eq!(s.syncing_state.max_sync_interval_ms, max_sync_interval_ms);
+            });
+        });
+    }
+
+    #[test]
+    fn test_set_blocks_sources() {
+        init(Config::default());
+        let source_1 = Principal::from_slice(&[1]);
+        let source_2 = Principal::from_slice(&[2]);
+        let source_3 = Principal::from_slice(&[3]);
+
+        set_config_no_verification(SetConfigRequest {
+            blocks_source: Some(source_1),
+            fallback_blocks_sources: Some(vec![source_2, source_3]),
+            blocks_source_failover_threshold: Some(5),
+            ..Default::default()
+        });
+        with_state(|s| {
+            assert_eq!(s.blocks_sources(), vec![source_1, source_2, source_3]);
+            assert_eq!(s.syncing_state.blocks_source_failover_threshold, 5);
+        });
+
+        // Setting the fallback sources keeps the blocks source, and blocks are
+        // retrieved from the blocks source again.
+        with_state_mut(|s| s.syncing_state.blocks_source_index = 1);
+        set_config_no_verification(SetConfigRequest {
+            fallback_blocks_sources: Some(vec![source_3]),
+            ..Default::default()
+        });
+        with_state(|s| {
+            assert_eq!(s.blocks_sources(), vec![source_1, source_3]);
+            assert_eq!(s.current_blocks_source(), source_1);
+        });
+
+        // Setting the blocks source keeps the fallback sources.
+        set_config_no_verification(SetConfigRequest {
+            blocks_source: Some(source_2),
+            ..Default::default()
+        });
+        with_state(|s| assert_eq!(s.blocks_sources(), vec![source_2, source_3]));
+    }
+
+    #[test]
+    fn test_set_fee_policy() {
+        init(Config::default());
+        let fee_policy = FeePolicy {
+            tiers: vec![FeeTier {
+                principals: vec![Principal::from_slice(&[1])],
+                fee_percent: 0,
+                free_calls_per_period: 0,
+            }],
+            free_calls_per_period: 10,
+            period_secs: 7 * 24 * 60 * 60,
+        };
+
+        set_config_no_verification(SetConfigRequest {
+            fee_policy: Some(fee_policy.clone()),
+            ..Default::default()
+        });
+
+        assert_eq!(with_state(|s| s.fee_policy.clone()), fee_policy);
+        assert_eq!(crate::get_config().fee_policy, fee_policy);
+    }
+
+    #[test]
+    fn test_set_rate_limit() {
+        init(Config::default());
+        let rate_limit = RateLimit {
+            calls_per_minute: 60,
+            max_burst: 10,
+            exempt_principals: vec![Principal::from_slice(&[1])],
+        };
+
+        set_config_no_verification(SetConfigRequest {
+            rate_limit: Some(Some(rate_limit.clone())),
+            ..Default::default()
+        });
+        assert_eq!(with_state(|s| s.rate_limit.clone()), Some(rate_limit));
+
+        set_config_no_verification(SetConfigRequest {
+            rate_limit: Some(None),
+            ..Default::default()
+        });
+        assert_eq!(with_state(|s| s.rate_limit.clone()), None);
+    }
+
+    #[test]
+    fn test_set_api_access_allow_list() {
+        init(Config::default());
+        let allow_list = vec![Principal::from_slice(&[1]), Principal::from_slice(&[2])];
+
+        set_config_no_verification(SetConfigRequest {
+            api_access_mode: Some(ApiAccessMode::Restricted),
+            api_access_allow_list: Some(allow_list.clone()),
+            ..Default::default()
+        });
+
+        with_state(|s| {
+            assert_eq!(s.api_access_mode, ApiAccessMode::Restricted);
+            assert_eq!(s.api_access_allow_list, allow_list);
+        });
+    }
+
+    #[test]
+    fn test_set_pruning_policy() {
+        init(Config::default());
+        let pruning_policy = PruningPolicy {
+            max_fork_work_deficit_in_blocks: 6,
+            max_unstable_blocks: 1_000,
+            max_unstable_tips: 10,
+        };
+
+        set_config_no_verification(SetConfigRequest {
+            pruning_policy: Some(pruning_policy.clone()),
+            ..Default::default()
+        });
+
+        assert_eq!(
+            with_state(|s| s.unstable_blocks.pruning_policy().clone()),
+            pruning_policy
+        );
+        assert_eq!(crate::get_config().pruning_policy, pruning_policy);
+    }
+
+    #[test]
+    fn print_logs_is_persisted_across_upgrades() {
+        init(Config::default());
+        assert_eq!(crate::get_config().print_logs, Flag::Enabled);
+
+        set_config_no_verification(SetConfigRequest {
+            print_logs: Some(Flag::Disabled),
+            ..Default::default()
+        });
+        assert_eq!(crate::get_config().print_logs, Flag::Disabled);
+
+        crate::pre_upgrade();
+        crate::post_upgrade();
+        assert_eq!(crate::get_config().print_logs, Flag::Disabled);
+    }
 }
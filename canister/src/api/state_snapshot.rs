@@ -0,0 +1,333 @@
+//! Exporting the canister's stable memory in chunks.
+//!
+//! The chunks have the same layout as the ones uploaded by the `uploader` canister, so
+//! an exported snapshot can be uploaded as-is to seed another deployment.
+use crate::{
+    memory,
+    runtime::{inc_performance_counter, time},
+    upgrade, with_state, with_state_mut,
+};
+use bitcoin::hashes::{sha256, Hash};
+use ic_btc_interface::{StateSnapshot, StateSnapshotChunk};
+use ic_stable_structures::Memory;
+use std::cmp::min;
+
+const WASM_PAGE_SIZE: u64 = 65536;
+
+/// The size of a chunk in Wasm pages, which keeps chunks below the 2MiB response limit.
+pub const CHUNK_SIZE_IN_PAGES: u64 = 31;
+
+/// The time, in seconds, after which a snapshot that wasn't finished expires, unless it's
+/// extended with `extend_state_snapshot`. Syncing can't be paused indefinitely by a
+/// snapshot that was never finished.
+pub const STATE_SNAPSHOT_EXPIRY_SECS: u64 = 60 * 60;
+
+// The number of instructions after which writing the state is abandoned, to stay within
+// the instruction limit of an update call.
+const MAX_INSTRUCTIONS_TO_WRITE_STATE: u64 = 15_000_000_000;
+
+/// An error returned by the snapshot endpoints.
+#[derive(Debug, PartialEq, Eq)]
+pub enum StateSnapshotError {
+    /// A snapshot is already being exported.
+    AlreadyInProgress,
+
+    /// No snapshot is being exported.
+    NotInProgress,
+
+    /// The index of the chunk is past the end of the snapshot.
+    ChunkOutOfRange { index: u64, num_chunks: u64 },
+
+    /// The state couldn't be written within the instruction limit.
+    StateTooLarge,
+}
+
+impl std::fmt::Display for StateSnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AlreadyInProgress => write!(f, "A snapshot is already in progress"),
+            Self::NotInProgress => write!(f, "No snapshot is in progress"),
+            Self::ChunkOutOfRange { index, num_chunks } => write!(
+                f,
+                "Chunk {} is out of range. The snapshot has {} chunks",
+                index, num_chunks
+            ),
+            Self::StateTooLarge => write!(
+                f,
+                "The state couldn't be written within the instruction limit"
+            ),
+        }
+    }
+}
+
+/// Takes a snapshot of the canister's stable memory.
+///
+/// The state is written into stable memory as it would be in an upgrade, so that the
+/// snapshot can be loaded with `post_upgrade`. Syncing is paused until the snapshot is
+/// finished or expires, as stable memory mustn't change while its chunks are being exported.
+pub fn start_state_snapshot() -> Result<StateSnapshot, StateSnapshotError> {
+    if with_state(|s| s.is_state_snapshot_in_progress()) {
+        return Err(StateSnapshotError::AlreadyInProgress);
+    }
+
+    // NOTE: The state is written before the snapshot is recorded so that the
+    // snapshot doesn't include itself.
+    with_state(|s| {
+        upgrade::try_write_state(s, || {
+            inc_performance_counter() > MAX_INSTRUCTIONS_TO_WRITE_STATE
+        })
+    })
+    .map_err(|_| StateSnapshotError::StateTooLarge)?;
+
+    let size_in_pages = memory::get_memory().size();
+    let taken_at = time();
+    let snapshot = StateSnapshot {
+        taken_at,
+        expires_at: taken_at + STATE_SNAPSHOT_EXPIRY_SECS,
+        stable_height: with_state(|s| s.stable_height()),
+        size_in_pages,
+        chunk_size_in_pages: CHUNK_SIZE_IN_PAGES,
+        num_chunks: (size_in_pages + CHUNK_SIZE_IN_PAGES - 1) / CHUNK_SIZE_IN_PAGES,
+    };
+    with_state_mut(|s| s.state_snapshot = Some(snapshot.clone()));
+    Ok(snapshot)
+}
+
+/// Returns the snapshot that is being exported, if any.
+pub fn get_state_snapshot() -> Option<StateSnapshot> {
+    with_state(|s| {
+        if s.is_state_snapshot_in_progress() {
+            s.state_snapshot.clone()
+        } else {
+            None
+        }
+    })
+}
+
+/// Returns the chunk of the snapshot at the given index.
+pub fn get_state_snapshot_chunk(index: u64) -> Result<StateSnapshotChunk, StateSnapshotError> {
+    let snapshot = get_state_snapshot().ok_or(StateSnapshotError::NotInProgress)?;
+    if index >= snapshot.num_chunks {
+        return Err(StateSnapshotError::ChunkOutOfRange {
+            index,
+            num_chunks: snapshot.num_chunks,
+        });
+    }
+
+    let start_page = index * snapshot.chunk_size_in_pages;
+    let end_page = min(
+        start_page + snapshot.chunk_size_in_pages,
+        snapshot.size_in_pages,
+    );
+    let mut bytes = vec![0; ((end_page - start_page) * WASM_PAGE_SIZE) as usize];
+    memory::get_memory().read(start_page * WASM_PAGE_SIZE, &mut bytes);
+
+    Ok(StateSnapshotChunk {
+        index,
+        sha256: hex::encode(sha256::Hash::hash(&bytes).into_inner()),
+        bytes,
+    })
+}
+
+/// Extends the snapshot that is being exported so that it expires `secs` seconds from now.
+///
+/// Exporting a large state, e.g. mainnet's, can take longer than the default expiry, so the
+/// controller can keep extending the snapshot while it downloads the chunks. Syncing stays
+/// paused for as long as the snapshot is extended.
+pub fn extend_state_snapshot(secs: u64) -> Result<StateSnapshot, StateSnapshotError> {
+    with_state_mut(|s| {
+        if !s.is_state_snapshot_in_progress() {
+            return Err(StateSnapshotError::NotInProgress);
+        }
+        let snapshot = s
+            .state_snapshot
+            .as_mut()
+            .expect("a snapshot must be in progress");
+        snapshot.expires_at = time() + secs;
+        Ok(snapshot.clone())
+    })
+}
+
+/// Finishes exporting the snapshot, which resumes syncing.
+pub fn finish_state_snapshot() -> Result<(), StateSnapshotError> {
+    with_state_mut(|s| {
+        let in_progress = s.is_state_snapshot_in_progress();
+        match s.state_snapshot.take() {
+            Some(_) if in_progress => Ok(()),
+            _ => Err(StateSnapshotError::NotInProgress),
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{init, runtime, state, test_utils::build_regtest_chain};
+    use ic_btc_interface::{Config, Network};
+    use std::{cell::RefCell, rc::Rc};
+
+    fn init_with_blocks() {
+        init(Config {
+            stability_threshold: 2,
+            network: Network::Regtest,
+            ..Default::default()
+        });
+
+        with_state_mut(|s| {
+            for block in build_regtest_chain(5, 3).into_iter().skip(1) {
+                state::insert_block(s, block).unwrap();
+                state::ingest_stable_blocks_into_utxoset(s);
+            }
+        });
+    }
+
+    #[test]
+    fn chunks_cover_stable_memory() {
+        init_with_blocks();
+        let snapshot = start_state_snapshot().unwrap();
+        assert_eq!(snapshot.size_in_pages, memory::get_memory().size());
+
+        let mut bytes = vec![];
+        for index in 0..snapshot.num_chunks {
+            let chunk = get_state_snapshot_chunk(index).unwrap();
+            assert_eq!(chunk.index, index);
+            assert_eq!(
+                chunk.sha256,
+                hex::encode(sha256::Hash::hash(&chunk.bytes).into_inner())
+            );
+            if index + 1 < snapshot.num_chunks {
+                assert_eq!(
+                    chunk.bytes.len() as u64,
+                    CHUNK_SIZE_IN_PAGES * WASM_PAGE_SIZE
+                );
+            }
+            bytes.extend(chunk.bytes);
+        }
+
+        let mut expected = vec![0; (snapshot.size_in_pages * WASM_PAGE_SIZE) as usize];
+        memory::get_memory().read(0, &mut expected);
+        assert_eq!(bytes, expected);
+    }
+
+    #[test]
+    fn reassembled_chunks_can_be_loaded() {
+        init_with_blocks();
+        let snapshot = start_state_snapshot().unwrap();
+        let bytes: Vec<u8> = (0..snapshot.num_chunks)
+            .flat_map(|index| get_state_snapshot_chunk(index).unwrap().bytes)
+            .collect();
+
+        // Load the snapshot into a fresh memory and compare it with the original state.
+        let mut original_state = crate::STATE.with(|s| s.borrow_mut().take().unwrap());
+        original_state.state_snapshot = None;
+        memory::set_memory(Rc::new(RefCell::new(bytes)));
+        crate::post_upgrade();
+
+        with_state(|s| assert!(*s == original_state));
+    }
+
+    #[test]
+    fn cannot_start_two_snapshots() {
+        init_with_blocks();
+        start_state_snapshot().unwrap();
+        assert_eq!(
+            start_state_snapshot(),
+            Err(StateSnapshotError::AlreadyInProgress)
+        );
+
+        finish_state_snapshot().unwrap();
+        assert_eq!(get_state_snapshot(), None);
+        assert!(start_state_snapshot().is_ok());
+    }
+
+    #[test]
+    fn rejects_chunks_without_a_snapshot_or_out_of_range() {
+        init_with_blocks();
+        assert_eq!(
+            get_state_snapshot_chunk(0),
+            Err(StateSnapshotError::NotInProgress)
+        );
+        assert_eq!(
+            finish_state_snapshot(),
+            Err(StateSnapshotError::NotInProgress)
+        );
+
+        let snapshot = start_state_snapshot().unwrap();
+        assert_eq!(
+            get_state_snapshot_chunk(snapshot.num_chunks),
+            Err(StateSnapshotError::ChunkOutOfRange {
+                index: snapshot.num_chunks,
+                num_chunks: snapshot.num_chunks
+            })
+        );
+    }
+
+    #[test]
+    fn snapshots_expire() {
+        init_with_blocks();
+        let snapshot = start_state_snapshot().unwrap();
+        assert_eq!(
+            snapshot.expires_at,
+            snapshot.taken_at + STATE_SNAPSHOT_EXPIRY_SECS
+        );
+        assert!(with_state(|s| s.is_state_snapshot_in_progress()));
+
+        // Expire the snapshot.
+        with_state_mut(|s| s.state_snapshot.as_mut().unwrap().expires_at = time());
+        assert!(!with_state(|s| s.is_state_snapshot_in_progress()));
+        assert_eq!(get_state_snapshot(), None);
+        assert_eq!(
+            get_state_snapshot_chunk(0),
+            Err(StateSnapshotError::NotInProgress)
+        );
+
+        // A new snapshot can be started in its place.
+        assert!(start_state_snapshot().is_ok());
+    }
+
+    #[test]
+    fn snapshots_can_be_extended() {
+        init_with_blocks();
+        assert_eq!(
+            extend_state_snapshot(10),
+            Err(StateSnapshotError::NotInProgress)
+        );
+
+        let snapshot = start_state_snapshot().unwrap();
+        let extended = extend_state_snapshot(2 * STATE_SNAPSHOT_EXPIRY_SECS).unwrap();
+        assert!(extended.expires_at >= snapshot.taken_at + 2 * STATE_SNAPSHOT_EXPIRY_SECS);
+        assert_eq!(
+            extended,
+            StateSnapshot {
+                expires_at: extended.expires_at,
+                ..snapshot
+            }
+        );
+        assert_eq!(get_state_snapshot(), Some(extended));
+
+        // An expired snapshot can't be extended.
+        with_state_mut(|s| s.state_snapshot.as_mut().unwrap().expires_at = time());
+        assert_eq!(
+            extend_state_snapshot(10),
+            Err(StateSnapshotError::NotInProgress)
+        );
+    }
+
+    #[test]
+    fn rejects_states_that_cannot_be_written_within_the_instruction_limit() {
+        init_with_blocks();
+        runtime::performance_counter_reset();
+        runtime::set_performance_counter_step(MAX_INSTRUCTIONS_TO_WRITE_STATE + 1);
+
+        assert_eq!(
+            start_state_snapshot(),
+            Err(StateSnapshotError::StateTooLarge)
+        );
+        assert_eq!(get_state_snapshot(), None);
+
+        // The upgrades memory doesn't contain a state that can be mistaken for a complete one.
+        let mut header = [0; 12];
+        memory::get_upgrades_memory().read(0, &mut header);
+        assert_eq!(header, [0; 12]);
+    }
+}
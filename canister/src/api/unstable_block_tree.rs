@@ -0,0 +1,166 @@
+use crate::{types::HttpResponse, unstable_blocks, with_state};
+use ic_btc_interface::UnstableBlockTree;
+use serde_bytes::ByteBuf;
+use std::fmt::Write;
+
+// The number of hex characters of a block hash to show in the graph's labels.
+const SHORT_HASH_LEN: usize = 12;
+
+/// Returns a snapshot of the unstable block tree.
+pub fn get_unstable_block_tree() -> UnstableBlockTree {
+    with_state(|s| unstable_blocks::get_tree_snapshot(&s.unstable_blocks, s.stable_height()))
+}
+
+/// Returns the unstable block tree as JSON.
+pub fn get_unstable_block_tree_json() -> HttpResponse {
+    match serde_json::to_vec(&get_unstable_block_tree()) {
+        Ok(body) => ok_response("application/json", body),
+        Err(err) => HttpResponse {
+            status_code: 500,
+            headers: vec![],
+            body: ByteBuf::from(format!("Failed to encode the unstable block tree: {}", err)),
+        },
+    }
+}
+
+/// Returns the unstable block tree as a Graphviz graph.
+pub fn get_unstable_block_tree_dot() -> HttpResponse {
+    ok_response(
+        "text/vnd.graphviz",
+        to_dot(&get_unstable_block_tree()).into_bytes(),
+    )
+}
+
+fn ok_response(content_type: &str, body: Vec<u8>) -> HttpResponse {
+    HttpResponse {
+        status_code: 200,
+        headers: vec![
+            ("Content-Type".to_string(), content_type.to_string()),
+            ("Content-Length".to_string(), body.len().to_string()),
+        ],
+        body: ByteBuf::from(body),
+    }
+}
+
+// Renders the tree in the DOT language.
+//
+// Blocks on the main chain are filled, and the headers of blocks that are
+// expected to be received are drawn with dashed lines.
+fn to_dot(tree: &UnstableBlockTree) -> String {
+    fn short(block_hash: &str) -> &str {
+        &block_hash[..std::cmp::min(SHORT_HASH_LEN, block_hash.len())]
+    }
+
+    let mut dot = String::new();
+    // Writing to a `String` cannot fail.
+    writeln!(dot, "digraph unstable_blocks {{").unwrap();
+    writeln!(dot, "  rankdir=LR;").unwrap();
+    writeln!(dot, "  node [shape=box, fontname=monospace];").unwrap();
+    writeln!(
+        dot,
+        "  label=\"stability threshold: {}, tips: {}\";",
+        tree.stability_threshold, tree.num_tips
+    )
+    .unwrap();
+
+    for block in tree.blocks.iter() {
+        writeln!(
+            dot,
+            "  \"{}\" [label=\"{}\\nheight: {}\\ndifficulty: {}\\ndepth: {}\"{}];",
+            block.block_hash,
+            short(&block.block_hash),
+            block.height,
+            block.difficulty,
+            block.depth,
+            if block.is_main_chain {
+                ", style=filled, fillcolor=lightblue"
+            } else {
+                ""
+            }
+        )
+        .unwrap();
+
+        if let Some(parent_block_hash) = &block.parent_block_hash {
+            writeln!(
+                dot,
+                "  \"{}\" -> \"{}\";",
+                parent_block_hash, block.block_hash
+            )
+            .unwrap();
+        }
+    }
+
+    for header in tree.next_block_headers.iter() {
+        writeln!(
+            dot,
+            "  \"{}\" [label=\"{}\\nheight: {}\", style=dashed];",
+            header.block_hash,
+            short(&header.block_hash),
+            header.height
+        )
+        .unwrap();
+        writeln!(
+            dot,
+            "  \"{}\" -> \"{}\" [style=dashed];",
+            header.parent_block_hash, header.block_hash
+        )
+        .unwrap();
+    }
+
+    writeln!(dot, "}}").unwrap();
+    dot
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_btc_interface::{NextBlockHeader, UnstableBlock};
+
+    #[test]
+    fn renders_dot() {
+        let tree = UnstableBlockTree {
+            stability_threshold: 2,
+            num_tips: 2,
+            blocks: vec![
+                UnstableBlock {
+                    block_hash: "aa".to_string(),
+                    parent_block_hash: None,
+                    height: 0,
+                    difficulty: 1,
+                    depth: 2,
+                    chainwork: "02".to_string(),
+                    is_main_chain: true,
+                },
+                UnstableBlock {
+                    block_hash: "bb".to_string(),
+                    parent_block_hash: Some("aa".to_string()),
+                    height: 1,
+                    difficulty: 1,
+                    depth: 1,
+                    chainwork: "01".to_string(),
+                    is_main_chain: false,
+                },
+            ],
+            next_block_headers: vec![NextBlockHeader {
+                block_hash: "cc".to_string(),
+                parent_block_hash: "bb".to_string(),
+                height: 2,
+            }],
+        };
+
+        assert_eq!(
+            to_dot(&tree),
+            r#"digraph unstable_blocks {
+  rankdir=LR;
+  node [shape=box, fontname=monospace];
+  label="stability threshold: 2, tips: 2";
+  "aa" [label="aa\nheight: 0\ndifficulty: 1\ndepth: 2", style=filled, fillcolor=lightblue];
+  "bb" [label="bb\nheight: 1\ndifficulty: 1\ndepth: 1"];
+  "aa" -> "bb";
+  "cc" [label="cc\nheight: 2", style=dashed];
+  "bb" -> "cc" [style=dashed];
+}
+"#
+        );
+    }
+}
@@ -0,0 +1,14 @@
+use crate::{runtime::time, with_state, with_state_mut};
+use ic_btc_interface::UtxoSetAuditStatus;
+
+/// Starts an audit of the UTXO set's indexes.
+/// Returns false if an audit is already in progress.
+pub fn start_utxo_set_audit() -> bool {
+    with_state_mut(|s| s.utxo_set_audit.start(&s.utxos, time()))
+}
+
+/// Returns the progress of the current audit of the UTXO set's indexes
+/// and the findings of the last completed one.
+pub fn get_utxo_set_audit_status() -> UtxoSetAuditStatus {
+    with_state(|s| s.utxo_set_audit.status())
+}
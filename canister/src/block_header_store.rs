@@ -1,6 +1,9 @@
-use crate::{memory::Memory, types::BlockHeaderBlob};
+use crate::{
+    memory::Memory,
+    types::{BlockHeaderBlob, Chainwork},
+};
 use bitcoin::consensus::{Decodable, Encodable};
-use bitcoin::BlockHeader;
+use bitcoin::{util::uint::Uint256, BlockHeader};
 use ic_btc_interface::Height;
 use ic_btc_types::{Block, BlockHash};
 use ic_stable_structures::StableBTreeMap;
@@ -18,6 +21,15 @@ pub struct BlockHeaderStore {
     // NOTE: Stable structures don't need to be serialized.
     #[serde(skip, default = "init_block_heights")]
     pub block_heights: StableBTreeMap<Height, BlockHash, Memory>,
+
+    /// A map of a block height to the cumulative work of the chain up to and
+    /// including the block at that height.
+    ///
+    /// NOTE: The chainwork isn't available for the headers that were stored by older
+    /// versions of the canister.
+    // NOTE: Stable structures don't need to be serialized.
+    #[serde(skip, default = "init_chainworks")]
+    pub chainworks: StableBTreeMap<Height, Chainwork, Memory>,
 }
 
 // NOTE: `PartialEq` is only available in tests as it would be impractically
@@ -28,6 +40,7 @@ impl PartialEq for BlockHeaderStore {
         use crate::test_utils::is_stable_btreemap_equal;
         is_stable_btreemap_equal(&self.block_headers, &other.block_headers)
             && is_stable_btreemap_equal(&self.block_heights, &other.block_heights)
+            && is_stable_btreemap_equal(&self.chainworks, &other.chainworks)
     }
 }
 
@@ -36,11 +49,12 @@ impl BlockHeaderStore {
         Self {
             block_headers: init_block_headers(),
             block_heights: init_block_heights(),
+            chainworks: init_chainworks(),
         }
     }
 
-    /// Inserts a block's header and hash into the store.
-    pub fn insert_block(&mut self, block: &Block, height: Height) {
+    /// Inserts a block's header, hash and chainwork into the store.
+    pub fn insert_block(&mut self, block: &Block, height: Height, chainwork: Uint256) {
         let block_hash = block.block_hash();
         let mut header_blob = vec![];
         block
@@ -48,13 +62,39 @@ impl BlockHeaderStore {
             .consensus_encode(&mut header_blob)
             .expect("block header must be valid");
 
-        self.insert(block_hash, BlockHeaderBlob::from(header_blob), height);
+        self.insert(
+            block_hash,
+            BlockHeaderBlob::from(header_blob),
+            height,
+            chainwork,
+        );
     }
 
-    /// Inserts a block's header and hash into the store.
-    pub fn insert(&mut self, block_hash: BlockHash, header_blob: BlockHeaderBlob, height: Height) {
+    /// Inserts a block's header, hash and chainwork into the store.
+    pub fn insert(
+        &mut self,
+        block_hash: BlockHash,
+        header_blob: BlockHeaderBlob,
+        height: Height,
+        chainwork: Uint256,
+    ) {
         self.block_headers.insert(block_hash.clone(), header_blob);
         self.block_heights.insert(height, block_hash);
+        self.chainworks.insert(height, Chainwork(chainwork));
+    }
+
+    /// Removes the header of the block at the given height from the store.
+    pub fn remove_with_height(&mut self, height: Height) {
+        if let Some(block_hash) = self.block_heights.remove(&height) {
+            self.block_headers.remove(&block_hash);
+        }
+        self.chainworks.remove(&height);
+    }
+
+    /// Returns the cumulative work of the chain up to and including the block at the
+    /// given height, if it's available.
+    pub fn get_chainwork_with_height(&self, height: Height) -> Option<Uint256> {
+        self.chainworks.get(&height).map(|chainwork| chainwork.0)
     }
 
     pub fn get_with_block_hash(&self, block_hash: &BlockHash) -> Option<BlockHeader> {
@@ -85,3 +125,7 @@ fn init_block_headers() -> StableBTreeMap<BlockHash, BlockHeaderBlob, Memory> {
 fn init_block_heights() -> StableBTreeMap<u32, BlockHash, Memory> {
     StableBTreeMap::init(crate::memory::get_block_heights_memory())
 }
+
+fn init_chainworks() -> StableBTreeMap<u32, Chainwork, Memory> {
+    StableBTreeMap::init(crate::memory::get_chainworks_memory())
+}
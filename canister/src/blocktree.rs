@@ -1,7 +1,77 @@
+use bitcoin::{util::uint::Uint256, BlockHeader};
 use ic_btc_interface::Network;
 use ic_btc_types::{Block, BlockHash};
 use std::fmt;
 mod serde;
+pub use self::serde::{EncodedBlock, FlattenedBlock, LegacyBlockTree};
+
+/// The metadata of a block that is kept in a `BlockTree`.
+///
+/// Blocks can be several megabytes in size, so the tree only keeps their headers
+/// and hashes. The blocks themselves are kept in an `UnstableBlockStore`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BlockMetadata {
+    header: BlockHeader,
+    block_hash: BlockHash,
+
+    #[cfg(test)]
+    pub mock_difficulty: Option<u64>,
+}
+
+impl BlockMetadata {
+    pub fn header(&self) -> &BlockHeader {
+        &self.header
+    }
+
+    pub fn block_hash(&self) -> BlockHash {
+        self.block_hash.clone()
+    }
+
+    pub fn difficulty(&self, network: Network) -> u64 {
+        #[cfg(test)]
+        if let Some(difficulty) = self.mock_difficulty {
+            return difficulty;
+        }
+
+        Block::target_difficulty(network, self.header.target())
+    }
+
+    /// Returns the expected number of hashes required to mine the block, as computed
+    /// from its target by Bitcoin Core.
+    pub fn work(&self) -> Uint256 {
+        #[cfg(test)]
+        if let Some(difficulty) = self.mock_difficulty {
+            return Uint256::from_u64(difficulty).expect("a u64 must fit in a Uint256");
+        }
+
+        self.header.work()
+    }
+}
+
+impl From<&Block> for BlockMetadata {
+    fn from(block: &Block) -> Self {
+        Self {
+            header: *block.header(),
+            block_hash: block.block_hash(),
+            #[cfg(test)]
+            mock_difficulty: block.mock_difficulty,
+        }
+    }
+}
+
+impl From<Block> for BlockMetadata {
+    fn from(block: Block) -> Self {
+        Self::from(&block)
+    }
+}
+
+// Allows comparing the blocks in a tree with the blocks they were built from.
+#[cfg(test)]
+impl PartialEq<Block> for BlockMetadata {
+    fn eq(&self, other: &Block) -> bool {
+        self.header == *other.header()
+    }
+}
 
 /// Represents a non-empty block chain as:
 /// * the first block of the chain
@@ -9,30 +79,24 @@ mod serde;
 #[derive(Debug, PartialEq, Eq)]
 pub struct BlockChain<'a> {
     // The first block of this `BlockChain`, i.e. the one at the lowest height.
-    first: &'a Block,
+    first: &'a BlockMetadata,
     // The successor blocks of this `BlockChain`, i.e. the chain after the
     // `first` block.
-    successors: Vec<&'a Block>,
+    successors: Vec<&'a BlockMetadata>,
 }
 
 impl<'a> BlockChain<'a> {
     /// Creates a new `BlockChain` with the given `first` block and an empty list
     /// of successors.
-    pub fn new(first: &'a Block) -> Self {
+    pub fn new(first: &'a BlockMetadata) -> Self {
         Self {
             first,
             successors: vec![],
         }
     }
 
-    /// This is only useful for tests to simplify the creation of a `BlockChain`.
-    #[cfg(test)]
-    pub fn new_with_successors(first: &'a Block, successors: Vec<&'a Block>) -> Self {
-        Self { first, successors }
-    }
-
     /// Appends a new block to the list of `successors` of this `BlockChain`.
-    pub fn push(&mut self, block: &'a Block) {
+    pub fn push(&mut self, block: &'a BlockMetadata) {
         self.successors.push(block);
     }
 
@@ -41,11 +105,11 @@ impl<'a> BlockChain<'a> {
         self.successors.len() + 1
     }
 
-    pub fn first(&self) -> &'a Block {
+    pub fn first(&self) -> &'a BlockMetadata {
         self.first
     }
 
-    pub fn tip(&self) -> &'a Block {
+    pub fn tip(&self) -> &'a BlockMetadata {
         match self.successors.last() {
             None => {
                 // The chain consists of only one block, and that is the tip.
@@ -56,7 +120,7 @@ impl<'a> BlockChain<'a> {
     }
 
     /// Consumes this `BlockChain` and returns the entire chain of blocks.
-    pub fn into_chain(self) -> Vec<&'a Block> {
+    pub fn into_chain(self) -> Vec<&'a BlockMetadata> {
         let mut chain = vec![self.first];
         chain.extend(self.successors);
         chain
@@ -77,30 +141,46 @@ impl fmt::Display for EmptyChainError {
 /// Maintains a tree of connected blocks.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BlockTree {
-    pub root: Block,
+    pub root: BlockMetadata,
+    /// The cumulative work of the chain up to and including the root.
+    pub chainwork: Uint256,
+    /// The order in which the root was added to the tree, relative to the other
+    /// blocks. It breaks ties between tips with the same chainwork.
+    pub first_seen: u64,
     pub children: Vec<BlockTree>,
 }
 
 impl BlockTree {
     /// Creates a new `BlockTree` with the given block as its root.
-    pub fn new(root: Block) -> Self {
+    ///
+    /// The chainwork of the tree is counted from the root.
+    pub fn new(root: BlockMetadata) -> Self {
+        let chainwork = root.work();
+        Self::with_chainwork(root, chainwork)
+    }
+
+    /// Creates a new `BlockTree` with the given block as its root and the given
+    /// cumulative work of the chain up to and including the root.
+    pub fn with_chainwork(root: BlockMetadata, chainwork: Uint256) -> Self {
         Self {
             root,
+            chainwork,
+            first_seen: 0,
             children: vec![],
         }
     }
 
     /// Returns all blocks in the tree with their depths
     /// separated by heights.
-    pub fn blocks_with_depths_by_heights(&self) -> Vec<Vec<(&Block, u32)>> {
-        let mut blocks_with_depths_by_heights: Vec<Vec<(&Block, u32)>> = vec![vec![]];
+    pub fn blocks_with_depths_by_heights(&self) -> Vec<Vec<(&BlockMetadata, u32)>> {
+        let mut blocks_with_depths_by_heights: Vec<Vec<(&BlockMetadata, u32)>> = vec![vec![]];
         self.blocks_with_depths_by_heights_helper(&mut blocks_with_depths_by_heights, 0);
         blocks_with_depths_by_heights
     }
 
     fn blocks_with_depths_by_heights_helper<'a>(
         &'a self,
-        blocks_with_depth_by_height: &mut Vec<Vec<(&'a Block, u32)>>,
+        blocks_with_depth_by_height: &mut Vec<Vec<(&'a BlockMetadata, u32)>>,
         height: usize,
     ) -> u32 {
         let mut depth: u32 = 0;
@@ -130,17 +210,34 @@ impl BlockTree {
         }
     }
 
-    /// Extends the tree with the given block.
+    /// Returns the number of blocks in the tree.
+    pub fn num_blocks(&self) -> usize {
+        1 + self.children.iter().map(|c| c.num_blocks()).sum::<usize>()
+    }
+
+    /// Returns the subtrees whose roots are the tips of the tree, in depth-first order.
+    pub fn tips(&self) -> Vec<&BlockTree> {
+        if self.children.is_empty() {
+            vec![self]
+        } else {
+            self.children.iter().flat_map(|c| c.tips()).collect()
+        }
+    }
+
+    /// Extends the tree with the given block. The block is marked as seen after all
+    /// the blocks already in the tree.
     ///
     /// Blocks can extend the tree in the following cases:
     ///   * The block is already present in the tree (no-op).
     ///   * The block is a successor of a block already in the tree.
-    pub fn extend(&mut self, block: Block) -> Result<(), BlockDoesNotExtendTree> {
+    pub fn extend(&mut self, block: BlockMetadata) -> Result<(), BlockDoesNotExtendTree> {
         if self.contains(&block) {
             // The block is already present in the tree. Nothing to do.
             return Ok(());
         }
 
+        let first_seen = self.max_first_seen() + 1;
+
         // Check if the block is a successor to any of the blocks in the tree.
         match self.find_mut(&block.header().prev_blockhash.into()) {
             Some((block_subtree, _)) => {
@@ -149,7 +246,11 @@ impl BlockTree {
                     block.header().prev_blockhash.to_vec()
                 );
                 // Add the block as a successor.
-                block_subtree.children.push(BlockTree::new(block));
+                let chainwork = block_subtree.chainwork + block.work();
+                block_subtree.children.push(BlockTree {
+                    first_seen,
+                    ..BlockTree::with_chainwork(block, chainwork)
+                });
                 Ok(())
             }
             None => Err(BlockDoesNotExtendTree(block.block_hash())),
@@ -205,7 +306,7 @@ impl BlockTree {
     // Do a depth-first search to find the blockchain that ends with the given `tip`.
     // For performance reasons, the list is returned in the reverse order, starting
     // from `tip` and ending with `anchor`.
-    fn get_chain_with_tip_reverse<'a>(&'a self, tip: &BlockHash) -> Option<Vec<&'a Block>> {
+    fn get_chain_with_tip_reverse<'a>(&'a self, tip: &BlockHash) -> Option<Vec<&'a BlockMetadata>> {
         if self.root.block_hash() == *tip {
             return Some(vec![&self.root]);
         }
@@ -239,6 +340,45 @@ impl BlockTree {
         res
     }
 
+    /// Returns the tip with the most chainwork. If several tips have the most
+    /// chainwork, the one that was seen first is returned, as in Bitcoin Core.
+    pub fn max_chainwork_tip(&self) -> &BlockTree {
+        let mut tip = self;
+        for child in self.children.iter() {
+            let child_tip = child.max_chainwork_tip();
+            if child_tip.chainwork > tip.chainwork
+                || (child_tip.chainwork == tip.chainwork && child_tip.first_seen < tip.first_seen)
+            {
+                tip = child_tip;
+            }
+        }
+        tip
+    }
+
+    // Returns the order in which the last block of the tree was seen.
+    fn max_first_seen(&self) -> u64 {
+        self.children
+            .iter()
+            .map(|child| child.max_first_seen())
+            .fold(self.first_seen, std::cmp::max)
+    }
+
+    // Returns a `BlockTree` where the hash of the root block matches the provided `block_hash`
+    // if it exists, and `None` otherwise.
+    pub fn find(&self, blockhash: &BlockHash) -> Option<&BlockTree> {
+        if self.root.block_hash() == *blockhash {
+            return Some(self);
+        }
+
+        for child in self.children.iter() {
+            if let res @ Some(_) = child.find(blockhash) {
+                return res;
+            }
+        }
+
+        None
+    }
+
     // Returns a `BlockTree` where the hash of the root block matches the provided `block_hash`
     // along with its depth if it exists, and `None` otherwise.
     pub fn find_mut<'a>(&'a mut self, blockhash: &BlockHash) -> Option<(&'a mut BlockTree, u32)> {
@@ -263,8 +403,49 @@ impl BlockTree {
         find_mut_helper(self, blockhash, 0)
     }
 
+    /// Removes the subtrees whose tips all have at most the given chainwork, and
+    /// returns them. The root of the tree is never removed.
+    pub fn remove_forks(&mut self, max_chainwork: Uint256) -> Vec<BlockTree> {
+        let mut removed = vec![];
+        let mut i = 0;
+        while i < self.children.len() {
+            if self.children[i].max_chainwork_tip().chainwork <= max_chainwork {
+                removed.push(self.children.remove(i));
+            } else {
+                removed.extend(self.children[i].remove_forks(max_chainwork));
+                i += 1;
+            }
+        }
+        removed
+    }
+
+    /// Removes the branch that ends with the given tip, i.e. the tip along with the
+    /// ancestors that have no other tips, and returns it. The root of the tree is never
+    /// removed.
+    pub fn remove_branch(&mut self, tip: &BlockHash) -> Option<BlockTree> {
+        let idx = self
+            .children
+            .iter()
+            .position(|child| child.find(tip).is_some())?;
+
+        if self.children[idx].num_tips() == 1 {
+            Some(self.children.remove(idx))
+        } else {
+            self.children[idx].remove_branch(tip)
+        }
+    }
+
+    /// Returns the hashes of all the blocks in the tree.
+    pub fn block_hashes(&self) -> Vec<BlockHash> {
+        let mut block_hashes = vec![self.root.block_hash()];
+        for child in self.children.iter() {
+            block_hashes.extend(child.block_hashes());
+        }
+        block_hashes
+    }
+
     // Returns true if a block exists in the tree, false otherwise.
-    fn contains(&self, block: &Block) -> bool {
+    fn contains(&self, block: &BlockMetadata) -> bool {
         if self.root.block_hash() == block.block_hash() {
             return true;
         }
@@ -287,7 +468,10 @@ pub struct BlockDoesNotExtendTree(pub BlockHash);
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_utils::{BlockBuilder, BlockChainBuilder};
+    use crate::test_utils::{
+        random_p2pkh_address, BlockBuilder, BlockChainBuilder, TransactionBuilder,
+    };
+    use bitcoin::consensus::Encodable;
     use proptest::collection::vec as pvec;
     use proptest::prelude::*;
     use test_strategy::proptest;
@@ -305,8 +489,9 @@ mod test {
                 }
 
                 for _ in 0..num_children[0] {
-                    let mut subtree =
-                        BlockTree::new(BlockBuilder::with_prev_header(tree.root.header()).build());
+                    let block = BlockBuilder::with_prev_header(tree.root.header()).build();
+                    let chainwork = tree.chainwork + block.work();
+                    let mut subtree = BlockTree::with_chainwork(block.into(), chainwork);
 
                     build_block_tree(&mut subtree, &num_children[1..]);
                     tree.children.push(subtree);
@@ -316,7 +501,7 @@ mod test {
             // Each depth can have up to 3 children, up to a depth of 10.
             pvec(1..3u8, 0..10)
                 .prop_map(|num_children| {
-                    let mut tree = BlockTree::new(BlockBuilder::genesis().build());
+                    let mut tree = BlockTree::new(BlockBuilder::genesis().build().into());
                     build_block_tree(&mut tree, &num_children);
                     tree
                 })
@@ -326,7 +511,7 @@ mod test {
 
     #[test]
     fn tree_single_block() {
-        let block_tree = BlockTree::new(BlockBuilder::genesis().build());
+        let block_tree = BlockTree::new(BlockBuilder::genesis().build().into());
 
         assert_eq!(
             block_tree.blockchains(),
@@ -341,13 +526,17 @@ mod test {
     fn tree_multiple_forks() {
         let genesis_block = BlockBuilder::genesis().build();
         let genesis_block_header = *genesis_block.header();
-        let mut block_tree = BlockTree::new(genesis_block);
+        let mut block_tree = BlockTree::new(genesis_block.into());
 
         for i in 1..5 {
             // Create different blocks extending the genesis block.
             // Each one of these should be a separate fork.
             block_tree
-                .extend(BlockBuilder::with_prev_header(&genesis_block_header).build())
+                .extend(
+                    BlockBuilder::with_prev_header(&genesis_block_header)
+                        .build()
+                        .into(),
+                )
                 .unwrap();
             assert_eq!(block_tree.blockchains().len(), i);
         }
@@ -362,10 +551,10 @@ mod test {
             blocks.push(BlockBuilder::with_prev_header(blocks[i - 1].header()).build())
         }
 
-        let mut block_tree = BlockTree::new(blocks[0].clone());
+        let mut block_tree = BlockTree::new((&blocks[0]).into());
 
         for block in blocks.iter() {
-            block_tree.extend(block.clone()).unwrap();
+            block_tree.extend(block.into()).unwrap();
         }
 
         for (i, block) in blocks.iter().enumerate() {
@@ -397,7 +586,7 @@ mod test {
     #[test]
     fn chain_with_tip_multiple_forks() {
         let mut blocks = vec![BlockBuilder::genesis().build()];
-        let mut block_tree = BlockTree::new(blocks[0].clone());
+        let mut block_tree = BlockTree::new((&blocks[0]).into());
 
         let num_forks = 5;
         for _ in 0..num_forks {
@@ -406,7 +595,7 @@ mod test {
             }
 
             for block in blocks.iter() {
-                block_tree.extend(block.clone()).unwrap();
+                block_tree.extend(block.into()).unwrap();
             }
 
             for (i, block) in blocks.iter().enumerate() {
@@ -440,7 +629,8 @@ mod test {
 
     #[test]
     fn test_difficulty_based_depth_single_block() {
-        let block_tree = BlockTree::new(BlockBuilder::genesis().build_with_mock_difficulty(5));
+        let block_tree =
+            BlockTree::new(BlockBuilder::genesis().build_with_mock_difficulty(5).into());
 
         assert_eq!(block_tree.difficulty_based_depth(Network::Mainnet), 5);
     }
@@ -449,13 +639,14 @@ mod test {
     fn test_difficulty_based_depth_root_with_children() {
         let genesis_block = BlockBuilder::genesis().build_with_mock_difficulty(5);
         let genesis_block_header = *genesis_block.header();
-        let mut block_tree = BlockTree::new(genesis_block);
+        let mut block_tree = BlockTree::new(genesis_block.into());
 
         for i in 1..11 {
             block_tree
                 .extend(
                     BlockBuilder::with_prev_header(&genesis_block_header)
-                        .build_with_mock_difficulty(i),
+                        .build_with_mock_difficulty(i)
+                        .into(),
                 )
                 .unwrap();
         }
@@ -465,10 +656,150 @@ mod test {
         assert_eq!(block_tree.difficulty_based_depth(Network::Mainnet), 15);
     }
 
+    #[test]
+    fn chainwork_is_cumulative() {
+        let genesis_block = BlockBuilder::genesis().build_with_mock_difficulty(5);
+        let block_1 =
+            BlockBuilder::with_prev_header(genesis_block.header()).build_with_mock_difficulty(3);
+        let block_2 =
+            BlockBuilder::with_prev_header(block_1.header()).build_with_mock_difficulty(4);
+        let fork_block_1 =
+            BlockBuilder::with_prev_header(genesis_block.header()).build_with_mock_difficulty(10);
+
+        let mut block_tree = BlockTree::new(genesis_block.into());
+        for block in [block_1, block_2.clone(), fork_block_1.clone()] {
+            block_tree.extend(block.into()).unwrap();
+        }
+
+        let chainwork = |block: &Block| block_tree.find(&block.block_hash()).unwrap().chainwork;
+        assert_eq!(chainwork(&block_2), Uint256::from_u64(12).unwrap());
+        assert_eq!(chainwork(&fork_block_1), Uint256::from_u64(15).unwrap());
+
+        // The fork has more work, even though it's shorter.
+        assert_eq!(
+            block_tree.max_chainwork_tip().root.block_hash(),
+            fork_block_1.block_hash()
+        );
+    }
+
+    #[test]
+    fn chainwork_can_start_from_the_anchor_chainwork() {
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let anchor_chainwork = Uint256::from_u64(1_000).unwrap();
+
+        let mut block_tree = BlockTree::with_chainwork(genesis_block.into(), anchor_chainwork);
+        block_tree.extend((&block_1).into()).unwrap();
+
+        assert_eq!(
+            block_tree.children[0].chainwork,
+            anchor_chainwork + block_1.header().work()
+        );
+    }
+
+    #[test]
+    fn max_chainwork_tip_prefers_the_first_tip() {
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let fork_block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+
+        let mut block_tree = BlockTree::new(genesis_block.into());
+        block_tree.extend((&block_1).into()).unwrap();
+        block_tree.extend(fork_block_1.into()).unwrap();
+
+        assert_eq!(
+            block_tree.max_chainwork_tip().root.block_hash(),
+            block_1.block_hash()
+        );
+    }
+
+    #[test]
+    fn max_chainwork_tip_prefers_the_tip_seen_first() {
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let fork_block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let fork_block_2 = BlockBuilder::with_prev_header(fork_block_1.header()).build();
+
+        // The tip of the fork is seen first, even though the fork comes second in
+        // depth-first order.
+        let mut block_tree = BlockTree::new(genesis_block.into());
+        for block in [&block_1, &fork_block_1, &fork_block_2, &block_2] {
+            block_tree.extend(block.into()).unwrap();
+        }
+
+        assert_eq!(
+            block_tree.max_chainwork_tip().root.block_hash(),
+            fork_block_2.block_hash()
+        );
+    }
+
+    #[test]
+    fn remove_forks_keeps_the_forks_with_more_chainwork() {
+        let genesis_block = BlockBuilder::genesis().build_with_mock_difficulty(1);
+        let block_1 =
+            BlockBuilder::with_prev_header(genesis_block.header()).build_with_mock_difficulty(5);
+        let block_2 =
+            BlockBuilder::with_prev_header(block_1.header()).build_with_mock_difficulty(5);
+        let fork_block_2 =
+            BlockBuilder::with_prev_header(block_1.header()).build_with_mock_difficulty(1);
+        let fork_block_1 =
+            BlockBuilder::with_prev_header(genesis_block.header()).build_with_mock_difficulty(2);
+
+        let mut block_tree = BlockTree::new((&genesis_block).into());
+        for block in [&block_1, &block_2, &fork_block_2, &fork_block_1] {
+            block_tree.extend(block.into()).unwrap();
+        }
+
+        // Removes the forks with a chainwork of at most 7, i.e. `fork_block_2` and
+        // `fork_block_1`.
+        let removed = block_tree.remove_forks(Uint256::from_u64(7).unwrap());
+        assert_eq!(
+            removed
+                .iter()
+                .map(|fork| fork.root.block_hash())
+                .collect::<Vec<_>>(),
+            vec![fork_block_2.block_hash(), fork_block_1.block_hash()]
+        );
+        assert_eq!(
+            block_tree.block_hashes(),
+            vec![
+                genesis_block.block_hash(),
+                block_1.block_hash(),
+                block_2.block_hash()
+            ]
+        );
+    }
+
+    #[test]
+    fn remove_branch_removes_the_ancestors_without_other_tips() {
+        let chain = BlockChainBuilder::new(3).build();
+        let fork = BlockChainBuilder::fork(&chain[0], 2).build();
+
+        let mut block_tree = BlockTree::new((&chain[0]).into());
+        for block in chain.iter().skip(1).chain(fork.iter()) {
+            block_tree.extend(block.into()).unwrap();
+        }
+        assert_eq!(block_tree.num_blocks(), 5);
+        assert_eq!(block_tree.num_tips(), 2);
+
+        let removed = block_tree.remove_branch(&fork[1].block_hash()).unwrap();
+        assert_eq!(
+            removed.block_hashes(),
+            vec![fork[0].block_hash(), fork[1].block_hash()]
+        );
+        assert_eq!(block_tree.num_blocks(), 3);
+        assert_eq!(block_tree.num_tips(), 1);
+
+        // Tips that aren't in the tree aren't removed.
+        assert_eq!(block_tree.remove_branch(&fork[1].block_hash()), None);
+        assert_eq!(block_tree.num_blocks(), 3);
+    }
+
     #[test]
     fn test_blocks_with_depths_by_heights_only_root() {
         let genesis_block = BlockBuilder::genesis().build();
-        let block_tree = BlockTree::new(genesis_block.clone());
+        let block_tree = BlockTree::new((&genesis_block).into());
         let blocks_with_depths_by_heights = block_tree.blocks_with_depths_by_heights();
 
         // The number of rows in blocks_with_depths_by_heights should be 1.
@@ -487,14 +818,14 @@ mod test {
         let chain_len: usize = 10;
         let chain = BlockChainBuilder::new(chain_len as u32).build();
 
-        let mut block_tree = BlockTree::new(chain[0].clone());
+        let mut block_tree = BlockTree::new((&chain[0]).into());
 
         let mut expected_blocks_with_depths_by_heights: Vec<Vec<(&Block, u32)>> =
             vec![vec![]; chain_len];
 
         for (i, block) in chain.iter().enumerate() {
             expected_blocks_with_depths_by_heights[i].push((block, (chain_len - i) as u32));
-            block_tree.extend(block.clone()).unwrap();
+            block_tree.extend(block.into()).unwrap();
         }
 
         let actual_blocks_with_depths_by_heights = block_tree.blocks_with_depths_by_heights();
@@ -517,10 +848,10 @@ mod test {
         // Create a fork from the genesis block with length 2.
         let fork = BlockChainBuilder::fork(&chain[0], 2).build();
 
-        let mut block_tree = BlockTree::new(chain[0].clone());
-        block_tree.extend(chain[1].clone()).unwrap();
-        block_tree.extend(fork[0].clone()).unwrap();
-        block_tree.extend(fork[1].clone()).unwrap();
+        let mut block_tree = BlockTree::new((&chain[0]).into());
+        block_tree.extend((&chain[1]).into()).unwrap();
+        block_tree.extend((&fork[0]).into()).unwrap();
+        block_tree.extend((&fork[1]).into()).unwrap();
 
         let blocks_with_depths_by_heights = block_tree.blocks_with_depths_by_heights();
 
@@ -568,10 +899,10 @@ mod test {
     #[test]
     fn deserialize_very_deep_block_tree() {
         let chain = BlockChainBuilder::new(5_000).build();
-        let mut tree = BlockTree::new(chain[0].clone());
+        let mut tree = BlockTree::new((&chain[0]).into());
 
         for block in chain.into_iter().skip(1) {
-            tree.extend(block).unwrap();
+            tree.extend(block.into()).unwrap();
         }
 
         let mut bytes = vec![];
@@ -580,6 +911,76 @@ mod test {
         assert_eq!(tree, new_tree);
     }
 
+    #[test]
+    fn serializes_only_the_block_headers() {
+        // A block that's considerably larger than its header.
+        let mut block_builder = BlockBuilder::genesis();
+        for _ in 0..100 {
+            block_builder = block_builder.with_transaction(
+                TransactionBuilder::coinbase(0)
+                    .with_output(&random_p2pkh_address(Network::Regtest), 1_000)
+                    .build(),
+            );
+        }
+        let block = block_builder.build();
+        let tree = BlockTree::new((&block).into());
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&tree, &mut bytes).unwrap();
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+        assert!(bytes.len() * 10 < block_bytes.len());
+
+        let new_tree: BlockTree = ciborium::de::from_reader(&bytes[..]).unwrap();
+        assert_eq!(tree, new_tree);
+    }
+
+    #[test]
+    fn blocks_of_legacy_trees_are_seen_in_the_order_they_are_serialized() {
+        // A block as it was encoded by older versions of the canister.
+        #[derive(serde::Serialize)]
+        struct LegacyEncodedBlock {
+            #[serde(with = "serde_bytes")]
+            bytes: Vec<u8>,
+        }
+
+        let encode = |block: &Block, num_children: usize| {
+            let mut bytes = vec![];
+            block.header().consensus_encode(&mut bytes).unwrap();
+            (LegacyEncodedBlock { bytes }, num_children)
+        };
+
+        let genesis_block = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+        let fork_block_1 = BlockBuilder::with_prev_header(genesis_block.header()).build();
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(
+            &vec![
+                encode(&genesis_block, 2),
+                encode(&block_1, 0),
+                encode(&fork_block_1, 0),
+            ],
+            &mut bytes,
+        )
+        .unwrap();
+        let tree: BlockTree = ciborium::de::from_reader(&bytes[..]).unwrap();
+
+        assert_eq!(tree.first_seen, 0);
+        assert_eq!(tree.children[0].first_seen, 1);
+        assert_eq!(tree.children[1].first_seen, 2);
+        assert_eq!(
+            tree.max_chainwork_tip().root.block_hash(),
+            block_1.block_hash()
+        );
+
+        // The order is kept once the tree is serialized again.
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&tree, &mut bytes).unwrap();
+        let new_tree: BlockTree = ciborium::de::from_reader(&bytes[..]).unwrap();
+        assert_eq!(tree, new_tree);
+    }
+
     #[proptest]
     fn serialize_deserialize(tree: BlockTree) {
         let mut bytes = vec![];
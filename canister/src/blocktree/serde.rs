@@ -1,11 +1,88 @@
-use super::BlockTree;
-use ic_btc_types::Block;
+use super::{BlockMetadata, BlockTree};
+use bitcoin::{
+    consensus::{Decodable, Encodable},
+    util::uint::Uint256,
+    BlockHeader,
+};
+use ic_btc_types::BlockHash;
 use serde::{
-    de::{Deserializer, SeqAccess, Visitor},
+    de::{DeserializeOwned, Deserializer, SeqAccess, Visitor},
     ser::SerializeSeq,
     Deserialize, Serialize, Serializer,
 };
-use std::fmt;
+use std::{fmt, marker::PhantomData};
+
+// The size of a block header in the standard bitcoin format.
+const BLOCK_HEADER_SIZE: usize = 80;
+
+// A block of a flattened tree.
+//
+// Only the header of the block is serialized, in the standard bitcoin format, as the
+// block itself is kept in the `UnstableBlockStore`.
+#[derive(Serialize, Deserialize)]
+pub struct EncodedBlock {
+    #[serde(with = "serde_bytes")]
+    bytes: Vec<u8>,
+
+    // The cumulative work of the chain up to and including the block. Trees that were
+    // serialized by older versions of the canister don't contain it, in which case
+    // it's recomputed.
+    #[serde(default)]
+    chainwork: Option<Uint256>,
+
+    // The order in which the block was added to the tree. Trees that were serialized by
+    // older versions of the canister don't contain it, in which case the blocks are
+    // assumed to have been seen in the order they're serialized.
+    #[serde(default)]
+    first_seen: Option<u64>,
+
+    #[cfg(test)]
+    #[serde(default)]
+    mock_difficulty: Option<u64>,
+}
+
+impl From<&BlockTree> for EncodedBlock {
+    fn from(tree: &BlockTree) -> Self {
+        let mut bytes = vec![];
+        tree.root
+            .header()
+            .consensus_encode(&mut bytes)
+            .expect("encoding a block header must succeed");
+        Self {
+            bytes,
+            chainwork: Some(tree.chainwork),
+            first_seen: Some(tree.first_seen),
+            #[cfg(test)]
+            mock_difficulty: tree.root.mock_difficulty,
+        }
+    }
+}
+
+/// A block of a flattened tree, in the encoding of some version of the canister.
+pub trait FlattenedBlock: DeserializeOwned {
+    /// Returns the metadata of the block along with its chainwork and the order in which
+    /// it was seen, if they're available.
+    fn into_metadata(self) -> (BlockMetadata, Option<Uint256>, Option<u64>);
+}
+
+impl FlattenedBlock for EncodedBlock {
+    fn into_metadata(self) -> (BlockMetadata, Option<Uint256>, Option<u64>) {
+        assert_eq!(
+            self.bytes.len(),
+            BLOCK_HEADER_SIZE,
+            "an encoded block must only contain its header"
+        );
+        let header = BlockHeader::consensus_decode(self.bytes.as_slice())
+            .expect("decoding a block header must succeed");
+        let metadata = BlockMetadata {
+            block_hash: BlockHash::from(header.block_hash()),
+            header,
+            #[cfg(test)]
+            mock_difficulty: self.mock_difficulty,
+        };
+        (metadata, self.chainwork, self.first_seen)
+    }
+}
 
 // Serialize a BlockTree by first flattening it into a list.
 //
@@ -14,8 +91,8 @@ use std::fmt;
 impl Serialize for BlockTree {
     fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
         // Flatten a block tree into a list.
-        fn flatten(tree: &BlockTree, flattened_tree: &mut Vec<(Block, usize)>) {
-            flattened_tree.push((tree.root.clone(), tree.children.len()));
+        fn flatten<'a>(tree: &'a BlockTree, flattened_tree: &mut Vec<(&'a BlockTree, usize)>) {
+            flattened_tree.push((tree, tree.children.len()));
 
             for child in &tree.children {
                 flatten(child, flattened_tree);
@@ -26,8 +103,9 @@ impl Serialize for BlockTree {
         flatten(self, &mut flattened_tree);
 
         let mut seq = serializer.serialize_seq(Some(flattened_tree.len()))?;
-        for e in flattened_tree {
-            seq.serialize_element(&e)?;
+        for (tree, num_children) in flattened_tree {
+            // Blocks are encoded one at a time to avoid holding a copy of the whole tree.
+            seq.serialize_element(&(EncodedBlock::from(tree), num_children))?;
         }
         seq.end()
     }
@@ -38,13 +116,35 @@ impl<'de> Deserialize<'de> for BlockTree {
     where
         D: Deserializer<'de>,
     {
-        deserializer.deserialize_seq(BlockTreeDeserializer)
+        deserializer.deserialize_seq(BlockTreeDeserializer::<EncodedBlock>(PhantomData))
+    }
+}
+
+/// A `BlockTree` as it was serialized by older versions of the canister, whose blocks
+/// are encoded as `B`.
+pub struct LegacyBlockTree<B>(BlockTree, PhantomData<B>);
+
+impl<B> From<LegacyBlockTree<B>> for BlockTree {
+    fn from(legacy: LegacyBlockTree<B>) -> Self {
+        legacy.0
+    }
+}
+
+impl<'de, B: FlattenedBlock> Deserialize<'de> for LegacyBlockTree<B> {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let tree = deserializer.deserialize_seq(BlockTreeDeserializer::<B>(PhantomData))?;
+        Ok(Self(tree, PhantomData))
     }
 }
 
-struct BlockTreeDeserializer;
+// Deserializes a flattened tree whose blocks are encoded as `B`. The blocks are
+// deserialized one at a time, so that a tree of large blocks is never held in full.
+struct BlockTreeDeserializer<B>(PhantomData<B>);
 
-impl<'de> Visitor<'de> for BlockTreeDeserializer {
+impl<'de, B: FlattenedBlock> Visitor<'de> for BlockTreeDeserializer<B> {
     type Value = BlockTree;
 
     fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
@@ -52,17 +152,42 @@ impl<'de> Visitor<'de> for BlockTreeDeserializer {
     }
 
     fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Self::Value, A::Error> {
-        fn next<'de, A: SeqAccess<'de>>(seq: &mut A) -> Option<(Block, usize)> {
-            seq.next_element()
+        // Returns the next block along with its chainwork, the order in which it was seen,
+        // and its number of children. Blocks without an order are ordered by their
+        // position in the sequence.
+        fn next<'de, A: SeqAccess<'de>, B: FlattenedBlock>(
+            seq: &mut A,
+            position: &mut u64,
+        ) -> Option<(BlockMetadata, Option<Uint256>, u64, usize)> {
+            seq.next_element::<(B, usize)>()
                 .expect("reading next element must succeed")
+                .map(|(block, num_children)| {
+                    let (block, chainwork, first_seen) = block.into_metadata();
+                    let first_seen = first_seen.unwrap_or(*position);
+                    *position += 1;
+                    (block, chainwork, first_seen, num_children)
+                })
         }
 
+        let mut position = 0;
+
         // A stack containing a `BlockTree` along with how many children remain to be added to it.
         let mut stack: Vec<(BlockTree, usize)> = Vec::new();
 
         // Read the root and add it to the stack.
-        let (root, children_to_add) = next(&mut seq).unwrap();
-        stack.push((BlockTree::new(root), children_to_add));
+        let (root, chainwork, first_seen, children_to_add) =
+            next::<_, B>(&mut seq, &mut position).unwrap();
+        let root_tree = match chainwork {
+            Some(chainwork) => BlockTree::with_chainwork(root, chainwork),
+            None => BlockTree::new(root),
+        };
+        stack.push((
+            BlockTree {
+                first_seen,
+                ..root_tree
+            },
+            children_to_add,
+        ));
 
         while let Some((tree, children_to_add)) = stack.pop() {
             if children_to_add == 0 {
@@ -72,7 +197,7 @@ impl<'de> Visitor<'de> for BlockTreeDeserializer {
                     None => {
                         // There's no parent to this tree. Deserialization is complete.
                         // Assert that there's no more data to deserialize.
-                        assert_eq!(next(&mut seq), None);
+                        assert_eq!(next::<_, B>(&mut seq, &mut position), None);
                         return Ok(tree);
                     }
                 }
@@ -81,9 +206,19 @@ impl<'de> Visitor<'de> for BlockTreeDeserializer {
                 // to be added.
                 stack.push((tree, children_to_add - 1));
 
-                // Add the child to the stack.
-                let (child, grand_children_to_add) = next(&mut seq).unwrap();
-                stack.push((BlockTree::new(child), grand_children_to_add));
+                // Add the child to the stack. Its chainwork is recomputed from its parent's
+                // if it's missing.
+                let (child, chainwork, first_seen, grand_children_to_add) =
+                    next::<_, B>(&mut seq, &mut position).unwrap();
+                let parent_chainwork = stack.last().expect("child must have a parent").0.chainwork;
+                let chainwork = chainwork.unwrap_or_else(|| parent_chainwork + child.work());
+                stack.push((
+                    BlockTree {
+                        first_seen,
+                        ..BlockTree::with_chainwork(child, chainwork)
+                    },
+                    grand_children_to_add,
+                ));
             }
         }
 
@@ -0,0 +1,215 @@
+use crate::state::State;
+use candid::Principal;
+use ic_btc_interface::{FeePolicy, FeeTier};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+// The maximum number of callers whose free calls are counted in a period. As principals
+// are free to create, callers that aren't in any tier of the policy don't get free calls
+// once it's reached, until the next period. Callers in a tier always do, as there's a
+// bounded number of them.
+const MAX_CALLERS_PER_PERIOD: usize = 10_000;
+
+/// How a call is charged under the fee policy.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CallCharge {
+    /// The call is free, as the caller had free calls left.
+    Free,
+
+    /// The caller is charged this percentage of the fees.
+    Percent(u32),
+}
+
+impl CallCharge {
+    /// Returns the amount of cycles charged for a fee of the given amount.
+    pub fn apply(&self, amount: u128) -> u128 {
+        match self {
+            Self::Free => 0,
+            Self::Percent(percent) => amount.saturating_mul(*percent as u128) / 100,
+        }
+    }
+}
+
+/// The number of free calls each caller made in the current period of the fee policy.
+// NOTE: Only the counts of the current period are kept, so that the counts of callers
+// that stopped calling don't accumulate.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct FreeCalls {
+    period: u64,
+    num_calls: BTreeMap<Principal, u64>,
+}
+
+impl FreeCalls {
+    // Returns the number of free calls the caller made in the given period.
+    fn get(&self, period: u64, caller: &Principal) -> u64 {
+        if self.period != period {
+            return 0;
+        }
+        self.num_calls.get(caller).cloned().unwrap_or(0)
+    }
+
+    // Returns true if a free call of a caller that isn't in any tier can be counted in
+    // the given period.
+    fn has_room_for(&self, period: u64, caller: &Principal) -> bool {
+        self.period != period
+            || self.num_calls.len() < MAX_CALLERS_PER_PERIOD
+            || self.num_calls.contains_key(caller)
+    }
+
+    // Counts a free call of the caller in the given period.
+    fn observe(&mut self, period: u64, caller: Principal) {
+        if self.period != period {
+            self.period = period;
+            self.num_calls.clear();
+        }
+        *self.num_calls.entry(caller).or_default() += 1;
+    }
+}
+
+/// Returns how the caller would be charged for a call made at the given time,
+/// in seconds since the UNIX epoch.
+pub fn call_charge(state: &State, caller: &Principal, now: u64) -> CallCharge {
+    let policy = &state.fee_policy;
+    let period = period(policy, now);
+    let (fee_percent, free_calls_per_period, has_room) = match get_tier(policy, caller) {
+        Some(tier) => (tier.fee_percent, tier.free_calls_per_period, true),
+        None => (
+            100,
+            policy.free_calls_per_period,
+            state.free_calls.has_room_for(period, caller),
+        ),
+    };
+
+    if has_room && state.free_calls.get(period, caller) < free_calls_per_period {
+        CallCharge::Free
+    } else {
+        CallCharge::Percent(fee_percent)
+    }
+}
+
+/// Same as `call_charge`, but uses up one of the caller's free calls if the call is free.
+pub fn charge_call(state: &mut State, caller: Principal, now: u64) -> CallCharge {
+    let charge = call_charge(state, &caller, now);
+    if charge == CallCharge::Free {
+        let period = period(&state.fee_policy, now);
+        state.free_calls.observe(period, caller);
+    }
+    charge
+}
+
+// Returns the first tier of the policy that the caller is in, if any.
+fn get_tier<'a>(policy: &'a FeePolicy, caller: &Principal) -> Option<&'a FeeTier> {
+    policy
+        .tiers
+        .iter()
+        .find(|tier| tier.principals.contains(caller))
+}
+
+// Returns the index of the period of the policy that the given time is in.
+fn period(policy: &FeePolicy, now: u64) -> u64 {
+    now.checked_div(policy.period_secs).unwrap_or(0)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{init, with_state_mut};
+    use ic_btc_interface::Config;
+
+    #[test]
+    fn callers_are_charged_the_fees_of_their_tier() {
+        let system_canister = Principal::from_slice(&[1]);
+        let discounted_canister = Principal::from_slice(&[2]);
+        let other_canister = Principal::from_slice(&[3]);
+        init(Config {
+            fee_policy: FeePolicy {
+                tiers: vec![
+                    FeeTier {
+                        principals: vec![system_canister],
+                        fee_percent: 0,
+                        free_calls_per_period: 0,
+                    },
+                    FeeTier {
+                        principals: vec![discounted_canister, system_canister],
+                        fee_percent: 50,
+                        free_calls_per_period: 0,
+                    },
+                ],
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        with_state_mut(|s| {
+            assert_eq!(charge_call(s, system_canister, 0).apply(1_000), 0);
+            assert_eq!(charge_call(s, discounted_canister, 0).apply(1_000), 500);
+            assert_eq!(charge_call(s, other_canister, 0).apply(1_000), 1_000);
+        });
+    }
+
+    #[test]
+    fn free_calls_are_replenished_every_period() {
+        let developer = Principal::from_slice(&[1]);
+        let other_developer = Principal::from_slice(&[2]);
+        init(Config {
+            fee_policy: FeePolicy {
+                tiers: vec![],
+                free_calls_per_period: 2,
+                period_secs: 100,
+            },
+            ..Default::default()
+        });
+
+        with_state_mut(|s| {
+            assert_eq!(call_charge(s, &developer, 0), CallCharge::Free);
+            assert_eq!(charge_call(s, developer, 0), CallCharge::Free);
+            assert_eq!(charge_call(s, developer, 99), CallCharge::Free);
+            assert_eq!(charge_call(s, developer, 99), CallCharge::Percent(100));
+
+            // Callers have free calls of their own.
+            assert_eq!(charge_call(s, other_developer, 99), CallCharge::Free);
+
+            // The free calls are replenished in the next period.
+            assert_eq!(charge_call(s, developer, 100), CallCharge::Free);
+            assert_eq!(charge_call(s, other_developer, 100), CallCharge::Free);
+            assert_eq!(s.free_calls.num_calls.len(), 2);
+        });
+    }
+
+    #[test]
+    fn free_calls_are_counted_for_a_bounded_number_of_callers() {
+        let caller = |i: u64| Principal::from_slice(&i.to_le_bytes());
+        let tier_member = Principal::from_slice(&[u8::MAX; 10]);
+        init(Config {
+            fee_policy: FeePolicy {
+                tiers: vec![FeeTier {
+                    principals: vec![tier_member],
+                    fee_percent: 100,
+                    free_calls_per_period: 1,
+                }],
+                free_calls_per_period: 1,
+                period_secs: 100,
+            },
+            ..Default::default()
+        });
+
+        with_state_mut(|s| {
+            let max = MAX_CALLERS_PER_PERIOD as u64;
+            for i in 0..max {
+                assert_eq!(charge_call(s, caller(i), 0), CallCharge::Free);
+            }
+
+            // New callers outside of the tiers don't get free calls beyond the max.
+            assert_eq!(charge_call(s, caller(max), 0), CallCharge::Percent(100));
+            assert_eq!(charge_call(s, caller(max + 1), 0), CallCharge::Percent(100));
+            assert_eq!(s.free_calls.num_calls.len(), MAX_CALLERS_PER_PERIOD);
+
+            // Callers in a tier still do.
+            assert_eq!(charge_call(s, tier_member, 0), CallCharge::Free);
+
+            // All callers get free calls again in the next period.
+            assert_eq!(charge_call(s, caller(max), 100), CallCharge::Free);
+            assert_eq!(s.free_calls.num_calls.len(), 1);
+        });
+    }
+}
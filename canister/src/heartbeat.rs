@@ -1,41 +1,194 @@
 use crate::{
-    runtime::{call_get_successors, print},
-    state::{self, ResponseToProcess},
+    logs,
+    runtime::{self, call_get_successors},
+    state::{self, InsertBlockError, ResponseToProcess, State},
     types::{
         GetSuccessorsCompleteResponse, GetSuccessorsRequest, GetSuccessorsRequestInitial,
         GetSuccessorsResponse,
     },
+    unstable_blocks,
 };
 use crate::{with_state, with_state_mut};
 use bitcoin::consensus::Decodable;
 use bitcoin::Block as BitcoinBlock;
-use ic_btc_interface::Flag;
+use candid::Principal;
+use ic_btc_interface::{Flag, LogComponent, LogLevel};
 use ic_btc_types::{Block, BlockHash};
+use ic_cdk::api::call::CallResult;
+use ic_cdk_timers::TimerId;
+use std::{cell::Cell, time::Duration};
+
+// The maximum size, in bytes, of the responses waiting to be processed. No more blocks
+// are fetched while the responses take more than that.
+const MAX_RESPONSES_TO_PROCESS_SIZE: usize = 16 * 1024 * 1024;
+
+// The interval at which the watchdog of the heartbeat checks that the heartbeat is
+// still scheduled.
+const HEARTBEAT_WATCHDOG_INTERVAL: Duration = Duration::from_secs(60);
+
+thread_local! {
+    // The timer of the next run of the heartbeat. There's at most one at any time.
+    static HEARTBEAT_TIMER: Cell<Option<TimerId>> = Cell::new(None);
+
+    // The time, in seconds, at which the next run of the heartbeat is due.
+    static HEARTBEAT_DUE_AT: Cell<Option<u64>> = Cell::new(None);
+
+    // The timer of the watchdog of the heartbeat.
+    static HEARTBEAT_WATCHDOG_TIMER: Cell<Option<TimerId>> = Cell::new(None);
+}
+
+/// Starts running the heartbeat on a timer.
+///
+/// After every run, the heartbeat is scheduled to run again after a delay that
+/// depends on whether or not there's work pending. See `next_heartbeat_delay`.
+/// A watchdog on a separate timer schedules the heartbeat again if it's overdue.
+/// Timers don't survive upgrades, so this needs to be called after every upgrade.
+pub fn start_heartbeat_timer() {
+    schedule_heartbeat(Duration::ZERO);
+
+    let watchdog_timer = runtime::set_timer_interval(HEARTBEAT_WATCHDOG_INTERVAL, || {
+        check_heartbeat(runtime::time());
+    });
+    if let Some(previous_timer) = HEARTBEAT_WATCHDOG_TIMER.with(|t| t.replace(Some(watchdog_timer)))
+    {
+        runtime::clear_timer(previous_timer);
+    }
+}
+
+// Schedules the next run of the heartbeat, replacing the one that was scheduled before.
+fn schedule_heartbeat(delay: Duration) {
+    let timer = runtime::set_timer(delay, || ic_cdk::spawn(run_heartbeat()));
+    if let Some(previous_timer) = HEARTBEAT_TIMER.with(|t| t.replace(Some(timer))) {
+        runtime::clear_timer(previous_timer);
+    }
+    HEARTBEAT_DUE_AT.with(|due_at| due_at.set(Some(runtime::time() + delay.as_secs())));
+}
+
+// Schedules the heartbeat to run right away if it's overdue. Returns true if it was.
+//
+// The timer of a heartbeat is consumed once it fires. Should the heartbeat trap before
+// its first await, the run that it schedules is rolled back along with the rest of its
+// changes, and only the watchdog can schedule it again.
+fn check_heartbeat(now: u64) -> bool {
+    let is_overdue = HEARTBEAT_DUE_AT.with(|due_at| due_at.get().map_or(true, |t| now > t));
+    if is_overdue {
+        logs::warning(
+            LogComponent::Heartbeat,
+            "The heartbeat is overdue. Scheduling it again...",
+        );
+        schedule_heartbeat(Duration::ZERO);
+    }
+    is_overdue
+}
+
+async fn run_heartbeat() {
+    // Should the heartbeat trap after it fetched blocks, it still runs again after the
+    // maximum delay. Should it trap before, the watchdog schedules it again.
+    schedule_heartbeat(Duration::from_millis(with_state(|s| {
+        s.syncing_state.max_sync_interval_ms
+    })));
+
+    heartbeat().await;
+
+    schedule_heartbeat(with_state(next_heartbeat_delay));
+}
+
+// Returns the delay before the heartbeat runs again.
+//
+// The heartbeat runs again immediately if there's work pending. Otherwise, it waits for
+// `sync_interval_ms`, doubled for every consecutive request for blocks that was rejected
+// or that returned nothing, up to `max_sync_interval_ms`.
+fn next_heartbeat_delay(state: &State) -> Duration {
+    let syncing_state = &state.syncing_state;
+    if state.is_state_snapshot_in_progress() {
+        // The heartbeat is skipped until the snapshot is finished or expires.
+        return Duration::from_millis(syncing_state.sync_interval_ms);
+    }
+
+    let has_work_pending = has_complete_response_to_process(state)
+        || syncing_state.rewind_target.is_some()
+        || state.utxos.ingesting_block.is_some()
+        || unstable_blocks::has_stable_child(&state.unstable_blocks);
+
+    // Unless a request is already in flight, blocks are fetched right away if there's a
+    // partial response to complete, or if the last request returned blocks, in which case
+    // there are likely more blocks to fetch.
+    let may_have_blocks_to_fetch = syncing_state.syncing == Flag::Enabled
+        && !syncing_state.is_fetching_blocks
+        && (matches!(
+            syncing_state.responses_to_process.back(),
+            Some(ResponseToProcess::Partial(..))
+        ) || syncing_state.num_consecutive_empty_fetches == 0);
+
+    if has_work_pending || may_have_blocks_to_fetch {
+        return Duration::ZERO;
+    }
+
+    let backoff = 2u64.saturating_pow(
+        syncing_state
+            .num_consecutive_empty_fetches
+            .saturating_sub(1),
+    );
+    Duration::from_millis(std::cmp::min(
+        syncing_state.sync_interval_ms.saturating_mul(backoff),
+        syncing_state.max_sync_interval_ms,
+    ))
+}
 
 /// The heartbeat of the Bitcoin canister.
 ///
 /// The heartbeat fetches new blocks from the bitcoin network and inserts them into the state.
 pub async fn heartbeat() {
-    print("Starting heartbeat...");
+    logs::debug(LogComponent::Heartbeat, "Starting heartbeat...");
+    if with_state(State::is_state_snapshot_in_progress) {
+        // Stable memory must not change while a snapshot of it is being exported.
+        logs::debug(
+            LogComponent::Heartbeat,
+            "Skipping heartbeat while a snapshot is in progress.",
+        );
+        return;
+    }
+
+    if with_state_mut(state::rewind_continue) {
+        // Exit the heartbeat while stable blocks are being rewound.
+        // No blocks are fetched or ingested until the rewind is complete.
+        logs::debug(LogComponent::Heartbeat, "Done rewinding stable blocks.");
+        return;
+    }
+
     if ingest_stable_blocks_into_utxoset() {
         // Exit the heartbeat if stable blocks had been ingested.
         // This is a precaution to not exceed the instructions limit.
-        print("Done ingesting stable blocks.");
+        logs::debug(LogComponent::Heartbeat, "Done ingesting stable blocks.");
         return;
     }
 
-    if maybe_fetch_blocks().await {
+    if with_state(has_complete_response_to_process) {
+        // Fetch more blocks while the response is being processed, so that downloading
+        // blocks overlaps with processing them.
+        runtime::spawn(async {
+            maybe_fetch_blocks().await;
+        });
+
+        maybe_process_response();
+    } else if maybe_fetch_blocks().await {
         // Exit the heartbeat if new blocks have been fetched.
         // This is a precaution to not exceed the instructions limit.
-        print("Done fetching new response.");
+        logs::debug(LogComponent::Heartbeat, "Done fetching new response.");
         return;
     }
 
-    maybe_process_response();
+    // Audit the UTXO set with whatever instructions are left.
+    with_state_mut(state::audit_utxo_set_continue);
 }
 
-// Fetches new blocks if there isn't a request in progress and no complete response to process.
-// Returns true if a call to the `blocks_source` has been made, false otherwise.
+// Fetches new blocks if there isn't a request in progress.
+//
+// Blocks are fetched while the responses that were fetched before are still waiting to be
+// processed, so that downloading blocks overlaps with processing them, unless these
+// responses take more than `MAX_RESPONSES_TO_PROCESS_SIZE` bytes.
+//
+// Returns true if a call to a blocks source has been made, false otherwise.
 async fn maybe_fetch_blocks() -> bool {
     if with_state(|s| s.syncing_state.syncing == Flag::Disabled) {
         // Syncing is disabled.
@@ -50,7 +203,7 @@ async fn maybe_fetch_blocks() -> bool {
 
     // Request additional blocks.
     let maybe_request = maybe_get_successors_request();
-    let request = match maybe_request {
+    let (blocks_source, request) = match maybe_request {
         Some(request) => request,
         None => {
             // No request to send at this time.
@@ -58,12 +211,20 @@ async fn maybe_fetch_blocks() -> bool {
         }
     };
 
-    print(&format!("Sending request: {:?}", request));
+    logs::debug(
+        LogComponent::Heartbeat,
+        &format!("Sending request to {}: {:?}", blocks_source, request),
+    );
 
-    let response: Result<(GetSuccessorsResponse,), _> =
-        call_get_successors(with_state(|s| s.blocks_source), request).await;
+    let responses_generation = with_state(|s| s.syncing_state.responses_generation);
 
-    print(&format!("Received response: {:?}", response));
+    let response: CallResult<(GetSuccessorsResponse,)> =
+        call_get_successors(blocks_source, request).await;
+
+    logs::debug(
+        LogComponent::Heartbeat,
+        &format!("Received response: {}", summarize_response(&response)),
+    );
 
     // Save the response.
     with_state_mut(|s| {
@@ -71,37 +232,100 @@ async fn maybe_fetch_blocks() -> bool {
             Ok((response,)) => response,
             Err((code, msg)) => {
                 s.syncing_state.num_get_successors_rejects += 1;
-                print(&format!("Error fetching blocks: [{:?}] {}", code, msg));
-                s.syncing_state.response_to_process = None;
+                s.metrics.observe_blocks_source_reject(blocks_source);
+                observe_blocks_source_failure(s, blocks_source);
+                s.syncing_state.num_consecutive_empty_fetches = s
+                    .syncing_state
+                    .num_consecutive_empty_fetches
+                    .saturating_add(1);
+                logs::error(
+                    LogComponent::Heartbeat,
+                    &format!(
+                        "Error fetching blocks from {}: [{:?}] {}",
+                        blocks_source, code, msg
+                    ),
+                );
+                if let Some(ResponseToProcess::Partial(..)) =
+                    s.syncing_state.responses_to_process.back()
+                {
+                    // The partial response can't be completed without its follow-ups.
+                    s.syncing_state.responses_to_process.pop_back();
+                }
                 return;
             }
         };
 
+        if is_stall(s, &response) {
+            s.metrics.observe_blocks_source_stall(blocks_source);
+            observe_blocks_source_failure(s, blocks_source);
+        } else {
+            s.metrics.observe_blocks_source_success(blocks_source);
+            if blocks_source == s.current_blocks_source() {
+                s.syncing_state.num_consecutive_blocks_source_failures = 0;
+            }
+        }
+
+        s.syncing_state.num_consecutive_empty_fetches = match &response {
+            GetSuccessorsResponse::Complete(response)
+                if response.blocks.is_empty() && response.next.is_empty() =>
+            {
+                s.syncing_state
+                    .num_consecutive_empty_fetches
+                    .saturating_add(1)
+            }
+            _ => 0,
+        };
+
+        if s.syncing_state.responses_generation != responses_generation {
+            // The responses to process were dropped while the request was in flight. The
+            // request assumed that the blocks of these responses were processed, so its
+            // response is dropped as well.
+            logs::debug(
+                LogComponent::Heartbeat,
+                "Dropping the response to a request sent before the responses were dropped.",
+            );
+            return;
+        }
+
+        let responses_to_process = &mut s.syncing_state.responses_to_process;
         match response {
             GetSuccessorsResponse::Complete(response) => {
                 // Received complete response.
                 assert!(
-                    s.syncing_state.response_to_process.is_none(),
-                    "Received complete response before processing previous response."
+                    !matches!(
+                        responses_to_process.back(),
+                        Some(ResponseToProcess::Partial(..))
+                    ),
+                    "Received complete response before completing the partial response."
                 );
-                s.syncing_state.response_to_process = Some(ResponseToProcess::Complete(response));
+                if response.blocks.is_empty() && response.next.is_empty() {
+                    // There's nothing to process.
+                    return;
+                }
+                responses_to_process.push_back(ResponseToProcess::Complete(response));
             }
             GetSuccessorsResponse::Partial(partial_response) => {
                 // Received partial response.
                 assert!(
-                    s.syncing_state.response_to_process.is_none(),
-                    "Received partial response before processing previous response."
+                    !matches!(
+                        responses_to_process.back(),
+                        Some(ResponseToProcess::Partial(..))
+                    ),
+                    "Received partial response before completing the partial response."
                 );
-                s.syncing_state.response_to_process =
-                    Some(ResponseToProcess::Partial(partial_response, 0));
+                responses_to_process.push_back(ResponseToProcess::Partial(
+                    partial_response,
+                    0,
+                    blocks_source,
+                ));
             }
             GetSuccessorsResponse::FollowUp(mut block_bytes) => {
                 // Received a follow-up response.
-                // A follow-up response is only expected, and only makes sense, when there's
-                // a partial response to process.
+                // A follow-up response is only expected, and only makes sense, when the last
+                // response is a partial response.
 
-                let (mut partial_response, mut follow_up_index) = match s.syncing_state.response_to_process.take() {
-                    Some(ResponseToProcess::Partial(res, pages)) => (res, pages),
+                let (mut partial_response, mut follow_up_index) = match responses_to_process.pop_back() {
+                    Some(ResponseToProcess::Partial(res, pages, _)) => (res, pages),
                     other => unreachable!("Cannot receive follow-up response without a previous partial response. Previous response found: {:?}", other)
                 };
 
@@ -111,120 +335,272 @@ async fn maybe_fetch_blocks() -> bool {
 
                 // If the response is now complete, store a complete response to process.
                 // Otherwise, store the updated partial response.
-                s.syncing_state.response_to_process = Some(
+                responses_to_process.push_back(
                     if follow_up_index == partial_response.remaining_follow_ups {
                         ResponseToProcess::Complete(GetSuccessorsCompleteResponse {
                             blocks: vec![partial_response.partial_block],
                             next: partial_response.next,
                         })
                     } else {
-                        ResponseToProcess::Partial(partial_response, follow_up_index)
+                        ResponseToProcess::Partial(partial_response, follow_up_index, blocks_source)
                     },
                 );
             }
         };
     });
 
+    // The request may have been sent while the heartbeat was processing another response,
+    // in which case the heartbeat is no longer scheduled to run right away.
+    schedule_heartbeat(with_state(next_heartbeat_delay));
+
     // A request to fetch new blocks has been made.
     true
 }
 
+// Returns true if the response has no blocks even though block headers beyond the tip
+// of the main chain are known, i.e. the blocks source is expected to have blocks to return.
+// Blocks that were received but not processed yet aren't on the main chain, so a response
+// isn't considered a stall while there are any.
+fn is_stall(state: &State, response: &GetSuccessorsResponse) -> bool {
+    match response {
+        GetSuccessorsResponse::Complete(response) => {
+            response.blocks.is_empty()
+                && state.syncing_state.responses_to_process.is_empty()
+                && state
+                    .unstable_blocks
+                    .next_block_headers_max_height()
+                    .map_or(false, |height| height > state::main_chain_height(state))
+        }
+        GetSuccessorsResponse::Partial(_) | GetSuccessorsResponse::FollowUp(_) => false,
+    }
+}
+
+// Counts a reject or a stall of the given blocks source. Once the blocks source that
+// blocks are currently retrieved from fails too many times in a row, blocks are
+// retrieved from the next blocks source instead.
+fn observe_blocks_source_failure(state: &mut State, blocks_source: Principal) {
+    if blocks_source != state.current_blocks_source() {
+        // The blocks sources were changed while the request was in flight.
+        return;
+    }
+
+    let num_blocks_sources = state.blocks_sources().len();
+    let syncing_state = &mut state.syncing_state;
+    syncing_state.num_consecutive_blocks_source_failures += 1;
+    if syncing_state.num_consecutive_blocks_source_failures
+        < syncing_state.blocks_source_failover_threshold
+    {
+        return;
+    }
+
+    let num_failures = syncing_state.num_consecutive_blocks_source_failures;
+    syncing_state.num_consecutive_blocks_source_failures = 0;
+    syncing_state.blocks_source_index =
+        (syncing_state.blocks_source_index + 1) % num_blocks_sources;
+
+    let next_blocks_source = state.current_blocks_source();
+    if next_blocks_source != blocks_source {
+        logs::warning(
+            LogComponent::Heartbeat,
+            &format!(
+                "Failing over from blocks source {} to {} after {} consecutive failures.",
+                blocks_source, next_blocks_source, num_failures
+            ),
+        );
+    }
+}
+
+// Returns a summary of the response that's suitable for logging, without the blocks' bytes.
+fn summarize_response(response: &CallResult<(GetSuccessorsResponse,)>) -> String {
+    match response {
+        Ok((GetSuccessorsResponse::Complete(response),)) => format!(
+            "Complete response with {} blocks and {} next block headers",
+            response.blocks.len(),
+            response.next.len()
+        ),
+        Ok((GetSuccessorsResponse::Partial(response),)) => format!(
+            "Partial response with {} remaining follow-ups",
+            response.remaining_follow_ups
+        ),
+        Ok((GetSuccessorsResponse::FollowUp(bytes),)) => {
+            format!("Follow-up response of {} bytes", bytes.len())
+        }
+        Err((code, msg)) => format!("Reject [{:?}] {}", code, msg),
+    }
+}
+
 fn ingest_stable_blocks_into_utxoset() -> bool {
     with_state_mut(state::ingest_stable_blocks_into_utxoset)
 }
 
-// Process a `GetSuccessorsResponse` if one is available.
+// Returns true if the oldest response waiting to be processed is complete.
+fn has_complete_response_to_process(state: &State) -> bool {
+    matches!(
+        state.syncing_state.responses_to_process.front(),
+        Some(ResponseToProcess::Complete(_))
+    )
+}
+
+// Processes the oldest `GetSuccessorsResponse` if it's complete.
 fn maybe_process_response() {
     with_state_mut(|state| {
-        let response_to_process = state.syncing_state.response_to_process.take();
-
-        match response_to_process {
-            Some(ResponseToProcess::Complete(response)) => {
-                print(&format!(
-                    "Inserting {} blocks from response...",
-                    response.blocks.len()
-                ));
-                for block_bytes in response.blocks.iter() {
-                    // Deserialize the block.
-                    let block = match BitcoinBlock::consensus_decode(block_bytes.as_slice()) {
-                        Ok(block) => block,
-                        Err(err) => {
-                            print(&format!(
-                                "ERROR: Cannot deserialize block. Err: {:?}, Block bytes: {:?}. Full Response: {:?}",
-                                err,
-                                block_bytes,
-                                response,
-                            ));
-
-                            // Return, the remaining blocks in the response are dropped.
-                            state.syncing_state.num_block_deserialize_errors += 1;
-                            return;
-                        }
-                    };
-
-                    if let Err(err) = state::insert_block(state, Block::new(block)) {
-                        print(&format!(
-                            "ERROR: Failed to insert block. Err: {:?}, Block bytes: {:?}",
-                            err, block_bytes,
-                        ));
-
-                        // Return, the remaining blocks in the response are dropped.
-                        state.syncing_state.num_insert_block_errors += 1;
-                        return;
-                    }
-                }
-
-                print(&format!(
-                    "Inserting {} next block headers...",
-                    response.next.len()
-                ));
-                state::insert_next_block_headers(state, &response.next);
-            }
+        let response = match state.syncing_state.responses_to_process.pop_front() {
+            Some(ResponseToProcess::Complete(response)) => response,
             other => {
-                if other.is_some() {
-                    print(&format!(
-                        "Complete response not yet available. Response so far: {:?}",
-                        other
-                    ));
+                if let Some(other) = other {
+                    logs::debug(
+                        LogComponent::Heartbeat,
+                        "Complete response not yet available.",
+                    );
+
+                    // Not a complete response. Put it back into the state.
+                    state.syncing_state.responses_to_process.push_front(other);
                 } else {
-                    print("No response available to process.");
+                    logs::debug(LogComponent::Heartbeat, "No response available to process.");
                 }
+                return;
+            }
+        };
 
-                // Not a complete response. Put it back into the state.
-                state.syncing_state.response_to_process = other;
+        logs::debug(
+            LogComponent::Heartbeat,
+            &format!(
+                "Inserting {} blocks from response...",
+                response.blocks.len()
+            ),
+        );
+        for block_bytes in response.blocks.iter() {
+            // Deserialize the block.
+            let block = match BitcoinBlock::consensus_decode(block_bytes.as_slice()) {
+                Ok(block) => block,
+                Err(err) => {
+                    logs::error(
+                        LogComponent::Heartbeat,
+                        &format!(
+                            "Cannot deserialize block. Err: {:?}, Block size: {} bytes.",
+                            err,
+                            block_bytes.len(),
+                        ),
+                    );
+
+                    // Return, the remaining blocks in the response are dropped. The
+                    // responses fetched after it are dropped too, as they were requested
+                    // assuming that these blocks were processed.
+                    state.syncing_state.num_block_deserialize_errors += 1;
+                    state.syncing_state.drop_responses();
+                    return;
+                }
+            };
+
+            let block = Block::new(block);
+            let block_hash = block.block_hash();
+            match state::insert_block(state, block) {
+                Ok(()) => {}
+                Err(InsertBlockError::PrunedBlock) => {
+                    // The block was sent before it was reported as processed. It's
+                    // skipped, as it would be pruned again.
+                    logs::log_block(
+                        LogLevel::Info,
+                        LogComponent::Ingestion,
+                        &block_hash,
+                        "Skipping a block that was pruned along with a stale fork.",
+                    );
+                }
+                Err(err) => {
+                    logs::log_block(
+                        LogLevel::Error,
+                        LogComponent::Ingestion,
+                        &block_hash,
+                        &format!("Failed to insert block. Err: {:?}", err),
+                    );
+
+                    // Return, the remaining blocks in the response are dropped, along with
+                    // the responses fetched after it.
+                    state.syncing_state.num_insert_block_errors += 1;
+                    state.syncing_state.drop_responses();
+                    return;
+                }
             }
         }
-    });
+
+        logs::debug(
+            LogComponent::Heartbeat,
+            &format!("Inserting {} next block headers...", response.next.len()),
+        );
+        state::insert_next_block_headers(state, &response.next);
+    })
 }
 
-// Retrieves a `GetSuccessorsRequest` to send to the adapter.
-fn maybe_get_successors_request() -> Option<GetSuccessorsRequest> {
-    with_state(|state| match &state.syncing_state.response_to_process {
-        Some(ResponseToProcess::Complete(_)) => {
-            // There's already a complete response waiting to be processed.
-            None
-        }
-        Some(ResponseToProcess::Partial(partial_response, follow_up_index)) => {
-            // There's a partial response. Create a follow-up request.
+// Retrieves a `GetSuccessorsRequest` to send to the adapter, along with the blocks source
+// to send it to.
+fn maybe_get_successors_request() -> Option<(Principal, GetSuccessorsRequest)> {
+    with_state(|state| {
+        let responses_to_process = &state.syncing_state.responses_to_process;
+        if let Some(ResponseToProcess::Partial(partial_response, follow_up_index, blocks_source)) =
+            responses_to_process.back()
+        {
+            // There's a partial response. Create a follow-up request to the blocks source
+            // that sent the partial response.
             assert!(partial_response.remaining_follow_ups >= *follow_up_index);
-            Some(GetSuccessorsRequest::FollowUp(*follow_up_index))
+            return Some((
+                *blocks_source,
+                GetSuccessorsRequest::FollowUp(*follow_up_index),
+            ));
         }
-        None => {
-            // No response is present. Send an initial request for new blocks.
-            let mut processed_block_hashes: Vec<BlockHash> = state::get_unstable_blocks(state)
+
+        if !responses_to_process.is_empty() {
+            // There are complete responses waiting to be processed. More blocks are only
+            // fetched ahead if the last request returned something, as otherwise there
+            // are likely no more blocks to fetch.
+            if state.syncing_state.num_consecutive_empty_fetches > 0 {
+                return None;
+            }
+
+            let size: usize = responses_to_process
                 .iter()
-                .map(|b| b.block_hash())
-                .collect();
+                .map(ResponseToProcess::size)
+                .sum();
+            if size >= MAX_RESPONSES_TO_PROCESS_SIZE {
+                logs::debug(
+                    LogComponent::Heartbeat,
+                    &format!(
+                        "Not fetching more blocks until some of the {} bytes of responses are processed.",
+                        size
+                    ),
+                );
+                return None;
+            }
+        }
 
-            // We are guaranteed that there's always at least one block.
-            let anchor = processed_block_hashes.remove(0);
+        // Send an initial request for new blocks.
+        let mut processed_block_hashes: Vec<BlockHash> = state::get_unstable_blocks(state)
+            .iter()
+            .map(|b| b.block_hash())
+            .collect();
+
+        // We are guaranteed that there's always at least one block.
+        let anchor = processed_block_hashes.remove(0);
 
-            Some(GetSuccessorsRequest::Initial(GetSuccessorsRequestInitial {
+        // The blocks that were received, but that are still waiting to be processed,
+        // must not be sent again.
+        processed_block_hashes.extend(
+            responses_to_process
+                .iter()
+                .flat_map(ResponseToProcess::block_hashes),
+        );
+
+        // The blocks that were pruned along with stale forks are reported as processed
+        // too, so that they aren't sent again.
+        processed_block_hashes.extend(state.unstable_blocks.pruned_block_hashes().cloned());
+
+        Some((
+            state.current_blocks_source(),
+            GetSuccessorsRequest::Initial(GetSuccessorsRequestInitial {
                 network: state.network(),
                 anchor,
                 processed_block_hashes,
-            }))
-        }
+            }),
+        ))
     })
 }
 
@@ -239,20 +615,33 @@ mod test {
         utxo_set::IngestingBlock,
     };
     use bitcoin::BlockHeader;
-    use ic_btc_interface::{Config, Network};
-
-    fn build_block(prev_header: &BlockHeader, address: Address, num_transactions: u128) -> Block {
-        let mut block = BlockBuilder::with_prev_header(prev_header);
-        let mut value = 1;
-        for _ in 0..num_transactions {
-            block = block.with_transaction(
-                TransactionBuilder::coinbase()
-                    .with_output(&address, value)
-                    .build(),
-            );
-
-            // Increment the value so that all transaction IDs are unique.
-            value += 1;
+    use ic_btc_interface::{Config, Network, PruningPolicy};
+    use ic_cdk::api::call::RejectionCode;
+
+    // Builds a block at the given height with a coinbase followed by transactions that
+    // each spend the output of the previous one. Every transaction has a single input and
+    // a single output.
+    fn build_block(
+        prev_header: &BlockHeader,
+        height: u32,
+        address: Address,
+        num_transactions: u128,
+    ) -> Block {
+        let mut prev_tx = TransactionBuilder::coinbase(height)
+            .with_output(&address, 1000)
+            .build();
+        let mut block =
+            BlockBuilder::with_prev_header(prev_header).with_transaction(prev_tx.clone());
+        for _ in 1..num_transactions {
+            let tx = TransactionBuilder::new()
+                .with_input(ic_btc_types::OutPoint {
+                    txid: prev_tx.txid(),
+                    vout: 0,
+                })
+                .with_output(&address, 1000)
+                .build();
+            block = block.with_transaction(tx.clone());
+            prev_tx = tx;
         }
 
         block.build()
@@ -336,6 +725,44 @@ mod test {
         assert_eq!(with_state(state::main_chain_height), 0);
     }
 
+    #[async_std::test]
+    async fn does_not_fetch_blocks_while_a_snapshot_is_in_progress() {
+        let network = Network::Regtest;
+
+        init(Config {
+            stability_threshold: 0,
+            network,
+            ..Default::default()
+        });
+
+        crate::start_state_snapshot().unwrap();
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![block_bytes],
+                next: vec![],
+            },
+        )));
+
+        // Try to fetch blocks
+        heartbeat().await;
+        heartbeat().await;
+
+        // Assert that the block has not been ingested.
+        assert_eq!(with_state(state::main_chain_height), 0);
+
+        // Syncing resumes once the snapshot is finished.
+        crate::finish_state_snapshot().unwrap();
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 1);
+    }
+
     #[async_std::test]
     async fn time_slices_large_blocks() {
         let network = Network::Regtest;
@@ -348,8 +775,8 @@ mod test {
 
         // Setup a chain of two blocks.
         let address = random_p2pkh_address(network);
-        let block_1 = build_block(genesis_block(network).header(), address.clone(), 6);
-        let block_2 = build_block(block_1.header(), address, 1);
+        let block_1 = build_block(genesis_block(network).header(), 1, address.clone(), 6);
+        let block_2 = build_block(block_1.header(), 2, address, 1);
 
         // Serialize the blocks.
         let blocks: Vec<BlockBlob> = [block_1.clone(), block_2]
@@ -386,23 +813,25 @@ mod test {
         heartbeat().await;
 
         // Assert that execution has been paused.
-        // Ingested the genesis block (1 tx) + 2 txs of block_1 into the UTXO set.
+        // Ingested the genesis block (1 tx), the coinbase of block_1 and the input of its
+        // second transaction into the UTXO set.
         let partial_block = with_state(|s| s.utxos.ingesting_block.clone().unwrap());
         assert_eq!(partial_block.block, block_1);
-        assert_eq!(partial_block.next_tx_idx, 2);
+        assert_eq!(partial_block.next_tx_idx, 1);
         assert_eq!(partial_block.next_input_idx, 1);
         assert_eq!(partial_block.next_output_idx, 0);
 
-        // Ingest more stable blocks.
-        runtime::performance_counter_reset();
-        heartbeat().await;
+        // Ingest more stable blocks, three inputs/outputs per round.
+        for (next_tx_idx, next_input_idx) in [(3, 0), (4, 1)] {
+            runtime::performance_counter_reset();
+            heartbeat().await;
 
-        // Assert that execution has been paused. Ingested 3 more txs in block_1.
-        let partial_block = with_state(|s| s.utxos.ingesting_block.clone().unwrap());
-        assert_eq!(partial_block.block, block_1);
-        assert_eq!(partial_block.next_tx_idx, 5);
-        assert_eq!(partial_block.next_input_idx, 1);
-        assert_eq!(partial_block.next_output_idx, 0);
+            let partial_block = with_state(|s| s.utxos.ingesting_block.clone().unwrap());
+            assert_eq!(partial_block.block, block_1);
+            assert_eq!(partial_block.next_tx_idx, next_tx_idx);
+            assert_eq!(partial_block.next_input_idx, next_input_idx);
+            assert_eq!(partial_block.next_output_idx, 0);
+        }
 
         // Only the genesis block has been fully processed, so the stable height is one.
         assert_eq!(with_state(|s| s.utxos.next_height()), 1);
@@ -437,7 +866,7 @@ mod test {
         let address_2 = random_p2pkh_address(network);
 
         // Create a transaction where a few inputs are given to address 1.
-        let mut tx_1 = TransactionBuilder::coinbase();
+        let mut tx_1 = TransactionBuilder::coinbase(1);
         for _ in 0..tx_cardinality {
             tx_1 = tx_1.with_output(&address_1, 1000);
         }
@@ -503,10 +932,11 @@ mod test {
         let expected_states = vec![
             IngestingBlock::new_with_args(block_1.clone(), 0, 1, 2),
             IngestingBlock::new_with_args(block_1.clone(), 0, 1, 5),
-            IngestingBlock::new_with_args(block_2.clone(), 0, 2, 0),
-            IngestingBlock::new_with_args(block_2.clone(), 0, 5, 0),
-            IngestingBlock::new_with_args(block_2.clone(), 0, 6, 2),
-            IngestingBlock::new_with_args(block_2.clone(), 0, 6, 5),
+            // The coinbase of block_2 is ingested before `tx_2`.
+            IngestingBlock::new_with_args(block_2.clone(), 1, 1, 0),
+            IngestingBlock::new_with_args(block_2.clone(), 1, 4, 0),
+            IngestingBlock::new_with_args(block_2.clone(), 1, 6, 1),
+            IngestingBlock::new_with_args(block_2.clone(), 1, 6, 4),
         ];
 
         for expected_state in expected_states.into_iter() {
@@ -588,7 +1018,7 @@ mod test {
         let address = random_p2pkh_address(network);
         let block = BlockBuilder::with_prev_header(genesis_block(network).header())
             .with_transaction(
-                TransactionBuilder::coinbase()
+                TransactionBuilder::coinbase(1)
                     .with_output(&address, 1000)
                     .build(),
             )
@@ -674,7 +1104,7 @@ mod test {
         // The number of deserialize errors has been incremented to one and response is dropped.
         with_state(|s| {
             assert_eq!(s.syncing_state.num_block_deserialize_errors, 1);
-            assert_eq!(s.syncing_state.response_to_process, None);
+            assert!(s.syncing_state.responses_to_process.is_empty());
         });
     }
 
@@ -709,8 +1139,45 @@ mod test {
         // The number of insert block errors has been incremented to one and response is dropped.
         with_state(|s| {
             assert_eq!(s.syncing_state.num_insert_block_errors, 1);
-            assert_eq!(s.syncing_state.response_to_process, None);
+            assert!(s.syncing_state.responses_to_process.is_empty());
+        });
+    }
+
+    #[async_std::test]
+    async fn handles_blocks_with_transactions_that_dont_match_the_header() {
+        let network = Network::Regtest;
+        init(Config {
+            network,
+            ..Default::default()
+        });
+
+        // Modify a transaction after the block was built, invalidating its merkle root.
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let mut block = block.as_bitcoin_block().clone();
+        block.txdata[0].output[0].value += 1;
+
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![block_bytes],
+                next: vec![],
+            },
+        )));
+
+        // Fetch response.
+        heartbeat().await;
+
+        // Process response.
+        heartbeat().await;
+
+        // The block is rejected and the number of insert block errors is incremented.
+        with_state(|s| {
+            assert_eq!(s.syncing_state.num_insert_block_errors, 1);
+            assert!(s.syncing_state.responses_to_process.is_empty());
         });
+        assert_eq!(with_state(state::main_chain_height), 0);
     }
 
     #[async_std::test]
@@ -760,4 +1227,443 @@ mod test {
             Some(30)
         );
     }
+
+    #[async_std::test]
+    async fn backs_off_while_there_are_no_blocks_to_fetch() {
+        let network = Network::Regtest;
+        init(Config {
+            network,
+            sync_interval_ms: 1_000,
+            max_sync_interval_ms: 5_000,
+            ..Default::default()
+        });
+        runtime::set_successors_responses(vec![]);
+
+        let next_delay = || with_state(next_heartbeat_delay);
+
+        // Nothing has been fetched yet, so the next heartbeat runs right away.
+        assert_eq!(next_delay(), Duration::ZERO);
+
+        // The adapter has no blocks to return. The delay doubles with every empty
+        // response, up to the maximum interval.
+        for expected_delay_ms in [1_000, 2_000, 4_000, 5_000] {
+            heartbeat().await;
+            assert!(with_state(|s| s
+                .syncing_state
+                .responses_to_process
+                .is_empty()));
+            assert_eq!(next_delay(), Duration::from_millis(expected_delay_ms));
+        }
+
+        // Rejected requests also back off.
+        runtime::set_successors_response(GetSuccessorsReply::Err(
+            RejectionCode::CanisterReject,
+            String::from("Test rejection."),
+        ));
+        heartbeat().await;
+        assert_eq!(
+            with_state(|s| s.syncing_state.num_consecutive_empty_fetches),
+            5
+        );
+        assert_eq!(next_delay(), Duration::from_millis(5_000));
+
+        // Once there's a block to fetch, the heartbeat runs right away again.
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![block_bytes],
+                next: vec![],
+            },
+        )));
+        heartbeat().await;
+        assert_eq!(
+            with_state(|s| s.syncing_state.num_consecutive_empty_fetches),
+            0
+        );
+        assert_eq!(next_delay(), Duration::ZERO);
+    }
+
+    fn reject() -> GetSuccessorsReply {
+        GetSuccessorsReply::Err(
+            RejectionCode::CanisterReject,
+            String::from("Test rejection."),
+        )
+    }
+
+    #[async_std::test]
+    async fn fails_over_to_the_next_blocks_source_after_consecutive_rejects() {
+        let source_1 = Principal::from_slice(&[1]);
+        let source_2 = Principal::from_slice(&[2]);
+        init(Config {
+            blocks_source: source_1,
+            fallback_blocks_sources: vec![source_2],
+            blocks_source_failover_threshold: 2,
+            ..Default::default()
+        });
+        runtime::set_successors_responses(vec![reject(), reject()]);
+
+        // The first reject doesn't fail over.
+        heartbeat().await;
+        assert_eq!(with_state(|s| s.current_blocks_source()), source_1);
+
+        // The second consecutive reject does.
+        heartbeat().await;
+        assert_eq!(with_state(|s| s.current_blocks_source()), source_2);
+
+        // Blocks are now fetched from the next source.
+        heartbeat().await;
+        assert_eq!(
+            runtime::get_successors_callees(),
+            vec![source_1, source_1, source_2]
+        );
+
+        with_state(|s| {
+            assert_eq!(s.metrics.blocks_sources[&source_1].rejects, 2);
+            assert_eq!(s.metrics.blocks_sources[&source_1].successes, 0);
+            assert_eq!(s.metrics.blocks_sources[&source_2].rejects, 0);
+            assert_eq!(s.metrics.blocks_sources[&source_2].successes, 1);
+        });
+
+        // Once the last source fails as well, blocks are fetched from the first source again.
+        runtime::set_successors_responses(vec![reject(), reject()]);
+        heartbeat().await;
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(
+            runtime::get_successors_callees(),
+            vec![source_2, source_2, source_1]
+        );
+    }
+
+    #[async_std::test]
+    async fn fails_over_to_the_next_blocks_source_after_consecutive_stalls() {
+        let source_1 = Principal::from_slice(&[1]);
+        let source_2 = Principal::from_slice(&[2]);
+        init(Config {
+            blocks_source: source_1,
+            fallback_blocks_sources: vec![source_2],
+            blocks_source_failover_threshold: 2,
+            ..Default::default()
+        });
+
+        // The source returns block headers, but never the blocks.
+        let next_block_headers = BlockChainBuilder::new(3)
+            .build()
+            .into_iter()
+            .skip(1)
+            .map(|b| b.header().into())
+            .collect();
+        runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: vec![],
+                next: next_block_headers,
+            },
+        )));
+
+        // Fetch the block headers. That's progress, not a stall.
+        heartbeat().await;
+        assert_eq!(
+            with_state(|s| s.syncing_state.num_consecutive_blocks_source_failures),
+            0
+        );
+
+        // Process the block headers while fetching more blocks, then fetch twice more.
+        // The response fetched while the block headers are processed isn't a stall, as
+        // the block headers aren't processed yet.
+        for _ in 0..3 {
+            heartbeat().await;
+        }
+
+        with_state(|s| {
+            assert_eq!(s.metrics.blocks_sources[&source_1].successes, 2);
+            assert_eq!(s.metrics.blocks_sources[&source_1].stalls, 2);
+            assert_eq!(s.current_blocks_source(), source_2);
+        });
+    }
+
+    #[async_std::test]
+    async fn follow_ups_are_requested_from_the_source_of_the_partial_response() {
+        let network = Network::Regtest;
+        let source_1 = Principal::from_slice(&[1]);
+        let source_2 = Principal::from_slice(&[2]);
+        init(Config {
+            stability_threshold: 10,
+            network,
+            blocks_source: source_1,
+            ..Default::default()
+        });
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+
+        runtime::set_successors_responses(vec![
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::Partial(
+                GetSuccessorsPartialResponse {
+                    partial_block: block_bytes[0..40].to_vec(),
+                    next: vec![],
+                    remaining_follow_ups: 1,
+                },
+            )),
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::FollowUp(block_bytes[40..].to_vec())),
+        ]);
+
+        // Fetch the partial response.
+        heartbeat().await;
+
+        // The blocks source changes before the follow-up is fetched.
+        with_state_mut(|s| s.set_blocks_sources(source_2, vec![]));
+
+        // Fetch the follow-up response.
+        heartbeat().await;
+
+        // Process the complete response, while fetching new blocks from the new
+        // blocks source.
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 1);
+        assert_eq!(
+            runtime::get_successors_callees(),
+            vec![source_1, source_1, source_2]
+        );
+    }
+
+    fn complete_response(blocks: &[&Block]) -> GetSuccessorsReply {
+        GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+            GetSuccessorsCompleteResponse {
+                blocks: blocks
+                    .iter()
+                    .map(|block| {
+                        let mut block_bytes = vec![];
+                        block.consensus_encode(&mut block_bytes).unwrap();
+                        block_bytes
+                    })
+                    .collect(),
+                next: vec![],
+            },
+        ))
+    }
+
+    #[async_std::test]
+    async fn fetches_blocks_while_processing_responses() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 10,
+            network,
+            ..Default::default()
+        });
+
+        let block_1 = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        runtime::set_successors_responses(vec![
+            complete_response(&[&block_1]),
+            complete_response(&[&block_2]),
+        ]);
+
+        // Fetch the first block.
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 0);
+
+        // Process the first block while fetching the second one. The request lists the
+        // first block as processed, as it's waiting to be processed.
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 1);
+        assert_eq!(
+            with_state(|s| s.syncing_state.responses_to_process.len()),
+            1
+        );
+        match &runtime::get_successors_requests()[1] {
+            GetSuccessorsRequest::Initial(request) => {
+                assert_eq!(request.processed_block_hashes, vec![block_1.block_hash()]);
+            }
+            other => panic!("Unexpected request: {:?}", other),
+        }
+
+        // Process the second block while fetching more blocks.
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 2);
+        assert!(with_state(|s| s
+            .syncing_state
+            .responses_to_process
+            .is_empty()));
+        match &runtime::get_successors_requests()[2] {
+            GetSuccessorsRequest::Initial(request) => {
+                assert_eq!(
+                    request.processed_block_hashes,
+                    vec![block_1.block_hash(), block_2.block_hash()]
+                );
+            }
+            other => panic!("Unexpected request: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn watchdog_schedules_the_heartbeat_again_if_its_first_slice_traps() {
+        init(Config::default());
+        start_heartbeat_timer();
+        let due_at = HEARTBEAT_DUE_AT.with(Cell::get).unwrap();
+        assert!(!check_heartbeat(due_at));
+
+        // The timer fires, but the heartbeat traps before its first await, so the run that
+        // it scheduled is rolled back and it's still due at the time its timer fired. The
+        // watchdog schedules it again.
+        assert!(check_heartbeat(due_at + 1));
+
+        // Once the first slice of the heartbeat is committed, the heartbeat is due after
+        // the maximum delay, and the watchdog leaves it alone until then.
+        let max_sync_interval_ms = with_state(|s| s.syncing_state.max_sync_interval_ms);
+        schedule_heartbeat(Duration::from_millis(max_sync_interval_ms));
+        let due_at = HEARTBEAT_DUE_AT.with(Cell::get).unwrap();
+        assert!(!check_heartbeat(due_at));
+        assert!(check_heartbeat(due_at + 1));
+    }
+
+    #[async_std::test]
+    async fn pruned_blocks_are_reported_as_processed_and_skipped() {
+        let network = Network::Regtest;
+        init(Config {
+            stability_threshold: 10,
+            network,
+            pruning_policy: PruningPolicy {
+                max_fork_work_deficit_in_blocks: 1,
+                ..PruningPolicy::default()
+            },
+            ..Default::default()
+        });
+
+        let genesis = genesis_block(network);
+        let fork_block = BlockBuilder::with_prev_header(genesis.header()).build();
+        let block_1 = BlockBuilder::with_prev_header(genesis.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+        runtime::set_successors_responses(vec![
+            complete_response(&[&fork_block, &block_1, &block_2, &block_3]),
+            complete_response(&[&fork_block]),
+        ]);
+
+        // Fetch the blocks, and then process them while the fork block is sent again.
+        heartbeat().await;
+        heartbeat().await;
+        assert_eq!(with_state(state::main_chain_height), 3);
+        assert!(with_state(|s| s
+            .unstable_blocks
+            .is_pruned(&fork_block.block_hash())));
+
+        // The fork block is skipped without dropping any responses, and it's reported as
+        // processed from then on.
+        heartbeat().await;
+        with_state(|s| {
+            assert!(!s.unstable_blocks.contains_block(&fork_block.block_hash()));
+            assert_eq!(s.syncing_state.num_insert_block_errors, 0);
+        });
+        match &runtime::get_successors_requests()[2] {
+            GetSuccessorsRequest::Initial(request) => {
+                assert!(request
+                    .processed_block_hashes
+                    .contains(&fork_block.block_hash()));
+            }
+            other => panic!("Unexpected request: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn does_not_fetch_blocks_while_responses_to_process_are_too_large() {
+        init(Config::default());
+
+        let push_response = |size: usize| {
+            with_state_mut(|s| {
+                s.syncing_state
+                    .responses_to_process
+                    .push_back(ResponseToProcess::Complete(GetSuccessorsCompleteResponse {
+                        blocks: vec![vec![0; size]],
+                        next: vec![],
+                    }))
+            })
+        };
+
+        push_response(MAX_RESPONSES_TO_PROCESS_SIZE - 1);
+        assert!(maybe_get_successors_request().is_some());
+
+        push_response(1);
+        assert!(maybe_get_successors_request().is_none());
+    }
+
+    #[async_std::test]
+    async fn drops_responses_fetched_ahead_of_a_response_that_fails_to_process() {
+        let network = Network::Regtest;
+        init(Config {
+            network,
+            ..Default::default()
+        });
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        runtime::set_successors_responses(vec![
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
+                GetSuccessorsCompleteResponse {
+                    // Invalid block.
+                    blocks: vec![vec![1, 2, 3]],
+                    next: vec![],
+                },
+            )),
+            complete_response(&[&block]),
+        ]);
+
+        // Fetch the invalid block.
+        heartbeat().await;
+
+        // Fetch the valid block while failing to process the invalid one. The valid block
+        // was requested assuming the invalid block was processed, so it's dropped too.
+        heartbeat().await;
+        assert_eq!(runtime::get_successors_callees().len(), 2);
+        with_state(|s| {
+            assert_eq!(s.syncing_state.num_block_deserialize_errors, 1);
+            assert!(s.syncing_state.responses_to_process.is_empty());
+            assert_eq!(s.syncing_state.responses_generation, 1);
+            assert_eq!(state::main_chain_height(s), 0);
+        });
+    }
+
+    #[async_std::test]
+    async fn rejected_follow_up_drops_the_partial_response() {
+        let network = Network::Regtest;
+        init(Config {
+            network,
+            ..Default::default()
+        });
+
+        let block = BlockBuilder::with_prev_header(genesis_block(network).header()).build();
+        let mut block_bytes = vec![];
+        block.consensus_encode(&mut block_bytes).unwrap();
+
+        runtime::set_successors_responses(vec![
+            GetSuccessorsReply::Ok(GetSuccessorsResponse::Partial(
+                GetSuccessorsPartialResponse {
+                    partial_block: block_bytes[0..40].to_vec(),
+                    next: vec![],
+                    remaining_follow_ups: 1,
+                },
+            )),
+            reject(),
+        ]);
+
+        // Fetch the partial response, then fail to fetch its follow-up.
+        heartbeat().await;
+        heartbeat().await;
+        assert!(with_state(|s| s
+            .syncing_state
+            .responses_to_process
+            .is_empty()));
+
+        // The blocks are requested again from scratch.
+        heartbeat().await;
+        assert!(matches!(
+            runtime::get_successors_requests().as_slice(),
+            [
+                GetSuccessorsRequest::Initial(_),
+                GetSuccessorsRequest::FollowUp(0),
+                GetSuccessorsRequest::Initial(_)
+            ]
+        ));
+    }
 }
@@ -2,11 +2,16 @@ mod address_utxoset;
 mod api;
 mod block_header_store;
 mod blocktree;
+mod fee_policy;
 mod guard;
 mod heartbeat;
+mod logs;
 pub mod memory;
 mod metrics;
+#[cfg(feature = "regtest_mining")]
+mod mining;
 mod multi_iter;
+mod rate_limiter;
 pub mod runtime;
 pub mod state;
 #[cfg(test)]
@@ -14,26 +19,41 @@ mod test_utils;
 #[cfg(test)]
 mod tests;
 pub mod types;
+mod unstable_block_store;
 pub mod unstable_blocks;
+mod upgrade;
 mod utxo_set;
 mod validation;
 
 use crate::{
+    fee_policy::CallCharge,
+    metrics::ErrorLabel,
     runtime::{msg_cycles_accept, msg_cycles_available},
     state::State,
-    types::{into_bitcoin_network, HttpRequest, HttpResponse},
+    types::{into_bitcoin_network, HttpRequest, HttpResponse, RejectionReason, RequestError},
 };
+pub use api::get_logs;
 pub use api::get_metrics;
-pub use api::send_transaction;
+pub use api::get_unstable_block_tree;
+pub use api::get_utxo_set_audit_status;
 pub use api::set_config;
-pub use heartbeat::heartbeat;
+pub use api::start_utxo_set_audit;
+pub use api::SetConfigError;
+pub use api::{
+    extend_state_snapshot, finish_state_snapshot, get_state_snapshot, get_state_snapshot_chunk,
+    start_state_snapshot, StateSnapshotError,
+};
+pub use heartbeat::{heartbeat, start_heartbeat_timer};
 use ic_btc_interface::{
-    Config, Flag, GetBalanceError, GetBalanceRequest, GetCurrentFeePercentilesRequest,
-    GetUtxosError, GetUtxosRequest, GetUtxosResponse, MillisatoshiPerByte, Network, Satoshi,
+    ApiAccessMode, Config, Fees, Flag, GetBalanceError, GetBalanceRequest,
+    GetCurrentFeePercentilesRequest, GetUtxosError, GetUtxosRequest, GetUtxosResponse, Height,
+    MillisatoshiPerByte, Network, Satoshi, SendTransactionError, SendTransactionRequest,
 };
 use ic_btc_types::Block;
-use ic_stable_structures::Memory;
 pub use memory::get_memory;
+use metrics::Endpoint;
+#[cfg(feature = "regtest_mining")]
+pub use mining::{generate_to_address, submit_raw_block};
 use serde_bytes::ByteBuf;
 use state::main_chain_height;
 use std::convert::TryInto;
@@ -73,6 +93,7 @@ fn set_state(state: State) {
             cell.borrow().is_none(),
             "cannot initialize an already initialized state"
         );
+        logs::set_print_logs(state.print_logs == Flag::Enabled);
         *cell.borrow_mut() = Some(state)
     });
 }
@@ -88,49 +109,134 @@ pub fn init(config: Config) {
         genesis_block(config.network),
     ));
 
-    with_state_mut(|s| s.blocks_source = config.blocks_source);
+    with_state_mut(|s| s.set_blocks_sources(config.blocks_source, config.fallback_blocks_sources));
     with_state_mut(|s| s.api_access = config.api_access);
+    with_state_mut(|s| s.api_access_mode = config.api_access_mode);
+    with_state_mut(|s| s.api_access_allow_list = config.api_access_allow_list);
     with_state_mut(|s| s.syncing_state.syncing = config.syncing);
     with_state_mut(|s| s.disable_api_if_not_fully_synced = config.disable_api_if_not_fully_synced);
     with_state_mut(|s| s.watchdog_canister = config.watchdog_canister);
     with_state_mut(|s| s.fees = config.fees);
+    with_state_mut(|s| s.fee_policy = config.fee_policy);
+    with_state_mut(|s| s.rate_limit = config.rate_limit);
+    with_state_mut(|s| s.unstable_blocks.set_pruning_policy(config.pruning_policy));
+    with_state_mut(|s| s.set_print_logs(config.print_logs));
+    with_state_mut(|s| s.syncing_state.sync_interval_ms = config.sync_interval_ms);
+    with_state_mut(|s| s.syncing_state.max_sync_interval_ms = config.max_sync_interval_ms);
+    with_state_mut(|s| {
+        s.syncing_state.blocks_source_failover_threshold = config.blocks_source_failover_threshold
+    });
 }
 
 pub fn get_current_fee_percentiles(
     request: GetCurrentFeePercentilesRequest,
-) -> Vec<MillisatoshiPerByte> {
-    verify_api_access();
-    verify_network(request.network.into());
-    verify_synced();
-    api::get_current_fee_percentiles()
+) -> Result<Vec<MillisatoshiPerByte>, RejectionReason> {
+    verify_request(
+        Endpoint::GetCurrentFeePercentiles,
+        request.network.into(),
+        |fees| fees.get_current_fee_percentiles_maximum,
+    )?;
+    Ok(api::get_current_fee_percentiles())
+}
+
+pub fn get_balance(request: GetBalanceRequest) -> Result<Satoshi, RequestError<GetBalanceError>> {
+    verify_request(Endpoint::GetBalance, request.network.into(), |fees| {
+        fees.get_balance_maximum
+    })
+    .map_err(RequestError::Rejected)?;
+    observe_result(Endpoint::GetBalance, api::get_balance(request.into()))
+        .map_err(RequestError::Failed)
+}
+
+pub fn get_balance_query(
+    request: GetBalanceRequest,
+) -> Result<Satoshi, RequestError<GetBalanceError>> {
+    check_request(Endpoint::GetBalance, request.network.into()).map_err(RequestError::Rejected)?;
+    api::get_balance_query(request.into()).map_err(RequestError::Failed)
 }
 
-pub fn get_balance(request: GetBalanceRequest) -> Result<Satoshi, GetBalanceError> {
-    verify_api_access();
-    verify_network(request.network.into());
-    verify_synced();
-    api::get_balance(request.into())
+pub fn get_utxos(
+    request: GetUtxosRequest,
+) -> Result<GetUtxosResponse, RequestError<GetUtxosError>> {
+    verify_request(Endpoint::GetUtxos, request.network.into(), |fees| {
+        fees.get_utxos_maximum
+    })
+    .map_err(RequestError::Rejected)?;
+    observe_result(Endpoint::GetUtxos, api::get_utxos(request.into())).map_err(RequestError::Failed)
 }
 
-pub fn get_balance_query(request: GetBalanceRequest) -> Result<Satoshi, GetBalanceError> {
-    verify_api_access();
-    verify_network(request.network.into());
-    verify_synced();
-    api::get_balance_query(request.into())
+pub fn get_utxos_query(
+    request: GetUtxosRequest,
+) -> Result<GetUtxosResponse, RequestError<GetUtxosError>> {
+    check_request(Endpoint::GetUtxos, request.network.into()).map_err(RequestError::Rejected)?;
+    api::get_utxos_query(request.into()).map_err(RequestError::Failed)
 }
 
-pub fn get_utxos(request: GetUtxosRequest) -> Result<GetUtxosResponse, GetUtxosError> {
-    verify_api_access();
-    verify_network(request.network.into());
-    verify_synced();
-    api::get_utxos(request.into())
+pub async fn send_transaction(
+    request: SendTransactionRequest,
+) -> Result<(), RequestError<SendTransactionError>> {
+    verify_request(Endpoint::SendTransaction, request.network.into(), |fees| {
+        fees.send_transaction_base
+            + fees.send_transaction_per_byte * request.transaction.len() as u128
+    })
+    .map_err(RequestError::Rejected)?;
+    observe_result(
+        Endpoint::SendTransaction,
+        api::send_transaction(request).await,
+    )
+    .map_err(RequestError::Failed)
 }
 
-pub fn get_utxos_query(request: GetUtxosRequest) -> Result<GetUtxosResponse, GetUtxosError> {
-    verify_api_access();
-    verify_network(request.network.into());
-    verify_synced();
-    api::get_utxos_query(request.into())
+// Verifies that a request to the given endpoint can be processed.
+//
+// Rather than trapping, which would roll back any state changes, the reason for
+// rejecting the request is returned. This allows the request and the rejection to
+// be recorded in the metrics before rejecting the call.
+//
+// Requests are checked against the rate limit first, so that callers exceeding
+// it are rejected with as few instructions as possible.
+//
+// `cycles_required` returns the amount of cycles the request must carry.
+fn verify_request(
+    endpoint: Endpoint,
+    network: Network,
+    cycles_required: impl FnOnce(&Fees) -> u128,
+) -> Result<(), RejectionReason> {
+    let result = check_rate_limit()
+        .and_then(|()| check_request(endpoint, network))
+        .and_then(|()| {
+            let cycles_required = with_state(|s| cycles_required(&s.fees));
+            check_has_enough_cycles(caller_fee(cycles_required))
+        });
+
+    with_state_mut(|s| {
+        s.metrics.observe_request(endpoint);
+        if let Err(err) = &result {
+            s.metrics.observe_error(endpoint, err);
+        }
+    });
+
+    result
+}
+
+// The checks of a request to the given endpoint that are shared by updates and
+// queries. Queries can't record metrics, nor be charged or rate limited.
+fn check_request(endpoint: Endpoint, network: Network) -> Result<(), RejectionReason> {
+    check_api_access()
+        .and_then(|()| check_network(network))
+        .and_then(|()| match endpoint {
+            // Transactions can be sent regardless of whether or not the canister is synced.
+            Endpoint::SendTransaction => Ok(()),
+            _ => check_synced(),
+        })
+}
+
+// Records the error of a request in the metrics, if any.
+fn observe_result<T, E: ErrorLabel>(endpoint: Endpoint, result: Result<T, E>) -> Result<T, E> {
+    if let Err(err) = &result {
+        with_state_mut(|s| s.metrics.observe_error(endpoint, err));
+    }
+    result
 }
 
 pub fn get_config() -> Config {
@@ -143,45 +249,40 @@ pub fn get_config() -> Config {
         api_access: s.api_access,
         disable_api_if_not_fully_synced: s.disable_api_if_not_fully_synced,
         watchdog_canister: s.watchdog_canister,
+        sync_interval_ms: s.syncing_state.sync_interval_ms,
+        max_sync_interval_ms: s.syncing_state.max_sync_interval_ms,
+        fallback_blocks_sources: s.fallback_blocks_sources.clone(),
+        blocks_source_failover_threshold: s.syncing_state.blocks_source_failover_threshold,
+        fee_policy: s.fee_policy.clone(),
+        rate_limit: s.rate_limit.clone(),
+        api_access_mode: s.api_access_mode,
+        api_access_allow_list: s.api_access_allow_list.clone(),
+        pruning_policy: s.unstable_blocks.pruning_policy().clone(),
+        print_logs: s.print_logs,
     })
 }
 
+/// Starts rewinding the stable blocks so that the block at the given height
+/// becomes the anchor, after which the canister re-syncs from that block.
+pub fn rewind_to_height(height: Height) -> Result<(), state::RewindError> {
+    with_state_mut(|s| state::rewind_to_height(s, height))
+}
+
 pub fn pre_upgrade() {
-    // Serialize the state.
-    let mut state_bytes = vec![];
-    with_state(|state| ciborium::ser::into_writer(state, &mut state_bytes))
-        .expect("failed to encode state");
-
-    // Write the length of the serialized bytes to memory, followed by the
-    // by the bytes themselves.
-    let len = state_bytes.len() as u32;
-    let memory = memory::get_upgrades_memory();
-    crate::memory::write(&memory, 0, &len.to_le_bytes());
-    crate::memory::write(&memory, 4, &state_bytes);
+    with_state(upgrade::write_state);
 }
 
 pub fn post_upgrade() {
-    let memory = memory::get_upgrades_memory();
-
-    // Read the length of the state bytes.
-    let mut state_len_bytes = [0; 4];
-    memory.read(0, &mut state_len_bytes);
-    let state_len = u32::from_le_bytes(state_len_bytes) as usize;
-
-    // Read the bytes
-    let mut state_bytes = vec![0; state_len];
-    memory.read(4, &mut state_bytes);
-
-    // Deserialize and set the state.
-    let state: State = ciborium::de::from_reader(&*state_bytes).expect("failed to decode state");
-
-    set_state(state);
+    set_state(upgrade::read_state());
 }
 
 pub fn http_request(req: HttpRequest) -> HttpResponse {
     let parts: Vec<&str> = req.url.split('?').collect();
     match parts[0] {
         "/metrics" => crate::api::get_metrics(),
+        "/logs" => crate::api::get_logs_http(parts.get(1).unwrap_or(&"")),
+        "/unstable_blocks.json" => crate::api::get_unstable_block_tree_json(),
+        "/unstable_blocks.dot" => crate::api::get_unstable_block_tree_dot(),
         _ => HttpResponse {
             status_code: 404,
             headers: vec![],
@@ -197,8 +298,26 @@ pub(crate) fn genesis_block(network: Network) -> Block {
     ))
 }
 
-pub(crate) fn charge_cycles(amount: u128) {
-    verify_has_enough_cycles(amount);
+/// Charges the caller for a call to the given endpoint with a fee of the given amount,
+/// according to the fee policy. Uses up one of the caller's free calls, if it has any left.
+///
+/// Returns how the call is charged, for charging more cycles for the same call with
+/// `charge_more_cycles`.
+pub(crate) fn charge_cycles(endpoint: Endpoint, amount: u128) -> CallCharge {
+    let charge = with_state_mut(|s| fee_policy::charge_call(s, runtime::caller(), runtime::time()));
+    if charge == CallCharge::Free {
+        with_state_mut(|s| s.metrics.observe_free_call(endpoint));
+    }
+    charge_more_cycles(endpoint, amount, charge);
+    charge
+}
+
+/// Charges more cycles for a call that was charged with `charge_cycles` before.
+pub(crate) fn charge_more_cycles(endpoint: Endpoint, amount: u128, charge: CallCharge) {
+    let amount = charge.apply(amount);
+    if let Err(err) = check_has_enough_cycles(amount) {
+        panic!("{}", err);
+    }
 
     let amount: u64 = amount.try_into().expect("amount must be u64");
     assert_eq!(
@@ -206,52 +325,92 @@ pub(crate) fn charge_cycles(amount: u128) {
         amount,
         "Accepting cycles must succeed"
     );
+
+    with_state_mut(|s| s.metrics.observe_cycles_charged(endpoint, amount as u128));
+}
+
+// Returns the amount of cycles that the caller is charged for a fee of the given amount.
+fn caller_fee(amount: u128) -> u128 {
+    with_state(|s| fee_policy::call_charge(s, &runtime::caller(), runtime::time())).apply(amount)
 }
 
-/// Panics if the request contains less than the amount of cycles given.
-pub(crate) fn verify_has_enough_cycles(amount: u128) {
+fn check_has_enough_cycles(amount: u128) -> Result<(), RejectionReason> {
     let amount: u64 = amount.try_into().expect("amount must be u64");
 
     if msg_cycles_available() < amount {
-        panic!(
-            "Received {} cycles. {} cycles are required.",
-            msg_cycles_available(),
-            amount
-        );
+        return Err(RejectionReason::NotEnoughCycles {
+            received: msg_cycles_available(),
+            required: amount,
+        });
     }
+
+    Ok(())
+}
+
+// Takes a call from the caller's bucket of the rate limit, if there's a rate limit.
+fn check_rate_limit() -> Result<(), RejectionReason> {
+    with_state_mut(|s| {
+        let rate_limit = match &s.rate_limit {
+            Some(rate_limit) => rate_limit,
+            None => return Ok(()),
+        };
+
+        let caller = runtime::caller();
+        if !s.rate_limiter.try_call(rate_limit, caller, runtime::time()) {
+            s.metrics.observe_throttled_call();
+            return Err(RejectionReason::RateLimited);
+        }
+
+        Ok(())
+    })
 }
 
 // Verifies that the network is equal to the one maintained by this canister's state.
-fn verify_network(network: Network) {
+fn check_network(network: Network) -> Result<(), RejectionReason> {
     with_state(|state| {
         if state.network() != network {
-            panic!("Network must be {}. Found {}", state.network(), network);
+            return Err(RejectionReason::NetworkMismatch {
+                expected: state.network(),
+                found: network,
+            });
         }
-    });
+        Ok(())
+    })
 }
 
-// Verifies that the access to bitcoin apis is enabled.
-fn verify_api_access() {
+// Verifies that the access to bitcoin apis is enabled for the caller.
+fn check_api_access() -> Result<(), RejectionReason> {
     with_state(|state| {
         if state.api_access == Flag::Disabled {
-            panic!("Bitcoin API is disabled");
+            return Err(RejectionReason::ApiDisabled);
         }
-    });
+        if state.api_access_mode == ApiAccessMode::Restricted
+            && !state.api_access_allow_list.contains(&runtime::caller())
+        {
+            return Err(RejectionReason::ApiRestricted);
+        }
+        Ok(())
+    })
 }
 
 // Verifies if the difference between the maximum height
 // of all block headers and the maximum height of all unstable
 // blocks is at most the SYNCED_THRESHOLD.
-fn verify_synced() {
-    with_state(|state| {
-        if state.disable_api_if_not_fully_synced == Flag::Disabled {
-            return;
-        }
+fn check_synced() -> Result<(), RejectionReason> {
+    // The UTXO set is inconsistent while stable blocks are being rewound.
+    if with_state(|state| state.syncing_state.rewind_target.is_some()) {
+        return Err(RejectionReason::NotSynced);
+    }
 
-        if !is_synced() {
-            panic!("Canister state is not fully synced.");
-        }
-    });
+    if with_state(|state| state.disable_api_if_not_fully_synced == Flag::Disabled) {
+        return Ok(());
+    }
+
+    if !is_synced() {
+        return Err(RejectionReason::NotSynced);
+    }
+
+    Ok(())
 }
 
 /// Returns true if the canister is synced with the network, false otherwise.
@@ -273,7 +432,7 @@ pub(crate) fn is_synced() -> bool {
 mod test {
     use super::*;
     use crate::test_utils::build_regtest_chain;
-    use ic_btc_interface::{Network, NetworkInRequest};
+    use ic_btc_interface::{FeePolicy, FeeTier, Network, NetworkInRequest, RateLimit};
     use proptest::prelude::*;
 
     proptest! {
@@ -342,97 +501,146 @@ mod test {
     }
 
     #[test]
-    #[should_panic(expected = "Network must be mainnet. Found testnet")]
+    fn upgrade_drops_responses_to_process() {
+        init(Config {
+            network: Network::Regtest,
+            ..Default::default()
+        });
+
+        with_state_mut(|s| {
+            s.syncing_state
+                .responses_to_process
+                .push_back(state::ResponseToProcess::Complete(
+                    types::GetSuccessorsCompleteResponse {
+                        blocks: vec![vec![1; 100]],
+                        next: vec![],
+                    },
+                ))
+        });
+
+        pre_upgrade();
+        STATE.with(|cell| cell.take());
+        post_upgrade();
+
+        // The blocks of the response are fetched again after the upgrade.
+        with_state(|s| assert!(s.syncing_state.responses_to_process.is_empty()));
+    }
+
+    #[test]
     fn get_balance_incorrect_network() {
         init(Config {
             stability_threshold: 0,
             network: Network::Mainnet,
             ..Default::default()
         });
-        get_balance(GetBalanceRequest {
-            address: String::from(""),
-            network: NetworkInRequest::Testnet,
-            min_confirmations: None,
-        })
-        .unwrap();
+        assert_eq!(
+            get_balance(GetBalanceRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Testnet,
+                min_confirmations: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::NetworkMismatch {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            }))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Network must be mainnet. Found testnet")]
     fn get_balance_query_incorrect_network() {
         init(Config {
             stability_threshold: 0,
             network: Network::Mainnet,
             ..Default::default()
         });
-        get_balance_query(GetBalanceRequest {
-            address: String::from(""),
-            network: NetworkInRequest::Testnet,
-            min_confirmations: None,
-        })
-        .unwrap();
+        assert_eq!(
+            get_balance_query(GetBalanceRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Testnet,
+                min_confirmations: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::NetworkMismatch {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            }))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Network must be mainnet. Found testnet")]
     fn get_utxos_incorrect_network() {
         init(Config {
             stability_threshold: 0,
             network: Network::Mainnet,
             ..Default::default()
         });
-        get_utxos(GetUtxosRequest {
-            address: String::from(""),
-            network: NetworkInRequest::Testnet,
-            filter: None,
-        })
-        .unwrap();
+        assert_eq!(
+            get_utxos(GetUtxosRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Testnet,
+                filter: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::NetworkMismatch {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            }))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Network must be mainnet. Found testnet")]
     fn get_utxos_query_incorrect_network() {
         init(Config {
             stability_threshold: 0,
             network: Network::Mainnet,
             ..Default::default()
         });
-        get_utxos_query(GetUtxosRequest {
-            address: String::from(""),
-            network: NetworkInRequest::Testnet,
-            filter: None,
-        })
-        .unwrap();
+        assert_eq!(
+            get_utxos_query(GetUtxosRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Testnet,
+                filter: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::NetworkMismatch {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            }))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Network must be mainnet. Found testnet")]
     fn get_current_fee_percentiles_incorrect_network() {
         init(Config {
             stability_threshold: 0,
             network: Network::Mainnet,
             ..Default::default()
         });
-        get_current_fee_percentiles(GetCurrentFeePercentilesRequest {
-            network: NetworkInRequest::Testnet,
-        });
+        assert_eq!(
+            get_current_fee_percentiles(GetCurrentFeePercentilesRequest {
+                network: NetworkInRequest::Testnet,
+            }),
+            Err(RejectionReason::NetworkMismatch {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            })
+        );
     }
 
     #[test]
-    fn test_verify_has_enough_cycles_does_not_panic_with_enough_cycles() {
-        verify_has_enough_cycles(1_000);
+    fn test_check_has_enough_cycles_accepts_enough_cycles() {
+        assert_eq!(check_has_enough_cycles(1_000), Ok(()));
     }
 
     #[test]
-    #[should_panic(
-        expected = "Received 9223372036854775807 cycles. 18446744073709551615 cycles are required."
-    )]
-    fn test_verify_has_enough_cycles_panics_with_not_enough_cycles() {
-        verify_has_enough_cycles(u64::MAX as u128);
+    fn test_check_has_enough_cycles_rejects_not_enough_cycles() {
+        assert_eq!(
+            check_has_enough_cycles(u64::MAX as u128),
+            Err(RejectionReason::NotEnoughCycles {
+                received: 9223372036854775807,
+                required: 18446744073709551615,
+            })
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Bitcoin API is disabled")]
     fn get_balance_access_disabled() {
         init(Config {
             stability_threshold: 0,
@@ -440,16 +648,17 @@ mod test {
             api_access: Flag::Disabled,
             ..Default::default()
         });
-        get_balance(GetBalanceRequest {
-            address: String::from(""),
-            network: NetworkInRequest::Mainnet,
-            min_confirmations: None,
-        })
-        .unwrap();
+        assert_eq!(
+            get_balance(GetBalanceRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Mainnet,
+                min_confirmations: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::ApiDisabled))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Bitcoin API is disabled")]
     fn get_balance_query_access_disabled() {
         init(Config {
             stability_threshold: 0,
@@ -457,16 +666,17 @@ mod test {
             api_access: Flag::Disabled,
             ..Default::default()
         });
-        get_balance_query(GetBalanceRequest {
-            address: String::from(""),
-            network: NetworkInRequest::Mainnet,
-            min_confirmations: None,
-        })
-        .unwrap();
+        assert_eq!(
+            get_balance_query(GetBalanceRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Mainnet,
+                min_confirmations: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::ApiDisabled))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Bitcoin API is disabled")]
     fn get_utxos_access_disabled() {
         init(Config {
             stability_threshold: 0,
@@ -474,16 +684,17 @@ mod test {
             api_access: Flag::Disabled,
             ..Default::default()
         });
-        get_utxos(GetUtxosRequest {
-            address: String::from(""),
-            network: NetworkInRequest::Mainnet,
-            filter: None,
-        })
-        .unwrap();
+        assert_eq!(
+            get_utxos(GetUtxosRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Mainnet,
+                filter: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::ApiDisabled))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Bitcoin API is disabled")]
     fn get_utxos_query_access_disabled() {
         init(Config {
             stability_threshold: 0,
@@ -491,16 +702,17 @@ mod test {
             api_access: Flag::Disabled,
             ..Default::default()
         });
-        get_utxos_query(GetUtxosRequest {
-            address: String::from(""),
-            network: NetworkInRequest::Mainnet,
-            filter: None,
-        })
-        .unwrap();
+        assert_eq!(
+            get_utxos_query(GetUtxosRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Mainnet,
+                filter: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::ApiDisabled))
+        );
     }
 
     #[test]
-    #[should_panic(expected = "Bitcoin API is disabled")]
     fn get_current_fee_percentiles_access_disabled() {
         init(Config {
             stability_threshold: 0,
@@ -508,9 +720,12 @@ mod test {
             api_access: Flag::Disabled,
             ..Default::default()
         });
-        get_current_fee_percentiles(GetCurrentFeePercentilesRequest {
-            network: NetworkInRequest::Mainnet,
-        });
+        assert_eq!(
+            get_current_fee_percentiles(GetCurrentFeePercentilesRequest {
+                network: NetworkInRequest::Mainnet,
+            }),
+            Err(RejectionReason::ApiDisabled)
+        );
     }
 
     #[test]
@@ -554,4 +769,258 @@ mod test {
             assert!(s.disable_api_if_not_fully_synced == Flag::Enabled);
         });
     }
+
+    #[test]
+    fn verify_request_records_requests_and_rejections() {
+        init(Config {
+            network: Network::Mainnet,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            verify_request(Endpoint::GetBalance, Network::Mainnet, |fees| fees
+                .get_balance_maximum),
+            Ok(())
+        );
+        assert_eq!(
+            verify_request(Endpoint::GetBalance, Network::Testnet, |fees| fees
+                .get_balance_maximum),
+            Err(RejectionReason::NetworkMismatch {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            })
+        );
+        assert_eq!(
+            verify_request(Endpoint::GetBalance, Network::Mainnet, |_| u64::MAX as u128),
+            Err(RejectionReason::NotEnoughCycles {
+                received: msg_cycles_available(),
+                required: u64::MAX,
+            })
+        );
+
+        with_state_mut(|s| s.api_access = Flag::Disabled);
+        assert_eq!(
+            verify_request(Endpoint::GetBalance, Network::Mainnet, |fees| fees
+                .get_balance_maximum),
+            Err(RejectionReason::ApiDisabled)
+        );
+
+        with_state(|s| {
+            let counters = s.metrics.endpoints.get(&Endpoint::GetBalance).unwrap();
+            assert_eq!(counters.requests, 4);
+            assert_eq!(
+                counters.errors,
+                maplit::btreemap! {
+                    "api_disabled".to_string() => 1,
+                    "network_mismatch".to_string() => 1,
+                    "not_enough_cycles".to_string() => 1,
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn get_balance_records_errors_and_cycles_charged() {
+        init(Config {
+            network: Network::Mainnet,
+            fees: Fees {
+                get_balance: 10,
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+
+        assert_eq!(
+            get_balance(GetBalanceRequest {
+                address: String::from("not an address"),
+                network: NetworkInRequest::Mainnet,
+                min_confirmations: None,
+            }),
+            Err(RequestError::Failed(GetBalanceError::MalformedAddress))
+        );
+
+        with_state(|s| {
+            let counters = s.metrics.endpoints.get(&Endpoint::GetBalance).unwrap();
+            assert_eq!(counters.requests, 1);
+            assert_eq!(counters.cycles_charged, 10);
+            assert_eq!(
+                counters.errors,
+                maplit::btreemap! { "malformed_address".to_string() => 1 }
+            );
+        });
+    }
+
+    #[test]
+    fn charges_callers_according_to_the_fee_policy() {
+        let developer = candid::Principal::from_slice(&[1]);
+        init(Config {
+            fee_policy: FeePolicy {
+                tiers: vec![FeeTier {
+                    principals: vec![developer],
+                    fee_percent: 50,
+                    free_calls_per_period: 1,
+                }],
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        runtime::set_caller(developer);
+
+        // The first call is free, including the cycles charged for it afterwards.
+        assert_eq!(
+            check_has_enough_cycles(caller_fee(u64::MAX as u128)),
+            Ok(())
+        );
+        let charge = charge_cycles(Endpoint::GetUtxos, 10);
+        charge_more_cycles(Endpoint::GetUtxos, 100, charge);
+
+        // The next calls are charged half the fees.
+        let charge = charge_cycles(Endpoint::GetUtxos, 10);
+        charge_more_cycles(Endpoint::GetUtxos, 100, charge);
+
+        with_state(|s| {
+            let counters = &s.metrics.endpoints[&Endpoint::GetUtxos];
+            assert_eq!(counters.cycles_charged, 55);
+            assert_eq!(counters.free_calls, 1);
+        });
+        assert_eq!(runtime::get_cycles_balance(), 55);
+    }
+
+    #[test]
+    fn verify_request_rejects_callers_exceeding_the_rate_limit() {
+        let caller = candid::Principal::from_slice(&[1]);
+        init(Config {
+            network: Network::Mainnet,
+            rate_limit: Some(RateLimit {
+                calls_per_minute: 1,
+                max_burst: 2,
+                exempt_principals: vec![],
+            }),
+            ..Default::default()
+        });
+        runtime::set_caller(caller);
+
+        let verify = || {
+            verify_request(Endpoint::GetBalance, Network::Mainnet, |fees| {
+                fees.get_balance_maximum
+            })
+        };
+        assert_eq!(verify(), Ok(()));
+        assert_eq!(verify(), Ok(()));
+        assert_eq!(verify(), Err(RejectionReason::RateLimited));
+
+        // Exempt callers aren't rate limited.
+        with_state_mut(|s| {
+            s.rate_limit
+                .as_mut()
+                .unwrap()
+                .exempt_principals
+                .push(caller)
+        });
+        assert_eq!(verify(), Ok(()));
+
+        with_state(|s| {
+            let counters = s.metrics.endpoints.get(&Endpoint::GetBalance).unwrap();
+            assert_eq!(counters.requests, 4);
+            assert_eq!(
+                counters.errors,
+                maplit::btreemap! { "rate_limited".to_string() => 1 }
+            );
+            assert_eq!(s.metrics.num_throttled_calls, 1);
+        });
+    }
+
+    #[test]
+    fn verify_request_rejects_callers_not_in_the_allow_list() {
+        let allowed_caller = candid::Principal::from_slice(&[1]);
+        init(Config {
+            network: Network::Mainnet,
+            api_access_mode: ApiAccessMode::Restricted,
+            api_access_allow_list: vec![allowed_caller],
+            ..Default::default()
+        });
+
+        let verify = || {
+            verify_request(Endpoint::GetBalance, Network::Mainnet, |fees| {
+                fees.get_balance_maximum
+            })
+        };
+
+        runtime::set_caller(candid::Principal::from_slice(&[2]));
+        assert_eq!(verify(), Err(RejectionReason::ApiRestricted));
+
+        runtime::set_caller(allowed_caller);
+        assert_eq!(verify(), Ok(()));
+
+        // Disabling the API applies to the callers in the allow-list as well.
+        with_state_mut(|s| s.api_access = Flag::Disabled);
+        assert_eq!(verify(), Err(RejectionReason::ApiDisabled));
+
+        // Everyone can access the API again once it's unrestricted.
+        with_state_mut(|s| {
+            s.api_access = Flag::Enabled;
+            s.api_access_mode = ApiAccessMode::Unrestricted;
+        });
+        runtime::set_caller(candid::Principal::from_slice(&[2]));
+        assert_eq!(verify(), Ok(()));
+    }
+
+    #[test]
+    fn get_balance_access_restricted() {
+        init(Config {
+            network: Network::Mainnet,
+            api_access_mode: ApiAccessMode::Restricted,
+            ..Default::default()
+        });
+        assert_eq!(
+            get_balance(GetBalanceRequest {
+                address: String::from(""),
+                network: NetworkInRequest::Mainnet,
+                min_confirmations: None,
+            }),
+            Err(RequestError::Rejected(RejectionReason::ApiRestricted))
+        );
+    }
+
+    #[async_std::test]
+    async fn send_transaction_records_requests_and_errors() {
+        init(Config {
+            network: Network::Mainnet,
+            ..Default::default()
+        });
+
+        assert_eq!(
+            send_transaction(SendTransactionRequest {
+                network: NetworkInRequest::Testnet,
+                transaction: vec![1, 2, 3],
+            })
+            .await,
+            Err(RequestError::Rejected(RejectionReason::NetworkMismatch {
+                expected: Network::Mainnet,
+                found: Network::Testnet,
+            }))
+        );
+        assert_eq!(
+            send_transaction(SendTransactionRequest {
+                network: NetworkInRequest::Mainnet,
+                transaction: vec![1, 2, 3],
+            })
+            .await,
+            Err(RequestError::Failed(
+                SendTransactionError::MalformedTransaction
+            ))
+        );
+
+        with_state(|s| {
+            let counters = s.metrics.endpoints.get(&Endpoint::SendTransaction).unwrap();
+            assert_eq!(counters.requests, 2);
+            assert_eq!(
+                counters.errors,
+                maplit::btreemap! {
+                    "malformed_transaction".to_string() => 1,
+                    "network_mismatch".to_string() => 1,
+                }
+            );
+        });
+    }
 }
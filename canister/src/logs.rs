@@ -0,0 +1,185 @@
+//! A bounded buffer of structured log entries.
+//!
+//! Entries are kept in memory in a ring buffer so that they can be inspected
+//! through the canister's endpoints. Optionally, entries are also printed to
+//! the replica's logs.
+use crate::runtime::{print, time};
+use ic_btc_interface::{LogComponent, LogEntry, LogLevel};
+use ic_btc_types::BlockHash;
+use std::{
+    cell::{Cell, RefCell},
+    collections::VecDeque,
+};
+
+/// The maximum number of entries kept in the buffer.
+/// Once full, the oldest entries are evicted.
+const MAX_ENTRIES: usize = 2_000;
+
+/// The maximum length of an entry's message. Longer messages are truncated.
+const MAX_MESSAGE_LENGTH: usize = 1_000;
+
+thread_local! {
+    static LOGS: RefCell<VecDeque<LogEntry>> = RefCell::new(VecDeque::with_capacity(MAX_ENTRIES));
+
+    // Whether or not entries are also printed to the replica's logs.
+    // NOTE: This is a copy of `State::print_logs`, as entries are logged while the state
+    // is borrowed. It's updated whenever the state's setting is set or the state is loaded.
+    static PRINT_LOGS: Cell<bool> = Cell::new(true);
+}
+
+pub fn debug(component: LogComponent, message: &str) {
+    log(LogLevel::Debug, component, None, message);
+}
+
+pub fn info(component: LogComponent, message: &str) {
+    log(LogLevel::Info, component, None, message);
+}
+
+pub fn warning(component: LogComponent, message: &str) {
+    log(LogLevel::Warning, component, None, message);
+}
+
+pub fn error(component: LogComponent, message: &str) {
+    log(LogLevel::Error, component, None, message);
+}
+
+/// Logs an entry that relates to the block with the given hash.
+pub fn log_block(level: LogLevel, component: LogComponent, block_hash: &BlockHash, message: &str) {
+    log(level, component, Some(block_hash), message);
+}
+
+/// Adds an entry to the buffer, evicting the oldest entry if the buffer is full.
+pub fn log(
+    level: LogLevel,
+    component: LogComponent,
+    block_hash: Option<&BlockHash>,
+    message: &str,
+) {
+    let entry = LogEntry {
+        timestamp: time(),
+        level,
+        component,
+        message: truncate(message),
+        block_hash: block_hash.map(|h| h.to_string()),
+    };
+
+    if PRINT_LOGS.with(|p| p.get()) {
+        print(&format!(
+            "[{}] [{:?}] {}{}",
+            entry.level,
+            entry.component,
+            entry.message,
+            entry
+                .block_hash
+                .as_ref()
+                .map(|h| format!(" (block {})", h))
+                .unwrap_or_default()
+        ));
+    }
+
+    LOGS.with(|logs| {
+        let mut logs = logs.borrow_mut();
+        if logs.len() == MAX_ENTRIES {
+            logs.pop_front();
+        }
+        logs.push_back(entry);
+    });
+}
+
+/// Returns the entries with at least the given level that were logged at or
+/// after `since`, oldest first.
+pub fn get_logs(level: Option<LogLevel>, since: Option<u64>) -> Vec<LogEntry> {
+    LOGS.with(|logs| {
+        logs.borrow()
+            .iter()
+            .filter(|e| level.map_or(true, |level| e.level >= level))
+            .filter(|e| since.map_or(true, |since| e.timestamp >= since))
+            .cloned()
+            .collect()
+    })
+}
+
+/// Sets whether or not entries are also printed to the replica's logs.
+pub fn set_print_logs(enabled: bool) {
+    PRINT_LOGS.with(|p| p.set(enabled));
+}
+
+fn truncate(message: &str) -> String {
+    if message.len() <= MAX_MESSAGE_LENGTH {
+        return message.to_string();
+    }
+
+    // Truncate on a char boundary.
+    let mut end = MAX_MESSAGE_LENGTH;
+    while !message.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}...", &message[..end])
+}
+
+#[cfg(test)]
+pub fn clear() {
+    LOGS.with(|logs| logs.borrow_mut().clear());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evicts_oldest_entries() {
+        clear();
+        for i in 0..MAX_ENTRIES + 10 {
+            info(LogComponent::Api, &i.to_string());
+        }
+
+        let logs = get_logs(None, None);
+        assert_eq!(logs.len(), MAX_ENTRIES);
+        assert_eq!(logs[0].message, "10");
+        assert_eq!(logs[MAX_ENTRIES - 1].message, (MAX_ENTRIES + 9).to_string());
+    }
+
+    #[test]
+    fn filters_by_level_and_time() {
+        clear();
+        debug(LogComponent::Heartbeat, "debug");
+        warning(LogComponent::Heartbeat, "warning");
+        log_block(
+            LogLevel::Error,
+            LogComponent::Ingestion,
+            &BlockHash::default(),
+            "error",
+        );
+
+        let messages =
+            |logs: Vec<LogEntry>| -> Vec<String> { logs.into_iter().map(|e| e.message).collect() };
+
+        assert_eq!(
+            messages(get_logs(Some(LogLevel::Warning), None)),
+            vec!["warning", "error"]
+        );
+        assert_eq!(
+            messages(get_logs(Some(LogLevel::Error), None)),
+            vec!["error"]
+        );
+        assert_eq!(
+            messages(get_logs(None, Some(u64::MAX))),
+            Vec::<String>::new()
+        );
+        assert_eq!(get_logs(None, None).len(), 3);
+        assert_eq!(
+            get_logs(Some(LogLevel::Error), None)[0].block_hash,
+            Some(BlockHash::default().to_string())
+        );
+    }
+
+    #[test]
+    fn truncates_long_messages() {
+        clear();
+        info(LogComponent::Api, &"a".repeat(MAX_MESSAGE_LENGTH + 1));
+        assert_eq!(
+            get_logs(None, None)[0].message,
+            format!("{}...", "a".repeat(MAX_MESSAGE_LENGTH))
+        );
+    }
+}
@@ -1,10 +1,11 @@
 use ic_btc_canister::types::{HttpRequest, HttpResponse};
 use ic_btc_interface::{
-    Config, GetBalanceRequest, GetCurrentFeePercentilesRequest, GetUtxosRequest,
-    MillisatoshiPerByte, SendTransactionRequest, SetConfigRequest,
+    Config, GetBalanceRequest, GetCurrentFeePercentilesRequest, GetLogsRequest, GetUtxosRequest,
+    LogEntry, SendTransactionRequest, SetConfigRequest, StateSnapshot, UnstableBlockTree,
+    UtxoSetAuditStatus,
 };
 use ic_cdk::api::call::{reject, reply};
-use ic_cdk_macros::{heartbeat, init, inspect_message, post_upgrade, pre_upgrade, query, update};
+use ic_cdk_macros::{init, inspect_message, post_upgrade, pre_upgrade, query, update};
 
 #[cfg(target_arch = "wasm32")]
 mod printer;
@@ -18,6 +19,7 @@ fn hook() {
 fn init(config: Config) {
     hook();
     ic_btc_canister::init(config);
+    ic_btc_canister::start_heartbeat_timer();
 }
 
 #[pre_upgrade]
@@ -29,18 +31,19 @@ fn pre_upgrade() {
 fn post_upgrade() {
     hook();
     ic_btc_canister::post_upgrade();
+    ic_btc_canister::start_heartbeat_timer();
 }
 
-#[heartbeat]
-async fn heartbeat() {
-    ic_btc_canister::heartbeat().await
-}
+// NOTE: Requests to the Bitcoin API that can't be processed, e.g. because the API is
+// disabled or the request doesn't carry enough cycles, are rejected with a message of
+// the form "<endpoint> failed: <reason>" rather than trapping, so that they are recorded
+// in the metrics.
 
 #[update(manual_reply = true)]
 pub fn bitcoin_get_balance(request: GetBalanceRequest) {
     match ic_btc_canister::get_balance(request) {
         Ok(response) => reply((response,)),
-        Err(e) => reject(format!("get_balance failed: {:?}", e).as_str()),
+        Err(e) => reject(format!("get_balance failed: {}", e).as_str()),
     }
 }
 
@@ -52,7 +55,7 @@ pub fn bitcoin_get_balance_query(request: GetBalanceRequest) {
     }
     match ic_btc_canister::get_balance_query(request) {
         Ok(response) => reply((response,)),
-        Err(e) => reject(format!("get_balance_query failed: {:?}", e).as_str()),
+        Err(e) => reject(format!("get_balance_query failed: {}", e).as_str()),
     }
 }
 
@@ -60,7 +63,7 @@ pub fn bitcoin_get_balance_query(request: GetBalanceRequest) {
 pub fn bitcoin_get_utxos(request: GetUtxosRequest) {
     match ic_btc_canister::get_utxos(request) {
         Ok(response) => reply((response,)),
-        Err(e) => reject(format!("get_utxos failed: {:?}", e).as_str()),
+        Err(e) => reject(format!("get_utxos failed: {}", e).as_str()),
     };
 }
 
@@ -72,7 +75,7 @@ pub fn bitcoin_get_utxos_query(request: GetUtxosRequest) {
     }
     match ic_btc_canister::get_utxos_query(request) {
         Ok(response) => reply((response,)),
-        Err(e) => reject(format!("get_utxos_query failed: {:?}", e).as_str()),
+        Err(e) => reject(format!("get_utxos_query failed: {}", e).as_str()),
     };
 }
 
@@ -80,15 +83,16 @@ pub fn bitcoin_get_utxos_query(request: GetUtxosRequest) {
 async fn bitcoin_send_transaction(request: SendTransactionRequest) {
     match ic_btc_canister::send_transaction(request).await {
         Ok(_) => reply(()),
-        Err(e) => reject(format!("send_transaction failed: {:?}", e).as_str()),
+        Err(e) => reject(format!("send_transaction failed: {}", e).as_str()),
     }
 }
 
-#[update]
-pub fn bitcoin_get_current_fee_percentiles(
-    request: GetCurrentFeePercentilesRequest,
-) -> Vec<MillisatoshiPerByte> {
-    ic_btc_canister::get_current_fee_percentiles(request)
+#[update(manual_reply = true)]
+pub fn bitcoin_get_current_fee_percentiles(request: GetCurrentFeePercentilesRequest) {
+    match ic_btc_canister::get_current_fee_percentiles(request) {
+        Ok(response) => reply((response,)),
+        Err(e) => reject(format!("get_current_fee_percentiles failed: {}", e).as_str()),
+    }
 }
 
 #[query]
@@ -96,9 +100,125 @@ pub fn get_config() -> Config {
     ic_btc_canister::get_config()
 }
 
-#[update]
+#[update(manual_reply = true)]
 async fn set_config(request: SetConfigRequest) {
-    ic_btc_canister::set_config(request).await
+    match ic_btc_canister::set_config(request).await {
+        Ok(()) => reply(()),
+        Err(e) => reject(format!("set_config failed: {}", e).as_str()),
+    }
+}
+
+#[query]
+pub fn get_logs(request: GetLogsRequest) -> Vec<LogEntry> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call get_logs");
+    }
+    ic_btc_canister::get_logs(request)
+}
+
+#[update(manual_reply = true)]
+pub fn rewind_to_height(height: u32) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call rewind_to_height");
+    }
+    match ic_btc_canister::rewind_to_height(height) {
+        Ok(()) => reply(()),
+        Err(e) => reject(format!("rewind_to_height failed: {}", e).as_str()),
+    }
+}
+
+#[update]
+pub fn start_utxo_set_audit() -> bool {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call start_utxo_set_audit");
+    }
+    ic_btc_canister::start_utxo_set_audit()
+}
+
+#[query]
+pub fn get_utxo_set_audit_status() -> UtxoSetAuditStatus {
+    ic_btc_canister::get_utxo_set_audit_status()
+}
+
+#[update(manual_reply = true)]
+pub fn start_state_snapshot() {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call start_state_snapshot");
+    }
+    match ic_btc_canister::start_state_snapshot() {
+        Ok(snapshot) => reply((snapshot,)),
+        Err(e) => reject(format!("start_state_snapshot failed: {}", e).as_str()),
+    }
+}
+
+#[query]
+pub fn get_state_snapshot() -> Option<StateSnapshot> {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call get_state_snapshot");
+    }
+    ic_btc_canister::get_state_snapshot()
+}
+
+#[query(manual_reply = true)]
+pub fn get_state_snapshot_chunk(index: u64) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call get_state_snapshot_chunk");
+    }
+    match ic_btc_canister::get_state_snapshot_chunk(index) {
+        Ok(chunk) => reply((chunk,)),
+        Err(e) => reject(format!("get_state_snapshot_chunk failed: {}", e).as_str()),
+    }
+}
+
+#[update(manual_reply = true)]
+pub fn extend_state_snapshot(secs: u64) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call extend_state_snapshot");
+    }
+    match ic_btc_canister::extend_state_snapshot(secs) {
+        Ok(snapshot) => reply((snapshot,)),
+        Err(e) => reject(format!("extend_state_snapshot failed: {}", e).as_str()),
+    }
+}
+
+#[update(manual_reply = true)]
+pub fn finish_state_snapshot() {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call finish_state_snapshot");
+    }
+    match ic_btc_canister::finish_state_snapshot() {
+        Ok(()) => reply(()),
+        Err(e) => reject(format!("finish_state_snapshot failed: {}", e).as_str()),
+    }
+}
+
+#[query]
+pub fn get_unstable_block_tree() -> UnstableBlockTree {
+    ic_btc_canister::get_unstable_block_tree()
+}
+
+#[cfg(feature = "regtest_mining")]
+#[update(manual_reply = true)]
+pub fn generate_to_address(address: String, num_blocks: u32) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call generate_to_address");
+    }
+    match ic_btc_canister::generate_to_address(&address, num_blocks) {
+        Ok(block_hashes) => reply((block_hashes,)),
+        Err(e) => reject(format!("generate_to_address failed: {}", e).as_str()),
+    }
+}
+
+#[cfg(feature = "regtest_mining")]
+#[update(manual_reply = true)]
+pub fn submit_raw_block(block: serde_bytes::ByteBuf) {
+    if !ic_cdk::api::is_controller(&ic_cdk::caller()) {
+        ic_cdk::trap("Only controllers can call submit_raw_block");
+    }
+    match ic_btc_canister::submit_raw_block(&block) {
+        Ok(block_hash) => reply((block_hash,)),
+        Err(e) => reject(format!("submit_raw_block failed: {}", e).as_str()),
+    }
 }
 
 #[query]
@@ -6,7 +6,7 @@ use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
     Memory as MemoryTrait,
 };
-use std::cell::RefCell;
+use std::{cell::RefCell, io};
 
 const WASM_PAGE_SIZE: u64 = 65536;
 
@@ -17,6 +17,13 @@ const MEDIUM_UTXOS: MemoryId = MemoryId::new(3);
 const BALANCES: MemoryId = MemoryId::new(4);
 const BLOCK_HEADERS: MemoryId = MemoryId::new(5);
 const BLOCK_HEIGHTS: MemoryId = MemoryId::new(6);
+const UNDO_RECORDS: MemoryId = MemoryId::new(7);
+const CHAINWORKS: MemoryId = MemoryId::new(8);
+const UNSTABLE_BLOCKS: MemoryId = MemoryId::new(9);
+const UNSTABLE_TX_OUTS: MemoryId = MemoryId::new(10);
+const UNSTABLE_ADDED_OUTPOINTS: MemoryId = MemoryId::new(11);
+const UNSTABLE_REMOVED_OUTPOINTS: MemoryId = MemoryId::new(12);
+const UNSTABLE_TX_BLOCKS: MemoryId = MemoryId::new(13);
 
 #[cfg(feature = "file_memory")]
 type InnerMemory = FileMemory;
@@ -96,6 +103,34 @@ pub fn get_block_heights_memory() -> Memory {
     with_memory_manager(|m| m.get(BLOCK_HEIGHTS))
 }
 
+pub fn get_undo_records_memory() -> Memory {
+    with_memory_manager(|m| m.get(UNDO_RECORDS))
+}
+
+pub fn get_chainworks_memory() -> Memory {
+    with_memory_manager(|m| m.get(CHAINWORKS))
+}
+
+pub fn get_unstable_blocks_memory() -> Memory {
+    with_memory_manager(|m| m.get(UNSTABLE_BLOCKS))
+}
+
+pub fn get_unstable_tx_outs_memory() -> Memory {
+    with_memory_manager(|m| m.get(UNSTABLE_TX_OUTS))
+}
+
+pub fn get_unstable_added_outpoints_memory() -> Memory {
+    with_memory_manager(|m| m.get(UNSTABLE_ADDED_OUTPOINTS))
+}
+
+pub fn get_unstable_removed_outpoints_memory() -> Memory {
+    with_memory_manager(|m| m.get(UNSTABLE_REMOVED_OUTPOINTS))
+}
+
+pub fn get_unstable_tx_blocks_memory() -> Memory {
+    with_memory_manager(|m| m.get(UNSTABLE_TX_BLOCKS))
+}
+
 /// Writes the bytes at the specified offset, growing the memory size if needed.
 pub fn write<M: MemoryTrait>(memory: &M, offset: u64, bytes: &[u8]) {
     let last_byte = offset
@@ -124,3 +159,83 @@ pub fn write<M: MemoryTrait>(memory: &M, offset: u64, bytes: &[u8]) {
     }
     memory.write(offset, bytes);
 }
+
+/// A writer that writes bytes sequentially into memory, starting at the given offset.
+pub struct Writer<'a, M: MemoryTrait> {
+    memory: &'a M,
+    offset: u64,
+}
+
+impl<'a, M: MemoryTrait> Writer<'a, M> {
+    pub fn new(memory: &'a M, offset: u64) -> Self {
+        Self { memory, offset }
+    }
+
+    /// The offset at which the next bytes will be written.
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+}
+
+impl<'a, M: MemoryTrait> io::Write for Writer<'a, M> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        write(self.memory, self.offset, buf);
+        self.offset += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// A reader that reads `len` bytes sequentially from memory, starting at the given offset.
+pub struct Reader<'a, M: MemoryTrait> {
+    memory: &'a M,
+    offset: u64,
+    end: u64,
+}
+
+impl<'a, M: MemoryTrait> Reader<'a, M> {
+    pub fn new(memory: &'a M, offset: u64, len: u64) -> Self {
+        Self {
+            memory,
+            offset,
+            end: offset + len,
+        }
+    }
+}
+
+impl<'a, M: MemoryTrait> io::Read for Reader<'a, M> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = std::cmp::min(buf.len() as u64, self.end - self.offset) as usize;
+        self.memory.read(self.offset, &mut buf[..len]);
+        self.offset += len as u64;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use ic_stable_structures::VectorMemory;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn writer_and_reader_round_trip() {
+        let memory = VectorMemory::default();
+        let bytes: Vec<u8> = (0..200_000).map(|i| (i % 256) as u8).collect();
+
+        let mut writer = Writer::new(&memory, 4);
+        for chunk in bytes.chunks(1000) {
+            writer.write_all(chunk).unwrap();
+        }
+        assert_eq!(writer.offset(), 4 + bytes.len() as u64);
+
+        let mut read_bytes = vec![];
+        Reader::new(&memory, 4, bytes.len() as u64)
+            .read_to_end(&mut read_bytes)
+            .unwrap();
+        assert_eq!(read_bytes, bytes);
+    }
+}
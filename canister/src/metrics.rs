@@ -1,13 +1,25 @@
+use crate::{
+    api::SetConfigError, types::RejectionReason, upgrade::MigrationReport,
+    utxo_set::BlockIngestionStats,
+};
+use candid::Principal;
+use ic_btc_interface::{GetBalanceError, GetUtxosError, SendTransactionError};
 use serde::{Deserialize, Serialize};
-
-use crate::utxo_set::BlockIngestionStats;
+use std::collections::BTreeMap;
 
 const M: u64 = 1_000_000;
 const BUCKET_SIZE: u64 = 500 * M;
 const NUM_BUCKETS: u64 = 21;
 
+/// The bucket size of histograms observing requests that are expected to be
+/// cheap enough to be served as queries.
+const QUERY_BUCKET_SIZE: u64 = 10 * M;
+
 /// Metrics for various endpoints.
+// NOTE: New fields are populated from `Metrics::default()` when deserializing
+// metrics that were serialized before these fields were introduced.
 #[derive(Serialize, Deserialize, PartialEq)]
+#[serde(default)]
 pub struct Metrics {
     pub get_utxos_total: InstructionHistogram,
     pub get_utxos_apply_unstable_blocks: InstructionHistogram,
@@ -26,6 +38,29 @@ pub struct Metrics {
 
     /// Instructions needed to insert a block into the pool of unstable blocks.
     pub block_insertion: InstructionHistogram,
+
+    /// Same as `get_utxos_total`, but with buckets fine enough for query-sized requests.
+    pub get_utxos_total_fine: InstructionHistogram,
+
+    /// Same as `get_balance_total`, but with buckets fine enough for query-sized requests.
+    pub get_balance_total_fine: InstructionHistogram,
+
+    /// Same as `get_current_fee_percentiles_total`, but with buckets fine enough
+    /// for query-sized requests.
+    pub get_current_fee_percentiles_total_fine: InstructionHistogram,
+
+    /// Request, error and cycles counters of the update endpoints.
+    pub endpoints: BTreeMap<Endpoint, EndpointCounters>,
+
+    /// Counters of the requests for blocks made to each blocks source.
+    pub blocks_sources: BTreeMap<Principal, BlocksSourceCounters>,
+
+    /// The number of requests rejected for exceeding the rate limit.
+    pub num_throttled_calls: u64,
+
+    /// The migrations of the state that ran in the most recent upgrade, if any.
+    #[serde(skip)]
+    pub state_migration: Option<MigrationReport>,
 }
 
 impl Default for Metrics {
@@ -66,13 +101,201 @@ impl Default for Metrics {
                 "ins_block_insertion",
                 "Instructions needed to insert a block into the pool of unstable blocks.",
             ),
+
+            get_utxos_total_fine: InstructionHistogram::with_bucket_size(
+                "ins_get_utxos_total_fine",
+                "Instructions needed to execute a get_utxos request (fine buckets).",
+                QUERY_BUCKET_SIZE,
+            ),
+            get_balance_total_fine: InstructionHistogram::with_bucket_size(
+                "ins_get_balance_total_fine",
+                "Instructions needed to execute a get_balance request (fine buckets).",
+                QUERY_BUCKET_SIZE,
+            ),
+            get_current_fee_percentiles_total_fine: InstructionHistogram::with_bucket_size(
+                "ins_get_current_fee_percentiles_total_fine",
+                "Instructions needed to execute a get_current_fee_percentiles request (fine buckets).",
+                QUERY_BUCKET_SIZE,
+            ),
+
+            endpoints: BTreeMap::new(),
+            blocks_sources: BTreeMap::new(),
+            num_throttled_calls: 0,
+            state_migration: None,
+        }
+    }
+}
+
+impl Metrics {
+    /// Counts a request made to the given endpoint.
+    pub fn observe_request(&mut self, endpoint: Endpoint) {
+        self.endpoints.entry(endpoint).or_default().requests += 1;
+    }
+
+    /// Counts an error returned by the given endpoint.
+    pub fn observe_error<E: ErrorLabel>(&mut self, endpoint: Endpoint, error: &E) {
+        *self
+            .endpoints
+            .entry(endpoint)
+            .or_default()
+            .errors
+            .entry(error.label().to_string())
+            .or_default() += 1;
+    }
+
+    /// Adds to the amount of cycles charged by the given endpoint.
+    pub fn observe_cycles_charged(&mut self, endpoint: Endpoint, amount: u128) {
+        self.endpoints.entry(endpoint).or_default().cycles_charged += amount;
+    }
+
+    /// Counts a request to the given endpoint that was free.
+    pub fn observe_free_call(&mut self, endpoint: Endpoint) {
+        self.endpoints.entry(endpoint).or_default().free_calls += 1;
+    }
+
+    /// Counts a request that was rejected for exceeding the rate limit.
+    pub fn observe_throttled_call(&mut self) {
+        self.num_throttled_calls += 1;
+    }
+
+    /// Counts a request for blocks that the given blocks source responded to.
+    pub fn observe_blocks_source_success(&mut self, blocks_source: Principal) {
+        self.blocks_sources
+            .entry(blocks_source)
+            .or_default()
+            .successes += 1;
+    }
+
+    /// Counts a request for blocks that the given blocks source rejected.
+    pub fn observe_blocks_source_reject(&mut self, blocks_source: Principal) {
+        self.blocks_sources
+            .entry(blocks_source)
+            .or_default()
+            .rejects += 1;
+    }
+
+    /// Counts a request for blocks that the given blocks source stalled on.
+    pub fn observe_blocks_source_stall(&mut self, blocks_source: Principal) {
+        self.blocks_sources.entry(blocks_source).or_default().stalls += 1;
+    }
+}
+
+/// The endpoints for which request, error and cycles counters are kept.
+///
+/// NOTE: Query endpoints are not included, as the state changes of a query
+/// call are discarded and their counters would never be updated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Endpoint {
+    GetBalance,
+    GetUtxos,
+    GetCurrentFeePercentiles,
+    SendTransaction,
+    SetConfig,
+}
+
+impl Endpoint {
+    /// The name of the endpoint as exposed in the canister's interface.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::GetBalance => "bitcoin_get_balance",
+            Self::GetUtxos => "bitcoin_get_utxos",
+            Self::GetCurrentFeePercentiles => "bitcoin_get_current_fee_percentiles",
+            Self::SendTransaction => "bitcoin_send_transaction",
+            Self::SetConfig => "set_config",
+        }
+    }
+}
+
+/// Counters of the requests made to an endpoint.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct EndpointCounters {
+    /// The total number of requests.
+    pub requests: u64,
+
+    /// The number of failed requests, keyed by the label of the error.
+    pub errors: BTreeMap<String, u64>,
+
+    /// The total amount of cycles charged.
+    pub cycles_charged: u128,
+
+    /// The number of requests that were free, as the caller had free calls left.
+    #[serde(default)]
+    pub free_calls: u64,
+}
+
+/// Counters of the requests for blocks made to a blocks source.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct BlocksSourceCounters {
+    /// The number of requests that the source responded to with progress.
+    pub successes: u64,
+
+    /// The number of requests that the source rejected.
+    pub rejects: u64,
+
+    /// The number of requests that the source responded to without the blocks
+    /// it was expected to have.
+    pub stalls: u64,
+}
+
+/// An error that can be counted in the metrics.
+pub trait ErrorLabel {
+    /// The label under which the error is counted.
+    fn label(&self) -> &'static str;
+}
+
+impl ErrorLabel for GetBalanceError {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::MalformedAddress => "malformed_address",
+            Self::MinConfirmationsTooLarge { .. } => "min_confirmations_too_large",
+        }
+    }
+}
+
+impl ErrorLabel for GetUtxosError {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::MalformedAddress => "malformed_address",
+            Self::MinConfirmationsTooLarge { .. } => "min_confirmations_too_large",
+            Self::UnknownTipBlockHash { .. } => "unknown_tip_block_hash",
+            Self::MalformedPage { .. } => "malformed_page",
+        }
+    }
+}
+
+impl ErrorLabel for SendTransactionError {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::MalformedTransaction => "malformed_transaction",
+            Self::QueueFull => "queue_full",
+        }
+    }
+}
+
+impl ErrorLabel for SetConfigError {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::NotAuthorized => "not_authorized",
+        }
+    }
+}
+
+impl ErrorLabel for RejectionReason {
+    fn label(&self) -> &'static str {
+        match self {
+            Self::ApiDisabled => "api_disabled",
+            Self::ApiRestricted => "api_restricted",
+            Self::NetworkMismatch { .. } => "network_mismatch",
+            Self::NotSynced => "not_synced",
+            Self::NotEnoughCycles { .. } => "not_enough_cycles",
+            Self::RateLimited => "rate_limited",
         }
     }
 }
 
 /// A histogram for observing instruction counts.
 ///
-/// The histogram observes the values in buckets of:
+/// By default, the histogram observes the values in buckets of:
 ///
 ///  (500M, 1B, 1.5B, ..., 9B, 9.5B, 10B, +Inf)
 #[derive(Serialize, Deserialize, PartialEq)]
@@ -81,21 +304,33 @@ pub struct InstructionHistogram {
     pub buckets: Vec<u64>,
     pub sum: f64,
     pub help: String,
+    #[serde(default = "default_bucket_size")]
+    pub bucket_size: u64,
+}
+
+fn default_bucket_size() -> u64 {
+    BUCKET_SIZE
 }
 
 impl InstructionHistogram {
     pub fn new<S: Into<String>>(name: S, help: S) -> Self {
+        Self::with_bucket_size(name, help, BUCKET_SIZE)
+    }
+
+    /// Creates a histogram with buckets of the given size instead of the default 500M.
+    pub fn with_bucket_size<S: Into<String>>(name: S, help: S, bucket_size: u64) -> Self {
         Self {
             name: name.into(),
             help: help.into(),
             sum: 0.0,
-            buckets: vec![0; 21],
+            buckets: vec![0; NUM_BUCKETS as usize],
+            bucket_size,
         }
     }
 
     /// Observes an instruction count.
     pub fn observe(&mut self, value: u64) {
-        let bucket_idx = Self::get_bucket(value);
+        let bucket_idx = self.get_bucket(value);
 
         // Divide value by 1M to keep the counts sane.
         let value: f64 = value as f64 / M as f64;
@@ -107,20 +342,19 @@ impl InstructionHistogram {
 
     /// Returns an iterator with the various buckets.
     pub fn buckets(&self) -> impl Iterator<Item = (f64, f64)> + '_ {
-        (500..10_500)
-            .step_by((BUCKET_SIZE / M) as usize)
-            .map(|e| e as f64)
+        (1..NUM_BUCKETS)
+            .map(|i| (i * self.bucket_size) as f64 / M as f64)
             .chain([f64::INFINITY])
             .zip(self.buckets.iter().map(|e| *e as f64))
     }
 
     // Returns the index of the bucket where the value belongs.
-    fn get_bucket(value: u64) -> usize {
+    fn get_bucket(&self, value: u64) -> usize {
         if value == 0 {
             return 0;
         }
 
-        let idx = (value - 1) / BUCKET_SIZE;
+        let idx = (value - 1) / self.bucket_size;
         std::cmp::min(idx, NUM_BUCKETS - 1) as usize
     }
 }
@@ -209,4 +443,46 @@ mod test {
             vec![(f64::INFINITY, 2.0)]
         );
     }
+
+    #[test]
+    fn fine_buckets() {
+        let mut h = InstructionHistogram::with_bucket_size("", "", QUERY_BUCKET_SIZE);
+        h.observe(10 * M);
+        h.observe(10 * M + 1);
+        h.observe(200 * M + 1);
+        assert_eq!(
+            h.buckets().take(3).collect::<Vec<_>>(),
+            vec![(10.0, 1.0), (20.0, 1.0), (30.0, 0.0)]
+        );
+        assert_eq!(
+            h.buckets().skip(19).collect::<Vec<_>>(),
+            vec![(200.0, 0.0), (f64::INFINITY, 1.0)]
+        );
+    }
+
+    #[test]
+    fn endpoint_counters() {
+        let mut metrics = Metrics::default();
+        metrics.observe_request(Endpoint::GetBalance);
+        metrics.observe_request(Endpoint::GetBalance);
+        metrics.observe_error(Endpoint::GetBalance, &GetBalanceError::MalformedAddress);
+        metrics.observe_error(Endpoint::GetBalance, &RejectionReason::ApiDisabled);
+        metrics.observe_error(Endpoint::GetBalance, &RejectionReason::ApiDisabled);
+        metrics.observe_cycles_charged(Endpoint::GetBalance, 10);
+        metrics.observe_cycles_charged(Endpoint::GetBalance, 5);
+
+        assert_eq!(
+            metrics.endpoints.get(&Endpoint::GetBalance),
+            Some(&EndpointCounters {
+                requests: 2,
+                errors: maplit::btreemap! {
+                    "api_disabled".to_string() => 2,
+                    "malformed_address".to_string() => 1,
+                },
+                cycles_charged: 15,
+                free_calls: 0,
+            })
+        );
+        assert_eq!(metrics.endpoints.get(&Endpoint::GetUtxos), None);
+    }
 }
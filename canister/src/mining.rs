@@ -0,0 +1,492 @@
+//! A developer mode in which the canister mines its own regtest blocks.
+//!
+//! Blocks are built inside the canister and inserted directly into the state,
+//! so no adapter is needed. Transactions sent with `send_transaction` are kept
+//! in a pool and included in the next mined blocks once all their inputs are
+//! available.
+//!
+//! This module is only available when the canister is built with the
+//! `regtest_mining` feature, and only operates on `Network::Regtest`. The
+//! canister should be initialized with syncing disabled so that it doesn't
+//! attempt to fetch blocks from an adapter.
+use crate::{
+    logs,
+    runtime::time,
+    state::{self, State},
+    types::into_bitcoin_network,
+    unstable_blocks, with_state, with_state_mut,
+};
+use bitcoin::{
+    blockdata::script::Builder, consensus::Decodable, Address as BitcoinAddress,
+    Block as BitcoinBlock, BlockHeader, Network as BitcoinNetwork, OutPoint as BitcoinOutPoint,
+    Script, Transaction as BitcoinTransaction, TxIn, TxOut, Witness,
+};
+use ic_btc_interface::{Height, LogComponent, LogLevel, Network};
+use ic_btc_types::{Block, BlockHash, OutPoint, Transaction};
+use ic_btc_validation::{
+    add_witness_commitment, block_subsidy, compute_merkle_root, max_target, solve_header,
+};
+use std::{cell::RefCell, collections::BTreeSet, str::FromStr};
+
+/// The maximum number of blocks that can be generated in a single call.
+const MAX_BLOCKS_PER_CALL: u32 = 100;
+
+/// The maximum number of transactions kept in the pool.
+/// Once full, the oldest transactions are evicted.
+const MAX_PENDING_TRANSACTIONS: usize = 1_000;
+
+thread_local! {
+    // Transactions waiting to be included in a block.
+    // NOTE: The pool isn't persisted across upgrades.
+    static PENDING_TRANSACTIONS: RefCell<Vec<BitcoinTransaction>> = RefCell::new(Vec::new());
+}
+
+/// Returns true if the canister mines its own blocks, which is only the case
+/// on regtest.
+pub fn is_enabled() -> bool {
+    with_state(|s| s.network() == Network::Regtest)
+}
+
+/// Adds a transaction to the pool of transactions to include in mined blocks.
+pub fn add_pending_transaction(tx: BitcoinTransaction) {
+    PENDING_TRANSACTIONS.with(|txs| {
+        let mut txs = txs.borrow_mut();
+        if txs.len() == MAX_PENDING_TRANSACTIONS {
+            txs.remove(0);
+        }
+        txs.push(tx);
+    });
+}
+
+/// Mines `num_blocks` blocks on top of the main chain, paying the block
+/// rewards to the given address.
+///
+/// Returns the hashes of the mined blocks.
+pub fn generate_to_address(address: &str, num_blocks: u32) -> Result<Vec<String>, String> {
+    verify_regtest()?;
+    if num_blocks > MAX_BLOCKS_PER_CALL {
+        return Err(format!(
+            "Cannot generate more than {} blocks per call",
+            MAX_BLOCKS_PER_CALL
+        ));
+    }
+
+    let script_pubkey = parse_address(address)?;
+
+    let mut block_hashes = vec![];
+    for _ in 0..num_blocks {
+        let pending = PENDING_TRANSACTIONS.with(|txs| txs.borrow().clone());
+        let (block, included) = with_state(|s| build_block(s, &script_pubkey, pending));
+
+        PENDING_TRANSACTIONS.with(|txs| {
+            txs.borrow_mut().retain(|tx| !included.contains(&tx.txid()));
+        });
+
+        let block_hash = block.block_hash();
+        insert_block(block)?;
+        block_hashes.push(block_hash.to_string());
+    }
+
+    Ok(block_hashes)
+}
+
+/// Inserts a consensus-encoded block into the state.
+pub fn submit_raw_block(bytes: &[u8]) -> Result<String, String> {
+    verify_regtest()?;
+
+    let block = BitcoinBlock::consensus_decode(bytes)
+        .map_err(|err| format!("Cannot decode block: {}", err))?;
+
+    // The block is validated when it's inserted, including its merkle root and inputs.
+    let block = Block::new(block);
+    let block_hash = block.block_hash();
+    if with_state(|s| unstable_blocks::get_chain_with_tip(&s.unstable_blocks, &block_hash))
+        .is_some()
+    {
+        return Err(format!("Block {} already exists", block_hash));
+    }
+
+    insert_block(block)?;
+    Ok(block_hash.to_string())
+}
+
+fn verify_regtest() -> Result<(), String> {
+    if is_enabled() {
+        Ok(())
+    } else {
+        Err("Mining is only available on regtest".to_string())
+    }
+}
+
+// Parses a regtest address into its script.
+fn parse_address(address: &str) -> Result<Script, String> {
+    let address =
+        BitcoinAddress::from_str(address).map_err(|_| format!("Invalid address: {}", address))?;
+
+    // Base58 regtest addresses share their prefixes with testnet and are parsed as such.
+    match address.network {
+        BitcoinNetwork::Regtest | BitcoinNetwork::Testnet => Ok(address.script_pubkey()),
+        _ => Err(format!("Not a regtest address: {}", address)),
+    }
+}
+
+fn insert_block(block: Block) -> Result<(), String> {
+    let block_hash = block.block_hash();
+    with_state_mut(|s| {
+        state::insert_block(s, block)
+            .map_err(|err| format!("Failed to insert block {}: {:?}", block_hash, err))?;
+        state::ingest_stable_blocks_into_utxoset(s);
+        Ok::<(), String>(())
+    })?;
+
+    logs::log_block(
+        LogLevel::Info,
+        LogComponent::Api,
+        &block_hash,
+        &format!(
+            "Inserted regtest block at height {}",
+            with_state(state::main_chain_height)
+        ),
+    );
+    Ok(())
+}
+
+// Builds a block on top of the main chain.
+//
+// Returns the block along with the IDs of the pending transactions included in it.
+fn build_block(
+    state: &State,
+    script_pubkey: &Script,
+    pending: Vec<BitcoinTransaction>,
+) -> (Block, BTreeSet<bitcoin::Txid>) {
+    let tip = unstable_blocks::get_main_chain(&state.unstable_blocks).tip();
+    let tip_hash = tip.block_hash();
+    let mut chain = ChainOutputs::new(state, &tip_hash).expect("the tip must exist");
+    let height = chain.next_height;
+
+    // Select the pending transactions whose inputs can all be spent.
+    let mut included = BTreeSet::new();
+    let mut txdata = vec![];
+    for tx in pending {
+        if chain.try_spend(&Transaction::new(tx.clone())) {
+            included.insert(tx.txid());
+            txdata.push(tx);
+        }
+    }
+
+    let coinbase = build_coinbase(height, &tip_hash, script_pubkey);
+    txdata.insert(0, coinbase);
+    add_witness_commitment(&mut txdata);
+
+    let prev_header = tip.header();
+    let mut header = BlockHeader {
+        version: 0x20000000,
+        prev_blockhash: prev_header.block_hash(),
+        merkle_root: compute_merkle_root(&txdata).expect("a block has at least one transaction"),
+        // The timestamp must increase to be above the median time of the past blocks.
+        time: std::cmp::max(prev_header.time + 1, time() as u32),
+        bits: BlockHeader::compact_target_from_u256(&max_target(&into_bitcoin_network(
+            Network::Regtest,
+        ))),
+        nonce: 0,
+    };
+    solve_header(&mut header);
+
+    (Block::new(BitcoinBlock { header, txdata }), included)
+}
+
+fn build_coinbase(
+    height: Height,
+    prev_block_hash: &BlockHash,
+    script_pubkey: &Script,
+) -> BitcoinTransaction {
+    // NOTE: The fees of the included transactions aren't claimed, as the values of
+    // their inputs aren't known here. Only the subsidy is paid.
+    BitcoinTransaction {
+        version: 2,
+        lock_time: 0,
+        input: vec![TxIn {
+            previous_output: BitcoinOutPoint::null(),
+            // The height is pushed first as required by BIP34. The previous block
+            // hash makes the coinbase unique across competing blocks at the same height.
+            script_sig: Builder::new()
+                .push_int(height as i64)
+                .push_slice(&prev_block_hash.to_vec())
+                .into_script(),
+            sequence: 0xffffffff,
+            witness: Witness::new(),
+        }],
+        output: vec![TxOut {
+            value: block_subsidy(&BitcoinNetwork::Regtest, height),
+            script_pubkey: script_pubkey.clone(),
+        }],
+    }
+}
+
+// The outputs that are available to spend in a block extending a given chain.
+struct ChainOutputs<'a> {
+    state: &'a State,
+    // The outputs created in the unstable blocks of the chain.
+    created: BTreeSet<OutPoint>,
+    // The outputs spent in the unstable blocks of the chain.
+    spent: BTreeSet<OutPoint>,
+    // The height of a block extending the chain.
+    next_height: Height,
+}
+
+impl<'a> ChainOutputs<'a> {
+    fn new(state: &'a State, tip: &BlockHash) -> Result<Self, String> {
+        let chain = unstable_blocks::get_chain_with_tip(&state.unstable_blocks, tip)
+            .ok_or_else(|| format!("Block {} is not an unstable block", tip))?
+            .into_chain();
+
+        let mut outputs = Self {
+            state,
+            created: BTreeSet::new(),
+            spent: BTreeSet::new(),
+            next_height: state.stable_height() + chain.len() as Height,
+        };
+
+        for block in chain {
+            let block = state
+                .unstable_blocks
+                .get_block(&block.block_hash())
+                .expect("unstable blocks must be stored");
+            for tx in block.txdata() {
+                outputs.apply(tx);
+            }
+        }
+
+        Ok(outputs)
+    }
+
+    // Spends the inputs of the transaction if they are all available.
+    fn try_spend(&mut self, tx: &Transaction) -> bool {
+        let mut inputs = BTreeSet::new();
+        for input in tx.input() {
+            let outpoint = OutPoint::from(&input.previous_output);
+            if !self.is_available(&outpoint) || !inputs.insert(outpoint) {
+                return false;
+            }
+        }
+
+        self.apply(tx);
+        true
+    }
+
+    fn apply(&mut self, tx: &Transaction) {
+        if !tx.is_coin_base() {
+            for input in tx.input() {
+                self.spent.insert(OutPoint::from(&input.previous_output));
+            }
+        }
+
+        let txid = tx.txid();
+        for vout in 0..tx.output().len() {
+            self.created
+                .insert(OutPoint::new(txid.clone(), vout as u32));
+        }
+    }
+
+    fn is_available(&self, outpoint: &OutPoint) -> bool {
+        if self.spent.contains(outpoint) {
+            return false;
+        }
+
+        self.created.contains(outpoint) || self.state.utxos.get_utxo(outpoint).is_some()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{test_utils::random_p2pkh_address, types::Address};
+    use ic_btc_interface::Config;
+
+    fn init() {
+        crate::init(Config {
+            network: Network::Regtest,
+            stability_threshold: 2,
+            ..Default::default()
+        });
+        PENDING_TRANSACTIONS.with(|txs| txs.borrow_mut().clear());
+    }
+
+    fn balance(address: &Address) -> u64 {
+        with_state(|s| {
+            s.get_utxos(address.clone())
+                .into_iter(None)
+                .map(|utxo| utxo.value)
+                .sum()
+        })
+    }
+
+    fn script_pubkey(address: &Address) -> Script {
+        BitcoinAddress::from_str(&address.to_string())
+            .unwrap()
+            .script_pubkey()
+    }
+
+    #[test]
+    fn generates_blocks_to_address() {
+        init();
+        let address = random_p2pkh_address(Network::Regtest);
+
+        let block_hashes = generate_to_address(&address.to_string(), 5).unwrap();
+
+        assert_eq!(block_hashes.len(), 5);
+        assert_eq!(with_state(state::main_chain_height), 5);
+        assert_eq!(balance(&address), 5 * 50 * 100_000_000);
+    }
+
+    #[test]
+    fn builds_blocks_on_the_main_chain() {
+        init();
+        let address = random_p2pkh_address(Network::Regtest);
+
+        // Two blocks at height 1. The one inserted second has more work, so it's the tip
+        // of the main chain even though it isn't the first block at its height.
+        let (block_1, _) = with_state(|s| build_block(s, &script_pubkey(&address), vec![]));
+        let (mut fork_block_1, _) = with_state(|s| {
+            build_block(
+                s,
+                &script_pubkey(&random_p2pkh_address(Network::Regtest)),
+                vec![],
+            )
+        });
+        fork_block_1.mock_difficulty = Some(100);
+        insert_block(block_1).unwrap();
+        insert_block(fork_block_1.clone()).unwrap();
+
+        let (block_2, _) = with_state(|s| build_block(s, &script_pubkey(&address), vec![]));
+        assert_eq!(
+            BlockHash::from(block_2.header().prev_blockhash),
+            fork_block_1.block_hash()
+        );
+    }
+
+    #[test]
+    fn includes_pending_transactions() {
+        init();
+        let address_1 = random_p2pkh_address(Network::Regtest);
+        let address_2 = random_p2pkh_address(Network::Regtest);
+        let block_hash = generate_to_address(&address_1.to_string(), 1).unwrap()[0].clone();
+
+        let coinbase: BitcoinTransaction = with_state(|s| {
+            let block_hash = unstable_blocks::get_blocks(&s.unstable_blocks)
+                .into_iter()
+                .map(|block| block.block_hash())
+                .find(|hash| hash.to_string() == block_hash)
+                .unwrap();
+            s.unstable_blocks.get_block(&block_hash).unwrap().txdata()[0]
+                .clone()
+                .into()
+        });
+
+        let tx = BitcoinTransaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: BitcoinOutPoint::new(coinbase.txid(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Witness::new(),
+            }],
+            output: vec![TxOut {
+                value: 1_000,
+                script_pubkey: script_pubkey(&address_2),
+            }],
+        };
+
+        // A transaction spending an unknown output isn't included.
+        let mut unknown_input_tx = tx.clone();
+        unknown_input_tx.input[0].previous_output.vout = 1;
+
+        add_pending_transaction(unknown_input_tx.clone());
+        add_pending_transaction(tx.clone());
+        // A transaction spending an output that's already spent isn't included.
+        let mut double_spend_tx = tx;
+        double_spend_tx.output[0].value = 2_000;
+        add_pending_transaction(double_spend_tx.clone());
+
+        generate_to_address(&address_1.to_string(), 1).unwrap();
+
+        assert_eq!(balance(&address_2), 1_000);
+        assert_eq!(
+            PENDING_TRANSACTIONS.with(|txs| txs.borrow().clone()),
+            vec![unknown_input_tx, double_spend_tx]
+        );
+    }
+
+    #[test]
+    fn submits_raw_blocks() {
+        init();
+        let address = random_p2pkh_address(Network::Regtest);
+        let (block, _) = with_state(|s| build_block(s, &script_pubkey(&address), vec![]));
+
+        let mut bytes = vec![];
+        block.consensus_encode(&mut bytes).unwrap();
+
+        assert_eq!(submit_raw_block(&bytes), Ok(block.block_hash().to_string()));
+        assert_eq!(with_state(state::main_chain_height), 1);
+
+        // The same block cannot be submitted twice.
+        assert!(submit_raw_block(&bytes).is_err());
+        assert!(submit_raw_block(&[1, 2, 3]).is_err());
+    }
+
+    #[test]
+    fn rejects_raw_blocks_spending_unknown_outputs() {
+        init();
+        let address = random_p2pkh_address(Network::Regtest);
+        let (block, _) = with_state(|s| build_block(s, &script_pubkey(&address), vec![]));
+
+        let mut block = block.as_bitcoin_block().clone();
+        block.txdata.push(BitcoinTransaction {
+            version: 2,
+            lock_time: 0,
+            input: vec![TxIn {
+                previous_output: BitcoinOutPoint::new(bitcoin::Txid::default(), 0),
+                script_sig: Script::new(),
+                sequence: 0xffffffff,
+                witness: Witness::new(),
+            }],
+            output: vec![],
+        });
+
+        // The block is rejected with an invalid merkle root...
+        assert!(submit_raw_block(&bitcoin::consensus::serialize(&block)).is_err());
+
+        // ...and with an input that doesn't exist.
+        block.header.merkle_root = compute_merkle_root(&block.txdata).unwrap();
+        solve_header(&mut block.header);
+        assert!(submit_raw_block(&bitcoin::consensus::serialize(&block)).is_err());
+        assert_eq!(with_state(state::main_chain_height), 0);
+    }
+
+    #[test]
+    fn only_available_on_regtest() {
+        crate::init(Config {
+            network: Network::Testnet,
+            ..Default::default()
+        });
+
+        let address = random_p2pkh_address(Network::Testnet);
+        assert_eq!(
+            generate_to_address(&address.to_string(), 1),
+            Err("Mining is only available on regtest".to_string())
+        );
+        assert_eq!(
+            submit_raw_block(&[]),
+            Err("Mining is only available on regtest".to_string())
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_addresses() {
+        init();
+        let mainnet_address = random_p2pkh_address(Network::Mainnet);
+        assert!(generate_to_address(&mainnet_address.to_string(), 1).is_err());
+        assert!(generate_to_address("not an address", 1).is_err());
+    }
+}
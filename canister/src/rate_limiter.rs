@@ -0,0 +1,189 @@
+use candid::Principal;
+use ic_btc_interface::RateLimit;
+use std::collections::BTreeMap;
+
+// Tokens are counted in sixtieths of a call, so that the number of tokens a bucket is
+// refilled with every second is a whole number.
+const TOKENS_PER_CALL: u64 = 60;
+
+// The maximum number of buckets. Beyond it, the buckets that are full are dropped, as a
+// full bucket is equivalent to no bucket at all, and then the bucket that was used least
+// recently.
+const MAX_BUCKETS: usize = 10_000;
+
+/// Limits the rate at which each caller can call the Bitcoin API, with a token bucket
+/// per caller.
+// NOTE: The buckets aren't persisted across upgrades, after which all callers start
+// with a full bucket.
+#[derive(Default, PartialEq, Eq, Debug)]
+pub struct RateLimiter {
+    buckets: BTreeMap<Principal, Bucket>,
+}
+
+#[derive(PartialEq, Eq, Debug)]
+struct Bucket {
+    tokens: u64,
+    // The time the bucket was last refilled, in seconds since the UNIX epoch.
+    last_refill: u64,
+}
+
+impl Bucket {
+    // Returns the number of tokens the bucket would have if it were refilled at the
+    // given time.
+    fn tokens_at(&self, rate_limit: &RateLimit, now: u64) -> u64 {
+        let elapsed = now.saturating_sub(self.last_refill);
+        std::cmp::min(
+            self.tokens
+                .saturating_add(elapsed.saturating_mul(rate_limit.calls_per_minute)),
+            capacity(rate_limit),
+        )
+    }
+
+    fn refill(&mut self, rate_limit: &RateLimit, now: u64) {
+        self.tokens = self.tokens_at(rate_limit, now);
+        self.last_refill = std::cmp::max(self.last_refill, now);
+    }
+}
+
+impl RateLimiter {
+    /// Takes a call made at the given time, in seconds since the UNIX epoch, from the
+    /// caller's bucket. Returns false if the caller's bucket is empty, i.e. if the
+    /// caller exceeded the rate limit.
+    pub fn try_call(&mut self, rate_limit: &RateLimit, caller: Principal, now: u64) -> bool {
+        if rate_limit.exempt_principals.contains(&caller) {
+            return true;
+        }
+
+        if self.buckets.len() >= MAX_BUCKETS && !self.buckets.contains_key(&caller) {
+            self.buckets
+                .retain(|_, bucket| bucket.tokens_at(rate_limit, now) < capacity(rate_limit));
+
+            // Buckets are refilled on every call, so the bucket that was refilled first is
+            // the one that was used least recently.
+            if self.buckets.len() >= MAX_BUCKETS {
+                let least_recently_used = self
+                    .buckets
+                    .iter()
+                    .min_by_key(|(_, bucket)| bucket.last_refill)
+                    .map(|(caller, _)| *caller)
+                    .expect("there must be buckets");
+                self.buckets.remove(&least_recently_used);
+            }
+        }
+
+        let bucket = self.buckets.entry(caller).or_insert_with(|| Bucket {
+            tokens: capacity(rate_limit),
+            last_refill: now,
+        });
+        bucket.refill(rate_limit, now);
+
+        if bucket.tokens < TOKENS_PER_CALL {
+            return false;
+        }
+        bucket.tokens -= TOKENS_PER_CALL;
+        true
+    }
+}
+
+fn capacity(rate_limit: &RateLimit) -> u64 {
+    rate_limit.max_burst.saturating_mul(TOKENS_PER_CALL)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn callers_are_limited_to_bursts_refilled_over_time() {
+        let rate_limit = RateLimit {
+            calls_per_minute: 6,
+            max_burst: 2,
+            exempt_principals: vec![],
+        };
+        let caller = Principal::from_slice(&[1]);
+        let other_caller = Principal::from_slice(&[2]);
+        let mut rate_limiter = RateLimiter::default();
+
+        assert!(rate_limiter.try_call(&rate_limit, caller, 0));
+        assert!(rate_limiter.try_call(&rate_limit, caller, 0));
+        assert!(!rate_limiter.try_call(&rate_limit, caller, 0));
+
+        // Every caller has a bucket of its own.
+        assert!(rate_limiter.try_call(&rate_limit, other_caller, 0));
+
+        // A call is refilled every 10 seconds.
+        assert!(!rate_limiter.try_call(&rate_limit, caller, 9));
+        assert!(rate_limiter.try_call(&rate_limit, caller, 10));
+        assert!(!rate_limiter.try_call(&rate_limit, caller, 10));
+
+        // Buckets aren't refilled beyond the maximum burst.
+        assert!(rate_limiter.try_call(&rate_limit, caller, 1_000));
+        assert!(rate_limiter.try_call(&rate_limit, caller, 1_000));
+        assert!(!rate_limiter.try_call(&rate_limit, caller, 1_000));
+    }
+
+    #[test]
+    fn exempt_callers_are_not_limited() {
+        let caller = Principal::from_slice(&[1]);
+        let rate_limit = RateLimit {
+            calls_per_minute: 0,
+            max_burst: 0,
+            exempt_principals: vec![caller],
+        };
+        let mut rate_limiter = RateLimiter::default();
+
+        for _ in 0..10 {
+            assert!(rate_limiter.try_call(&rate_limit, caller, 0));
+        }
+        assert!(rate_limiter.buckets.is_empty());
+    }
+
+    #[test]
+    fn full_buckets_are_dropped_beyond_the_max_buckets() {
+        let rate_limit = RateLimit {
+            calls_per_minute: 60,
+            max_burst: 1,
+            exempt_principals: vec![],
+        };
+        let mut rate_limiter = RateLimiter::default();
+
+        for i in 0..MAX_BUCKETS as u32 {
+            assert!(rate_limiter.try_call(&rate_limit, Principal::from_slice(&i.to_be_bytes()), 0));
+        }
+        assert_eq!(rate_limiter.buckets.len(), MAX_BUCKETS);
+
+        // The buckets are full again a second later.
+        let caller = Principal::from_slice(&[1, 2, 3, 4, 5]);
+        assert!(rate_limiter.try_call(&rate_limit, caller, 1));
+        assert_eq!(rate_limiter.buckets.len(), 1);
+        assert!(!rate_limiter.try_call(&rate_limit, caller, 1));
+    }
+
+    #[test]
+    fn least_recently_used_buckets_are_dropped_beyond_the_max_buckets() {
+        let rate_limit = RateLimit {
+            calls_per_minute: 1,
+            max_burst: 2,
+            exempt_principals: vec![],
+        };
+        let caller = |i: u32| Principal::from_slice(&i.to_be_bytes());
+        let mut rate_limiter = RateLimiter::default();
+
+        // A bucket takes a minute to be full again after a call, so none of the buckets
+        // are full when the max buckets are reached. The first caller uses its bucket last.
+        for i in 0..MAX_BUCKETS as u32 {
+            assert!(rate_limiter.try_call(&rate_limit, caller(i), i as u64 / 200));
+        }
+        assert!(rate_limiter.try_call(&rate_limit, caller(0), 50));
+        assert!(!rate_limiter.try_call(&rate_limit, caller(0), 50));
+        assert_eq!(rate_limiter.buckets.len(), MAX_BUCKETS);
+
+        // The bucket of the caller that used it least recently is dropped.
+        let new_caller = caller(MAX_BUCKETS as u32);
+        assert!(rate_limiter.try_call(&rate_limit, new_caller, 50));
+        assert_eq!(rate_limiter.buckets.len(), MAX_BUCKETS);
+        assert!(!rate_limiter.buckets.contains_key(&caller(1)));
+        assert!(rate_limiter.buckets.contains_key(&caller(0)));
+        assert!(rate_limiter.buckets.contains_key(&new_caller));
+    }
+}
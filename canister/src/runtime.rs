@@ -7,11 +7,12 @@ use candid::Principal;
 use ic_cdk::api::call::CallResult;
 #[cfg(not(target_arch = "wasm32"))]
 use ic_cdk::api::call::RejectionCode;
+use ic_cdk_timers::TimerId;
 #[cfg(not(target_arch = "wasm32"))]
 use serde::Deserialize;
 #[cfg(not(target_arch = "wasm32"))]
 use std::cell::RefCell;
-use std::future::Future;
+use std::{future::Future, time::Duration};
 
 // The instruction limit in system subnets is 50B.
 #[cfg(not(target_arch = "wasm32"))]
@@ -46,11 +47,16 @@ thread_local! {
 
     pub static GET_SUCCESSORS_RESPONSES_INDEX: RefCell<usize> = RefCell::new(0);
 
+    // The principals and requests `call_get_successors` was invoked with, in order.
+    static GET_SUCCESSORS_REQUESTS: RefCell<Vec<(Principal, GetSuccessorsRequest)>> = RefCell::new(Vec::default());
+
     static PERFORMANCE_COUNTER: RefCell<u64> = RefCell::new(0);
 
     static PERFORMANCE_COUNTER_STEP: RefCell<u64> = RefCell::new(0);
 
     static CYCLES_BALANCE: RefCell<u64> = RefCell::new(0);
+
+    static CALLER: RefCell<Principal> = RefCell::new(Principal::anonymous());
 }
 
 #[cfg(target_arch = "wasm32")]
@@ -63,11 +69,13 @@ pub fn call_get_successors(
 
 #[cfg(not(target_arch = "wasm32"))]
 pub fn call_get_successors(
-    _id: Principal,
-    _request: GetSuccessorsRequest,
+    id: Principal,
+    request: GetSuccessorsRequest,
 ) -> impl Future<Output = CallResult<(GetSuccessorsResponse,)>> {
     use crate::types::GetSuccessorsCompleteResponse;
 
+    GET_SUCCESSORS_REQUESTS.with(|requests| requests.borrow_mut().push((id, request)));
+
     let reply = GET_SUCCESSORS_RESPONSES.with(|responses| {
         // Get the response at the current index.
         GET_SUCCESSORS_RESPONSES_INDEX.with(|i| {
@@ -124,6 +132,21 @@ pub fn set_successors_response(response: GetSuccessorsReply) {
 pub fn set_successors_responses(responses: Vec<GetSuccessorsReply>) {
     GET_SUCCESSORS_RESPONSES.with(|e| e.replace(responses));
     GET_SUCCESSORS_RESPONSES_INDEX.with(|e| e.replace(0));
+    GET_SUCCESSORS_REQUESTS.with(|e| e.replace(vec![]));
+}
+
+/// Returns the principals `call_get_successors` was invoked with since the (mock)
+/// responses were last set, in order.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn get_successors_callees() -> Vec<Principal> {
+    GET_SUCCESSORS_REQUESTS.with(|e| e.borrow().iter().map(|(id, _)| *id).collect())
+}
+
+/// Returns the requests `call_get_successors` was invoked with since the (mock)
+/// responses were last set, in order.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn get_successors_requests() -> Vec<GetSuccessorsRequest> {
+    GET_SUCCESSORS_REQUESTS.with(|e| e.borrow().iter().map(|(_, r)| r.clone()).collect())
 }
 
 /// In production this is equivalent to `performance_counter`.
@@ -201,6 +224,87 @@ pub fn get_cycles_balance() -> u64 {
     CYCLES_BALANCE.with(|c| *c.borrow())
 }
 
+/// Returns the principal of the caller of the current message.
+#[cfg(target_arch = "wasm32")]
+pub fn caller() -> Principal {
+    ic_cdk::caller()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn caller() -> Principal {
+    CALLER.with(|c| *c.borrow())
+}
+
+/// Sets the (mock) principal returned by `caller`.
+#[cfg(test)]
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_caller(caller: Principal) {
+    CALLER.with(|c| *c.borrow_mut() = caller)
+}
+
+/// Runs `func` once after the given delay.
+#[cfg(target_arch = "wasm32")]
+pub fn set_timer(delay: Duration, func: impl FnOnce() + 'static) -> TimerId {
+    ic_cdk_timers::set_timer(delay, func)
+}
+
+/// Timers aren't run in non-wasm environments. Tests run the heartbeat explicitly instead.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_timer(_delay: Duration, _func: impl FnOnce() + 'static) -> TimerId {
+    TimerId::default()
+}
+
+/// Runs `func` periodically, with the given interval between runs.
+#[cfg(target_arch = "wasm32")]
+pub fn set_timer_interval(interval: Duration, func: impl FnMut() + 'static) -> TimerId {
+    ic_cdk_timers::set_timer_interval(interval, func)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn set_timer_interval(_interval: Duration, _func: impl FnMut() + 'static) -> TimerId {
+    TimerId::default()
+}
+
+/// Cancels a timer that was set with `set_timer` or `set_timer_interval`.
+#[cfg(target_arch = "wasm32")]
+pub fn clear_timer(id: TimerId) {
+    ic_cdk_timers::clear_timer(id)
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn clear_timer(_id: TimerId) {}
+
+/// Runs the given future in the background, up to its first await.
+#[cfg(target_arch = "wasm32")]
+pub fn spawn(future: impl Future<Output = ()> + 'static) {
+    ic_cdk::spawn(future)
+}
+
+/// The (mock) calls made in non-wasm environments complete immediately, so the future
+/// runs to completion right away.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn spawn(future: impl Future<Output = ()> + 'static) {
+    use std::task::{Context, Poll, RawWaker, RawWakerVTable, Waker};
+
+    // A waker that does nothing, as the future is never woken up.
+    fn noop_raw_waker() -> RawWaker {
+        fn clone(_: *const ()) -> RawWaker {
+            noop_raw_waker()
+        }
+        fn noop(_: *const ()) {}
+        static VTABLE: RawWakerVTable = RawWakerVTable::new(clone, noop, noop, noop);
+        RawWaker::new(std::ptr::null(), &VTABLE)
+    }
+
+    let waker = unsafe { Waker::from_raw(noop_raw_waker()) };
+    let mut future = Box::pin(future);
+    assert_eq!(
+        future.as_mut().poll(&mut Context::from_waker(&waker)),
+        Poll::Ready(()),
+        "a spawned future must complete immediately in non-wasm environments"
+    );
+}
+
 /// Returns the current time in seconds.
 #[cfg(target_arch = "wasm32")]
 pub fn time() -> u64 {
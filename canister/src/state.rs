@@ -1,22 +1,33 @@
 use crate::{
     address_utxoset::AddressUtxoSet,
     block_header_store::BlockHeaderStore,
+    blocktree::BlockMetadata,
+    fee_policy::FreeCalls,
+    logs,
     metrics::Metrics,
-    runtime::{inc_performance_counter, performance_counter, print, time},
+    rate_limiter::RateLimiter,
+    runtime::{inc_performance_counter, performance_counter, time},
     types::{
         into_bitcoin_network, Address, BlockHeaderBlob, GetSuccessorsCompleteResponse,
         GetSuccessorsPartialResponse, Slicing,
     },
     unstable_blocks::{self, UnstableBlocks},
+    utxo_set::UtxoSetAudit,
     validation::ValidationContext,
     UtxoSet,
 };
 use bitcoin::{consensus::Decodable, BlockHeader};
 use candid::Principal;
-use ic_btc_interface::{Fees, Flag, Height, MillisatoshiPerByte, Network};
+use ic_btc_interface::{
+    ApiAccessMode, Config, FeePolicy, Fees, Flag, Height, LogComponent, LogLevel,
+    MillisatoshiPerByte, Network, RateLimit, StateSnapshot,
+};
 use ic_btc_types::{Block, BlockHash, OutPoint};
-use ic_btc_validation::{validate_header, ValidateHeaderError as InsertBlockError};
+use ic_btc_validation::{
+    validate_block, validate_header, HeaderStore, ValidateBlockError, ValidateHeaderError,
+};
 use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
 
 /// A structure used to maintain the entire state.
 // NOTE: `PartialEq` is only available in tests as it would be impractically
@@ -37,6 +48,11 @@ pub struct State {
     /// Defaults to the management canister in production.
     pub blocks_source: Principal,
 
+    /// The canisters from which blocks are retrieved if `blocks_source` fails,
+    /// in the order in which they're tried.
+    #[serde(default)]
+    pub fallback_blocks_sources: Vec<Principal>,
+
     /// Cache for the current fee percentiles.
     pub fee_percentiles_cache: Option<FeePercentilesCache>,
 
@@ -46,12 +62,36 @@ pub struct State {
     /// The fees to charge for each endpoint.
     pub fees: Fees,
 
+    /// The policy that determines how much of the fees each caller is charged.
+    #[serde(default)]
+    pub fee_policy: FeePolicy,
+
+    /// The free calls made by each caller in the current period of the fee policy.
+    #[serde(default)]
+    pub free_calls: FreeCalls,
+
+    /// The limit on the rate at which each caller can call the Bitcoin API, if any.
+    #[serde(default)]
+    pub rate_limit: Option<RateLimit>,
+
+    /// Enforces `rate_limit`.
+    #[serde(skip)]
+    pub rate_limiter: RateLimiter,
+
     /// Metrics for the various endpoints.
     pub metrics: Metrics,
 
     /// Flag to control access to the APIs provided by the canister.
     pub api_access: Flag,
 
+    /// Who can access the APIs provided by the canister while `api_access` is enabled.
+    #[serde(default)]
+    pub api_access_mode: ApiAccessMode,
+
+    /// The principals that can access the APIs in the restricted access mode.
+    #[serde(default)]
+    pub api_access_allow_list: Vec<Principal>,
+
     /// Flag to determine if the API should be automatically disabled
     /// if the canister isn't fully synced.
     pub disable_api_if_not_fully_synced: Flag,
@@ -60,6 +100,25 @@ pub struct State {
     /// The watchdog canister has the authority to disable the Bitcoin canister's API
     /// if it suspects that there is a problem.
     pub watchdog_canister: Option<Principal>,
+
+    /// The audit of the consistency of the UTXO set's indexes.
+    #[serde(default)]
+    pub utxo_set_audit: UtxoSetAudit,
+
+    /// Checkpoints set in the config, which are enforced in addition to the
+    /// hard-coded ones of the network.
+    #[serde(default)]
+    pub checkpoints: Vec<(Height, BlockHash)>,
+
+    /// Whether or not log entries are also printed to the replica's logs.
+    /// Use `set_print_logs` to change it, which also applies it to the logs.
+    #[serde(default)]
+    pub print_logs: Flag,
+
+    /// The snapshot of stable memory that is being exported, if any.
+    /// Snapshots don't survive upgrades, as upgrades overwrite the exported state.
+    #[serde(skip)]
+    pub state_snapshot: Option<StateSnapshot>,
 }
 
 impl State {
@@ -78,13 +137,24 @@ impl State {
             unstable_blocks,
             syncing_state: SyncingState::default(),
             blocks_source: Principal::management_canister(),
+            fallback_blocks_sources: vec![],
             fee_percentiles_cache: None,
             stable_block_headers: BlockHeaderStore::init(),
             fees: Fees::default(),
+            fee_policy: FeePolicy::default(),
+            free_calls: FreeCalls::default(),
+            rate_limit: None,
+            rate_limiter: RateLimiter::default(),
             metrics: Metrics::default(),
             api_access: Flag::Enabled,
+            api_access_mode: ApiAccessMode::Unrestricted,
+            api_access_allow_list: vec![],
             disable_api_if_not_fully_synced: Flag::Enabled,
             watchdog_canister: None,
+            utxo_set_audit: UtxoSetAudit::default(),
+            checkpoints: vec![],
+            print_logs: Flag::Enabled,
+            state_snapshot: None,
         }
     }
 
@@ -97,25 +167,82 @@ impl State {
         self.utxos.next_height()
     }
 
+    /// Sets whether or not log entries are also printed to the replica's logs.
+    pub fn set_print_logs(&mut self, print_logs: Flag) {
+        self.print_logs = print_logs;
+        logs::set_print_logs(print_logs == Flag::Enabled);
+    }
+
+    /// Returns true if a snapshot of stable memory is being exported and hasn't expired.
+    pub fn is_state_snapshot_in_progress(&self) -> bool {
+        matches!(&self.state_snapshot, Some(snapshot) if time() < snapshot.expires_at)
+    }
+
     /// Returns the UTXO set of a given bitcoin address.
     pub fn get_utxos(&self, address: Address) -> AddressUtxoSet<'_> {
         AddressUtxoSet::new(address, &self.utxos, &self.unstable_blocks)
     }
+
+    /// Returns the canisters from which blocks are retrieved, in the order in which
+    /// they're tried.
+    pub fn blocks_sources(&self) -> Vec<Principal> {
+        std::iter::once(self.blocks_source)
+            .chain(self.fallback_blocks_sources.iter().cloned())
+            .collect()
+    }
+
+    /// Returns the canister from which blocks are currently retrieved.
+    pub fn current_blocks_source(&self) -> Principal {
+        let blocks_sources = self.blocks_sources();
+        blocks_sources[self.syncing_state.blocks_source_index % blocks_sources.len()]
+    }
+
+    /// Sets the canisters from which blocks are retrieved, and starts retrieving
+    /// blocks from the first one.
+    pub fn set_blocks_sources(
+        &mut self,
+        blocks_source: Principal,
+        fallback_blocks_sources: Vec<Principal>,
+    ) {
+        self.blocks_source = blocks_source;
+        self.fallback_blocks_sources = fallback_blocks_sources;
+        self.syncing_state.blocks_source_index = 0;
+        self.syncing_state.num_consecutive_blocks_source_failures = 0;
+    }
 }
 
 /// Inserts a block into the state.
-/// Returns an error if the block doesn't extend any known block in the state.
+/// Returns an error if the block doesn't extend any known block in the state, or
+/// if its header or its transactions are invalid.
 pub fn insert_block(state: &mut State, block: Block) -> Result<(), InsertBlockError> {
+    if state.unstable_blocks.is_pruned(&block.block_hash()) {
+        return Err(InsertBlockError::PrunedBlock);
+    }
+
     let start = performance_counter();
-    validate_header(
-        &into_bitcoin_network(state.network()),
-        &ValidationContext::new(state, block.header())
-            .map_err(|_| InsertBlockError::PrevHeaderNotFound)?,
-        block.header(),
-        time(),
+    let network = into_bitcoin_network(state.network());
+    let context = ValidationContext::new(state, block.header(), main_chain_height(state))
+        .map_err(|_| ValidateHeaderError::PrevHeaderNotFound)?;
+    if let Err(err) = validate_header(&network, &context, block.header(), time()) {
+        if is_checkpoint_rejection(&err) {
+            state.syncing_state.num_checkpoint_rejections += 1;
+        }
+        return Err(err.into());
+    }
+
+    // The transactions aren't covered by the header's proof of work, so they're
+    // verified against the commitments in the header and the coinbase, and their
+    // inputs are resolved to check the value claimed by the coinbase.
+    let inputs = context.resolve_inputs(&block);
+    validate_block(
+        &network,
+        &inputs,
+        block.as_bitcoin_block(),
+        context.height() + 1,
     )?;
+    let inputs = inputs.into_tx_outs();
 
-    unstable_blocks::push(&mut state.unstable_blocks, &state.utxos, block)
+    unstable_blocks::push_with_inputs(&mut state.unstable_blocks, &state.utxos, block, &inputs)
         .expect("Inserting a block with a validated header must succeed.");
 
     let instructions_count = performance_counter() - start;
@@ -139,6 +266,11 @@ pub fn ingest_stable_blocks_into_utxoset(state: &mut State) -> bool {
         assert_eq!(popped_block.unwrap().block_hash(), ingested_block_hash);
     }
 
+    if state.syncing_state.rewind_target.is_some() {
+        // Stable blocks are being reverted.
+        return false;
+    }
+
     let prev_state = (
         state.utxos.next_height(),
         &state.utxos.ingesting_block.clone(),
@@ -148,7 +280,7 @@ pub fn ingest_stable_blocks_into_utxoset(state: &mut State) -> bool {
     };
 
     // Finish ingesting the stable block that's partially ingested, if that exists.
-    print("Running ingest_block_continue...");
+    logs::debug(LogComponent::Ingestion, "Running ingest_block_continue...");
     match state.utxos.ingest_block_continue() {
         None => {}
         Some(Slicing::Paused(())) => return has_state_changed(state),
@@ -159,19 +291,26 @@ pub fn ingest_stable_blocks_into_utxoset(state: &mut State) -> bool {
     }
 
     // Check if there are any stable blocks and ingest those into the UTXO set.
-    print("Looking for new stable blocks to ingest...");
+    logs::debug(
+        LogComponent::Ingestion,
+        "Looking for new stable blocks to ingest...",
+    );
     while let Some(new_stable_block) = unstable_blocks::peek(&state.unstable_blocks) {
-        print(&format!(
-            "Ingesting new stable block {:?}...",
-            new_stable_block.block_hash()
-        ));
+        logs::log_block(
+            LogLevel::Info,
+            LogComponent::Ingestion,
+            &new_stable_block.block_hash(),
+            "Ingesting new stable block...",
+        );
 
         // Store the block's header.
-        state
-            .stable_block_headers
-            .insert_block(new_stable_block, state.utxos.next_height());
+        state.stable_block_headers.insert_block(
+            &new_stable_block,
+            state.utxos.next_height(),
+            state.unstable_blocks.anchor_chainwork(),
+        );
 
-        match state.utxos.ingest_block(new_stable_block.clone()) {
+        match state.utxos.ingest_block(new_stable_block) {
             Slicing::Paused(()) => return has_state_changed(state),
             Slicing::Done((ingested_block_hash, stats)) => {
                 state.metrics.block_ingestion_stats = stats;
@@ -183,25 +322,137 @@ pub fn ingest_stable_blocks_into_utxoset(state: &mut State) -> bool {
     has_state_changed(state)
 }
 
+/// Starts rewinding the stable blocks such that the block at the given height
+/// becomes the anchor of the unstable blocks again.
+///
+/// This allows the canister to recover from a reorg that's deeper than the stability
+/// threshold by re-syncing the correct branch. The rewind itself is performed by
+/// `rewind_continue`.
+pub fn rewind_to_height(state: &mut State, height: Height) -> Result<(), RewindError> {
+    if state.syncing_state.rewind_target.is_some() {
+        return Err(RewindError::AlreadyRewinding);
+    }
+
+    if state.utxos.ingesting_block.is_some() {
+        return Err(RewindError::IngestionInProgress);
+    }
+
+    let stable_height = state.stable_height();
+    if height >= stable_height {
+        return Err(RewindError::HeightNotStable {
+            height,
+            stable_height,
+        });
+    }
+
+    let min_height = state.utxos.min_revertible_height();
+    if min_height.map_or(true, |min_height| height < min_height) {
+        return Err(RewindError::UndoDataUnavailable { height, min_height });
+    }
+
+    logs::info(
+        LogComponent::Ingestion,
+        &format!(
+            "Rewinding stable blocks from height {} to height {}...",
+            stable_height, height
+        ),
+    );
+    state.syncing_state.rewind_target = Some(height);
+
+    // The audit's progress no longer applies to the UTXO set once it's rewound.
+    state.utxo_set_audit.cancel();
+    Ok(())
+}
+
+/// Continues the audit of the UTXO set's indexes, starting a new one if it's due.
+///
+/// NOTE: This method does a form of time-slicing to stay within the instruction limit, and
+/// multiple calls may be required for the audit to complete.
+pub fn audit_utxo_set_continue(state: &mut State) {
+    let now = time();
+    if state.utxo_set_audit.is_due(now) {
+        state.utxo_set_audit.start(&state.utxos, now);
+    }
+
+    state.utxo_set_audit.audit_continue(&mut state.utxos, now);
+}
+
+/// Continues rewinding the stable blocks, if a rewind is in progress.
+///
+/// NOTE: This method does a form of time-slicing to stay within the instruction limit, and
+/// multiple calls may be required for the rewind to complete.
+///
+/// Returns a bool indicating whether or not a rewind was in progress.
+pub fn rewind_continue(state: &mut State) -> bool {
+    let target = match state.syncing_state.rewind_target {
+        Some(target) => target,
+        None => return false,
+    };
+
+    loop {
+        let block = match state.utxos.revert_block() {
+            Slicing::Paused(()) => return true,
+            Slicing::Done(block) => block,
+        };
+
+        let height = state.utxos.next_height();
+        let chainwork = state.stable_block_headers.get_chainwork_with_height(height);
+        state.stable_block_headers.remove_with_height(height);
+        logs::log_block(
+            LogLevel::Info,
+            LogComponent::Ingestion,
+            &block.block_hash(),
+            &format!("Reverted stable block at height {}", height),
+        );
+
+        if height == target {
+            // The reverted block becomes the anchor. The unstable blocks built on top of
+            // the previous anchor are discarded and are re-synced from the network.
+            unstable_blocks::reset(&mut state.unstable_blocks, &state.utxos, block);
+            if let Some(chainwork) = chainwork {
+                state.unstable_blocks.set_anchor_chainwork(chainwork);
+            }
+            state.syncing_state.drop_responses();
+            state.syncing_state.rewind_target = None;
+            state.fee_percentiles_cache = None;
+
+            logs::info(
+                LogComponent::Ingestion,
+                &format!("Done rewinding stable blocks to height {}.", height),
+            );
+            return true;
+        }
+    }
+}
+
 pub fn insert_next_block_headers(state: &mut State, next_block_headers: &[BlockHeaderBlob]) {
     // The limit at which no further next block headers are processed.
     // Note that the actual limit available on system subnets is 50B. The threshold is set
     // lower to be conservative.
     const MAX_INSTRUCTIONS_THRESHOLD: u64 = 30_000_000_000;
 
+    // Headers don't change the main chain, so its height is only computed once.
+    let main_chain_height = main_chain_height(state);
+
     for block_header_blob in next_block_headers.iter() {
         if inc_performance_counter() > MAX_INSTRUCTIONS_THRESHOLD {
-            print("Reaching instruction threshold while inserting next block headers. Breaking...");
+            logs::warning(
+                LogComponent::Ingestion,
+                "Reaching instruction threshold while inserting next block headers. Breaking...",
+            );
             break;
         }
 
         let block_header = match BlockHeader::consensus_decode(block_header_blob.as_slice()) {
             Ok(header) => header,
             Err(err) => {
-                print(&format!(
-                    "ERROR: Failed decode block header. Err: {:?}, Block header: {:?}",
-                    err, block_header_blob,
-                ));
+                logs::error(
+                    LogComponent::Ingestion,
+                    &format!(
+                        "Failed decode block header. Err: {:?}, Block header: {:?}",
+                        err, block_header_blob,
+                    ),
+                );
                 return;
             }
         };
@@ -211,24 +462,33 @@ pub fn insert_next_block_headers(state: &mut State, next_block_headers: &[BlockH
             continue;
         }
 
-        let validation_result =
-            match ValidationContext::new_with_next_block_headers(state, &block_header)
-                .map_err(|_| InsertBlockError::PrevHeaderNotFound)
-            {
-                Ok(store) => validate_header(
-                    &into_bitcoin_network(state.network()),
-                    &store,
-                    &block_header,
-                    time(),
-                ),
-                Err(err) => Err(err),
-            };
+        let validation_result = match ValidationContext::new_with_next_block_headers(
+            state,
+            &block_header,
+            main_chain_height,
+        )
+        .map_err(|_| ValidateHeaderError::PrevHeaderNotFound)
+        {
+            Ok(store) => validate_header(
+                &into_bitcoin_network(state.network()),
+                &store,
+                &block_header,
+                time(),
+            ),
+            Err(err) => Err(err),
+        };
 
         if let Err(err) = validation_result {
-            print(&format!(
-                "ERROR: Failed to validate block header. Err: {:?}, Block header: {:?}",
-                err, block_header,
-            ));
+            if is_checkpoint_rejection(&err) {
+                state.syncing_state.num_checkpoint_rejections += 1;
+            }
+
+            logs::log_block(
+                LogLevel::Error,
+                LogComponent::Ingestion,
+                &BlockHash::from(block_header.block_hash()),
+                &format!("Failed to validate block header. Err: {:?}", err),
+            );
 
             return;
         }
@@ -237,22 +497,33 @@ pub fn insert_next_block_headers(state: &mut State, next_block_headers: &[BlockH
             .unstable_blocks
             .insert_next_block_header(block_header, state.stable_height())
         {
-            print(&format!(
-                "ERROR: Failed to insert next block header. Err: {:?}, Block header: {:?}",
-                err, block_header,
-            ));
+            logs::log_block(
+                LogLevel::Error,
+                LogComponent::Ingestion,
+                &BlockHash::from(block_header.block_hash()),
+                &format!("Failed to insert next block header. Err: {:?}", err),
+            );
             return;
         }
     }
 }
 
+// Returns true if the header was rejected for conflicting with a checkpoint.
+fn is_checkpoint_rejection(err: &ValidateHeaderError) -> bool {
+    matches!(
+        err,
+        ValidateHeaderError::DoesNotMatchCheckpoint { .. }
+            | ValidateHeaderError::ForkBelowCheckpoint { .. }
+    )
+}
+
 pub fn main_chain_height(state: &State) -> Height {
     unstable_blocks::get_main_chain_length(&state.unstable_blocks) as u32
         + state.utxos.next_height()
         - 1
 }
 
-pub fn get_unstable_blocks(state: &State) -> Vec<&Block> {
+pub fn get_unstable_blocks(state: &State) -> Vec<&BlockMetadata> {
     unstable_blocks::get_blocks(&state.unstable_blocks)
 }
 
@@ -281,6 +552,83 @@ pub const UTXO_VALUE_MAX_SIZE_SMALL: usize = (TX_OUT_MAX_SIZE_SMALL + HEIGHT_SIZ
 /// The max size of a value in the "medium UTXOs" map.
 pub const UTXO_VALUE_MAX_SIZE_MEDIUM: usize = (TX_OUT_MAX_SIZE_MEDIUM + HEIGHT_SIZE) as usize;
 
+/// An error returned when a block cannot be inserted into the state.
+#[derive(Debug, PartialEq)]
+pub enum InsertBlockError {
+    /// The block's header is invalid or doesn't extend any known block.
+    InvalidHeader(ValidateHeaderError),
+
+    /// The block's transactions don't match its header, or break the rules on
+    /// the block's structure.
+    InvalidBlock(ValidateBlockError),
+
+    /// The block was recently pruned along with a stale fork.
+    PrunedBlock,
+}
+
+impl From<ValidateHeaderError> for InsertBlockError {
+    fn from(err: ValidateHeaderError) -> Self {
+        Self::InvalidHeader(err)
+    }
+}
+
+impl From<ValidateBlockError> for InsertBlockError {
+    fn from(err: ValidateBlockError) -> Self {
+        Self::InvalidBlock(err)
+    }
+}
+
+/// An error returned when a rewind of the stable blocks cannot be started.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RewindError {
+    /// A rewind is already in progress.
+    AlreadyRewinding,
+
+    /// A stable block is being ingested. The rewind can be retried once it's ingested.
+    IngestionInProgress,
+
+    /// Only stable blocks can be rewound, i.e. the height must be below the stable height.
+    HeightNotStable {
+        height: Height,
+        stable_height: Height,
+    },
+
+    /// There isn't enough undo data to rewind to the given height.
+    UndoDataUnavailable {
+        height: Height,
+        min_height: Option<Height>,
+    },
+}
+
+impl std::fmt::Display for RewindError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::AlreadyRewinding => write!(f, "A rewind is already in progress"),
+            Self::IngestionInProgress => write!(f, "A stable block is being ingested"),
+            Self::HeightNotStable {
+                height,
+                stable_height,
+            } => write!(
+                f,
+                "Height {} is not below the stable height {}",
+                height, stable_height
+            ),
+            Self::UndoDataUnavailable { height, min_height } => match min_height {
+                Some(min_height) => write!(
+                    f,
+                    "Cannot rewind to height {}: undo data is only available from height {}",
+                    height, min_height
+                ),
+                None => write!(
+                    f,
+                    "Cannot rewind to height {}: no undo data available",
+                    height
+                ),
+            },
+        }
+    }
+}
+
 /// A response awaiting to be processed.
 #[derive(Serialize, Deserialize, PartialEq, Eq, Debug)]
 pub enum ResponseToProcess {
@@ -289,8 +637,33 @@ pub enum ResponseToProcess {
 
     /// A partial response that needs more follow-up requests for it to be complete.
     /// The partial response is stored along with the number of pages of the complete
-    /// response that has been processed.
-    Partial(GetSuccessorsPartialResponse, u8),
+    /// response that has been processed, and the blocks source it was received from.
+    /// The follow-up responses are requested from that same source.
+    Partial(GetSuccessorsPartialResponse, u8, Principal),
+}
+
+impl ResponseToProcess {
+    /// Returns the size, in bytes, of the blocks in the response.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Complete(response) => response.blocks.iter().map(|block| block.len()).sum(),
+            Self::Partial(response, _, _) => response.partial_block.len(),
+        }
+    }
+
+    /// Returns the hashes of the blocks in the response.
+    /// A partial response has none, as its block isn't complete yet.
+    pub fn block_hashes(&self) -> Vec<BlockHash> {
+        match self {
+            Self::Complete(response) => response
+                .blocks
+                .iter()
+                .filter_map(|block| BlockHeader::consensus_decode(block.as_slice()).ok())
+                .map(|header| BlockHash::from(header.block_hash()))
+                .collect(),
+            Self::Partial(..) => vec![],
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, PartialEq, Eq)]
@@ -302,8 +675,17 @@ pub struct SyncingState {
     /// being sent at a time.
     pub is_fetching_blocks: bool,
 
-    /// A response that needs to be processed.
-    pub response_to_process: Option<ResponseToProcess>,
+    /// The responses that need to be processed, in the order in which they were
+    /// received. Only the last one can be a partial response.
+    // NOTE: The responses aren't persisted across upgrades to keep their cost bounded.
+    // The blocks they contain are fetched again after the upgrade.
+    #[serde(skip)]
+    pub responses_to_process: VecDeque<ResponseToProcess>,
+
+    /// Incremented whenever the responses to process are dropped, so that the response
+    /// to a request that was in flight at the time is dropped as well.
+    #[serde(skip)]
+    pub responses_generation: u64,
 
     /// The number of rejects received when calling GetSuccessors.
     pub num_get_successors_rejects: u64,
@@ -313,6 +695,41 @@ pub struct SyncingState {
 
     /// The number of errors occurred when inserting a block.
     pub num_insert_block_errors: u64,
+
+    /// The number of blocks and block headers rejected for conflicting with a checkpoint.
+    #[serde(default)]
+    pub num_checkpoint_rejections: u64,
+
+    /// The height to rewind the stable blocks to, if a rewind is in progress.
+    #[serde(default)]
+    pub rewind_target: Option<Height>,
+
+    /// The delay, in milliseconds, before syncing again when there's nothing to do.
+    /// See `Config::sync_interval_ms`.
+    #[serde(default = "default_sync_interval_ms")]
+    pub sync_interval_ms: u64,
+
+    /// The maximum delay, in milliseconds, before syncing again.
+    #[serde(default = "default_max_sync_interval_ms")]
+    pub max_sync_interval_ms: u64,
+
+    /// The number of consecutive requests for blocks that were rejected or that
+    /// returned nothing, which determines how long to wait before syncing again.
+    #[serde(default)]
+    pub num_consecutive_empty_fetches: u32,
+
+    /// The index, in `State::blocks_sources`, of the canister from which blocks
+    /// are currently retrieved.
+    #[serde(default)]
+    pub blocks_source_index: usize,
+
+    /// The number of consecutive rejects or stalls of the current blocks source.
+    #[serde(default)]
+    pub num_consecutive_blocks_source_failures: u32,
+
+    /// See `Config::blocks_source_failover_threshold`.
+    #[serde(default = "default_blocks_source_failover_threshold")]
+    pub blocks_source_failover_threshold: u32,
 }
 
 impl Default for SyncingState {
@@ -320,14 +737,44 @@ impl Default for SyncingState {
         Self {
             syncing: Flag::Enabled,
             is_fetching_blocks: false,
-            response_to_process: None,
+            responses_to_process: VecDeque::new(),
+            responses_generation: 0,
             num_get_successors_rejects: 0,
             num_block_deserialize_errors: 0,
             num_insert_block_errors: 0,
+            num_checkpoint_rejections: 0,
+            rewind_target: None,
+            sync_interval_ms: default_sync_interval_ms(),
+            max_sync_interval_ms: default_max_sync_interval_ms(),
+            num_consecutive_empty_fetches: 0,
+            blocks_source_index: 0,
+            num_consecutive_blocks_source_failures: 0,
+            blocks_source_failover_threshold: default_blocks_source_failover_threshold(),
         }
     }
 }
 
+impl SyncingState {
+    /// Drops the responses to process, along with the response to the request in
+    /// flight, if any.
+    pub fn drop_responses(&mut self) {
+        self.responses_to_process.clear();
+        self.responses_generation += 1;
+    }
+}
+
+fn default_sync_interval_ms() -> u64 {
+    Config::default().sync_interval_ms
+}
+
+fn default_max_sync_interval_ms() -> u64 {
+    Config::default().max_sync_interval_ms
+}
+
+fn default_blocks_source_failover_threshold() -> u32 {
+    Config::default().blocks_source_failover_threshold
+}
+
 /// Cache for storing last calculated fee percentiles
 ///
 /// Stores last tip block hash and fee percentiles associated with it.
@@ -340,7 +787,8 @@ pub struct FeePercentilesCache {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_utils::build_chain;
+    use crate::test_utils::{build_chain, BlockBuilder};
+    use bitcoin::util::uint::Uint256;
     use proptest::prelude::*;
 
     proptest! {
@@ -370,6 +818,185 @@ mod test {
         }
     }
 
+    #[test]
+    fn rejects_blocks_that_do_not_match_a_checkpoint() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 3, 1);
+        let mut state = State::new(2, network, blocks[0].clone());
+        state.checkpoints = vec![(2, BlockHash::default())];
+
+        insert_block(&mut state, blocks[1].clone()).unwrap();
+        assert_eq!(
+            insert_block(&mut state, blocks[2].clone()),
+            Err(InsertBlockError::InvalidHeader(
+                ValidateHeaderError::DoesNotMatchCheckpoint { height: 2 }
+            ))
+        );
+        assert_eq!(state.syncing_state.num_checkpoint_rejections, 1);
+
+        state.checkpoints = vec![(2, blocks[2].block_hash())];
+        insert_block(&mut state, blocks[2].clone()).unwrap();
+        assert_eq!(main_chain_height(&state), 2);
+    }
+
+    #[test]
+    fn rejects_forks_below_a_reached_checkpoint() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 3, 1);
+        let mut state = State::new(2, network, blocks[0].clone());
+        state.checkpoints = vec![(2, blocks[2].block_hash())];
+
+        // A fork is accepted until the main chain reaches the checkpoint.
+        let fork_block = BlockBuilder::with_prev_header(blocks[0].header()).build();
+        insert_block(&mut state, blocks[1].clone()).unwrap();
+        insert_block(&mut state, fork_block).unwrap();
+
+        insert_block(&mut state, blocks[2].clone()).unwrap();
+        let fork_block = BlockBuilder::with_prev_header(blocks[0].header()).build();
+        assert_eq!(
+            insert_block(&mut state, fork_block),
+            Err(InsertBlockError::InvalidHeader(
+                ValidateHeaderError::ForkBelowCheckpoint {
+                    height: 1,
+                    checkpoint_height: 2
+                }
+            ))
+        );
+        assert_eq!(state.syncing_state.num_checkpoint_rejections, 1);
+    }
+
+    #[test]
+    fn stores_the_chainwork_of_stable_blocks() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 5, 1);
+        let mut state = State::new(1, network, blocks[0].clone());
+
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+            ingest_stable_blocks_into_utxoset(&mut state);
+        }
+        assert!(state.stable_height() > 0);
+
+        // The chainwork is counted from the genesis block.
+        let mut chainwork = Uint256::default();
+        for height in 0..state.stable_height() {
+            chainwork = chainwork + blocks[height as usize].header().work();
+            assert_eq!(
+                state.stable_block_headers.get_chainwork_with_height(height),
+                Some(chainwork)
+            );
+        }
+
+        let anchor = &blocks[state.stable_height() as usize];
+        assert_eq!(
+            state.unstable_blocks.anchor_chainwork(),
+            chainwork + anchor.header().work()
+        );
+    }
+
+    #[test]
+    fn rewinds_stable_blocks() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 10, 3);
+        let mut state = State::new(1, network, blocks[0].clone());
+
+        let snapshot = |state: &State| {
+            (
+                state.stable_height(),
+                state.utxos.utxos_len(),
+                state.utxos.address_utxos_len(),
+                state.utxos.balances_len(),
+            )
+        };
+
+        for block in blocks[1..6].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+            ingest_stable_blocks_into_utxoset(&mut state);
+        }
+        let rewind_height = state.stable_height();
+        let state_before = snapshot(&state);
+        let anchor_chainwork = state.unstable_blocks.anchor_chainwork();
+
+        for block in blocks[6..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+            ingest_stable_blocks_into_utxoset(&mut state);
+        }
+        let state_after = snapshot(&state);
+        assert!(state.stable_height() > rewind_height);
+
+        assert_eq!(
+            rewind_to_height(&mut state, state.stable_height()),
+            Err(RewindError::HeightNotStable {
+                height: state.stable_height(),
+                stable_height: state.stable_height()
+            })
+        );
+
+        assert_eq!(rewind_to_height(&mut state, rewind_height), Ok(()));
+        assert_eq!(
+            rewind_to_height(&mut state, rewind_height),
+            Err(RewindError::AlreadyRewinding)
+        );
+
+        // Stable blocks aren't ingested while rewinding.
+        assert!(!ingest_stable_blocks_into_utxoset(&mut state));
+
+        while rewind_continue(&mut state) {}
+
+        // The block at the rewind height is the anchor again.
+        assert_eq!(snapshot(&state), state_before);
+        assert_eq!(
+            get_unstable_blocks(&state),
+            vec![&blocks[rewind_height as usize]]
+        );
+        assert_eq!(state.unstable_blocks.anchor_chainwork(), anchor_chainwork);
+        assert!(state
+            .stable_block_headers
+            .get_with_height(rewind_height)
+            .is_none());
+        assert!(state
+            .stable_block_headers
+            .get_with_height(rewind_height - 1)
+            .is_some());
+
+        // The canister can sync again from the anchor.
+        for block in blocks[rewind_height as usize + 1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+            ingest_stable_blocks_into_utxoset(&mut state);
+        }
+        assert_eq!(snapshot(&state), state_after);
+    }
+
+    #[test]
+    fn cannot_rewind_without_undo_data() {
+        let network = Network::Regtest;
+        let blocks = build_chain(network, 3, 1);
+        let mut state = State::new(1, network, blocks[0].clone());
+
+        assert_eq!(
+            rewind_to_height(&mut state, 0),
+            Err(RewindError::HeightNotStable {
+                height: 0,
+                stable_height: 0
+            })
+        );
+
+        for block in blocks[1..].iter() {
+            insert_block(&mut state, block.clone()).unwrap();
+            ingest_stable_blocks_into_utxoset(&mut state);
+        }
+
+        // Discard the undo data of the genesis block.
+        state.utxos.remove_undo_record(0);
+        assert_eq!(
+            rewind_to_height(&mut state, 0),
+            Err(RewindError::UndoDataUnavailable {
+                height: 0,
+                min_height: Some(1)
+            })
+        );
+    }
+
     #[test]
     fn block_ingestion_stats_are_updated() {
         let stability_threshold = 0;
@@ -95,22 +95,29 @@ fn build_chain_with_genesis_block(
     let mut value = 1;
 
     // Since we start with a genesis block, we need `num_blocks - 1` additional blocks.
-    for _ in 0..num_blocks - 1 {
-        let mut block_builder = BlockBuilder::with_prev_header(prev_block.header());
-        let mut transactions = vec![];
+    for height in 1..num_blocks {
+        // A block has a single coinbase, so the coinbase has an output for every
+        // transaction in the block, and the other transactions spend these outputs.
+        let mut coinbase = TransactionBuilder::coinbase(height);
+        let mut values = vec![];
         for _ in 0..num_transactions_per_block {
-            transactions.push(
-                TransactionBuilder::coinbase()
-                    .with_output(&address, value)
-                    .build(),
-            );
-            // Vary the value of the transaction to ensure that
+            coinbase = coinbase.with_output(&address, value);
+            values.push(value);
+            // Vary the value of the outputs to ensure that
             // we get unique outpoints in the blockchain.
             value += 1;
         }
-
-        for transaction in transactions.iter() {
-            block_builder = block_builder.with_transaction(transaction.clone());
+        let coinbase = coinbase.build();
+
+        let mut block_builder =
+            BlockBuilder::with_prev_header(prev_block.header()).with_transaction(coinbase.clone());
+        for vout in 1..num_transactions_per_block {
+            block_builder = block_builder.with_transaction(
+                TransactionBuilder::new()
+                    .with_input(OutPoint::new(coinbase.txid(), vout))
+                    .with_output(&address, values[vout as usize])
+                    .build(),
+            );
         }
 
         let block = block_builder.build();
@@ -205,9 +212,9 @@ impl TransactionBuilder {
         }
     }
 
-    pub fn coinbase() -> Self {
+    pub fn coinbase(height: u32) -> Self {
         Self {
-            builder: ExternalTransactionBuilder::coinbase(),
+            builder: ExternalTransactionBuilder::coinbase(height),
         }
     }
 
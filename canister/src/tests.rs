@@ -1,15 +1,15 @@
 use crate::{
     api::{get_balance, get_utxos},
-    genesis_block, heartbeat,
+    check_synced, genesis_block, heartbeat,
     runtime::{self, GetSuccessorsReply},
     state::main_chain_height,
     test_utils::{BlockBuilder, BlockChainBuilder, TransactionBuilder},
     types::{
         BlockBlob, BlockHeaderBlob, GetBalanceRequest, GetSuccessorsCompleteResponse,
-        GetSuccessorsResponse, GetUtxosRequest,
+        GetSuccessorsResponse, GetUtxosRequest, RejectionReason,
     },
     utxo_set::{IngestingBlock, DUPLICATE_TX_IDS},
-    verify_synced, with_state, SYNCED_THRESHOLD,
+    with_state, SYNCED_THRESHOLD,
 };
 use crate::{init, test_utils::random_p2pkh_address, Config};
 use bitcoin::consensus::{Decodable, Encodable};
@@ -19,9 +19,9 @@ use ic_btc_interface::{GetUtxosResponse, Network, Txid, UtxosFilter};
 use ic_btc_interface::{OutPoint, Utxo};
 use ic_btc_types::{Block, BlockHash};
 use ic_cdk::api::call::RejectionCode;
+use std::fs::File;
 use std::str::FromStr;
 use std::{collections::HashMap, io::BufReader, path::PathBuf};
-use std::{fs::File, panic::catch_unwind};
 mod confirmation_counts;
 
 async fn process_chain(network: Network, blocks_file: &str, num_blocks: u32) {
@@ -424,12 +424,17 @@ async fn time_slices_large_block_with_multiple_transactions() {
     let address_1 = random_p2pkh_address(network);
     let address_2 = random_p2pkh_address(network);
 
-    let tx_1 = TransactionBuilder::coinbase()
-        .with_output(&address_1, 1000)
-        .with_output(&address_1, 1000)
-        .build();
+    // A coinbase giving address 1 four outputs, two of which are then transferred
+    // to address 2 in the same block.
+    let mut tx_1 = TransactionBuilder::coinbase(1);
+    for _ in 0..4 {
+        tx_1 = tx_1.with_output(&address_1, 1000);
+    }
+    let tx_1 = tx_1.build();
 
     let tx_2 = TransactionBuilder::new()
+        .with_input(ic_btc_types::OutPoint::new(tx_1.txid(), 2))
+        .with_input(ic_btc_types::OutPoint::new(tx_1.txid(), 3))
         .with_output(&address_2, 1000)
         .with_output(&address_2, 1000)
         .build();
@@ -475,7 +480,9 @@ async fn time_slices_large_block_with_multiple_transactions() {
     // Run the heartbeat a few rounds to ingest the blocks.
     let expected_states = vec![
         IngestingBlock::new_with_args(block_1.clone(), 0, 1, 1),
-        IngestingBlock::new_with_args(block_1.clone(), 1, 1, 1),
+        IngestingBlock::new_with_args(block_1.clone(), 0, 1, 3),
+        IngestingBlock::new_with_args(block_1.clone(), 1, 1, 0),
+        IngestingBlock::new_with_args(block_1.clone(), 1, 2, 1),
     ];
 
     for expected_state in expected_states.into_iter() {
@@ -614,7 +621,7 @@ async fn test_syncing_with_next_block_headers() {
         with_state(main_chain_height) + SYNCED_THRESHOLD + 1
     );
 
-    assert!(catch_unwind(verify_synced).is_err());
+    assert_eq!(check_synced(), Err(RejectionReason::NotSynced));
 
     let mut first_next_block_bytes = vec![];
 
@@ -651,7 +658,7 @@ async fn test_syncing_with_next_block_headers() {
         with_state(main_chain_height) + SYNCED_THRESHOLD
     );
 
-    verify_synced();
+    assert_eq!(check_synced(), Ok(()));
 
     let (next_blocks, next_blocks_blobs) =
         get_chain_with_n_block_and_header_blobs(&block_2, (SYNCED_THRESHOLD + 1) as usize);
@@ -685,7 +692,7 @@ async fn test_syncing_with_next_block_headers() {
         with_state(main_chain_height) + SYNCED_THRESHOLD
     );
 
-    verify_synced();
+    assert_eq!(check_synced(), Ok(()));
 
     // We are extending the longest chain of next blocks.
     runtime::set_successors_response(GetSuccessorsReply::Ok(GetSuccessorsResponse::Complete(
@@ -714,5 +721,5 @@ async fn test_syncing_with_next_block_headers() {
         with_state(main_chain_height) + SYNCED_THRESHOLD + 1
     );
 
-    assert!(catch_unwind(verify_synced).is_err());
+    assert_eq!(check_synced(), Err(RejectionReason::NotSynced));
 }
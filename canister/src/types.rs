@@ -1,5 +1,6 @@
 use bitcoin::{
-    Address as BitcoinAddress, Network as BitcoinNetwork, Script, TxOut as BitcoinTxOut,
+    util::uint::Uint256, Address as BitcoinAddress, Network as BitcoinNetwork, Script,
+    TxOut as BitcoinTxOut,
 };
 use candid::CandidType;
 use ic_btc_interface::{
@@ -26,6 +27,7 @@ const EXPECTED_PAGE_LENGTH: usize = 72;
 #[derive(Ord, PartialOrd, Eq, PartialEq, Clone, Debug, Serialize, Deserialize)]
 pub struct TxOut {
     pub value: u64,
+    #[serde(with = "serde_bytes")]
     pub script_pubkey: Vec<u8>,
 }
 
@@ -342,6 +344,25 @@ impl From<Vec<u8>> for BlockHeaderBlob {
     }
 }
 
+/// The cumulative work of a chain of blocks, as stored in stable memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Chainwork(pub Uint256);
+
+impl StableStructuresStorable for Chainwork {
+    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+        Cow::Owned(self.0.to_be_bytes().to_vec())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self(Uint256::from_be_slice(&bytes).expect("chainwork must be 32 bytes"))
+    }
+}
+
+impl BoundedStorable for Chainwork {
+    const MAX_SIZE: u32 = 32;
+    const IS_FIXED_SIZE: bool = true;
+}
+
 type PageNumber = u8;
 
 #[derive(CandidType, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -563,6 +584,53 @@ impl PartialOrd for Utxo {
     }
 }
 
+/// The reasons a request to the Bitcoin API can be rejected before it's processed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RejectionReason {
+    ApiDisabled,
+    ApiRestricted,
+    NetworkMismatch { expected: Network, found: Network },
+    NotSynced,
+    NotEnoughCycles { received: u64, required: u64 },
+    RateLimited,
+}
+
+impl std::fmt::Display for RejectionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::ApiDisabled => write!(f, "Bitcoin API is disabled"),
+            Self::ApiRestricted => write!(f, "Bitcoin API is restricted to allowed callers"),
+            Self::NetworkMismatch { expected, found } => {
+                write!(f, "Network must be {}. Found {}", expected, found)
+            }
+            Self::NotSynced => write!(f, "Canister state is not fully synced."),
+            Self::NotEnoughCycles { received, required } => write!(
+                f,
+                "Received {} cycles. {} cycles are required.",
+                received, required
+            ),
+            Self::RateLimited => write!(f, "Too many requests. Please retry later."),
+        }
+    }
+}
+
+/// The error of a request to the Bitcoin API, which is either rejected before it's
+/// processed or fails while it's processed.
+#[derive(Debug, PartialEq, Eq)]
+pub enum RequestError<E> {
+    Rejected(RejectionReason),
+    Failed(E),
+}
+
+impl<E: std::fmt::Debug> std::fmt::Display for RequestError<E> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Rejected(reason) => write!(f, "{}", reason),
+            Self::Failed(err) => write!(f, "{:?}", err),
+        }
+    }
+}
+
 pub fn into_bitcoin_network(network: Network) -> BitcoinNetwork {
     match network {
         Network::Mainnet => BitcoinNetwork::Bitcoin,
@@ -658,3 +726,11 @@ fn address_handles_script_edge_case() {
         Err(InvalidAddress)
     );
 }
+
+#[test]
+fn chainwork_to_and_from_bytes() {
+    let chainwork = Chainwork(Uint256::from_u64(0x1_0001_0001).unwrap() << 200);
+    let bytes = chainwork.to_bytes();
+    assert_eq!(bytes.len(), Chainwork::MAX_SIZE as usize);
+    assert_eq!(Chainwork::from_bytes(bytes), chainwork);
+}
@@ -0,0 +1,184 @@
+use crate::memory::Memory;
+use bitcoin::{consensus::Decodable, Block as BitcoinBlock};
+use ic_btc_types::{Block, BlockHash};
+use ic_stable_structures::{storable::Blob, BoundedStorable, StableBTreeMap, Storable};
+use std::{
+    borrow::Cow,
+    convert::{TryFrom, TryInto},
+};
+
+// Values in a `StableBTreeMap` are bounded, so blocks are stored in chunks of this size.
+const CHUNK_SIZE: usize = 4 * 1024;
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    block_hash: BlockHash,
+    index: u32,
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        // Big-endian encoding preserves the ordering of the chunks of a block.
+        let mut bytes = self.block_hash.clone().to_vec();
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            block_hash: BlockHash::from(bytes[0..32].to_vec()),
+            index: u32::from_be_bytes(bytes[32..36].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = BlockHash::MAX_SIZE + 4;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Stores the unstable blocks in the standard bitcoin format, indexed by block hash.
+///
+/// Blocks are kept in stable memory rather than on the heap, so that the size of the
+/// heap, and with it the cost of upgrades, doesn't grow with the stability threshold.
+pub struct UnstableBlockStore {
+    chunks: StableBTreeMap<ChunkKey, Blob<CHUNK_SIZE>, Memory>,
+}
+
+// NOTE: `PartialEq` is only available in tests as it would be impractically
+// expensive in production.
+#[cfg(test)]
+impl PartialEq for UnstableBlockStore {
+    fn eq(&self, other: &Self) -> bool {
+        use crate::test_utils::is_stable_btreemap_equal;
+        is_stable_btreemap_equal(&self.chunks, &other.chunks)
+    }
+}
+
+impl UnstableBlockStore {
+    pub fn init() -> Self {
+        Self {
+            chunks: StableBTreeMap::init(crate::memory::get_unstable_blocks_memory()),
+        }
+    }
+
+    /// Stores the given block. Storing a block that's already stored is a no-op.
+    pub fn insert(&mut self, block: &Block) {
+        let block_hash = block.block_hash();
+        if self.contains(&block_hash) {
+            return;
+        }
+
+        let mut bytes = vec![];
+        block
+            .consensus_encode(&mut bytes)
+            .expect("encoding a block must succeed");
+        for (index, chunk) in bytes.chunks(CHUNK_SIZE).enumerate() {
+            self.chunks.insert(
+                ChunkKey {
+                    block_hash: block_hash.clone(),
+                    index: index as u32,
+                },
+                Blob::try_from(chunk).unwrap(),
+            );
+        }
+    }
+
+    /// Returns the block with the given hash, if it's stored.
+    pub fn get(&self, block_hash: &BlockHash) -> Option<Block> {
+        let mut bytes = vec![];
+        for (_, chunk) in self.chunks.range(Self::range(block_hash)) {
+            bytes.extend_from_slice(chunk.as_slice());
+        }
+
+        if bytes.is_empty() {
+            return None;
+        }
+
+        Some(Block::new(
+            BitcoinBlock::consensus_decode(bytes.as_slice())
+                .expect("decoding a stored block must succeed"),
+        ))
+    }
+
+    /// Returns true if the block with the given hash is stored.
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.chunks.range(Self::range(block_hash)).next().is_some()
+    }
+
+    /// Removes the block with the given hash.
+    pub fn remove(&mut self, block_hash: &BlockHash) {
+        let keys: Vec<_> = self
+            .chunks
+            .range(Self::range(block_hash))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.chunks.remove(&key);
+        }
+    }
+
+    fn range(block_hash: &BlockHash) -> std::ops::RangeInclusive<ChunkKey> {
+        ChunkKey {
+            block_hash: block_hash.clone(),
+            index: 0,
+        }..=ChunkKey {
+            block_hash: block_hash.clone(),
+            index: u32::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
+    use ic_btc_interface::Network;
+
+    #[test]
+    fn stores_blocks_in_chunks() {
+        let mut store = UnstableBlockStore::init();
+
+        // A block large enough to span multiple chunks.
+        let mut block_builder = BlockBuilder::genesis();
+        for _ in 0..100 {
+            block_builder = block_builder.with_transaction(
+                TransactionBuilder::coinbase(0)
+                    .with_output(&random_p2pkh_address(Network::Regtest), 1_000)
+                    .build(),
+            );
+        }
+        let block = block_builder.build();
+        let other_block = BlockBuilder::with_prev_header(block.header()).build();
+
+        store.insert(&block);
+        store.insert(&other_block);
+        assert!(store.chunks.len() > 2);
+        assert_eq!(store.get(&block.block_hash()), Some(block.clone()));
+        assert_eq!(
+            store.get(&other_block.block_hash()),
+            Some(other_block.clone())
+        );
+
+        store.remove(&block.block_hash());
+        assert_eq!(store.get(&block.block_hash()), None);
+        assert!(!store.contains(&block.block_hash()));
+        assert!(store.contains(&other_block.block_hash()));
+
+        store.remove(&other_block.block_hash());
+        assert!(store.chunks.is_empty());
+    }
+
+    #[test]
+    fn inserting_a_stored_block_is_a_no_op() {
+        let mut store = UnstableBlockStore::init();
+        let block = BlockBuilder::genesis().build();
+
+        store.insert(&block);
+        let num_chunks = store.chunks.len();
+        store.insert(&block);
+
+        assert_eq!(store.chunks.len(), num_chunks);
+        assert_eq!(store.get(&block.block_hash()), Some(block));
+    }
+}
@@ -1,29 +1,44 @@
 mod outpoints_cache;
 use crate::{
-    blocktree::{BlockChain, BlockDoesNotExtendTree, BlockTree},
-    runtime::print,
+    blocktree::{
+        BlockChain, BlockDoesNotExtendTree, BlockMetadata, BlockTree, FlattenedBlock,
+        LegacyBlockTree,
+    },
+    logs,
     types::{Address, TxOut},
+    unstable_block_store::UnstableBlockStore,
     UtxoSet,
 };
-use bitcoin::BlockHeader;
-use ic_btc_interface::{Height, Network};
-use ic_btc_types::{Block, BlockHash, OutPoint};
-use outpoints_cache::OutPointsCache;
+use bitcoin::{util::uint::Uint256, BlockHeader};
+use ic_btc_interface::{
+    Height, LogComponent, Network, NextBlockHeader, PruningPolicy, UnstableBlock, UnstableBlockTree,
+};
+use ic_btc_types::{Block, BlockHash, OutPoint, Txid};
+pub(crate) use outpoints_cache::{LegacyOutPointsCache, OutPointsCache};
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
 
 mod next_block_headers;
 use self::next_block_headers::NextBlockHeaders;
 
+mod pruned_block_hashes;
+use self::pruned_block_hashes::PrunedBlockHashes;
+
 // The maximum number of blocks that a chain on testnet can exceed other chains before its
 // anchor block is marked as stable.
 const TESTNET_CHAIN_MAX_DEPTH: u128 = 1000;
 
 /// A data structure for maintaining all unstable blocks.
 ///
-/// A block `b` is considered stable if:
-///   depth(block) ≥ stability_threshold
-///   ∀ b', height(b') = height(b): depth(b) - depth(b’) ≥ stability_threshold
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+/// A block `b` is considered stable if, with `work(b)` the chainwork of the best chain
+/// that `b` is part of, counted from `b`:
+///   work(b) ≥ stability_threshold * work(parent(b))
+///   ∀ b', height(b') = height(b): work(b) - work(b’) ≥ stability_threshold * work(parent(b))
+///
+/// Only the metadata of the blocks is kept on the heap. The blocks themselves, along
+/// with the cache of their outpoints, are kept in stable memory and are loaded when needed.
+#[derive(Serialize, Deserialize)]
+#[cfg_attr(test, derive(PartialEq))]
 pub struct UnstableBlocks {
     stability_threshold: u32,
     tree: BlockTree,
@@ -31,6 +46,56 @@ pub struct UnstableBlocks {
     network: Network,
     // The headers of the blocks that are expected to be received.
     next_block_headers: NextBlockHeaders,
+    // The tip of the main chain. See `get_main_chain` for what defines a main chain.
+    // Trees that were serialized by older versions of the canister don't have one, in
+    // which case the first tip with the most chainwork is used.
+    #[serde(default)]
+    main_chain_tip: Option<BlockHash>,
+    // The blocks of the tree, in stable memory.
+    // NOTE: Stable structures don't need to be serialized.
+    #[serde(skip, default = "UnstableBlockStore::init")]
+    block_store: UnstableBlockStore,
+    // The number of blocks that were pruned along with stale forks.
+    #[serde(default)]
+    num_pruned_blocks: u64,
+    // The hashes of the blocks that were most recently pruned, which are rejected if
+    // they're received again.
+    #[serde(default)]
+    pruned_block_hashes: PrunedBlockHashes,
+    // The policy that bounds the size of the tree.
+    #[serde(default)]
+    pruning_policy: PruningPolicy,
+}
+
+/// `UnstableBlocks` as they were serialized by older versions of the canister, which
+/// kept the outpoints cache on the heap and encoded the blocks of the tree as `B`.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "B: FlattenedBlock"))]
+pub(crate) struct LegacyUnstableBlocks<B> {
+    stability_threshold: u32,
+    tree: LegacyBlockTree<B>,
+    outpoints_cache: LegacyOutPointsCache,
+    network: Network,
+    next_block_headers: NextBlockHeaders,
+    #[serde(default)]
+    main_chain_tip: Option<BlockHash>,
+}
+
+impl<B> From<LegacyUnstableBlocks<B>> for UnstableBlocks {
+    fn from(legacy: LegacyUnstableBlocks<B>) -> Self {
+        Self {
+            stability_threshold: legacy.stability_threshold,
+            tree: BlockTree::from(legacy.tree),
+            outpoints_cache: OutPointsCache::from(legacy.outpoints_cache),
+            network: legacy.network,
+            next_block_headers: legacy.next_block_headers,
+            main_chain_tip: legacy.main_chain_tip,
+            block_store: UnstableBlockStore::init(),
+            num_pruned_blocks: 0,
+            pruned_block_hashes: PrunedBlockHashes::default(),
+            pruning_policy: PruningPolicy::default(),
+        }
+    }
 }
 
 impl UnstableBlocks {
@@ -41,28 +106,96 @@ impl UnstableBlocks {
             .insert(utxos, &anchor, utxos.next_height())
             .expect("anchor block must be valid.");
 
+        let mut block_store = UnstableBlockStore::init();
+        block_store.insert(&anchor);
+
         Self {
             stability_threshold,
-            tree: BlockTree::new(anchor.clone()),
+            tree: BlockTree::new(BlockMetadata::from(&anchor)),
             outpoints_cache,
             network,
             next_block_headers: NextBlockHeaders::default(),
+            main_chain_tip: Some(anchor.block_hash()),
+            block_store,
+            num_pruned_blocks: 0,
+            pruned_block_hashes: PrunedBlockHashes::default(),
+            pruning_policy: PruningPolicy::default(),
+        }
+    }
+
+    /// Returns the unstable block with the given hash, if it exists.
+    ///
+    /// NOTE: Blocks are loaded from stable memory, which is costly for large blocks.
+    pub fn get_block(&self, block_hash: &BlockHash) -> Option<Block> {
+        #[allow(unused_mut)]
+        let mut block = self.block_store.get(block_hash)?;
+        #[cfg(test)]
+        {
+            block.mock_difficulty = self
+                .tree
+                .find(block_hash)
+                .and_then(|tree| tree.root.mock_difficulty);
         }
+        Some(block)
+    }
+
+    /// Returns true if the unstable block with the given hash is stored.
+    pub fn contains_block(&self, block_hash: &BlockHash) -> bool {
+        self.block_store.contains(block_hash)
+    }
+
+    /// Returns the cumulative work of the chain up to and including the anchor.
+    pub fn anchor_chainwork(&self) -> Uint256 {
+        self.tree.chainwork
+    }
+
+    /// Sets the cumulative work of the chain up to and including the anchor. By
+    /// default, the chainwork of the unstable blocks is counted from the anchor.
+    ///
+    /// Panics if blocks have already been pushed on top of the anchor.
+    pub fn set_anchor_chainwork(&mut self, chainwork: Uint256) {
+        assert!(
+            self.tree.children.is_empty(),
+            "the anchor chainwork must be set before pushing blocks"
+        );
+        self.tree.chainwork = chainwork;
     }
 
     /// Retrieves the `TxOut` associated with the given `outpoint`, along with its height.
-    pub fn get_tx_out(&self, outpoint: &OutPoint) -> Option<(&TxOut, Height)> {
+    pub fn get_tx_out(&self, outpoint: &OutPoint) -> Option<(TxOut, Height)> {
         self.outpoints_cache.get_tx_out(outpoint)
     }
 
+    /// Retrieves the hashes of the unstable blocks that contain the transaction with the
+    /// given txid.
+    pub fn get_tx_blocks(&self, txid: &Txid) -> Vec<BlockHash> {
+        self.outpoints_cache.get_tx_blocks(txid)
+    }
+
+    /// Records the blocks that contain each transaction of the unstable blocks, which
+    /// states of older versions of the canister don't have.
+    pub(crate) fn insert_tx_blocks(&mut self) {
+        for block_hash in self.tree.block_hashes() {
+            let block = self
+                .block_store
+                .get(&block_hash)
+                .expect("unstable block must be stored");
+            self.outpoints_cache.insert_tx_blocks(&block);
+        }
+    }
+
     /// Retrieves the list of outpoints that were added for the given address in the given block.
-    pub fn get_added_outpoints(&self, block_hash: &BlockHash, address: &Address) -> &[OutPoint] {
+    pub fn get_added_outpoints(&self, block_hash: &BlockHash, address: &Address) -> Vec<OutPoint> {
         self.outpoints_cache
             .get_added_outpoints(block_hash, address)
     }
 
     /// Retrieves the list of outpoints that were removed for the given address in the given block.
-    pub fn get_removed_outpoints(&self, block_hash: &BlockHash, address: &Address) -> &[OutPoint] {
+    pub fn get_removed_outpoints(
+        &self,
+        block_hash: &BlockHash,
+        address: &Address,
+    ) -> Vec<OutPoint> {
         self.outpoints_cache
             .get_removed_outpoints(block_hash, address)
     }
@@ -88,13 +221,60 @@ impl UnstableBlocks {
         self.tree.num_tips()
     }
 
+    /// Returns the number of blocks that were pruned along with stale forks.
+    pub fn num_pruned_blocks(&self) -> u64 {
+        self.num_pruned_blocks
+    }
+
+    /// Returns true if the block with the given hash was recently pruned along with a
+    /// stale fork.
+    pub fn is_pruned(&self, block_hash: &BlockHash) -> bool {
+        self.pruned_block_hashes.contains(block_hash)
+    }
+
+    /// Returns the hashes of the blocks that were recently pruned along with stale forks.
+    pub fn pruned_block_hashes(&self) -> impl Iterator<Item = &BlockHash> {
+        self.pruned_block_hashes.iter()
+    }
+
+    pub fn pruning_policy(&self) -> &PruningPolicy {
+        &self.pruning_policy
+    }
+
+    /// Sets the policy that bounds the size of the tree. It's applied the next time a
+    /// block is pushed.
+    pub fn set_pruning_policy(&mut self, pruning_policy: PruningPolicy) {
+        self.pruning_policy = pruning_policy;
+    }
+
     fn get_network(&self) -> Network {
         self.network
     }
 
+    // Returns the subtree whose root is the tip of the main chain.
+    fn main_chain_tip(&self) -> &BlockTree {
+        self.main_chain_tip
+            .as_ref()
+            .and_then(|tip| self.tree.find(tip))
+            .unwrap_or_else(|| self.tree.max_chainwork_tip())
+    }
+
+    // Removes the blocks of a fork that was removed from the tree from the block store and
+    // the outpoints cache.
+    fn discard_fork(&mut self, fork: &BlockTree) {
+        for block_hash in fork.block_hashes() {
+            let block = self
+                .block_store
+                .get(&block_hash)
+                .expect("the blocks of the tree must be stored");
+            self.outpoints_cache.remove(&block);
+            self.block_store.remove(&block_hash);
+        }
+    }
+
     /// Returns all blocks in the tree with their respective depths
     /// separated by heights.
-    pub fn blocks_with_depths_by_heights(&self) -> Vec<Vec<(&Block, u32)>> {
+    pub fn blocks_with_depths_by_heights(&self) -> Vec<Vec<(&BlockMetadata, u32)>> {
         self.tree.blocks_with_depths_by_heights()
     }
 
@@ -169,9 +349,19 @@ impl UnstableBlocks {
     }
 }
 
-/// Returns a reference to the `anchor` block iff ∃ a child `C` of `anchor` that is stable.
-pub fn peek(blocks: &UnstableBlocks) -> Option<&Block> {
-    get_stable_child(blocks).map(|_| &blocks.tree.root)
+/// Returns the `anchor` block iff ∃ a child `C` of `anchor` that is stable.
+pub fn peek(blocks: &UnstableBlocks) -> Option<Block> {
+    get_stable_child(blocks).map(|_| {
+        blocks
+            .get_block(&blocks.tree.root.block_hash())
+            .expect("the anchor block must be stored")
+    })
+}
+
+/// Returns true iff ∃ a child `C` of `anchor` that is stable, i.e. iff the `anchor`
+/// block can be popped. Unlike `peek`, the `anchor` block isn't loaded.
+pub fn has_stable_child(blocks: &UnstableBlocks) -> bool {
+    get_stable_child(blocks).is_some()
 }
 
 /// Pops the `anchor` block iff ∃ a child `C` of the `anchor` block that
@@ -180,10 +370,22 @@ pub fn peek(blocks: &UnstableBlocks) -> Option<&Block> {
 pub fn pop(blocks: &mut UnstableBlocks, stable_height: Height) -> Option<Block> {
     match get_stable_child(blocks) {
         Some(stable_child_idx) => {
-            let old_anchor = blocks.tree.root.clone();
+            let old_anchor = blocks
+                .get_block(&blocks.tree.root.block_hash())
+                .expect("the anchor block must be stored");
+            blocks.block_store.remove(&old_anchor.block_hash());
+
+            // Replace the unstable block tree with that of the stable child, and discard
+            // the blocks of its siblings.
+            let stable_child = blocks.tree.children.swap_remove(stable_child_idx);
+            let siblings = std::mem::replace(&mut blocks.tree, stable_child).children;
+            for sibling in siblings.iter() {
+                blocks.discard_fork(sibling);
+            }
 
-            // Replace the unstable block tree with that of the stable child.
-            blocks.tree = blocks.tree.children.swap_remove(stable_child_idx);
+            // The tip of the main chain is recomputed if it was in one of the discarded
+            // siblings.
+            blocks.main_chain_tip = Some(blocks.main_chain_tip().root.block_hash());
 
             // Remove the outpoints of the old anchor from the cache.
             blocks.outpoints_cache.remove(&old_anchor);
@@ -202,86 +404,142 @@ pub fn push(
     utxos: &UtxoSet,
     block: Block,
 ) -> Result<(), BlockDoesNotExtendTree> {
+    push_with_inputs(blocks, utxos, block, &BTreeMap::new())
+}
+
+/// Same as `push`, but with some of the transaction outputs spent by the block already
+/// resolved, so that they aren't looked up again when caching them.
+pub fn push_with_inputs(
+    blocks: &mut UnstableBlocks,
+    utxos: &UtxoSet,
+    block: Block,
+    inputs: &BTreeMap<OutPoint, (TxOut, Height)>,
+) -> Result<(), BlockDoesNotExtendTree> {
+    // A block that was pruned along with a stale fork isn't added back to the tree.
+    if blocks.is_pruned(&block.block_hash()) {
+        return Err(BlockDoesNotExtendTree(block.block_hash()));
+    }
+
+    let main_chain_tip = blocks.main_chain_tip();
+    let main_chain_tip_hash = main_chain_tip.root.block_hash();
+    let main_chain_tip_chainwork = main_chain_tip.chainwork;
+
     let (parent_block_tree, depth) = blocks
         .tree
         .find_mut(&block.header().prev_blockhash.into())
         .ok_or_else(|| BlockDoesNotExtendTree(block.block_hash()))?;
 
     let height = utxos.next_height() + depth + 1;
+    let chainwork = parent_block_tree.chainwork + block.work();
 
     blocks
         .outpoints_cache
-        .insert(utxos, &block, height)
+        .insert_with_inputs(utxos, &block, height, inputs)
         .expect("inserting to outpoints cache must succeed.");
 
     let block_hash = block.block_hash();
 
-    parent_block_tree.extend(block)?;
+    // The block is added from the root, so that it's marked as seen after all the
+    // blocks of the tree.
+    blocks.tree.extend(BlockMetadata::from(&block))?;
+    blocks.block_store.insert(&block);
+
+    // The main chain only switches to a tip with strictly more chainwork, so that the
+    // tip that was received first is kept in case of a tie.
+    blocks.main_chain_tip = Some(if chainwork > main_chain_tip_chainwork {
+        block_hash.clone()
+    } else {
+        main_chain_tip_hash
+    });
 
     blocks.next_block_headers.remove(&block_hash);
 
+    prune_forks(blocks);
+
     Ok(())
 }
 
-/// Returns the best guess on what the main blockchain is.
-///
-/// The most likely chain to be "main", we hypothesize, is the longest
-/// chain of blocks with an "uncontested" tip. As in, there exists no other
-/// block at the same height as the tip.
-pub fn get_main_chain(blocks: &UnstableBlocks) -> BlockChain {
-    // Get all the blockchains that extend the anchor.
-    let blockchains: Vec<BlockChain> = blocks.tree.blockchains();
-
-    // Find the length of the longest blockchain.
-    let mut longest_blockchain_len = 0;
-    for blockchain in blockchains.iter() {
-        longest_blockchain_len = longest_blockchain_len.max(blockchain.len());
+// Prunes the forks whose chainwork fell too far behind the main chain, and then the forks
+// with the least chainwork until the tree is within the bounds of the pruning policy.
+fn prune_forks(blocks: &mut UnstableBlocks) {
+    let mut pruned_forks = vec![];
+    let policy = blocks.pruning_policy.clone();
+
+    // The deficit is counted in blocks at the difficulty of either the anchor or the tip
+    // of the main chain, whichever is higher, so that blocks that are mined at the minimum
+    // difficulty on testnet don't make the other forks look stale.
+    let main_chain_tip = blocks.main_chain_tip();
+    let main_chain_chainwork = main_chain_tip.chainwork;
+    let max_work_deficit = std::cmp::max(blocks.tree.root.work(), main_chain_tip.root.work())
+        * Uint256::from_u64(policy.max_fork_work_deficit_in_blocks)
+            .expect("a u64 must fit in a Uint256");
+    if main_chain_chainwork > max_work_deficit {
+        // As the deficit is positive, the main chain is never among the removed forks.
+        pruned_forks.extend(
+            blocks
+                .tree
+                .remove_forks(main_chain_chainwork - max_work_deficit),
+        );
     }
 
-    // Get all the longest blockchains.
-    let longest_blockchains: Vec<Vec<&'_ Block>> = blockchains
-        .into_iter()
-        .filter(|bc| bc.len() == longest_blockchain_len)
-        .map(|bc| bc.into_chain())
-        .collect();
-
-    // A `BlockChain` contains at least one block which means we can safely index at
-    // height 0 of the chain.
-    let mut main_chain = BlockChain::new(longest_blockchains[0][0]);
-    for height_idx in 1..longest_blockchain_len {
-        // If all the blocks on the same height are identical, then this block is part of the
-        // "main" chain.
-        let block = longest_blockchains[0][height_idx];
-        let block_hash = block.block_hash();
-        for chain in longest_blockchains.iter().skip(1) {
-            if chain[height_idx].block_hash() != block_hash {
-                return main_chain;
-            }
+    while blocks.tree.num_blocks() as u64 > policy.max_unstable_blocks
+        || blocks.tree.num_tips() > policy.max_unstable_tips
+    {
+        let main_chain_tip = blocks.main_chain_tip().root.block_hash();
+        // Of the tips with the least chainwork, the one that was seen last is pruned.
+        let weakest_tip = blocks
+            .tree
+            .tips()
+            .into_iter()
+            .filter(|tip| tip.root.block_hash() != main_chain_tip)
+            .min_by_key(|tip| (tip.chainwork, std::cmp::Reverse(tip.first_seen)))
+            .map(|tip| tip.root.block_hash());
+
+        match weakest_tip.and_then(|tip| blocks.tree.remove_branch(&tip)) {
+            Some(fork) => pruned_forks.push(fork),
+            // Only the main chain is left.
+            None => break,
         }
+    }
 
-        main_chain.push(block);
+    for fork in pruned_forks {
+        let num_blocks = fork.num_blocks();
+        logs::info(
+            LogComponent::Ingestion,
+            &format!(
+                "Pruned a stale fork of {} block(s) starting at block {}.",
+                num_blocks,
+                fork.root.block_hash().to_string()
+            ),
+        );
+        blocks.discard_fork(&fork);
+        blocks.num_pruned_blocks += num_blocks as u64;
+        for block_hash in fork.block_hashes() {
+            blocks.pruned_block_hashes.insert(block_hash);
+        }
     }
+}
 
-    main_chain
+/// Returns the best guess on what the main blockchain is.
+///
+/// As in Bitcoin Core, the main chain is the chain with the most cumulative work
+/// (chainwork). If several chains have the most chainwork, the main chain is the
+/// one whose tip was received first.
+pub fn get_main_chain(blocks: &UnstableBlocks) -> BlockChain {
+    let tip = blocks.main_chain_tip().root.block_hash();
+    blocks
+        .tree
+        .get_chain_with_tip(&tip)
+        .expect("the tip of the main chain must be in the tree")
 }
 
 /// Returns the length of the "main chain".
 /// See `get_main_chain` for what defines a main chain.
 pub fn get_main_chain_length(blocks: &UnstableBlocks) -> usize {
-    let blocks_by_height = blocks.blocks_with_depths_by_heights();
-
-    // Traverse the heights in reverse order. The highest height with a single block corresponds to
-    // the tip of the main chain.
-    for height in (0..blocks_by_height.len()).rev() {
-        if blocks_by_height[height].len() == 1 {
-            return height + 1;
-        }
-    }
-
-    unreachable!("There must be at least one height with exactly one block.");
+    get_main_chain(blocks).len()
 }
 
-pub fn get_blocks(blocks: &UnstableBlocks) -> Vec<&Block> {
+pub fn get_blocks(blocks: &UnstableBlocks) -> Vec<&BlockMetadata> {
     blocks
         .tree
         .blockchains()
@@ -290,6 +548,17 @@ pub fn get_blocks(blocks: &UnstableBlocks) -> Vec<&Block> {
         .collect()
 }
 
+/// Discards all the unstable blocks and makes the given block the new anchor.
+pub fn reset(blocks: &mut UnstableBlocks, utxos: &UtxoSet, anchor: Block) {
+    for block_hash in blocks.tree.block_hashes() {
+        blocks.block_store.remove(&block_hash);
+    }
+
+    let pruning_policy = blocks.pruning_policy.clone();
+    *blocks = UnstableBlocks::new(utxos, blocks.stability_threshold, anchor, blocks.network);
+    blocks.pruning_policy = pruning_policy;
+}
+
 /// Returns a blockchain starting from the anchor and ending with the `tip`.
 ///
 /// If the `tip` doesn't exist in the tree, `None` is returned.
@@ -300,26 +569,108 @@ pub fn get_chain_with_tip<'a>(
     blocks.tree.get_chain_with_tip(tip)
 }
 
+/// Returns a snapshot of the unstable block tree, given the height of its anchor.
+pub fn get_tree_snapshot(blocks: &UnstableBlocks, anchor_height: Height) -> UnstableBlockTree {
+    // Adds the blocks of the tree to `out`, every block after its parent, and returns the
+    // depth of the tree. A block's depth is only known once its children are visited, so
+    // it's filled in after them to visit every block once.
+    fn add_blocks(
+        tree: &BlockTree,
+        parent_block_hash: Option<&BlockHash>,
+        height: Height,
+        network: Network,
+        main_chain: &BTreeSet<BlockHash>,
+        out: &mut Vec<UnstableBlock>,
+    ) -> u128 {
+        let block_hash = tree.root.block_hash();
+        let index = out.len();
+        out.push(UnstableBlock {
+            block_hash: block_hash.to_string(),
+            parent_block_hash: parent_block_hash.map(|h| h.to_string()),
+            height,
+            difficulty: tree.root.difficulty(network),
+            depth: 0,
+            chainwork: hex::encode(tree.chainwork.to_be_bytes()),
+            is_main_chain: main_chain.contains(&block_hash),
+        });
+
+        let mut children_depth = 0;
+        for child in tree.children.iter() {
+            children_depth = std::cmp::max(
+                children_depth,
+                add_blocks(
+                    child,
+                    Some(&block_hash),
+                    height + 1,
+                    network,
+                    main_chain,
+                    out,
+                ),
+            );
+        }
+
+        out[index].depth = children_depth + 1;
+        out[index].depth
+    }
+
+    let main_chain: BTreeSet<BlockHash> = get_main_chain(blocks)
+        .into_chain()
+        .iter()
+        .map(|b| b.block_hash())
+        .collect();
+
+    let mut unstable_blocks = vec![];
+    add_blocks(
+        &blocks.tree,
+        None,
+        anchor_height,
+        blocks.network,
+        &main_chain,
+        &mut unstable_blocks,
+    );
+
+    UnstableBlockTree {
+        stability_threshold: blocks.stability_threshold,
+        num_tips: blocks.num_tips(),
+        blocks: unstable_blocks,
+        next_block_headers: blocks
+            .next_block_headers
+            .iter()
+            .map(|(block_hash, height, header)| NextBlockHeader {
+                block_hash: block_hash.to_string(),
+                parent_block_hash: BlockHash::from(header.prev_blockhash).to_string(),
+                height,
+            })
+            .collect(),
+    }
+}
+
 // Returns the index of the `anchor`'s stable child if it exists.
+//
+// The children are compared by the chainwork of their best chains, in the same way as the
+// main chain is selected, and the work that a child needs to be stable is the work of the
+// anchor multiplied by the stability threshold.
 fn get_stable_child(blocks: &UnstableBlocks) -> Option<usize> {
-    // Compute the difficulty based depth of all the children.
     let network = blocks.get_network();
 
-    let mut depths: Vec<_> = blocks
+    // The best tip of every child, ordered as in `BlockTree::max_chainwork_tip`, so that the
+    // last child is the one with the main chain if the tree has more than one block.
+    let mut tips: Vec<_> = blocks
         .tree
         .children
         .iter()
         .enumerate()
-        .map(|(idx, child)| (child.difficulty_based_depth(network), idx))
+        .map(|(idx, child)| (child.max_chainwork_tip(), idx))
         .collect();
+    tips.sort_by_key(|(tip, _child_idx)| (tip.chainwork, std::cmp::Reverse(tip.first_seen)));
 
-    // Sort by depth.
-    depths.sort_by_key(|(depth, _child_idx)| *depth);
+    // The work of the best chain of a child, counted from the child.
+    let work = |tip: &BlockTree| tip.chainwork - blocks.tree.chainwork;
 
-    let normalized_stability_threshold = blocks.normalized_stability_threshold();
+    let stability_threshold = blocks.tree.root.work().mul_u32(blocks.stability_threshold);
 
-    match depths.last() {
-        Some((deepest_depth, child_idx)) => {
+    match tips.last() {
+        Some((deepest_tip, child_idx)) => {
             match network {
                 Network::Testnet | Network::Regtest => {
                     // The difficulty in the Bitcoin testnet/regtest can be reset to the minimum
@@ -328,8 +679,9 @@ fn get_stable_child(blocks: &UnstableBlocks) -> Option<usize> {
                     //
                     // * Assume a `stability_threshold` of 144.
                     // * The anchor at height `h` has difficulty of 4642.
-                    // * The anchor will be marked as stable if the difficulty-based depth of
-                    //   the successor blocks is `stability_threshold * difficulty(anchor)` = 668,448
+                    // * The anchor will be marked as stable if the work of the successor blocks
+                    //   is `stability_threshold * work(anchor)`, i.e. the work of 668,448 blocks
+                    //   at the minimum difficulty.
                     // * The difficulty is reset to the minimum of 1.
                     // * The canister will now need to maintain a chain of length 668,448 just to
                     //   mark the anchor block as stable!
@@ -339,29 +691,30 @@ fn get_stable_child(blocks: &UnstableBlocks) -> Option<usize> {
                     //
                     // The pragmatic solution in this case is to bound the length of the chain. If
                     // one chain starts exceeding other chains by a certain length, we assume that
-                    // the anchor is stable even if the difficulty requirement hasn't been met.
+                    // the anchor is stable even if the work requirement hasn't been met.
                     //
                     // This scenario is only relevant for testnets, so this addition is safe and
                     // has no impact on the behavior of the mainnet canister.
-                    if blocks.tree.children[*child_idx].depth() >= TESTNET_CHAIN_MAX_DEPTH {
+                    let deepest_depth = blocks.tree.children[*child_idx].depth();
+                    if deepest_depth >= TESTNET_CHAIN_MAX_DEPTH {
                         // If there's another competing chain, verify that it's at least
                         // `TESTNET_CHAIN_MAX_DEPTH` blocks behind the longest chain to mark the
                         // current anchor as stable.
-                        let second_deepest_depth = match depths.len().checked_sub(2) {
+                        let second_deepest_depth = match tips.len().checked_sub(2) {
                             None => 0,
                             Some(idx) => {
-                                let (_, second_child_idx) = depths[idx];
+                                let (_, second_child_idx) = tips[idx];
                                 blocks.tree.children[second_child_idx].depth()
                             }
                         };
 
-                        // NOTE: We use a `saturating_sub` here because `depths` is ordered by
-                        // `difficulty_based_depth`, whereas here the chains are compared by their
-                        // `depth`, so it's not guaranteed that `deepest_depth >= second_deepest_depth`.
+                        // NOTE: We use a `saturating_sub` here because `tips` is ordered by
+                        // chainwork, whereas here the chains are compared by their `depth`, so
+                        // it's not guaranteed that `deepest_depth >= second_deepest_depth`.
                         if deepest_depth.saturating_sub(second_deepest_depth)
                             >= TESTNET_CHAIN_MAX_DEPTH
                         {
-                            print(&format!("Detected a chain that's > {TESTNET_CHAIN_MAX_DEPTH} blocks ahead of any other chain. Assuming its root is stable..."));
+                            logs::warning(LogComponent::Ingestion, &format!("Detected a chain that's > {TESTNET_CHAIN_MAX_DEPTH} blocks ahead of any other chain. Assuming its root is stable..."));
                             return Some(*child_idx);
                         }
                     }
@@ -372,20 +725,18 @@ fn get_stable_child(blocks: &UnstableBlocks) -> Option<usize> {
                 }
             }
 
-            // The deepest child tree must have a depth >= normalized_stability_threshold.
-            if *deepest_depth < normalized_stability_threshold {
-                // Need a depth of at least >= normalized_stability_threshold.
+            // The deepest child must have at least the work of the stability threshold.
+            let deepest_work = work(deepest_tip);
+            if deepest_work < stability_threshold {
                 return None;
             }
 
-            // If there is more than one child, the difference in depth
-            // between the deepest child and all the others must be >= normalized_stability_threshold.
-            if depths.len() >= 2 {
-                if let Some((second_deepest_depth, _)) = depths.get(depths.len() - 2) {
-                    if deepest_depth - second_deepest_depth < normalized_stability_threshold {
-                        // Difference must be >= normalized_stability_threshold.
-                        return None;
-                    }
+            // If there is more than one child, the difference in work between the deepest
+            // child and all the others must be at least the work of the stability threshold.
+            if let Some(idx) = tips.len().checked_sub(2) {
+                let (second_deepest_tip, _) = tips[idx];
+                if deepest_work - work(second_deepest_tip) < stability_threshold {
+                    return None;
                 }
             }
 
@@ -401,7 +752,9 @@ fn get_stable_child(blocks: &UnstableBlocks) -> Option<usize> {
 #[cfg(test)]
 mod test {
     use super::*;
-    use crate::test_utils::{BlockBuilder, BlockChainBuilder};
+    use crate::test_utils::{
+        random_p2pkh_address, BlockBuilder, BlockChainBuilder, TransactionBuilder,
+    };
     use ic_btc_interface::Network;
 
     #[test]
@@ -431,7 +784,7 @@ mod test {
 
         // Block 0 (the anchor) now has one stable child (Block 1).
         // Block 0 should be returned when calling `pop`.
-        assert_eq!(peek(&forest), Some(&block_0));
+        assert_eq!(peek(&forest), Some(block_0.clone()));
         assert_eq!(pop(&mut forest, 0), Some(block_0));
 
         // Block 1 is now the anchor. It doesn't have stable
@@ -466,12 +819,12 @@ mod test {
         // any siblings. Hence, block_0 should be returned when calling `pop`.
         assert_eq!(forest.tree.children[0].difficulty_based_depth(network), 145);
 
-        assert_eq!(peek(&forest), Some(&block_0));
+        assert_eq!(peek(&forest), Some(block_0.clone()));
         assert_eq!(pop(&mut forest, 0), Some(block_0));
 
         // block_1 (the anchor) now has one stable child (block_2).
         // block_1 should be returned when calling `pop`.
-        assert_eq!(peek(&forest), Some(&block_1));
+        assert_eq!(peek(&forest), Some(block_1.clone()));
         assert_eq!(pop(&mut forest, 0), Some(block_1));
 
         // block_2 is now the anchor. It doesn't have stable
@@ -511,12 +864,12 @@ mod test {
         push(&mut forest, &utxos, block_2).unwrap();
         //Now, fork2 has a difficulty_based_depth of 3, while fork1 has a difficulty_based_depth of 1,
         //hence we can get a stable child.
-        assert_eq!(peek(&forest), Some(&genesis_block));
+        assert_eq!(peek(&forest), Some(genesis_block.clone()));
         assert_eq!(pop(&mut forest, 0), Some(genesis_block));
         assert_eq!(forest.tree.root, forked_block);
 
         //fork2 is still stable, hence we can get a stable child.
-        assert_eq!(peek(&forest), Some(&forked_block));
+        assert_eq!(peek(&forest), Some(forked_block.clone()));
         assert_eq!(pop(&mut forest, 0), Some(forked_block));
         assert_eq!(forest.tree.root, block_1);
 
@@ -568,7 +921,7 @@ mod test {
         assert_eq!(forest.tree.children[0].difficulty_based_depth(network), 11);
         assert_eq!(forest.tree.children[1].difficulty_based_depth(network), 30);
 
-        assert_eq!(peek(&forest), Some(&genesis_block));
+        assert_eq!(peek(&forest), Some(genesis_block.clone()));
         assert_eq!(pop(&mut forest, 0), Some(genesis_block));
         assert_eq!(forest.tree.root, fork2_block);
 
@@ -578,7 +931,7 @@ mod test {
         // and it does not have any siblings.
         assert_eq!(forest.tree.children[0].difficulty_based_depth(network), 25);
 
-        assert_eq!(peek(&forest), Some(&fork2_block));
+        assert_eq!(peek(&forest), Some(fork2_block.clone()));
         assert_eq!(pop(&mut forest, 0), Some(fork2_block));
 
         // No stable child for block_2, because it does not have any children.
@@ -596,7 +949,7 @@ mod test {
         // hence difficulty_based_depth >= normalized_stability_threshold.
         assert_eq!(forest.tree.children[0].difficulty_based_depth(network), 75);
 
-        assert_eq!(peek(&forest), Some(&block_2));
+        assert_eq!(peek(&forest), Some(block_2.clone()));
         assert_eq!(pop(&mut forest, 0), Some(block_2));
         assert_eq!(forest.tree.root, block_3);
 
@@ -617,9 +970,9 @@ mod test {
         push(&mut forest, &utxos, block_1.clone()).unwrap();
         push(&mut forest, &utxos, block_2).unwrap();
 
-        assert_eq!(peek(&forest), Some(&block_0));
+        assert_eq!(peek(&forest), Some(block_0.clone()));
         assert_eq!(pop(&mut forest, 0), Some(block_0));
-        assert_eq!(peek(&forest), Some(&block_1));
+        assert_eq!(peek(&forest), Some(block_1.clone()));
         assert_eq!(pop(&mut forest, 0), Some(block_1));
         assert_eq!(peek(&forest), None);
         assert_eq!(pop(&mut forest, 0), None);
@@ -643,8 +996,8 @@ mod test {
         push(&mut forest, &utxos, block_1.clone()).unwrap();
         push(&mut forest, &utxos, block_2.clone()).unwrap();
         assert_eq!(
-            get_main_chain(&forest),
-            BlockChain::new_with_successors(&block_0, vec![&block_1, &block_2])
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_1, &block_2]
         );
     }
 
@@ -653,7 +1006,8 @@ mod test {
     // * -> 1
     // * -> 2
     //
-    // Both blocks 1 and 2 contest with each other -> main chain is empty.
+    // Both blocks 1 and 2 have the same chainwork, and block 1 is kept as the tip
+    // since it was received first.
     #[test]
     fn get_main_chain_two_contesting_trees() {
         let block_0 = BlockBuilder::genesis().build();
@@ -664,9 +1018,12 @@ mod test {
         let utxos = UtxoSet::new(network);
         let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
-        push(&mut forest, &utxos, block_1).unwrap();
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
         push(&mut forest, &utxos, block_2).unwrap();
-        assert_eq!(get_main_chain(&forest), BlockChain::new(&block_0));
+        assert_eq!(
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_1]
+        );
     }
 
     // Creating the following forest:
@@ -690,8 +1047,8 @@ mod test {
         push(&mut forest, &utxos, block_2.clone()).unwrap();
         push(&mut forest, &utxos, block_3.clone()).unwrap();
         assert_eq!(
-            get_main_chain(&forest),
-            BlockChain::new_with_successors(&block_0, vec![&block_2, &block_3])
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_2, &block_3]
         );
     }
 
@@ -700,8 +1057,7 @@ mod test {
     // * -> 1 -> 2 -> 3
     //       \-> a -> b
     //
-    // "1" should be returned in this case, as its the longest chain
-    // without a contested tip.
+    // "1 -> 2 -> 3" should be returned in this case, as it has the most chainwork.
     #[test]
     fn get_main_chain_fork_at_first_block() {
         let block_0 = BlockBuilder::genesis().build();
@@ -716,13 +1072,13 @@ mod test {
         let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
         push(&mut forest, &utxos, block_1.clone()).unwrap();
-        push(&mut forest, &utxos, block_2).unwrap();
-        push(&mut forest, &utxos, block_3).unwrap();
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        push(&mut forest, &utxos, block_3.clone()).unwrap();
         push(&mut forest, &utxos, block_a).unwrap();
         push(&mut forest, &utxos, block_b).unwrap();
         assert_eq!(
-            get_main_chain(&forest),
-            BlockChain::new_with_successors(&block_0, vec![&block_1])
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_1, &block_2, &block_3]
         );
     }
 
@@ -732,10 +1088,11 @@ mod test {
     //       \-> a -> b
     //   -> x -> y -> z
     //
-    // All blocks are contested.
+    // All the chains have the same chainwork, and `x -> y -> z` is the main
+    // chain since it was received first.
     //
     // Then add block `c` that extends block `b`, at that point
-    // `1 -> a -> b -> c` becomes the only longest chain, and therefore
+    // `1 -> a -> b -> c` has the most chainwork, and is therefore
     // the "main" chain.
     #[test]
     fn get_main_chain_multiple_forks() {
@@ -753,15 +1110,18 @@ mod test {
         let utxos = UtxoSet::new(network);
         let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
-        push(&mut forest, &utxos, block_x).unwrap();
-        push(&mut forest, &utxos, block_y).unwrap();
-        push(&mut forest, &utxos, block_z).unwrap();
+        push(&mut forest, &utxos, block_x.clone()).unwrap();
+        push(&mut forest, &utxos, block_y.clone()).unwrap();
+        push(&mut forest, &utxos, block_z.clone()).unwrap();
         push(&mut forest, &utxos, block_1.clone()).unwrap();
         push(&mut forest, &utxos, block_2).unwrap();
         push(&mut forest, &utxos, block_3).unwrap();
         push(&mut forest, &utxos, block_a.clone()).unwrap();
         push(&mut forest, &utxos, block_b.clone()).unwrap();
-        assert_eq!(get_main_chain(&forest), BlockChain::new(&block_0));
+        assert_eq!(
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_x, &block_y, &block_z]
+        );
 
         // Now add block c to b.
         let block_c = BlockBuilder::with_prev_header(block_b.header()).build();
@@ -769,8 +1129,8 @@ mod test {
 
         // Now the main chain should be "1 -> a -> b -> c"
         assert_eq!(
-            get_main_chain(&forest),
-            BlockChain::new_with_successors(&block_0, vec![&block_1, &block_a, &block_b, &block_c])
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_1, &block_a, &block_b, &block_c]
         );
     }
 
@@ -791,15 +1151,18 @@ mod test {
         let utxos = UtxoSet::new(network);
         let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
-        push(&mut forest, &utxos, block_1).unwrap();
-        push(&mut forest, &utxos, block_2).unwrap();
-        push(&mut forest, &utxos, block_3).unwrap();
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        push(&mut forest, &utxos, block_3.clone()).unwrap();
         push(&mut forest, &utxos, block_a).unwrap();
         push(&mut forest, &utxos, block_b).unwrap();
         push(&mut forest, &utxos, block_x).unwrap();
         push(&mut forest, &utxos, block_y).unwrap();
         push(&mut forest, &utxos, block_z).unwrap();
-        assert_eq!(get_main_chain(&forest), BlockChain::new(&block_0));
+        assert_eq!(
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_1, &block_2, &block_3]
+        );
     }
 
     #[test]
@@ -809,7 +1172,212 @@ mod test {
         let utxos = UtxoSet::new(network);
         let forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
 
-        assert_eq!(get_main_chain(&forest), BlockChain::new(&block_0));
+        assert_eq!(get_main_chain(&forest).into_chain(), vec![&block_0]);
+    }
+
+    // The compact target of testnet blocks that are mined at the minimum difficulty.
+    const TESTNET_MIN_DIFFICULTY_BITS: u32 = 0x1d00ffff;
+
+    // A compact target with a difficulty of 86,564,599, as mined on testnet.
+    const TESTNET_HIGH_DIFFICULTY_BITS: u32 = 0x19319d70;
+
+    // Returns a block on top of the given header with the given compact target. The forks
+    // built from these blocks are synthetic, as their proof of work isn't valid, but the
+    // main chain only depends on their targets and the order in which they're received.
+    fn block_with_bits(prev_header: &BlockHeader, bits: u32) -> Block {
+        let mut block = BlockBuilder::with_prev_header(prev_header)
+            .build()
+            .as_bitcoin_block()
+            .clone();
+        block.header.bits = bits;
+        Block::new(block)
+    }
+
+    #[test]
+    fn chainwork_matches_bitcoin_core() {
+        // Bitcoin Core reports a chainwork of 0x100010001 for the genesis blocks of
+        // mainnet and testnet.
+        for network in [Network::Mainnet, Network::Testnet] {
+            let genesis_block = crate::genesis_block(network);
+            let tree = BlockTree::new(BlockMetadata::from(&genesis_block));
+            assert_eq!(tree.chainwork, Uint256::from_u64(0x1_0001_0001).unwrap());
+        }
+
+        // Bitcoin Core computes the work of a block as 2**256 / (target + 1).
+        let block_0 = BlockBuilder::genesis().build();
+        assert_eq!(
+            block_with_bits(block_0.header(), TESTNET_MIN_DIFFICULTY_BITS).work(),
+            Uint256::from_u64(0x1_0001_0001).unwrap()
+        );
+        assert_eq!(
+            block_with_bits(block_0.header(), TESTNET_HIGH_DIFFICULTY_BITS).work(),
+            Uint256::from_u64(0x0528_e420_6a23_24a2).unwrap()
+        );
+    }
+
+    // Creating the following forest on testnet:
+    //
+    // * -> 1 -> 2 -> 3  (minimum difficulty)
+    //   -> a            (difficulty of 86,564,599)
+    //
+    // Even though "1 -> 2 -> 3" is longer, Bitcoin Core selects "a" since it has
+    // the most chainwork.
+    #[test]
+    fn get_main_chain_testnet_fork_with_min_difficulty_blocks() {
+        let network = Network::Testnet;
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = block_with_bits(block_0.header(), TESTNET_MIN_DIFFICULTY_BITS);
+        let block_2 = block_with_bits(block_1.header(), TESTNET_MIN_DIFFICULTY_BITS);
+        let block_3 = block_with_bits(block_2.header(), TESTNET_MIN_DIFFICULTY_BITS);
+        let block_a = block_with_bits(block_0.header(), TESTNET_HIGH_DIFFICULTY_BITS);
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
+
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        push(&mut forest, &utxos, block_3.clone()).unwrap();
+        assert_eq!(
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_1, &block_2, &block_3]
+        );
+
+        push(&mut forest, &utxos, block_a.clone()).unwrap();
+        assert_eq!(
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_a]
+        );
+        assert_eq!(get_main_chain_length(&forest), 2);
+
+        // Extending the longer chain with more minimum difficulty blocks doesn't
+        // change the main chain.
+        let mut tip = block_3;
+        for _ in 0..10 {
+            tip = block_with_bits(tip.header(), TESTNET_MIN_DIFFICULTY_BITS);
+            push(&mut forest, &utxos, tip.clone()).unwrap();
+        }
+        assert_eq!(
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_a]
+        );
+    }
+
+    // Creating the following forest on testnet, where all blocks have the minimum
+    // difficulty, and are received in the order 1, x, y, 2:
+    //
+    // * -> 1 -> 2
+    //   -> x -> y
+    //
+    // Both chains have the same chainwork. Bitcoin Core switches to "x -> y" as soon
+    // as "y" is received, and keeps it when "2" is received, since "y" was received
+    // first.
+    #[test]
+    fn get_main_chain_testnet_fork_with_the_same_chainwork() {
+        let network = Network::Testnet;
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = block_with_bits(block_0.header(), TESTNET_MIN_DIFFICULTY_BITS);
+        let block_2 = block_with_bits(block_1.header(), TESTNET_MIN_DIFFICULTY_BITS);
+        let block_x = block_with_bits(block_0.header(), TESTNET_MIN_DIFFICULTY_BITS);
+        let block_y = block_with_bits(block_x.header(), TESTNET_MIN_DIFFICULTY_BITS);
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
+
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+        push(&mut forest, &utxos, block_x.clone()).unwrap();
+        assert_eq!(
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_1]
+        );
+
+        push(&mut forest, &utxos, block_y.clone()).unwrap();
+        push(&mut forest, &utxos, block_2).unwrap();
+        assert_eq!(
+            get_main_chain(&forest).into_chain(),
+            vec![&block_0, &block_x, &block_y]
+        );
+    }
+
+    #[test]
+    fn main_chain_is_recomputed_from_the_first_seen_order() {
+        // Creating the following forest, where the blocks are received in the order
+        // 1, x, y, 2, and where "x -> y" is the second fork in depth-first order:
+        //
+        // 0 -> 1 -> 2
+        //   -> x -> y
+        let network = Network::Regtest;
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_x = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_y = BlockBuilder::with_prev_header(block_x.header()).build();
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 0, block_0, network);
+        for block in [block_1, block_x, block_y.clone(), block_2] {
+            push(&mut forest, &utxos, block).unwrap();
+        }
+
+        // Without a stored tip, as in trees serialized by older versions of the canister,
+        // the main chain is recomputed from the order in which the blocks were seen.
+        forest.main_chain_tip = None;
+        assert_eq!(get_main_chain(&forest).tip(), &block_y);
+
+        // The order is kept after the tree is serialized.
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&forest, &mut bytes).unwrap();
+        let forest: UnstableBlocks = ciborium::de::from_reader(&bytes[..]).unwrap();
+        assert_eq!(get_main_chain(&forest).tip(), &block_y);
+    }
+
+    #[test]
+    fn first_seen_order_is_kept_after_pop() {
+        // Creating the following forest, where the blocks are received in the order
+        // 1, 2, x, y, 3:
+        //
+        // 0 -> 1 -> 2 -> 3
+        //        -> x -> y
+        let network = Network::Regtest;
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
+        let block_x = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_y = BlockBuilder::with_prev_header(block_x.header()).build();
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
+        for block in [block_1, block_2, block_x, block_y.clone(), block_3] {
+            push(&mut forest, &utxos, block).unwrap();
+        }
+        assert_eq!(get_main_chain(&forest).tip(), &block_y);
+
+        assert_eq!(pop(&mut forest, 0), Some(block_0));
+        forest.main_chain_tip = None;
+        assert_eq!(get_main_chain(&forest).tip(), &block_y);
+    }
+
+    #[test]
+    fn main_chain_is_kept_after_serialization() {
+        let network = Network::Regtest;
+        let block_0 = BlockBuilder::genesis().build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_2 = BlockBuilder::with_prev_header(block_1.header()).build();
+        let block_x = BlockBuilder::with_prev_header(block_0.header()).build();
+        let block_y = BlockBuilder::with_prev_header(block_x.header()).build();
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 1, block_0.clone(), network);
+        for block in [block_1, block_x, block_y.clone(), block_2] {
+            push(&mut forest, &utxos, block).unwrap();
+        }
+        assert_eq!(get_main_chain(&forest).tip(), &block_y);
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&forest, &mut bytes).unwrap();
+        let new_forest: UnstableBlocks = ciborium::de::from_reader(&bytes[..]).unwrap();
+        assert!(new_forest == forest);
+        assert_eq!(get_main_chain(&new_forest).tip(), &block_y);
     }
 
     #[test]
@@ -933,7 +1501,7 @@ mod test {
         // Even though the chain's difficulty-based depth doesn't exceed the normalized stability
         // threshold, the anchor block can now be popped because the chain's length has exceeded
         // the maximum allowed.
-        assert_eq!(peek(&unstable_blocks), Some(&chain[0]));
+        assert_eq!(peek(&unstable_blocks), Some(chain[0].clone()));
     }
 
     #[test]
@@ -983,7 +1551,7 @@ mod test {
 
         // If there's a very long testnet chain `A`, and there exists another chain `B` s.t.
         // depth(A) - depth(B) < TESTNET_CHAIN_MAX_DEPTH, the root of chain `A` is considered stable.
-        assert_eq!(peek(&unstable_blocks), Some(&chain[0]));
+        assert_eq!(peek(&unstable_blocks), Some(chain[0].clone()));
 
         // Add one more block to the second chain, so that it's depth is `TESTNET_CHAIN_MAX_DEPTH`.
         push(
@@ -997,4 +1565,228 @@ mod test {
         // is considered unstable.
         assert_eq!(peek(&unstable_blocks), None);
     }
+
+    #[test]
+    fn prunes_forks_that_fall_behind_the_main_chain() {
+        let network = Network::Regtest;
+        let utxos = UtxoSet::new(network);
+        let address = random_p2pkh_address(network);
+
+        let pruning_policy = PruningPolicy {
+            max_fork_work_deficit_in_blocks: 6,
+            ..PruningPolicy::default()
+        };
+
+        // A chain that gets `max_fork_work_deficit_in_blocks` blocks ahead of a fork of
+        // the same difficulty.
+        let chain =
+            BlockChainBuilder::new(pruning_policy.max_fork_work_deficit_in_blocks as u32 + 2)
+                .build();
+        let fork_block = BlockBuilder::with_prev_header(chain[0].header())
+            .with_transaction(
+                TransactionBuilder::coinbase(1)
+                    .with_output(&address, 1_000)
+                    .build(),
+            )
+            .build();
+
+        let mut forest = UnstableBlocks::new(&utxos, 1, chain[0].clone(), network);
+        forest.set_pruning_policy(pruning_policy);
+        push(&mut forest, &utxos, fork_block.clone()).unwrap();
+        for block in chain.iter().skip(1).take(chain.len() - 2) {
+            push(&mut forest, &utxos, block.clone()).unwrap();
+        }
+
+        // The fork is one block short of being pruned.
+        assert!(forest.contains_block(&fork_block.block_hash()));
+        assert_eq!(
+            forest
+                .get_added_outpoints(&fork_block.block_hash(), &address)
+                .len(),
+            1
+        );
+        assert_eq!(forest.num_tips(), 2);
+
+        push(&mut forest, &utxos, chain.last().unwrap().clone()).unwrap();
+
+        // The fork, along with its outpoints, is pruned.
+        assert!(!forest.contains_block(&fork_block.block_hash()));
+        assert!(forest
+            .get_added_outpoints(&fork_block.block_hash(), &address)
+            .is_empty());
+        assert_eq!(
+            forest.get_tx_out(&OutPoint {
+                txid: fork_block.txdata()[0].txid(),
+                vout: 0
+            }),
+            None
+        );
+        assert_eq!(forest.num_tips(), 1);
+        assert_eq!(forest.num_pruned_blocks(), 1);
+        assert_eq!(get_main_chain_length(&forest), chain.len());
+
+        // The pruned block is remembered, and isn't added back to the tree.
+        assert!(forest.is_pruned(&fork_block.block_hash()));
+        assert_eq!(
+            forest.pruned_block_hashes().collect::<Vec<_>>(),
+            vec![&fork_block.block_hash()]
+        );
+        assert_eq!(
+            push(&mut forest, &utxos, fork_block.clone()).unwrap_err().0,
+            fork_block.block_hash()
+        );
+        assert!(!forest.contains_block(&fork_block.block_hash()));
+        assert_eq!(forest.num_tips(), 1);
+    }
+
+    #[test]
+    fn prunes_the_forks_with_the_least_chainwork_beyond_the_max_tips() {
+        let network = Network::Regtest;
+        let utxos = UtxoSet::new(network);
+        let anchor = BlockBuilder::genesis().build();
+        let mut forest = UnstableBlocks::new(&utxos, 1, anchor.clone(), network);
+        let max_unstable_tips = 3;
+        forest.set_pruning_policy(PruningPolicy {
+            max_unstable_tips,
+            ..PruningPolicy::default()
+        });
+
+        let forks: Vec<_> = (0..=max_unstable_tips)
+            .map(|_| BlockBuilder::with_prev_header(anchor.header()).build())
+            .collect();
+        for block in forks.iter() {
+            push(&mut forest, &utxos, block.clone()).unwrap();
+        }
+
+        // All the forks have the same chainwork, so the one that exceeded the bound
+        // is pruned.
+        assert_eq!(forest.num_tips(), max_unstable_tips);
+        assert_eq!(forest.num_pruned_blocks(), 1);
+        assert!(!forest.contains_block(&forks.last().unwrap().block_hash()));
+        assert_eq!(get_main_chain(&forest).tip(), &forks[0]);
+
+        // Extend one of the forks with two blocks. The second one adds a tip with more
+        // chainwork than the forks of a single block, one of which is pruned in its place.
+        let block_a = BlockBuilder::with_prev_header(forks[1].header()).build();
+        let block_b = BlockBuilder::with_prev_header(forks[1].header()).build();
+        push(&mut forest, &utxos, block_a.clone()).unwrap();
+        push(&mut forest, &utxos, block_b.clone()).unwrap();
+        assert_eq!(forest.num_tips(), max_unstable_tips);
+        assert_eq!(forest.num_pruned_blocks(), 2);
+        assert_eq!(get_main_chain(&forest).tip(), &block_a);
+        assert!(forest.contains_block(&block_b.block_hash()));
+        assert!(!forest.contains_block(&forks[max_unstable_tips as usize - 1].block_hash()));
+    }
+
+    #[test]
+    fn prunes_the_forks_with_the_least_chainwork_beyond_the_max_blocks() {
+        let network = Network::Regtest;
+        let utxos = UtxoSet::new(network);
+        let chain = BlockChainBuilder::new(3).build();
+        let fork_block = BlockBuilder::with_prev_header(chain[0].header()).build();
+
+        let mut forest = UnstableBlocks::new(&utxos, 1, chain[0].clone(), network);
+        forest.set_pruning_policy(PruningPolicy {
+            max_unstable_blocks: 3,
+            ..PruningPolicy::default()
+        });
+        push(&mut forest, &utxos, fork_block.clone()).unwrap();
+        push(&mut forest, &utxos, chain[1].clone()).unwrap();
+        assert_eq!(forest.num_tips(), 2);
+
+        // The tree exceeds the max number of blocks, so the fork with the least chainwork
+        // is pruned.
+        push(&mut forest, &utxos, chain[2].clone()).unwrap();
+        assert_eq!(forest.num_tips(), 1);
+        assert_eq!(forest.num_pruned_blocks(), 1);
+        assert!(forest.is_pruned(&fork_block.block_hash()));
+        assert_eq!(get_main_chain_length(&forest), chain.len());
+    }
+
+    #[test]
+    fn tree_snapshot() {
+        let network = Network::Regtest;
+        let block_0 = BlockBuilder::genesis().build_with_mock_difficulty(1);
+        let block_1 =
+            BlockBuilder::with_prev_header(block_0.header()).build_with_mock_difficulty(2);
+        let forked_block_1 =
+            BlockBuilder::with_prev_header(block_0.header()).build_with_mock_difficulty(2);
+        let block_2 =
+            BlockBuilder::with_prev_header(block_1.header()).build_with_mock_difficulty(4);
+        let next_header = *BlockBuilder::with_prev_header(block_2.header())
+            .build()
+            .header();
+
+        let utxos = UtxoSet::new(network);
+        let mut forest = UnstableBlocks::new(&utxos, 5, block_0.clone(), network);
+        push(&mut forest, &utxos, block_1.clone()).unwrap();
+        push(&mut forest, &utxos, forked_block_1.clone()).unwrap();
+        push(&mut forest, &utxos, block_2.clone()).unwrap();
+        forest.insert_next_block_header(next_header, 10).unwrap();
+
+        let snapshot = get_tree_snapshot(&forest, 10);
+        assert_eq!(snapshot.stability_threshold, 5);
+        assert_eq!(snapshot.num_tips, 2);
+
+        let summary: Vec<_> = snapshot
+            .blocks
+            .iter()
+            .map(|b| {
+                (
+                    b.block_hash.clone(),
+                    b.parent_block_hash.clone(),
+                    b.height,
+                    b.depth,
+                    b.is_main_chain,
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            vec![
+                (block_0.block_hash().to_string(), None, 10, 3, true),
+                (
+                    block_1.block_hash().to_string(),
+                    Some(block_0.block_hash().to_string()),
+                    11,
+                    2,
+                    true
+                ),
+                (
+                    block_2.block_hash().to_string(),
+                    Some(block_1.block_hash().to_string()),
+                    12,
+                    1,
+                    true
+                ),
+                (
+                    forked_block_1.block_hash().to_string(),
+                    Some(block_0.block_hash().to_string()),
+                    11,
+                    1,
+                    false
+                ),
+            ]
+        );
+
+        // The chainwork of every block is counted from the anchor.
+        let chainwork = |work: u64| hex::encode(Uint256::from_u64(work).unwrap().to_be_bytes());
+        assert_eq!(
+            snapshot
+                .blocks
+                .iter()
+                .map(|b| b.chainwork.clone())
+                .collect::<Vec<_>>(),
+            vec![chainwork(1), chainwork(3), chainwork(7), chainwork(3)]
+        );
+
+        assert_eq!(
+            snapshot.next_block_headers,
+            vec![NextBlockHeader {
+                block_hash: BlockHash::from(next_header.block_hash()).to_string(),
+                parent_block_hash: block_2.block_hash().to_string(),
+                height: 13,
+            }]
+        );
+    }
 }
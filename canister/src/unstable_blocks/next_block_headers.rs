@@ -60,6 +60,18 @@ impl NextBlockHeaders {
     pub fn get_header(&self, hash: &BlockHash) -> Option<&BlockHeader> {
         self.hash_to_height_and_header.get(hash).map(|res| &res.1)
     }
+
+    /// Returns an iterator over the headers along with their hashes and heights,
+    /// ordered by height.
+    pub fn iter(&self) -> impl Iterator<Item = (&BlockHash, Height, &BlockHeader)> {
+        self.height_to_hash
+            .iter()
+            .flat_map(move |(height, hashes)| {
+                hashes
+                    .iter()
+                    .map(move |hash| (hash, *height, &self.hash_to_height_and_header[hash].1))
+            })
+    }
 }
 
 #[cfg(test)]
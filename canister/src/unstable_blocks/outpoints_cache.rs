@@ -1,65 +1,128 @@
 use crate::{
-    types::{Address, TxOut},
+    memory::{
+        get_unstable_added_outpoints_memory, get_unstable_removed_outpoints_memory,
+        get_unstable_tx_blocks_memory, get_unstable_tx_outs_memory, Memory,
+    },
+    state::{UTXO_KEY_SIZE, UTXO_VALUE_MAX_SIZE_MEDIUM},
+    types::{Address, Storable, TxOut},
     UtxoSet,
 };
 use ic_btc_interface::Height;
-use ic_btc_types::{Block, BlockHash, OutPoint};
+use ic_btc_types::{Block, BlockHash, OutPoint, Txid};
+use ic_stable_structures::{
+    storable::Blob, BoundedStorable, StableBTreeMap, Storable as StableStructuresStorable,
+};
 use serde::{Deserialize, Serialize};
-use std::collections::BTreeMap;
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    convert::{TryFrom, TryInto},
+};
+
+// The maximum size of a `TxOutInfo` that's stored in stable memory. The few `TxOutInfo`s
+// with larger scripts are kept on the heap. See `Utxos` for the distribution of script sizes.
+const TX_OUT_INFO_MAX_SIZE: usize = UTXO_VALUE_MAX_SIZE_MEDIUM + 4 /* count bytes */;
 
 /// A cache maintaining data related to outpoints in unstable blocks.
-#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+///
+/// The cache grows with the number of transactions in the unstable blocks, so it's kept in
+/// stable memory, and only the transaction outputs with large scripts are serialized on upgrades.
+#[derive(Serialize, Deserialize)]
 pub struct OutPointsCache {
     /// Caches outpoints and their corresponding transaction outputs.
-    tx_outs: BTreeMap<OutPoint, TxOutInfo>,
+    // NOTE: Stable structures don't need to be serialized.
+    #[serde(skip, default = "init_tx_outs")]
+    tx_outs: StableBTreeMap<Blob<UTXO_KEY_SIZE>, Blob<TX_OUT_INFO_MAX_SIZE>, Memory>,
+
+    /// Caches the transaction outputs that are too large to be stored in `tx_outs`.
+    large_tx_outs: BTreeMap<OutPoint, TxOutInfo>,
 
     /// Caches the outpoints added for each address in a block.
-    added_outpoints: BTreeMap<BlockHash, BTreeMap<Address, Vec<OutPoint>>>,
+    #[serde(skip, default = "init_added_outpoints")]
+    added_outpoints: StableBTreeMap<BlockOutPoint, (), Memory>,
 
     /// Caches the outpoints removed for each address in a block.
-    removed_outpoints: BTreeMap<BlockHash, BTreeMap<Address, Vec<OutPoint>>>,
+    #[serde(skip, default = "init_removed_outpoints")]
+    removed_outpoints: StableBTreeMap<BlockOutPoint, (), Memory>,
+
+    /// Caches the blocks that contain each transaction. A transaction is in several
+    /// blocks if they're on different forks.
+    #[serde(skip, default = "init_tx_blocks")]
+    tx_blocks: StableBTreeMap<TxBlock, (), Memory>,
+}
+
+// NOTE: `PartialEq` is only available in tests as it would be impractically
+// expensive in production.
+#[cfg(test)]
+impl PartialEq for OutPointsCache {
+    fn eq(&self, other: &Self) -> bool {
+        use crate::test_utils::is_stable_btreemap_equal;
+        is_stable_btreemap_equal(&self.tx_outs, &other.tx_outs)
+            && self.large_tx_outs == other.large_tx_outs
+            && is_stable_btreemap_equal(&self.added_outpoints, &other.added_outpoints)
+            && is_stable_btreemap_equal(&self.removed_outpoints, &other.removed_outpoints)
+            && is_stable_btreemap_equal(&self.tx_blocks, &other.tx_blocks)
+    }
+}
+
+impl std::fmt::Debug for OutPointsCache {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OutPointsCache")
+            .field("tx_outs", &self.tx_outs.len())
+            .field("large_tx_outs", &self.large_tx_outs.len())
+            .field("added_outpoints", &self.added_outpoints.len())
+            .field("removed_outpoints", &self.removed_outpoints.len())
+            .field("tx_blocks", &self.tx_blocks.len())
+            .finish()
+    }
 }
 
 impl OutPointsCache {
+    /// Creates an empty cache, discarding the contents of any cache that's in stable memory.
     pub fn new() -> Self {
         Self {
-            tx_outs: BTreeMap::new(),
-            added_outpoints: BTreeMap::new(),
-            removed_outpoints: BTreeMap::new(),
+            tx_outs: StableBTreeMap::new(get_unstable_tx_outs_memory()),
+            large_tx_outs: BTreeMap::new(),
+            added_outpoints: StableBTreeMap::new(get_unstable_added_outpoints_memory()),
+            removed_outpoints: StableBTreeMap::new(get_unstable_removed_outpoints_memory()),
+            tx_blocks: StableBTreeMap::new(get_unstable_tx_blocks_memory()),
         }
     }
 
     /// Retrieves the list of outpoints that were added for the given address in the given block.
-    pub fn get_added_outpoints(&self, block_hash: &BlockHash, address: &Address) -> &[OutPoint] {
-        self.added_outpoints
-            .get(block_hash)
-            .map(|address_utxos| {
-                address_utxos
-                    .get(address)
-                    .map(|outpoints| outpoints.as_slice())
-                    .unwrap_or(&[])
-            })
-            .unwrap_or(&[])
+    pub fn get_added_outpoints(&self, block_hash: &BlockHash, address: &Address) -> Vec<OutPoint> {
+        get_outpoints(&self.added_outpoints, block_hash, address)
     }
 
     /// Retrieves the list of outpoints that were removed for the given address in the given block.
-    pub fn get_removed_outpoints(&self, block_hash: &BlockHash, address: &Address) -> &[OutPoint] {
-        self.removed_outpoints
-            .get(block_hash)
-            .map(|address_utxos| {
-                address_utxos
-                    .get(address)
-                    .map(|outpoints| outpoints.as_slice())
-                    .unwrap_or(&[])
-            })
-            .unwrap_or(&[])
+    pub fn get_removed_outpoints(
+        &self,
+        block_hash: &BlockHash,
+        address: &Address,
+    ) -> Vec<OutPoint> {
+        get_outpoints(&self.removed_outpoints, block_hash, address)
     }
 
     /// Retrieves the `TxOut` associated with the given `outpoint`, along with its height.
-    pub fn get_tx_out(&self, outpoint: &OutPoint) -> Option<(&TxOut, Height)> {
-        self.tx_outs
-            .get(outpoint)
-            .map(|info| (&info.txout, info.height))
+    pub fn get_tx_out(&self, outpoint: &OutPoint) -> Option<(TxOut, Height)> {
+        self.get_tx_out_info(outpoint)
+            .map(|info| (info.txout, info.height))
+    }
+
+    /// Retrieves the hashes of the blocks that contain the transaction with the given txid.
+    pub fn get_tx_blocks(&self, txid: &Txid) -> Vec<BlockHash> {
+        let start = TxBlock {
+            txid: txid.clone(),
+            block_hash: BlockHash::from(vec![0; 32]),
+        };
+        let end = TxBlock {
+            txid: txid.clone(),
+            block_hash: BlockHash::from(vec![255; 32]),
+        };
+        self.tx_blocks
+            .range(start..=end)
+            .map(|(key, _)| key.block_hash)
+            .collect()
     }
 
     /// Inserts the outpoints in a block, along with their transaction outputs, into the cache.
@@ -68,6 +131,18 @@ impl OutPointsCache {
         utxos: &UtxoSet,
         block: &Block,
         height: Height,
+    ) -> Result<(), TxOutNotFound> {
+        self.insert_with_inputs(utxos, block, height, &BTreeMap::new())
+    }
+
+    /// Same as `insert`, but with some of the transaction outputs spent by the block already
+    /// resolved (e.g. while validating the block), so they aren't looked up again.
+    pub fn insert_with_inputs(
+        &mut self,
+        utxos: &UtxoSet,
+        block: &Block,
+        height: Height,
+        inputs: &BTreeMap<OutPoint, (TxOut, Height)>,
     ) -> Result<(), TxOutNotFound> {
         // A map to store all the transaction outputs referenced by the given block.
         let mut tx_outs: BTreeMap<OutPoint, TxOutInfo> = BTreeMap::new();
@@ -93,9 +168,13 @@ impl OutPointsCache {
 
                 let outpoint = (&input.previous_output).into();
 
-                // Lookup the `TxOut` in the current cache.
-                let (txout, height) = match self.get_tx_out(&outpoint) {
-                    Some((txout, height)) => (txout.clone(), height),
+                // Lookup the `TxOut` in the resolved inputs, then in the current cache.
+                let cached = match inputs.get(&outpoint) {
+                    Some(tx_out) => Some(tx_out.clone()),
+                    None => self.get_tx_out(&outpoint),
+                };
+                let (txout, height) = match cached {
+                    Some(tx_out) => tx_out,
 
                     // Lookup the `TxOut` in the current block.
                     None => match tx_outs.get(&outpoint) {
@@ -148,28 +227,42 @@ impl OutPointsCache {
         }
 
         // Merge all the transaction outputs of this block into the cache.
-        for (outpoint, tx_out_info) in tx_outs {
-            self.tx_outs
-                .entry(outpoint)
-                .and_modify(|t| t.count += tx_out_info.count)
-                .or_insert(tx_out_info);
+        for (outpoint, mut tx_out_info) in tx_outs {
+            if let Some(cached) = self.get_tx_out_info(&outpoint) {
+                tx_out_info.count += cached.count;
+            }
+            self.insert_tx_out_info(outpoint, tx_out_info);
         }
 
-        self.added_outpoints
-            .insert(block.block_hash(), added_outpoints);
-        self.removed_outpoints
-            .insert(block.block_hash(), removed_outpoints);
+        let block_hash = block.block_hash();
+        insert_outpoints(&mut self.added_outpoints, &block_hash, added_outpoints);
+        insert_outpoints(&mut self.removed_outpoints, &block_hash, removed_outpoints);
+        self.insert_tx_blocks(block);
 
         Ok(())
     }
 
+    /// Records that the transactions of the given block are in it.
+    pub fn insert_tx_blocks(&mut self, block: &Block) {
+        let block_hash = block.block_hash();
+        for tx in block.txdata() {
+            self.tx_blocks.insert(
+                TxBlock {
+                    txid: tx.txid(),
+                    block_hash: block_hash.clone(),
+                },
+                (),
+            );
+        }
+    }
+
     /// Removes the outpoints of a block from the cache.
     ///
     /// Note that an outpoint can be referenced by multiple blocks, so an outpoint is only removed
     /// from the cache when there are no more blocks referencing it.
     pub fn remove(&mut self, block: &Block) {
         fn decrement_count_and_maybe_remove(cache: &mut OutPointsCache, outpoint: &OutPoint) {
-            let entry = cache.tx_outs.get_mut(outpoint).unwrap_or_else(|| {
+            let mut entry = cache.get_tx_out_info(outpoint).unwrap_or_else(|| {
                 panic!(
                     "outpoint {:?} must be present in the outpoints cache.",
                     outpoint
@@ -181,7 +274,9 @@ impl OutPointsCache {
 
             // Remove the outpoint if there are no more blocks in the cache referencing it.
             if entry.count == 0 {
-                cache.tx_outs.remove(outpoint);
+                cache.remove_tx_out_info(outpoint);
+            } else {
+                cache.insert_tx_out_info(outpoint.clone(), entry);
             }
         }
 
@@ -207,8 +302,141 @@ impl OutPointsCache {
         }
 
         let block_hash = block.block_hash();
-        self.added_outpoints.remove(&block_hash);
-        self.removed_outpoints.remove(&block_hash);
+        remove_outpoints(&mut self.added_outpoints, &block_hash);
+        remove_outpoints(&mut self.removed_outpoints, &block_hash);
+        for tx in block.txdata() {
+            self.tx_blocks.remove(&TxBlock {
+                txid: tx.txid(),
+                block_hash: block_hash.clone(),
+            });
+        }
+    }
+
+    fn get_tx_out_info(&self, outpoint: &OutPoint) -> Option<TxOutInfo> {
+        match self.tx_outs.get(&outpoint_key(outpoint)) {
+            Some(bytes) => Some(TxOutInfo::from_bytes(bytes.as_slice().to_vec())),
+            None => self.large_tx_outs.get(outpoint).cloned(),
+        }
+    }
+
+    fn insert_tx_out_info(&mut self, outpoint: OutPoint, tx_out_info: TxOutInfo) {
+        let bytes = tx_out_info.to_bytes();
+        if bytes.len() <= TX_OUT_INFO_MAX_SIZE {
+            self.tx_outs.insert(
+                outpoint_key(&outpoint),
+                Blob::try_from(bytes.as_slice()).unwrap(),
+            );
+        } else {
+            self.large_tx_outs.insert(outpoint, tx_out_info);
+        }
+    }
+
+    fn remove_tx_out_info(&mut self, outpoint: &OutPoint) {
+        if self.tx_outs.remove(&outpoint_key(outpoint)).is_none() {
+            self.large_tx_outs.remove(outpoint);
+        }
+    }
+}
+
+/// An `OutPointsCache` as it was serialized by older versions of the canister, which
+/// kept the whole cache on the heap.
+#[derive(Deserialize)]
+pub struct LegacyOutPointsCache {
+    tx_outs: BTreeMap<OutPoint, TxOutInfo>,
+    added_outpoints: BTreeMap<BlockHash, BTreeMap<Address, Vec<OutPoint>>>,
+    removed_outpoints: BTreeMap<BlockHash, BTreeMap<Address, Vec<OutPoint>>>,
+}
+
+impl From<LegacyOutPointsCache> for OutPointsCache {
+    fn from(legacy: LegacyOutPointsCache) -> Self {
+        let mut cache = Self::new();
+        for (outpoint, tx_out_info) in legacy.tx_outs {
+            cache.insert_tx_out_info(outpoint, tx_out_info);
+        }
+        for (block_hash, outpoints) in legacy.added_outpoints {
+            insert_outpoints(&mut cache.added_outpoints, &block_hash, outpoints);
+        }
+        for (block_hash, outpoints) in legacy.removed_outpoints {
+            insert_outpoints(&mut cache.removed_outpoints, &block_hash, outpoints);
+        }
+        cache
+    }
+}
+
+fn init_tx_outs() -> StableBTreeMap<Blob<UTXO_KEY_SIZE>, Blob<TX_OUT_INFO_MAX_SIZE>, Memory> {
+    StableBTreeMap::init(get_unstable_tx_outs_memory())
+}
+
+fn init_added_outpoints() -> StableBTreeMap<BlockOutPoint, (), Memory> {
+    StableBTreeMap::init(get_unstable_added_outpoints_memory())
+}
+
+fn init_removed_outpoints() -> StableBTreeMap<BlockOutPoint, (), Memory> {
+    StableBTreeMap::init(get_unstable_removed_outpoints_memory())
+}
+
+fn init_tx_blocks() -> StableBTreeMap<TxBlock, (), Memory> {
+    StableBTreeMap::init(get_unstable_tx_blocks_memory())
+}
+
+fn outpoint_key(outpoint: &OutPoint) -> Blob<UTXO_KEY_SIZE> {
+    Blob::try_from(outpoint.to_bytes().as_ref()).unwrap()
+}
+
+fn get_outpoints(
+    map: &StableBTreeMap<BlockOutPoint, (), Memory>,
+    block_hash: &BlockHash,
+    address: &Address,
+) -> Vec<OutPoint> {
+    let start = BlockOutPoint {
+        block_hash: block_hash.clone(),
+        address: address.clone(),
+        outpoint: OutPoint::new(Txid::from(vec![0; 32]), 0),
+    };
+    let end = BlockOutPoint {
+        block_hash: block_hash.clone(),
+        address: address.clone(),
+        outpoint: OutPoint::new(Txid::from(vec![255; 32]), u32::MAX),
+    };
+    map.range(start..=end)
+        .map(|(key, _)| key.outpoint)
+        .collect()
+}
+
+fn insert_outpoints(
+    map: &mut StableBTreeMap<BlockOutPoint, (), Memory>,
+    block_hash: &BlockHash,
+    outpoints: BTreeMap<Address, Vec<OutPoint>>,
+) {
+    for (address, outpoints) in outpoints {
+        for outpoint in outpoints {
+            map.insert(
+                BlockOutPoint {
+                    block_hash: block_hash.clone(),
+                    address: address.clone(),
+                    outpoint,
+                },
+                (),
+            );
+        }
+    }
+}
+
+fn remove_outpoints(map: &mut StableBTreeMap<BlockOutPoint, (), Memory>, block_hash: &BlockHash) {
+    // The smallest key of the block. Addresses are never empty.
+    let start = BlockOutPoint {
+        block_hash: block_hash.clone(),
+        address: Address::from_bytes(Cow::Borrowed(&[])),
+        outpoint: OutPoint::new(Txid::from(vec![0; 32]), 0),
+    };
+
+    let keys: Vec<_> = map
+        .range(start..)
+        .map(|(key, _)| key)
+        .take_while(|key| key.block_hash == *block_hash)
+        .collect();
+    for key in keys {
+        map.remove(&key);
     }
 }
 
@@ -231,16 +459,132 @@ struct TxOutInfo {
     count: u32,
 }
 
+impl Storable for TxOutInfo {
+    fn to_bytes(&self) -> Vec<u8> {
+        vec![
+            self.count.to_be_bytes().to_vec(), // Store the count (4 bytes)
+            (self.txout.clone(), self.height).to_bytes(), // Then the `TxOut` and its height
+        ]
+        .into_iter()
+        .flatten()
+        .collect()
+    }
+
+    fn from_bytes(mut bytes: Vec<u8>) -> Self {
+        let tx_out_bytes = bytes.split_off(4);
+        let count = u32::from_be_bytes(bytes.try_into().unwrap());
+        let (txout, height) = <(TxOut, Height)>::from_bytes(tx_out_bytes);
+        Self {
+            txout,
+            height,
+            count,
+        }
+    }
+}
+
+// An outpoint that was added or removed for an address in a block.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct BlockOutPoint {
+    block_hash: BlockHash,
+    address: Address,
+    outpoint: OutPoint,
+}
+
+impl StableStructuresStorable for BlockOutPoint {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let address = self.address.to_bytes();
+        let mut bytes = self.block_hash.clone().to_vec();
+        bytes.push(address.len() as u8); // Addresses are at most 90 bytes.
+        bytes.extend_from_slice(&address);
+        bytes.extend_from_slice(&self.outpoint.to_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        let address_end = 33 + bytes[32] as usize;
+        Self {
+            block_hash: BlockHash::from(bytes[0..32].to_vec()),
+            address: Address::from_bytes(Cow::Borrowed(&bytes[33..address_end])),
+            outpoint: OutPoint::from_bytes(Cow::Borrowed(&bytes[address_end..])),
+        }
+    }
+}
+
+impl BoundedStorable for BlockOutPoint {
+    const MAX_SIZE: u32 =
+        BlockHash::MAX_SIZE + 1 /* address length */ + Address::MAX_SIZE + OutPoint::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = false;
+}
+
+// A transaction along with a block that contains it.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct TxBlock {
+    txid: Txid,
+    block_hash: BlockHash,
+}
+
+impl StableStructuresStorable for TxBlock {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        let mut bytes = self.txid.as_bytes().to_vec();
+        bytes.extend_from_slice(&self.block_hash.clone().to_vec());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            txid: Txid::from(bytes[0..32].to_vec()),
+            block_hash: BlockHash::from(bytes[32..64].to_vec()),
+        }
+    }
+}
+
+impl BoundedStorable for TxBlock {
+    const MAX_SIZE: u32 = 32 /* txid */ + BlockHash::MAX_SIZE;
+    const IS_FIXED_SIZE: bool = true;
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
     use ic_btc_interface::Network;
 
+    type OutPointsByBlock = BTreeMap<BlockHash, BTreeMap<Address, Vec<OutPoint>>>;
+
+    // Returns all the transaction outputs in the cache.
+    fn tx_outs(cache: &OutPointsCache) -> BTreeMap<OutPoint, TxOutInfo> {
+        let mut tx_outs: BTreeMap<_, _> = cache
+            .tx_outs
+            .iter()
+            .map(|(key, value)| {
+                (
+                    OutPoint::from_bytes(Cow::Owned(key.as_slice().to_vec())),
+                    TxOutInfo::from_bytes(value.as_slice().to_vec()),
+                )
+            })
+            .collect();
+        tx_outs.extend(cache.large_tx_outs.clone());
+        tx_outs
+    }
+
+    // Returns all the outpoints in the given map, grouped by block and address.
+    fn outpoints(map: &StableBTreeMap<BlockOutPoint, (), Memory>) -> OutPointsByBlock {
+        let mut outpoints: OutPointsByBlock = BTreeMap::new();
+        for (key, _) in map.iter() {
+            outpoints
+                .entry(key.block_hash)
+                .or_default()
+                .entry(key.address)
+                .or_default()
+                .push(key.outpoint);
+        }
+        outpoints
+    }
+
     #[test]
     fn empty_when_initialized() {
         let cache = OutPointsCache::new();
-        assert_eq!(cache.tx_outs, maplit::btreemap! {},);
+        assert_eq!(tx_outs(&cache), maplit::btreemap! {},);
     }
 
     #[test]
@@ -249,7 +593,7 @@ mod test {
         let address_1 = random_p2pkh_address(network);
         let address_2 = random_p2pkh_address(network);
 
-        let tx_0 = TransactionBuilder::coinbase()
+        let tx_0 = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .build();
         let block_0 = BlockBuilder::genesis()
@@ -268,7 +612,7 @@ mod test {
             vout: 0,
         };
         assert_eq!(
-            cache.tx_outs,
+            tx_outs(&cache),
             maplit::btreemap! {
                 outpoint_0.clone() => TxOutInfo {
                     txout: (&tx_0.output()[0]).into(),
@@ -297,33 +641,36 @@ mod test {
 
         // The outpoints info cache contains the outpoints of block 0 and block 1.
         assert_eq!(
-            cache,
-            OutPointsCache {
-                tx_outs: maplit::btreemap! {
-                    outpoint_0.clone() => TxOutInfo {
-                        txout: (&tx_0.output()[0]).into(),
-                        height: 0,
-                        count: 2
-                    },
-                    outpoint_1.clone() => TxOutInfo {
-                        txout: (&tx_1.output()[0]).into(),
-                        height: 1,
-                        count: 1
-                    }
+            tx_outs(&cache),
+            maplit::btreemap! {
+                outpoint_0.clone() => TxOutInfo {
+                    txout: (&tx_0.output()[0]).into(),
+                    height: 0,
+                    count: 2
                 },
-                added_outpoints: maplit::btreemap! {
-                    block_0.block_hash() => maplit::btreemap! {
-                        address_1.clone() => vec![OutPoint::new(tx_0.txid(), 0)]
-                    },
-                    block_1.block_hash() => maplit::btreemap! {
-                        address_2.clone() => vec![OutPoint::new(tx_1.txid(), 0)]
-                    },
+                outpoint_1.clone() => TxOutInfo {
+                    txout: (&tx_1.output()[0]).into(),
+                    height: 1,
+                    count: 1
+                }
+            }
+        );
+        assert_eq!(
+            outpoints(&cache.added_outpoints),
+            maplit::btreemap! {
+                block_0.block_hash() => maplit::btreemap! {
+                    address_1.clone() => vec![OutPoint::new(tx_0.txid(), 0)]
                 },
-                removed_outpoints: maplit::btreemap! {
-                    block_0.block_hash() => maplit::btreemap! {},
-                    block_1.block_hash() => maplit::btreemap! {
-                        address_1.clone() => vec![OutPoint::new(tx_0.txid(), 0)]
-                    },
+                block_1.block_hash() => maplit::btreemap! {
+                    address_2.clone() => vec![OutPoint::new(tx_1.txid(), 0)]
+                },
+            }
+        );
+        assert_eq!(
+            outpoints(&cache.removed_outpoints),
+            maplit::btreemap! {
+                block_1.block_hash() => maplit::btreemap! {
+                    address_1.clone() => vec![OutPoint::new(tx_0.txid(), 0)]
                 },
             }
         );
@@ -331,43 +678,42 @@ mod test {
         cache.remove(&block_0);
 
         assert_eq!(
-            cache,
-            OutPointsCache {
-                tx_outs: maplit::btreemap! {
-                    outpoint_0 => TxOutInfo {
-                        txout: (&tx_0.output()[0]).into(),
-                        height: 0,
-                        count: 1
-                    },
-                    outpoint_1 => TxOutInfo {
-                        txout: (&tx_1.output()[0]).into(),
-                        height: 1,
-                        count: 1
-                    }
+            tx_outs(&cache),
+            maplit::btreemap! {
+                outpoint_0 => TxOutInfo {
+                    txout: (&tx_0.output()[0]).into(),
+                    height: 0,
+                    count: 1
                 },
-                added_outpoints: maplit::btreemap! {
-                    block_1.block_hash() => maplit::btreemap! {
-                        address_2 => vec![OutPoint::new(tx_1.txid(), 0)]
-                    },
+                outpoint_1 => TxOutInfo {
+                    txout: (&tx_1.output()[0]).into(),
+                    height: 1,
+                    count: 1
+                }
+            }
+        );
+        assert_eq!(
+            outpoints(&cache.added_outpoints),
+            maplit::btreemap! {
+                block_1.block_hash() => maplit::btreemap! {
+                    address_2 => vec![OutPoint::new(tx_1.txid(), 0)]
                 },
-                removed_outpoints: maplit::btreemap! {
-                    block_1.block_hash() => maplit::btreemap! {
-                        address_1 => vec![OutPoint::new(tx_0.txid(), 0)]
-                    },
+            }
+        );
+        assert_eq!(
+            outpoints(&cache.removed_outpoints),
+            maplit::btreemap! {
+                block_1.block_hash() => maplit::btreemap! {
+                    address_1 => vec![OutPoint::new(tx_0.txid(), 0)]
                 },
             }
         );
 
         // Removing block 1 makes the cache empty again.
         cache.remove(&block_1);
-        assert_eq!(
-            cache,
-            OutPointsCache {
-                tx_outs: maplit::btreemap! {},
-                added_outpoints: maplit::btreemap! {},
-                removed_outpoints: maplit::btreemap! {}
-            }
-        );
+        assert_eq!(tx_outs(&cache), maplit::btreemap! {});
+        assert_eq!(outpoints(&cache.added_outpoints), maplit::btreemap! {});
+        assert_eq!(outpoints(&cache.removed_outpoints), maplit::btreemap! {});
     }
 
     #[test]
@@ -376,7 +722,7 @@ mod test {
         let address_1 = random_p2pkh_address(network);
         let address_2 = random_p2pkh_address(network);
 
-        let tx_0 = TransactionBuilder::coinbase()
+        let tx_0 = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .build();
         let block_0 = BlockBuilder::genesis()
@@ -414,7 +760,7 @@ mod test {
         let address_1 = random_p2pkh_address(network);
         let address_2 = random_p2pkh_address(network);
 
-        let tx_0 = TransactionBuilder::coinbase()
+        let tx_0 = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .build();
         let block_0 = BlockBuilder::genesis()
@@ -457,24 +803,130 @@ mod test {
 
         // The cache doesn't contain anything from block 1
         assert_eq!(
-            cache,
-            OutPointsCache {
-                tx_outs: maplit::btreemap! {
-                    outpoint_0 => TxOutInfo {
-                        txout: (&tx_0.output()[0]).into(),
-                        height: 0,
-                        count: 1
-                    },
-                },
-                added_outpoints: maplit::btreemap! {
-                    block_0.block_hash() => maplit::btreemap! {
-                        address_1 => vec![OutPoint::new(tx_0.txid(), 0)]
-                    },
+            tx_outs(&cache),
+            maplit::btreemap! {
+                outpoint_0 => TxOutInfo {
+                    txout: (&tx_0.output()[0]).into(),
+                    height: 0,
+                    count: 1
                 },
-                removed_outpoints: maplit::btreemap! {
-                    block_0.block_hash() => maplit::btreemap! {}
+            }
+        );
+        assert_eq!(
+            outpoints(&cache.added_outpoints),
+            maplit::btreemap! {
+                block_0.block_hash() => maplit::btreemap! {
+                    address_1 => vec![OutPoint::new(tx_0.txid(), 0)]
                 },
             }
         );
+        assert_eq!(outpoints(&cache.removed_outpoints), maplit::btreemap! {});
+    }
+
+    #[test]
+    fn keeps_large_tx_outs_on_the_heap() {
+        let mut cache = OutPointsCache::new();
+        let outpoint = OutPoint::new(Txid::from(vec![1; 32]), 0);
+        let tx_out_info = TxOutInfo {
+            txout: TxOut {
+                value: 1_000,
+                script_pubkey: vec![0; 300],
+            },
+            height: 3,
+            count: 1,
+        };
+
+        cache.insert_tx_out_info(outpoint.clone(), tx_out_info.clone());
+        assert!(cache.tx_outs.is_empty());
+        assert_eq!(cache.get_tx_out_info(&outpoint), Some(tx_out_info.clone()));
+
+        // Large transaction outputs are serialized along with the cache.
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&cache, &mut bytes).unwrap();
+        let decoded: OutPointsCache = ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        assert_eq!(decoded.get_tx_out_info(&outpoint), Some(tx_out_info));
+
+        cache.remove_tx_out_info(&outpoint);
+        assert!(cache.large_tx_outs.is_empty());
+    }
+
+    #[test]
+    fn converts_legacy_caches() {
+        // `TxOut` and `OutPointsCache` as they were encoded by older versions of the canister,
+        // where scripts were encoded as sequences and the whole cache was serialized.
+        #[derive(Serialize)]
+        struct LegacyTxOut {
+            value: u64,
+            script_pubkey: Vec<u8>,
+        }
+
+        #[derive(Serialize)]
+        struct LegacyTxOutInfo {
+            txout: LegacyTxOut,
+            height: Height,
+            count: u32,
+        }
+
+        #[derive(Serialize)]
+        struct EncodedLegacyOutPointsCache {
+            tx_outs: BTreeMap<OutPoint, LegacyTxOutInfo>,
+            added_outpoints: OutPointsByBlock,
+            removed_outpoints: OutPointsByBlock,
+        }
+
+        let network = Network::Mainnet;
+        let address = random_p2pkh_address(network);
+        let tx = TransactionBuilder::coinbase(0)
+            .with_output(&address, 1000)
+            .build();
+        let block = BlockBuilder::genesis().with_transaction(tx.clone()).build();
+        let outpoint = OutPoint::new(tx.txid(), 0);
+        let txout: TxOut = (&tx.output()[0]).into();
+
+        let legacy_txout = LegacyTxOut {
+            value: txout.value,
+            script_pubkey: txout.script_pubkey.clone(),
+        };
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&legacy_txout, &mut bytes).unwrap();
+        assert_eq!(
+            ciborium::de::from_reader::<TxOut, _>(bytes.as_slice()).unwrap(),
+            txout
+        );
+
+        let legacy_cache = EncodedLegacyOutPointsCache {
+            tx_outs: maplit::btreemap! {
+                outpoint.clone() => LegacyTxOutInfo {
+                    txout: legacy_txout,
+                    height: 0,
+                    count: 1,
+                }
+            },
+            added_outpoints: maplit::btreemap! {
+                block.block_hash() => maplit::btreemap! {
+                    address.clone() => vec![outpoint.clone()]
+                }
+            },
+            removed_outpoints: maplit::btreemap! {
+                block.block_hash() => maplit::btreemap! {}
+            },
+        };
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(&legacy_cache, &mut bytes).unwrap();
+
+        // The contents of the legacy cache are moved into stable memory.
+        let legacy_cache: LegacyOutPointsCache =
+            ciborium::de::from_reader(bytes.as_slice()).unwrap();
+        let cache = OutPointsCache::from(legacy_cache);
+        assert_eq!(cache.get_tx_out(&outpoint), Some((txout, 0)));
+        assert_eq!(
+            cache.get_added_outpoints(&block.block_hash(), &address),
+            vec![outpoint]
+        );
+        assert_eq!(
+            cache.get_removed_outpoints(&block.block_hash(), &address),
+            vec![]
+        );
+        assert!(cache.large_tx_outs.is_empty());
     }
 }
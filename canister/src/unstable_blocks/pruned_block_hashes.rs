@@ -0,0 +1,66 @@
+use ic_btc_types::BlockHash;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, VecDeque};
+
+// The maximum number of pruned block hashes that are kept. Beyond it, the hashes that
+// were pruned first are forgotten.
+const MAX_PRUNED_BLOCK_HASHES: usize = 1_000;
+
+/// The hashes of the blocks that were pruned along with stale forks, so that they're
+/// neither inserted nor requested again. Only the most recently pruned hashes are kept.
+#[derive(Serialize, Deserialize, Default, Debug, Clone, PartialEq)]
+pub struct PrunedBlockHashes {
+    // The hashes, in the order in which they were pruned.
+    hashes: VecDeque<BlockHash>,
+    hashes_set: BTreeSet<BlockHash>,
+}
+
+impl PrunedBlockHashes {
+    pub fn insert(&mut self, block_hash: BlockHash) {
+        if !self.hashes_set.insert(block_hash.clone()) {
+            return;
+        }
+        self.hashes.push_back(block_hash);
+
+        while self.hashes.len() > MAX_PRUNED_BLOCK_HASHES {
+            let oldest = self.hashes.pop_front().expect("hashes cannot be empty");
+            self.hashes_set.remove(&oldest);
+        }
+    }
+
+    pub fn contains(&self, block_hash: &BlockHash) -> bool {
+        self.hashes_set.contains(block_hash)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &BlockHash> {
+        self.hashes.iter()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn forgets_the_oldest_hashes_beyond_the_max() {
+        let block_hash = |i: usize| {
+            let mut bytes = vec![0; 32];
+            bytes[..8].copy_from_slice(&(i as u64).to_le_bytes());
+            BlockHash::from(bytes)
+        };
+
+        let mut pruned = PrunedBlockHashes::default();
+        for i in 0..=MAX_PRUNED_BLOCK_HASHES {
+            pruned.insert(block_hash(i));
+        }
+
+        // Inserting a hash again doesn't change anything.
+        pruned.insert(block_hash(1));
+
+        assert!(!pruned.contains(&block_hash(0)));
+        assert!(pruned.contains(&block_hash(1)));
+        assert!(pruned.contains(&block_hash(MAX_PRUNED_BLOCK_HASHES)));
+        assert_eq!(pruned.iter().count(), MAX_PRUNED_BLOCK_HASHES);
+        assert_eq!(pruned.iter().next(), Some(&block_hash(1)));
+    }
+}
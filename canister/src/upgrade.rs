@@ -0,0 +1,520 @@
+//! Persisting the state across upgrades.
+//!
+//! The state is serialized with CBOR into the upgrades memory, which is laid out as:
+//!
+//! ```text
+//! [0..4)   The length of the serialized state (u32, little-endian).
+//! [4..8)   The magic bytes `BTCS`, marking that the state is versioned.
+//! [8..12)  The version of the state's schema (u32, little-endian).
+//! [12..)   The serialized state.
+//! ```
+//!
+//! States that were written before versioning was introduced are considered to be
+//! version 1. They have no magic bytes and no version, and the serialized state
+//! directly follows its length. The two layouts can't be confused, as a serialized
+//! state starts with a CBOR map header, which is never equal to `B`.
+use crate::{
+    block_header_store::BlockHeaderStore,
+    blocktree::{BlockMetadata, EncodedBlock, FlattenedBlock},
+    fee_policy::FreeCalls,
+    logs, memory,
+    metrics::Metrics,
+    rate_limiter::RateLimiter,
+    state::{FeePercentilesCache, State, SyncingState},
+    unstable_block_store::UnstableBlockStore,
+    unstable_blocks::{LegacyUnstableBlocks, UnstableBlocks},
+    utxo_set::UtxoSetAudit,
+    UtxoSet,
+};
+use bitcoin::{consensus::Decodable, util::uint::Uint256, Block as BitcoinBlock};
+use candid::Principal;
+use ic_btc_interface::{ApiAccessMode, FeePolicy, Fees, Flag, Height, LogComponent, RateLimit};
+use ic_btc_types::{Block, BlockHash};
+use ic_stable_structures::Memory as _;
+use serde::{de::DeserializeOwned, Deserialize};
+use std::{
+    convert::TryInto,
+    io::{self, BufReader, BufWriter, Read, Write},
+};
+
+/// The current version of the state's schema.
+///
+/// NOTE: When `State` changes in a way that requires changes to the data of previous
+/// versions, bump the version, register the migration in `MIGRATIONS`, and decode the
+/// previous versions into the current `State` in `read_state`.
+pub const STATE_VERSION: u32 = 5;
+
+const MAGIC: &[u8; 4] = b"BTCS";
+
+// The offsets of the serialized state in the versioned and the unversioned layouts.
+const VERSIONED_STATE_OFFSET: u64 = 12;
+const UNVERSIONED_STATE_OFFSET: u64 = 4;
+
+// The size of the buffers used to write and read the state.
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+// The names of the migrations. The migration at index `i` migrates a state of
+// version `i + 1` to version `i + 2`:
+//
+// 1. Version 2 introduced the version header. Along with it, the response to process
+//    stopped being persisted, and the blocks of the unstable block tree started being
+//    encoded in the standard bitcoin format rather than in their serde representation.
+// 2. Version 3 moved the unstable blocks from the heap into the `UnstableBlockStore`,
+//    keeping only their headers in the unstable block tree.
+// 3. Version 4 moved the outpoints cache of the unstable blocks into stable memory,
+//    keeping only the transaction outputs with large scripts on the heap.
+// 4. Version 5 recorded the blocks that contain each transaction of the unstable
+//    blocks in the outpoints cache.
+//
+// A state of an older version is decoded in a single pass into a `LegacyState`, whose
+// unstable blocks have the representation of that version, and is then converted into
+// the current `State`.
+//
+// NOTE: Fields that were added to `State` without changing the data of previous
+// versions only need a `#[serde(default)]`.
+const MIGRATIONS: &[&str] = &[
+    "introduce_version_header",
+    "move_unstable_blocks_to_stable_memory",
+    "move_outpoints_cache_to_stable_memory",
+    "record_the_blocks_of_unstable_transactions",
+];
+
+/// The migrations that ran when the state was last read.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MigrationReport {
+    pub from_version: u32,
+    pub to_version: u32,
+    pub migrations: Vec<&'static str>,
+}
+
+/// Writes the state into the upgrades memory.
+pub fn write_state(state: &State) {
+    try_write_state(state, || false).expect("failed to write state");
+}
+
+/// An error returned when writing the state was stopped before it was complete.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WriteStateStopped;
+
+/// Writes the state into the upgrades memory, unless `should_stop` returns true while
+/// the state is being written.
+///
+/// The header is cleared first and written last, so a state that was stopped is never
+/// mistaken for a complete one.
+pub fn try_write_state(
+    state: &State,
+    should_stop: impl Fn() -> bool,
+) -> Result<(), WriteStateStopped> {
+    let memory = memory::get_upgrades_memory();
+    memory::write(&memory, 0, &[0; VERSIONED_STATE_OFFSET as usize]);
+
+    // Serialize the state directly into memory, leaving room for its header.
+    // Writes are buffered, as every write to stable memory has a fixed overhead.
+    let mut writer = BufWriter::with_capacity(
+        BUFFER_SIZE,
+        StoppableWriter {
+            writer: memory::Writer::new(&memory, VERSIONED_STATE_OFFSET),
+            should_stop,
+        },
+    );
+    match ciborium::ser::into_writer(state, &mut writer) {
+        Ok(()) => {}
+        Err(ciborium::ser::Error::Io(_)) => return Err(WriteStateStopped),
+        Err(err) => panic!("failed to encode state: {:?}", err),
+    }
+    let state_end = writer
+        .into_inner()
+        .map_err(|_| WriteStateStopped)?
+        .writer
+        .offset();
+
+    // Write the header.
+    let len: u32 = (state_end - VERSIONED_STATE_OFFSET)
+        .try_into()
+        .expect("state must be smaller than 4GiB");
+    memory::write(&memory, 0, &len.to_le_bytes());
+    memory::write(&memory, 4, MAGIC);
+    memory::write(&memory, 8, &STATE_VERSION.to_le_bytes());
+    Ok(())
+}
+
+// A writer that fails once `should_stop` returns true.
+struct StoppableWriter<W, F> {
+    writer: W,
+    should_stop: F,
+}
+
+impl<W: Write, F: Fn() -> bool> Write for StoppableWriter<W, F> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if (self.should_stop)() {
+            return Err(io::Error::new(io::ErrorKind::Other, "writing was stopped"));
+        }
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+/// Reads the state from the upgrades memory, migrating it to the current version if needed.
+pub fn read_state() -> State {
+    let memory = memory::get_upgrades_memory();
+
+    let mut len_bytes = [0; 4];
+    memory.read(0, &mut len_bytes);
+    let len = u32::from_le_bytes(len_bytes) as u64;
+
+    let mut magic = [0; 4];
+    memory.read(4, &mut magic);
+    let (version, offset) = if &magic == MAGIC {
+        let mut version_bytes = [0; 4];
+        memory.read(8, &mut version_bytes);
+        (u32::from_le_bytes(version_bytes), VERSIONED_STATE_OFFSET)
+    } else {
+        (1, UNVERSIONED_STATE_OFFSET)
+    };
+
+    assert!(
+        (1..=STATE_VERSION).contains(&version),
+        "Cannot read a state of version {}. The current version is {}.",
+        version,
+        STATE_VERSION
+    );
+
+    let reader = BufReader::with_capacity(BUFFER_SIZE, memory::Reader::new(&memory, offset, len));
+    let mut state: State = match version {
+        // The response to process that version 1 persisted is ignored when decoding.
+        1 => decode::<LegacyState<SerdeBlock>, _>(reader).into(),
+        2 => decode::<LegacyState<FullBlock>, _>(reader).into(),
+        3 => decode::<LegacyState<EncodedBlock>, _>(reader).into(),
+        4 => decode(reader),
+        _ => return decode(reader),
+    };
+    state.unstable_blocks.insert_tx_blocks();
+    state.metrics.state_migration = Some(migration_report(version));
+    state
+}
+
+fn decode<T: DeserializeOwned, R: Read>(reader: R) -> T {
+    ciborium::de::from_reader(reader).expect("failed to decode state")
+}
+
+// Logs the migrations of a state from the given version to the current one, which
+// were applied while decoding it.
+fn migration_report(from_version: u32) -> MigrationReport {
+    let mut migrations = vec![];
+    for version in from_version..STATE_VERSION {
+        let name = MIGRATIONS[(version - 1) as usize];
+        logs::info(
+            LogComponent::Upgrade,
+            &format!(
+                "Migrating the state from version {} to version {} ({}).",
+                version,
+                version + 1,
+                name
+            ),
+        );
+        migrations.push(name);
+    }
+
+    MigrationReport {
+        from_version,
+        to_version: STATE_VERSION,
+        migrations,
+    }
+}
+
+// A `State` as it was serialized by older versions of the canister, whose unstable
+// blocks encoded the blocks of their tree as `B`.
+#[derive(Deserialize)]
+#[serde(bound(deserialize = "B: FlattenedBlock"))]
+struct LegacyState<B> {
+    utxos: UtxoSet,
+    unstable_blocks: LegacyUnstableBlocks<B>,
+    syncing_state: SyncingState,
+    blocks_source: Principal,
+    #[serde(default)]
+    fallback_blocks_sources: Vec<Principal>,
+    fee_percentiles_cache: Option<FeePercentilesCache>,
+    stable_block_headers: BlockHeaderStore,
+    fees: Fees,
+    #[serde(default)]
+    fee_policy: FeePolicy,
+    #[serde(default)]
+    free_calls: FreeCalls,
+    #[serde(default)]
+    rate_limit: Option<RateLimit>,
+    metrics: Metrics,
+    api_access: Flag,
+    #[serde(default)]
+    api_access_mode: ApiAccessMode,
+    #[serde(default)]
+    api_access_allow_list: Vec<Principal>,
+    disable_api_if_not_fully_synced: Flag,
+    watchdog_canister: Option<Principal>,
+    #[serde(default)]
+    utxo_set_audit: UtxoSetAudit,
+    #[serde(default)]
+    checkpoints: Vec<(Height, BlockHash)>,
+}
+
+impl<B> From<LegacyState<B>> for State {
+    fn from(legacy: LegacyState<B>) -> Self {
+        Self {
+            utxos: legacy.utxos,
+            unstable_blocks: UnstableBlocks::from(legacy.unstable_blocks),
+            syncing_state: legacy.syncing_state,
+            blocks_source: legacy.blocks_source,
+            fallback_blocks_sources: legacy.fallback_blocks_sources,
+            fee_percentiles_cache: legacy.fee_percentiles_cache,
+            stable_block_headers: legacy.stable_block_headers,
+            fees: legacy.fees,
+            fee_policy: legacy.fee_policy,
+            free_calls: legacy.free_calls,
+            rate_limit: legacy.rate_limit,
+            rate_limiter: RateLimiter::default(),
+            metrics: legacy.metrics,
+            api_access: legacy.api_access,
+            api_access_mode: legacy.api_access_mode,
+            api_access_allow_list: legacy.api_access_allow_list,
+            disable_api_if_not_fully_synced: legacy.disable_api_if_not_fully_synced,
+            watchdog_canister: legacy.watchdog_canister,
+            utxo_set_audit: legacy.utxo_set_audit,
+            checkpoints: legacy.checkpoints,
+            print_logs: Flag::Enabled,
+            state_snapshot: None,
+        }
+    }
+}
+
+// A block of the unstable block tree of version 1, in its serde representation.
+//
+// NOTE: Scripts were encoded as sequences rather than as bytes, but `TxOut` decodes
+// both, so they don't need to be converted.
+#[derive(Deserialize)]
+#[serde(transparent)]
+struct SerdeBlock(Block);
+
+impl FlattenedBlock for SerdeBlock {
+    fn into_metadata(self) -> (BlockMetadata, Option<Uint256>, Option<u64>) {
+        (move_to_block_store(self.0), None, None)
+    }
+}
+
+// A block of the unstable block tree of version 2, in the standard bitcoin format.
+#[derive(Deserialize)]
+struct FullBlock {
+    #[serde(with = "serde_bytes")]
+    bytes: Vec<u8>,
+    #[serde(default)]
+    chainwork: Option<Uint256>,
+}
+
+impl FlattenedBlock for FullBlock {
+    fn into_metadata(self) -> (BlockMetadata, Option<Uint256>, Option<u64>) {
+        let block = Block::new(
+            BitcoinBlock::consensus_decode(self.bytes.as_slice())
+                .expect("decoding a block must succeed"),
+        );
+        (move_to_block_store(block), self.chainwork, None)
+    }
+}
+
+// Moves a block of a legacy unstable block tree into the `UnstableBlockStore`, as it's
+// decoded, returning the metadata that the tree keeps instead.
+fn move_to_block_store(block: Block) -> BlockMetadata {
+    UnstableBlockStore::init().insert(&block);
+    BlockMetadata::from(&block)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        genesis_block, state, test_utils::build_regtest_chain, types::Address, unstable_blocks,
+    };
+    use ic_btc_interface::Network;
+    use ic_btc_types::{BlockHash, OutPoint, Txid};
+    use std::str::FromStr;
+
+    // The fixtures of states written by older versions of the canister.
+    // See `test-data/upgrade/write_fixture.rs` for their contents, and
+    // `test-data/upgrade/generate_fixtures.sh` to regenerate them.
+    const STATE_V1: &[u8] = include_bytes!("../test-data/upgrade/state_v1.bin");
+    const STATE_V2: &[u8] = include_bytes!("../test-data/upgrade/state_v2.bin");
+
+    fn state_with_blocks() -> State {
+        let network = Network::Regtest;
+        let mut state = State::new(2, network, genesis_block(network));
+        for block in build_regtest_chain(5, 3).into_iter().skip(1) {
+            state::insert_block(&mut state, block).unwrap();
+            state::ingest_stable_blocks_into_utxoset(&mut state);
+        }
+        state
+    }
+
+    // Writes the contents of the upgrades memory that were written by an older version.
+    fn write_fixture(fixture: &[u8]) {
+        memory::write(&memory::get_upgrades_memory(), 0, fixture);
+    }
+
+    // Asserts that the unstable blocks of a fixture were migrated.
+    fn assert_fixture_is_migrated(state: &State) {
+        let block_1 =
+            BlockHash::from_str("33654c1a911f7149b37cfbe81c89c91f92d36a9492e713a859a893dfd752ad22")
+                .unwrap();
+        let block_2 =
+            BlockHash::from_str("2270c42d34ec8f84103d0b502ca95e17381277e693819eb5c184e83d85fbbe2c")
+                .unwrap();
+        let address_1 = Address::from_str("mmWPA5LJp5Yc5ZJqJ222D5b38F868LY2B5").unwrap();
+        let address_2 = Address::from_str("mfuh5wvtSkBJStrbjugMa5HobErgsSRjEg").unwrap();
+        let unstable_blocks = &state.unstable_blocks;
+
+        // The blocks are in the unstable block store, and the tree only has their headers.
+        let blocks = unstable_blocks::get_blocks(unstable_blocks);
+        assert_eq!(blocks.len(), 3);
+        for block in blocks {
+            let stored = unstable_blocks.get_block(&block.block_hash).unwrap();
+            assert_eq!(stored.header(), &block.header);
+        }
+        assert_eq!(
+            unstable_blocks::get_main_chain(unstable_blocks)
+                .tip()
+                .block_hash,
+            block_2
+        );
+
+        // The outpoints cache is in stable memory.
+        let block_1_txdata = unstable_blocks
+            .get_block(&block_1)
+            .unwrap()
+            .txdata()
+            .to_vec();
+        let block_2_txdata = unstable_blocks
+            .get_block(&block_2)
+            .unwrap()
+            .txdata()
+            .to_vec();
+        let coinbase_1 = OutPoint::new(block_1_txdata[0].txid(), 0);
+        assert_eq!(
+            unstable_blocks.get_tx_blocks(&block_1_txdata[0].txid()),
+            vec![block_1.clone()]
+        );
+        let (tx_out, height) = unstable_blocks.get_tx_out(&coinbase_1).unwrap();
+        assert_eq!((tx_out.value, height), (5_000_000_000, 1));
+        assert_eq!(
+            unstable_blocks.get_added_outpoints(&block_1, &address_1),
+            vec![coinbase_1.clone()]
+        );
+        assert_eq!(
+            unstable_blocks.get_removed_outpoints(&block_2, &address_1),
+            vec![coinbase_1]
+        );
+
+        let mut added = unstable_blocks.get_added_outpoints(&block_2, &address_2);
+        added.sort();
+        let mut expected: Vec<_> = block_2_txdata
+            .iter()
+            .map(|tx| OutPoint::new(tx.txid(), 0))
+            .collect();
+        expected.sort();
+        assert_eq!(added, expected);
+        assert_eq!(
+            unstable_blocks.get_added_outpoints(&block_2, &address_1),
+            vec![]
+        );
+        assert!(unstable_blocks
+            .get_tx_out(&OutPoint::new(Txid::from(vec![0; 32]), 0))
+            .is_none());
+    }
+
+    #[test]
+    fn there_is_a_migration_for_every_previous_version() {
+        assert_eq!(MIGRATIONS.len() as u32, STATE_VERSION - 1);
+    }
+
+    #[test]
+    fn reads_the_current_version() {
+        let state = state_with_blocks();
+        write_state(&state);
+
+        let mut header = [0; 12];
+        memory::get_upgrades_memory().read(0, &mut header);
+        assert_eq!(&header[4..8], MAGIC);
+        assert_eq!(header[8..12], STATE_VERSION.to_le_bytes());
+
+        let new_state = read_state();
+        assert_eq!(new_state.metrics.state_migration, None);
+        assert!(new_state == state);
+    }
+
+    #[test]
+    fn reads_and_migrates_version_1() {
+        write_fixture(STATE_V1);
+
+        let state = read_state();
+        assert_eq!(
+            state.metrics.state_migration,
+            Some(MigrationReport {
+                from_version: 1,
+                to_version: STATE_VERSION,
+                migrations: vec![
+                    "introduce_version_header",
+                    "move_unstable_blocks_to_stable_memory",
+                    "move_outpoints_cache_to_stable_memory",
+                    "record_the_blocks_of_unstable_transactions",
+                ],
+            })
+        );
+        assert_fixture_is_migrated(&state);
+    }
+
+    #[test]
+    fn reads_and_migrates_version_2() {
+        write_fixture(STATE_V2);
+
+        let state = read_state();
+        assert_eq!(
+            state.metrics.state_migration,
+            Some(MigrationReport {
+                from_version: 2,
+                to_version: STATE_VERSION,
+                migrations: vec![
+                    "move_unstable_blocks_to_stable_memory",
+                    "move_outpoints_cache_to_stable_memory",
+                    "record_the_blocks_of_unstable_transactions",
+                ],
+            })
+        );
+        assert_fixture_is_migrated(&state);
+    }
+
+    #[test]
+    fn migrated_states_are_written_in_the_current_version() {
+        write_fixture(STATE_V1);
+        let state = read_state();
+
+        write_state(&state);
+        let mut new_state = read_state();
+        assert_eq!(new_state.metrics.state_migration.take(), None);
+        assert_fixture_is_migrated(&new_state);
+        assert_eq!(
+            unstable_blocks::get_blocks(&new_state.unstable_blocks),
+            unstable_blocks::get_blocks(&state.unstable_blocks)
+        );
+    }
+
+    #[test]
+    #[should_panic(expected = "Cannot read a state of version 6. The current version is 5.")]
+    fn cannot_read_a_newer_version() {
+        write_state(&state_with_blocks());
+        memory::write(
+            &memory::get_upgrades_memory(),
+            8,
+            &(STATE_VERSION + 1).to_le_bytes(),
+        );
+
+        read_state();
+    }
+}
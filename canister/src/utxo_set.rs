@@ -1,26 +1,29 @@
 use crate::{
+    logs,
     memory::Memory,
     multi_iter::MultiIter,
-    runtime::{inc_performance_counter, performance_counter, print},
+    runtime::{inc_performance_counter, performance_counter},
     types::{Address, AddressUtxo, AddressUtxoRange, Slicing, TxOut, Utxo},
 };
 use bitcoin::{Script, TxOut as BitcoinTxOut};
-use ic_btc_interface::{Height, Network, Satoshi};
+use ic_btc_interface::{Height, LogComponent, LogLevel, Network, Satoshi};
 use ic_btc_types::{Block, BlockHash, OutPoint, Transaction, Txid};
 use ic_stable_structures::{storable::Blob, BoundedStorable, StableBTreeMap, Storable as _};
 use serde::{Deserialize, Serialize};
 use std::{collections::BTreeSet, iter::Iterator, str::FromStr};
+mod audit;
+mod undo;
 mod utxos;
 mod utxos_delta;
+pub use audit::UtxoSetAudit;
 use std::convert::TryFrom;
+use undo::{BlockUndo, UndoRecord, UndoStore};
 use utxos::Utxos;
 use utxos_delta::UtxosDelta;
 
 lazy_static::lazy_static! {
-    pub static ref DUPLICATE_TX_IDS: [Txid; 2] = [
-        Txid::from_str("d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599").unwrap(),
-        Txid::from_str("e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468").unwrap(),
-    ];
+    pub static ref DUPLICATE_TX_IDS: [Txid; 2] =
+        ic_btc_validation::DUPLICATE_TX_IDS.map(|txid| Txid::from_str(txid).unwrap());
 }
 
 #[derive(Serialize, Deserialize)]
@@ -55,6 +58,15 @@ pub struct UtxoSet {
 
     /// A block that is currently being ingested into the UtxoSet. Used for time slicing.
     pub ingesting_block: Option<IngestingBlock>,
+
+    // The undo records of the most recently ingested blocks.
+    // NOTE: Stable structures don't need to be serialized.
+    #[serde(skip, default = "UndoStore::init")]
+    undo_records: UndoStore,
+
+    /// A block whose ingestion is currently being reverted. Used for time slicing.
+    #[serde(default)]
+    pub reverting_block: Option<RevertingBlock>,
 }
 
 impl UtxoSet {
@@ -67,6 +79,8 @@ impl UtxoSet {
             next_height: 0,
             ingesting_block: None,
             should_time_slice: default_should_time_slice(),
+            undo_records: UndoStore::init(),
+            reverting_block: None,
         }
     }
 
@@ -84,6 +98,10 @@ impl UtxoSet {
             "Cannot ingest new block while previous block (height {}) isn't fully ingested",
             self.next_height
         );
+        assert!(
+            self.reverting_block.is_none(),
+            "Cannot ingest new block while a block is being reverted"
+        );
 
         // Store in the state the new block to be ingested.
         self.ingesting_block = Some(IngestingBlock::new(block));
@@ -111,6 +129,7 @@ impl UtxoSet {
             mut next_input_idx,
             mut next_output_idx,
             mut utxos_delta,
+            mut undo,
             mut stats,
         } = match self.ingesting_block.take() {
             Some(p) => p,
@@ -124,6 +143,7 @@ impl UtxoSet {
                 next_input_idx,
                 next_output_idx,
                 &mut utxos_delta,
+                &mut undo,
                 &mut stats,
             ) {
                 stats.ins_total += performance_counter() - ins_start;
@@ -135,6 +155,7 @@ impl UtxoSet {
                     next_input_idx,
                     next_output_idx,
                     utxos_delta,
+                    undo,
                     stats,
                 });
 
@@ -147,16 +168,91 @@ impl UtxoSet {
         }
 
         stats.ins_total += performance_counter() - ins_start;
-        print(&format!(
-            "[INSTRUCTION COUNT] Ingest Block {}: {:?}",
-            self.next_height, stats
-        ));
+        logs::log_block(
+            LogLevel::Debug,
+            LogComponent::Ingestion,
+            &block.block_hash(),
+            &format!(
+                "[INSTRUCTION COUNT] Ingest Block {}: {:?}",
+                self.next_height, stats
+            ),
+        );
+
+        // Keep the data needed to revert the block in case of a deep reorg.
+        self.undo_records
+            .insert(self.next_height, &UndoRecord::new(&block, undo));
 
         // Block ingestion complete.
         self.next_height += 1;
         Some(Slicing::Done((block.block_hash(), stats)))
     }
 
+    /// Returns the lowest height of a block whose ingestion can be reverted, if any.
+    pub fn min_revertible_height(&self) -> Option<Height> {
+        self.undo_records.oldest_height()
+    }
+
+    /// Reverts the ingestion of the most recently ingested block, using its undo record.
+    ///
+    /// Returns `Slicing::Done(block)` with the reverted block if reverting is complete, or
+    /// `Slicing::Paused` if it hasn't fully completed due to instruction limits. In the
+    /// latter case, one or more additional calls are necessary to finish reverting the block.
+    ///
+    /// Panics if a block is being ingested or if the block's undo record isn't available.
+    pub fn revert_block(&mut self) -> Slicing<(), Block> {
+        assert!(
+            self.ingesting_block.is_none(),
+            "Cannot revert a block while another block is being ingested"
+        );
+
+        let height = self
+            .next_height
+            .checked_sub(1)
+            .expect("there must be a block to revert");
+
+        let mut reverting_block = match self.reverting_block.take() {
+            Some(reverting_block) => reverting_block,
+            None => RevertingBlock {
+                record: self.undo_records.get(height).unwrap_or_else(|| {
+                    panic!("Undo record of block at height {} must exist", height)
+                }),
+                next_spent_idx: 0,
+                next_created_idx: 0,
+            },
+        };
+
+        // Restore the spent UTXOs before removing the created ones. Outputs that were
+        // both created and spent in the block are then restored and removed again.
+        let num_spent = reverting_block.record.undo().spent.len();
+        for idx in reverting_block.next_spent_idx..num_spent {
+            if (self.should_time_slice)() {
+                reverting_block.next_spent_idx = idx;
+                self.reverting_block = Some(reverting_block);
+                return Slicing::Paused(());
+            }
+
+            let (outpoint, txout, utxo_height) = reverting_block.record.undo().spent[idx].clone();
+            self.restore_utxo(outpoint, txout, utxo_height);
+        }
+        reverting_block.next_spent_idx = num_spent;
+
+        let num_created = reverting_block.record.undo().created.len();
+        for idx in reverting_block.next_created_idx..num_created {
+            if (self.should_time_slice)() {
+                reverting_block.next_created_idx = idx;
+                self.reverting_block = Some(reverting_block);
+                return Slicing::Paused(());
+            }
+
+            let outpoint = reverting_block.record.undo().created[idx].clone();
+            self.remove_created_utxo(&outpoint, height);
+        }
+
+        self.undo_records.remove(height);
+        self.next_height = height;
+        Slicing::Done(reverting_block.record.block())
+    }
+
     /// Returns the balance of the given address.
     pub fn get_balance(&self, address: &Address) -> Satoshi {
         let mut balance = self.balances.get(address).unwrap_or(0);
@@ -270,17 +366,18 @@ impl UtxoSet {
         start_input_idx: usize,
         start_output_idx: usize,
         utxos_delta: &mut UtxosDelta,
+        undo: &mut BlockUndo,
         stats: &mut BlockIngestionStats,
     ) -> Slicing<(usize, usize), ()> {
         let ins_start = performance_counter();
-        let res = self.remove_inputs(tx, start_input_idx, utxos_delta);
+        let res = self.remove_inputs(tx, start_input_idx, utxos_delta, undo);
         stats.ins_remove_inputs += performance_counter() - ins_start;
         if let Slicing::Paused(input_idx) = res {
             return Slicing::Paused((input_idx, 0));
         }
 
         let ins_start = performance_counter();
-        let res = self.insert_outputs(tx, start_output_idx, utxos_delta, stats);
+        let res = self.insert_outputs(tx, start_output_idx, utxos_delta, undo, stats);
         stats.ins_insert_outputs += performance_counter() - ins_start;
         if let Slicing::Paused(output_idx) = res {
             return Slicing::Paused((tx.input().len(), output_idx));
@@ -295,6 +392,7 @@ impl UtxoSet {
         tx: &Transaction,
         start_idx: usize,
         utxos_delta: &mut UtxosDelta,
+        undo: &mut BlockUndo,
     ) -> Slicing<usize, ()> {
         if tx.is_coin_base() {
             return Slicing::Done(());
@@ -309,6 +407,8 @@ impl UtxoSet {
             let outpoint = (&input.previous_output).into();
             match self.utxos.remove(&outpoint) {
                 Some((txout, height)) => {
+                    undo.spent.push((outpoint.clone(), txout.clone(), height));
+
                     if let Ok(address) = Address::from_script(
                         &Script::from(txout.script_pubkey.clone()),
                         self.network,
@@ -362,6 +462,7 @@ impl UtxoSet {
         tx: &Transaction,
         start_idx: usize,
         utxos_delta: &mut UtxosDelta,
+        undo: &mut BlockUndo,
         stats: &mut BlockIngestionStats,
     ) -> Slicing<usize, ()> {
         for (vout, output) in tx.output().iter().enumerate().skip(start_idx) {
@@ -375,11 +476,9 @@ impl UtxoSet {
                 stats.ins_txids += performance_counter() - ins_start;
 
                 let ins_start = performance_counter();
-                self.insert_utxo(
-                    OutPoint::new(txid, vout as u32),
-                    output.clone(),
-                    utxos_delta,
-                );
+                let outpoint = OutPoint::new(txid, vout as u32);
+                self.insert_utxo(outpoint.clone(), output.clone(), utxos_delta);
+                undo.created.push(outpoint);
                 stats.ins_insert_utxos += performance_counter() - ins_start;
             }
         }
@@ -440,6 +539,91 @@ impl UtxoSet {
         }
     }
 
+    // Re-inserts a UTXO that was spent by a reverted block.
+    fn restore_utxo(&mut self, outpoint: OutPoint, txout: TxOut, height: Height) {
+        if let Ok(address) =
+            Address::from_script(&Script::from(txout.script_pubkey.clone()), self.network)
+        {
+            self.address_utxos.insert(
+                Blob::try_from(
+                    AddressUtxo {
+                        address: address.clone(),
+                        height,
+                        outpoint: outpoint.clone(),
+                    }
+                    .to_bytes()
+                    .as_ref(),
+                )
+                .unwrap(),
+                (),
+            );
+
+            // Mirrors `remove_inputs`, where the balance is only updated for non-zero values.
+            if txout.value != 0 {
+                let address_balance = self.balances.get(&address).unwrap_or(0);
+                self.balances.insert(address, address_balance + txout.value);
+            }
+        }
+
+        let outpoint_already_exists = self.utxos.insert(outpoint.clone(), (txout, height));
+        assert!(
+            !outpoint_already_exists,
+            "Cannot restore outpoint {:?} because it already exists.",
+            outpoint
+        );
+    }
+
+    // Removes a UTXO that was created by a reverted block at the given height.
+    fn remove_created_utxo(&mut self, outpoint: &OutPoint, height: Height) {
+        let (txout, _) = self
+            .utxos
+            .remove(outpoint)
+            .unwrap_or_else(|| panic!("Outpoint {:?} not found.", outpoint));
+
+        if let Ok(address) =
+            Address::from_script(&Script::from(txout.script_pubkey.clone()), self.network)
+        {
+            let found = self.address_utxos.remove(
+                &Blob::try_from(
+                    AddressUtxo {
+                        address: address.clone(),
+                        height,
+                        outpoint: outpoint.clone(),
+                    }
+                    .to_bytes()
+                    .as_ref(),
+                )
+                .unwrap(),
+            );
+            assert!(
+                found.is_some(),
+                "Outpoint {:?} not found in the index.",
+                outpoint
+            );
+
+            if txout.value != 0 {
+                let address_balance = self.balances.get(&address).unwrap_or_else(|| {
+                    panic!(
+                        "Address {} must exist in the balances map (trying to remove outpoint {:?})",
+                        address, outpoint
+                    )
+                });
+
+                match address_balance - txout.value {
+                    // Remove the address from the map if balance is zero.
+                    0 => self.balances.remove(&address),
+                    // Update the balance in the map.
+                    balance => self.balances.insert(address, balance),
+                };
+            }
+        }
+    }
+
+    #[cfg(test)]
+    pub fn remove_undo_record(&mut self, height: Height) {
+        self.undo_records.remove(height);
+    }
+
     #[cfg(test)]
     pub fn get_total_supply(&self) -> Satoshi {
         self.utxos.iter().map(|(_, (v, _))| v.value).sum()
@@ -464,6 +648,8 @@ pub struct IngestingBlock {
     pub next_output_idx: usize,
     stats: BlockIngestionStats,
     utxos_delta: UtxosDelta,
+    #[serde(default)]
+    undo: BlockUndo,
 }
 
 impl IngestingBlock {
@@ -475,6 +661,7 @@ impl IngestingBlock {
             next_output_idx: 0,
             stats: BlockIngestionStats::default(),
             utxos_delta: UtxosDelta::default(),
+            undo: BlockUndo::default(),
         }
     }
 
@@ -492,10 +679,20 @@ impl IngestingBlock {
             next_output_idx,
             stats: BlockIngestionStats::default(),
             utxos_delta: UtxosDelta::default(),
+            undo: BlockUndo::default(),
         }
     }
 }
 
+/// A state for maintaining a stable block whose ingestion is partially reverted.
+/// Used for time slicing.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq)]
+pub struct RevertingBlock {
+    record: UndoRecord,
+    next_spent_idx: usize,
+    next_created_idx: usize,
+}
+
 // Various profiling stats for tracking the performance of block ingestion.
 #[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq, Default)]
 pub struct BlockIngestionStats {
@@ -546,6 +743,8 @@ impl PartialEq for UtxoSet {
             && self.network == other.network
             && self.next_height == other.next_height
             && self.ingesting_block == other.ingesting_block
+            && self.reverting_block == other.reverting_block
+            && self.undo_records == other.undo_records
             && is_stable_btreemap_equal(&self.address_utxos, &other.address_utxos)
             && is_stable_btreemap_equal(&self.balances, &other.balances)
     }
@@ -582,6 +781,7 @@ mod test {
                 0,
                 0,
                 &mut UtxosDelta::default(),
+                &mut BlockUndo::default(),
                 &mut BlockIngestionStats::default()
             ),
             Slicing::Done(())
@@ -661,7 +861,7 @@ mod test {
 
         let mut utxo = UtxoSet::new(network);
 
-        let coinbase_tx = TransactionBuilder::coinbase()
+        let coinbase_tx = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .build();
         ingest_tx(&mut utxo, &coinbase_tx);
@@ -785,13 +985,13 @@ mod test {
         let mut utxo_set = UtxoSet::new(network);
         let address = random_p2pkh_address(network);
 
-        let tx_out_1 = TransactionBuilder::coinbase()
+        let tx_out_1 = TransactionBuilder::coinbase(0)
             .with_output(&address, 1000)
             .build()
             .output()[0]
             .clone();
 
-        let tx_out_2 = TransactionBuilder::coinbase()
+        let tx_out_2 = TransactionBuilder::coinbase(0)
             .with_output(&address, 2000)
             .build()
             .output()[0]
@@ -812,7 +1012,7 @@ mod test {
         let address_1 = random_p2pkh_address(network);
         let address_2 = random_p2pkh_address(network);
 
-        let tx_1 = TransactionBuilder::coinbase()
+        let tx_1 = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .build();
 
@@ -848,7 +1048,7 @@ mod test {
         let address_1 = random_p2pkh_address(network);
         let address_2 = random_p2pkh_address(network);
 
-        let tx_1 = TransactionBuilder::coinbase()
+        let tx_1 = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .with_output(&address_1, 0) // an input with zero value
             .build();
@@ -892,7 +1092,7 @@ mod test {
         let mut utxo_set = UtxoSet::new(network);
         let address_1 = random_p2pkh_address(network);
         let address_2 = random_p2pkh_address(network);
-        let tx_1 = TransactionBuilder::coinbase()
+        let tx_1 = TransactionBuilder::coinbase(0)
             .with_output(&address_1, 1000)
             .with_output(&address_1, 0) // an input with zero value
             .build();
@@ -953,7 +1153,7 @@ mod test {
 
             // Transaction 0: A coinbase tx with `tx_cardinality` inputs, each giving 1 Satoshi to
             // address 1.
-            let mut tx_0 = TransactionBuilder::coinbase();
+            let mut tx_0 = TransactionBuilder::coinbase(0);
             for i in 0..tx_cardinality {
                 tx_0 = tx_0.with_output(&address_1, 1).with_lock_time(i as u32)
             }
@@ -1154,6 +1354,93 @@ mod test {
 
     // A predicate that allows the Utxo Set to ingest `ingestion_rate` inputs/outputs,
     // then triggers time-slicing.
+    #[allow(clippy::type_complexity)]
+    fn snapshot(
+        utxo_set: &UtxoSet,
+    ) -> (
+        Height,
+        Vec<(OutPoint, (TxOut, Height))>,
+        Vec<Blob<{ AddressUtxo::MAX_SIZE as usize }>>,
+        Vec<(Address, u64)>,
+    ) {
+        (
+            utxo_set.next_height,
+            utxo_set.utxos.iter().collect(),
+            utxo_set.address_utxos.iter().map(|(k, _)| k).collect(),
+            utxo_set.balances.iter().collect(),
+        )
+    }
+
+    #[test]
+    fn revert_block() {
+        let network = Network::Regtest;
+        let address_1 = random_p2pkh_address(network);
+        let address_2 = random_p2pkh_address(network);
+        let mut utxo_set = UtxoSet::new(network);
+
+        let coinbase_0 = TransactionBuilder::coinbase(0)
+            .with_output(&address_1, 1000)
+            .build();
+        let block_0 = BlockBuilder::genesis()
+            .with_transaction(coinbase_0.clone())
+            .build();
+        assert!(matches!(
+            utxo_set.ingest_block(block_0.clone()),
+            Slicing::Done(_)
+        ));
+        let state_after_block_0 = snapshot(&utxo_set);
+
+        // A block that spends an output of the previous block, as well as an output
+        // that's created within the block itself.
+        let coinbase_1 = TransactionBuilder::coinbase(1)
+            .with_output(&address_1, 500)
+            .build();
+        let tx_1 = TransactionBuilder::new()
+            .with_input(OutPoint::new(coinbase_0.txid(), 0))
+            .with_output(&address_2, 600)
+            .with_output(&address_1, 400)
+            .build();
+        let tx_2 = TransactionBuilder::new()
+            .with_input(OutPoint::new(tx_1.txid(), 1))
+            .with_output(&address_2, 400)
+            .build();
+        let block_1 = BlockBuilder::with_prev_header(block_0.header())
+            .with_transaction(coinbase_1)
+            .with_transaction(tx_1)
+            .with_transaction(tx_2)
+            .build();
+        assert!(matches!(
+            utxo_set.ingest_block(block_1.clone()),
+            Slicing::Done(_)
+        ));
+        assert_eq!(utxo_set.get_balance(&address_2), 1000);
+        assert_eq!(utxo_set.min_revertible_height(), Some(0));
+
+        // Revert the block with time slicing.
+        utxo_set.should_time_slice = ingestion_rate_predicate(1);
+        let mut num_rounds = 1;
+        let reverted_block = loop {
+            match utxo_set.revert_block() {
+                Slicing::Paused(()) => num_rounds += 1,
+                Slicing::Done(block) => break block,
+            }
+        };
+
+        assert!(num_rounds > 1);
+        assert_eq!(reverted_block, block_1);
+        assert_eq!(snapshot(&utxo_set), state_after_block_0);
+        assert_eq!(utxo_set.get_balance(&address_1), 1000);
+        assert_eq!(utxo_set.get_balance(&address_2), 0);
+
+        // The undo record of the reverted block is discarded.
+        assert_eq!(utxo_set.undo_records.get(1), None);
+
+        // The block can be ingested again.
+        utxo_set.should_time_slice = default_should_time_slice();
+        assert!(matches!(utxo_set.ingest_block(block_1), Slicing::Done(_)));
+        assert_eq!(utxo_set.get_balance(&address_2), 1000);
+    }
+
     fn ingestion_rate_predicate(ingestion_rate: u32) -> Box<dyn FnMut() -> bool> {
         let mut count = ingestion_rate + 1;
         Box::new(move || {
@@ -1167,4 +1454,29 @@ mod test {
             }
         })
     }
+
+    #[test]
+    fn iterates_over_utxos_by_txid() {
+        let mut utxos = Utxos::default();
+        // Scripts that are stored in the small, medium and large UTXOs respectively.
+        let script_sizes = [25, 100, 300];
+        for i in 0..30u8 {
+            let script_size = script_sizes[i as usize % script_sizes.len()];
+            let outpoint = OutPoint::new(Txid::from(vec![i / 2; 32]), 256 - i as u32);
+            let txout = TxOut {
+                value: i as u64,
+                script_pubkey: vec![0; script_size],
+            };
+            utxos.insert(outpoint, (txout, 0));
+        }
+
+        let txids: Vec<_> = utxos
+            .iter_by_txid()
+            .map(|(outpoint, _)| outpoint.txid)
+            .collect();
+        let mut sorted_txids = txids.clone();
+        sorted_txids.sort();
+        assert_eq!(txids.len(), 30);
+        assert_eq!(txids, sorted_txids);
+    }
 }
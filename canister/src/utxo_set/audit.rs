@@ -0,0 +1,554 @@
+use super::UtxoSet;
+use crate::{
+    logs,
+    types::{Address, AddressUtxo, AddressUtxoRange, Slicing},
+};
+use bitcoin::Script;
+use ic_btc_interface::{
+    Height, LogComponent, UtxoSetAuditMismatch, UtxoSetAuditMismatchKind, UtxoSetAuditReport,
+    UtxoSetAuditStatus,
+};
+use ic_btc_types::OutPoint;
+use ic_stable_structures::{storable::Blob, BoundedStorable, Storable as _};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    convert::TryFrom,
+    ops::{Bound, RangeBounds},
+};
+
+/// The interval at which audits are started automatically, in seconds.
+pub const AUDIT_INTERVAL_SECS: u64 = 24 * 60 * 60;
+
+// The maximum number of mismatches that are kept in an audit's report.
+const MAX_REPORTED_MISMATCHES: usize = 100;
+
+type AddressUtxoKey = Blob<{ AddressUtxo::MAX_SIZE as usize }>;
+
+/// Audits that the UTXOs, the address UTXOs and the balances of the UTXO set
+/// agree with each other.
+///
+/// The audit walks the indexes in time-sliced steps, so that it can run in the
+/// background without exceeding the instructions limit.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Debug, Default)]
+pub struct UtxoSetAudit {
+    current: Option<AuditRun>,
+    last_completed: Option<UtxoSetAuditReport>,
+    runs_completed: u64,
+}
+
+impl UtxoSetAudit {
+    /// Starts a new audit. Returns false if an audit is already in progress.
+    pub fn start(&mut self, utxo_set: &UtxoSet, now: u64) -> bool {
+        if self.current.is_some() {
+            return false;
+        }
+
+        logs::info(
+            LogComponent::Audit,
+            &format!(
+                "Starting an audit of the UTXO set at height {}.",
+                utxo_set.next_height()
+            ),
+        );
+        self.current = Some(AuditRun {
+            cursor: Cursor::AddressUtxos { last_key: None },
+            report: UtxoSetAuditReport {
+                started_at: now,
+                completed_at: None,
+                start_height: utxo_set.next_height(),
+                address_utxos_checked: 0,
+                balances_checked: 0,
+                num_mismatches: 0,
+                mismatches: vec![],
+            },
+        });
+        true
+    }
+
+    /// Returns true if no audit is in progress and enough time has passed
+    /// since the last one completed for a new audit to start.
+    pub fn is_due(&self, now: u64) -> bool {
+        if self.current.is_some() {
+            return false;
+        }
+
+        match &self.last_completed {
+            Some(report) => {
+                now >= report.completed_at.unwrap_or(report.started_at) + AUDIT_INTERVAL_SECS
+            }
+            None => true,
+        }
+    }
+
+    /// Cancels the audit in progress, if any.
+    pub fn cancel(&mut self) {
+        if self.current.take().is_some() {
+            logs::info(LogComponent::Audit, "Cancelled the audit of the UTXO set.");
+        }
+    }
+
+    /// Continues the audit in progress, if any.
+    /// Returns true if the audit has completed.
+    pub fn audit_continue(&mut self, utxo_set: &mut UtxoSet, now: u64) -> bool {
+        let run = match &mut self.current {
+            Some(run) => run,
+            None => return false,
+        };
+
+        if let Slicing::Paused(()) = utxo_set.audit_continue(run) {
+            return false;
+        }
+
+        let mut report = self
+            .current
+            .take()
+            .expect("audit must be in progress")
+            .report;
+        report.completed_at = Some(now);
+        logs::info(
+            LogComponent::Audit,
+            &format!(
+                "Completed the audit of the UTXO set. Checked {} address UTXOs and {} balances, found {} mismatches.",
+                report.address_utxos_checked, report.balances_checked, report.num_mismatches
+            ),
+        );
+        self.last_completed = Some(report);
+        self.runs_completed += 1;
+        true
+    }
+
+    /// Returns the report of the audit in progress, if any.
+    pub fn current(&self) -> Option<&UtxoSetAuditReport> {
+        self.current.as_ref().map(|run| &run.report)
+    }
+
+    /// Returns the report of the most recently completed audit, if any.
+    pub fn last_completed(&self) -> Option<&UtxoSetAuditReport> {
+        self.last_completed.as_ref()
+    }
+
+    pub fn runs_completed(&self) -> u64 {
+        self.runs_completed
+    }
+
+    pub fn status(&self) -> UtxoSetAuditStatus {
+        UtxoSetAuditStatus {
+            current: self.current().cloned(),
+            last_completed: self.last_completed.clone(),
+            runs_completed: self.runs_completed,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+struct AuditRun {
+    cursor: Cursor,
+    report: UtxoSetAuditReport,
+}
+
+// The position of an audit in the UTXO set's indexes.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+enum Cursor {
+    // Walking the address UTXOs, checking that each of them refers to a UTXO.
+    AddressUtxos {
+        last_key: Option<Vec<u8>>,
+    },
+
+    // Walking the balances, checking that each of them is equal to the sum of
+    // the address's UTXOs.
+    Balances {
+        last_address: Option<Address>,
+        partial_sum: Option<PartialSum>,
+    },
+}
+
+// The sum of an address's UTXOs that had been computed when the audit was paused.
+#[derive(Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+struct PartialSum {
+    address: Address,
+    last_key: Option<Vec<u8>>,
+    sum: u64,
+    // The `next_height` of the UTXO set when the sum was computed. If the UTXO set
+    // changes before the audit resumes, the sum is computed again from scratch.
+    next_height: Height,
+}
+
+impl UtxoSet {
+    // Continues the given audit run until either all the entries are checked, in which
+    // case `Slicing::Done` is returned, or the audit is time-sliced.
+    fn audit_continue(&mut self, run: &mut AuditRun) -> Slicing<(), ()> {
+        if self.ingesting_block.is_some() || self.reverting_block.is_some() {
+            // The indexes are only consistent between blocks.
+            return Slicing::Paused(());
+        }
+
+        loop {
+            if (self.should_time_slice)() {
+                return Slicing::Paused(());
+            }
+
+            match &mut run.cursor {
+                Cursor::AddressUtxos { last_key } => match self.next_address_utxo(last_key) {
+                    Some((key, address_utxo)) => {
+                        self.audit_address_utxo(&address_utxo, &mut run.report);
+                        *last_key = Some(key);
+                    }
+                    None => {
+                        run.cursor = Cursor::Balances {
+                            last_address: None,
+                            partial_sum: None,
+                        };
+                    }
+                },
+                Cursor::Balances {
+                    last_address,
+                    partial_sum,
+                } => {
+                    let (address, mut sum, mut last_key) = match partial_sum.take() {
+                        Some(p) if p.next_height == self.next_height => {
+                            (p.address, p.sum, p.last_key)
+                        }
+                        // The UTXO set changed since the audit was paused. Start over.
+                        Some(p) => (p.address, 0, None),
+                        None => match self.next_balance_address(last_address) {
+                            Some(address) => (address, 0, None),
+                            None => return Slicing::Done(()),
+                        },
+                    };
+
+                    let balance = match self.balances.get(&address) {
+                        Some(balance) => balance,
+                        None => {
+                            // The balance was removed since the audit was paused.
+                            *last_address = Some(address);
+                            continue;
+                        }
+                    };
+
+                    let range = AddressUtxoRange::new(&address, &None);
+                    let start_bound = match &last_key {
+                        Some(key) => Bound::Excluded(address_utxo_key(key)),
+                        None => range.start_bound().cloned(),
+                    };
+
+                    for (key, _) in self
+                        .address_utxos
+                        .range((start_bound, range.end_bound().cloned()))
+                    {
+                        let address_utxo = AddressUtxo::from_bytes(Cow::Borrowed(key.as_slice()));
+                        // The range can include the UTXOs of other addresses that
+                        // have this address as a prefix.
+                        if address_utxo.address == address {
+                            if let Some((txout, _)) = self.utxos.get(&address_utxo.outpoint) {
+                                sum += txout.value;
+                            }
+                        }
+                        last_key = Some(key.as_slice().to_vec());
+
+                        if (self.should_time_slice)() {
+                            *partial_sum = Some(PartialSum {
+                                address,
+                                last_key,
+                                sum,
+                                next_height: self.next_height,
+                            });
+                            return Slicing::Paused(());
+                        }
+                    }
+
+                    run.report.balances_checked += 1;
+                    if sum != balance {
+                        report_mismatch(
+                            &mut run.report,
+                            UtxoSetAuditMismatchKind::BalanceMismatch,
+                            &address,
+                            format!(
+                                "The balance is {}, but the address's UTXOs add up to {}.",
+                                balance, sum
+                            ),
+                        );
+                    } else if balance == 0 {
+                        report_mismatch(
+                            &mut run.report,
+                            UtxoSetAuditMismatchKind::ZeroBalance,
+                            &address,
+                            "The balance is zero.".to_string(),
+                        );
+                    }
+                    *last_address = Some(address);
+                }
+            }
+        }
+    }
+
+    // Returns the address UTXO that follows the given key, along with its key.
+    fn next_address_utxo(&self, last_key: &Option<Vec<u8>>) -> Option<(Vec<u8>, AddressUtxo)> {
+        let start_bound = match last_key {
+            Some(key) => Bound::Excluded(address_utxo_key(key)),
+            None => Bound::Unbounded,
+        };
+
+        self.address_utxos
+            .range((start_bound, Bound::Unbounded))
+            .next()
+            .map(|(key, _)| {
+                (
+                    key.as_slice().to_vec(),
+                    AddressUtxo::from_bytes(Cow::Borrowed(key.as_slice())),
+                )
+            })
+    }
+
+    // Returns the address with a balance that follows the given address.
+    fn next_balance_address(&self, last_address: &Option<Address>) -> Option<Address> {
+        let start_bound = match last_address {
+            Some(address) => Bound::Excluded(address.clone()),
+            None => Bound::Unbounded,
+        };
+
+        self.balances
+            .range((start_bound, Bound::Unbounded))
+            .next()
+            .map(|(address, _)| address)
+    }
+
+    // Checks that the given address UTXO refers to a UTXO of the same height and address,
+    // and that the address has a balance if the UTXO has a value.
+    fn audit_address_utxo(&self, address_utxo: &AddressUtxo, report: &mut UtxoSetAuditReport) {
+        report.address_utxos_checked += 1;
+
+        let AddressUtxo {
+            address,
+            height,
+            outpoint,
+        } = address_utxo;
+
+        match self.utxos.get(outpoint) {
+            None => report_mismatch(
+                report,
+                UtxoSetAuditMismatchKind::MissingUtxo,
+                address,
+                format!(
+                    "The UTXO {} indexed at height {} doesn't exist.",
+                    display(outpoint),
+                    height
+                ),
+            ),
+            Some((txout, utxo_height)) => {
+                let utxo_address =
+                    Address::from_script(&Script::from(txout.script_pubkey), self.network).ok();
+                if utxo_height != *height || utxo_address.as_ref() != Some(address) {
+                    report_mismatch(
+                        report,
+                        UtxoSetAuditMismatchKind::UtxoMismatch,
+                        address,
+                        format!(
+                            "The UTXO {} is indexed at height {}, but it was created at height {} for address {}.",
+                            display(outpoint),
+                            height,
+                            utxo_height,
+                            utxo_address.map_or("<none>".to_string(), |a| a.to_string())
+                        ),
+                    );
+                } else if txout.value > 0 && self.balances.get(address).is_none() {
+                    report_mismatch(
+                        report,
+                        UtxoSetAuditMismatchKind::MissingBalance,
+                        address,
+                        format!(
+                            "The UTXO {} has a value of {}, but the address has no balance.",
+                            display(outpoint),
+                            txout.value
+                        ),
+                    );
+                }
+            }
+        }
+    }
+}
+
+fn address_utxo_key(bytes: &[u8]) -> AddressUtxoKey {
+    Blob::try_from(bytes).expect("an address UTXO key must fit in a blob")
+}
+
+fn display(outpoint: &OutPoint) -> String {
+    format!("{}:{}", outpoint.txid, outpoint.vout)
+}
+
+fn report_mismatch(
+    report: &mut UtxoSetAuditReport,
+    kind: UtxoSetAuditMismatchKind,
+    address: &Address,
+    message: String,
+) {
+    report.num_mismatches += 1;
+    if report.mismatches.len() < MAX_REPORTED_MISMATCHES {
+        logs::error(
+            LogComponent::Audit,
+            &format!("UTXO set mismatch for address {}: {}", address, message),
+        );
+        report.mismatches.push(UtxoSetAuditMismatch {
+            kind,
+            address: address.to_string(),
+            message,
+        });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
+    use ic_btc_interface::Network;
+
+    // Returns a UTXO set with a few addresses, each owning a few UTXOs.
+    fn utxo_set_with_addresses(num_addresses: usize) -> (UtxoSet, Vec<Address>) {
+        let network = Network::Regtest;
+        let mut utxo_set = UtxoSet::new(network);
+        let addresses: Vec<Address> = (0..num_addresses)
+            .map(|_| random_p2pkh_address(network))
+            .collect();
+
+        let mut block = BlockBuilder::genesis();
+        for (i, address) in addresses.iter().enumerate() {
+            let mut tx = TransactionBuilder::coinbase(0).with_lock_time(i as u32);
+            for value in 1..=3 {
+                tx = tx.with_output(address, value * 1000);
+            }
+            block = block.with_transaction(tx.build());
+        }
+        utxo_set.ingest_block(block.build());
+
+        (utxo_set, addresses)
+    }
+
+    fn audit(utxo_set: &mut UtxoSet) -> UtxoSetAuditReport {
+        let mut audit = UtxoSetAudit::default();
+        assert!(audit.start(utxo_set, 0));
+        assert!(audit.audit_continue(utxo_set, 1));
+        audit.last_completed().cloned().unwrap()
+    }
+
+    fn mismatch_kinds(report: &UtxoSetAuditReport) -> Vec<UtxoSetAuditMismatchKind> {
+        report.mismatches.iter().map(|m| m.kind).collect()
+    }
+
+    #[test]
+    fn consistent_utxo_set_has_no_mismatches() {
+        let (mut utxo_set, _) = utxo_set_with_addresses(5);
+
+        let report = audit(&mut utxo_set);
+        assert_eq!(report.address_utxos_checked, 15);
+        assert_eq!(report.balances_checked, 5);
+        assert_eq!(report.num_mismatches, 0);
+        assert_eq!(report.completed_at, Some(1));
+    }
+
+    #[test]
+    fn detects_missing_utxo_and_wrong_balance() {
+        let (mut utxo_set, addresses) = utxo_set_with_addresses(3);
+
+        // Remove one of the address's UTXOs from the UTXOs only.
+        let key = utxo_set
+            .address_utxos
+            .range(AddressUtxoRange::new(&addresses[1], &None))
+            .next()
+            .unwrap()
+            .0;
+        let outpoint = AddressUtxo::from_bytes(Cow::Borrowed(key.as_slice())).outpoint;
+        utxo_set.utxos.remove(&outpoint);
+
+        let report = audit(&mut utxo_set);
+        assert_eq!(
+            mismatch_kinds(&report),
+            vec![
+                UtxoSetAuditMismatchKind::MissingUtxo,
+                UtxoSetAuditMismatchKind::BalanceMismatch
+            ]
+        );
+        assert!(report
+            .mismatches
+            .iter()
+            .all(|m| m.address == addresses[1].to_string()));
+    }
+
+    #[test]
+    fn detects_zero_and_missing_balances() {
+        let (mut utxo_set, addresses) = utxo_set_with_addresses(2);
+
+        let zero_balance_address = random_p2pkh_address(Network::Regtest);
+        utxo_set.balances.insert(zero_balance_address.clone(), 0);
+        utxo_set.balances.remove(&addresses[0]);
+
+        let report = audit(&mut utxo_set);
+        // The three UTXOs of the first address have no balance.
+        assert_eq!(
+            report
+                .mismatches
+                .iter()
+                .filter(|m| m.kind == UtxoSetAuditMismatchKind::MissingBalance)
+                .count(),
+            3
+        );
+        assert!(report.mismatches.contains(&UtxoSetAuditMismatch {
+            kind: UtxoSetAuditMismatchKind::ZeroBalance,
+            address: zero_balance_address.to_string(),
+            message: "The balance is zero.".to_string(),
+        }));
+        assert_eq!(report.num_mismatches, 4);
+    }
+
+    #[test]
+    fn audit_is_time_sliced() {
+        let (mut utxo_set, addresses) = utxo_set_with_addresses(4);
+        let expected = audit(&mut utxo_set);
+
+        // Time slice after every check.
+        let mut paused = false;
+        utxo_set.should_time_slice = Box::new(move || {
+            paused = !paused;
+            paused
+        });
+
+        // Introduce a mismatch to verify it's found across time slices.
+        let balance = utxo_set.balances.get(&addresses[2]).unwrap();
+        utxo_set.balances.insert(addresses[2].clone(), balance + 1);
+
+        let mut audit = UtxoSetAudit::default();
+        assert!(audit.start(&utxo_set, 0));
+        let mut num_slices = 0;
+        while !audit.audit_continue(&mut utxo_set, 1) {
+            num_slices += 1;
+            assert!(audit.current().is_some());
+        }
+
+        // Each of the address UTXOs and balances is checked in a slice of its own,
+        // and the UTXOs of each address are summed one per slice.
+        assert!(num_slices > 15 + 4);
+        let report = audit.last_completed().unwrap();
+        assert_eq!(report.address_utxos_checked, expected.address_utxos_checked);
+        assert_eq!(report.balances_checked, expected.balances_checked);
+        assert_eq!(
+            mismatch_kinds(report),
+            vec![UtxoSetAuditMismatchKind::BalanceMismatch]
+        );
+        assert_eq!(audit.runs_completed(), 1);
+        assert!(audit.current().is_none());
+    }
+
+    #[test]
+    fn audits_are_started_periodically() {
+        let (mut utxo_set, _) = utxo_set_with_addresses(1);
+        let mut audit = UtxoSetAudit::default();
+        assert!(audit.is_due(0));
+
+        assert!(audit.start(&utxo_set, 0));
+        assert!(!audit.is_due(0));
+        assert!(!audit.start(&utxo_set, 0));
+
+        assert!(audit.audit_continue(&mut utxo_set, 10));
+        assert!(!audit.is_due(10 + AUDIT_INTERVAL_SECS - 1));
+        assert!(audit.is_due(10 + AUDIT_INTERVAL_SECS));
+    }
+}
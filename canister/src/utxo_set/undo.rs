@@ -0,0 +1,228 @@
+use crate::{memory::Memory, types::TxOut};
+use bitcoin::consensus::Decodable;
+use ic_btc_interface::Height;
+use ic_btc_types::{Block, OutPoint};
+use ic_stable_structures::{storable::Blob, BoundedStorable, StableBTreeMap, Storable};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    convert::{TryFrom, TryInto},
+};
+
+/// The number of most recent stable blocks for which undo records are kept.
+pub const MAX_UNDO_RECORDS: u32 = 100;
+
+// Values in a `StableBTreeMap` are bounded, so records are stored in chunks of this size.
+const CHUNK_SIZE: usize = 4 * 1024;
+
+/// The changes a block made to the UTXO set.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq, Default)]
+pub struct BlockUndo {
+    /// The UTXOs spent by the block, along with the heights they were created at.
+    pub spent: Vec<(OutPoint, TxOut, Height)>,
+
+    /// The outpoints of the UTXOs created by the block.
+    pub created: Vec<OutPoint>,
+}
+
+/// The data needed to revert the ingestion of a stable block.
+#[derive(Serialize, Deserialize, PartialEq, Clone, Debug, Eq)]
+pub struct UndoRecord {
+    /// The block in the standard bitcoin format.
+    /// It's kept so that the block can become the anchor again after a rewind.
+    #[serde(with = "serde_bytes")]
+    block: Vec<u8>,
+
+    undo: BlockUndo,
+}
+
+impl UndoRecord {
+    pub fn new(block: &Block, undo: BlockUndo) -> Self {
+        let mut bytes = vec![];
+        block
+            .consensus_encode(&mut bytes)
+            .expect("encoding a block must succeed");
+        Self { block: bytes, undo }
+    }
+
+    pub fn block(&self) -> Block {
+        Block::new(
+            bitcoin::Block::consensus_decode(self.block.as_slice())
+                .expect("decoding a stored block must succeed"),
+        )
+    }
+
+    pub fn undo(&self) -> &BlockUndo {
+        &self.undo
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+struct ChunkKey {
+    height: Height,
+    index: u32,
+}
+
+impl Storable for ChunkKey {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        // Big-endian encoding preserves the ordering of the keys.
+        let mut bytes = self.height.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+        Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Self {
+            height: Height::from_be_bytes(bytes[0..4].try_into().unwrap()),
+            index: u32::from_be_bytes(bytes[4..8].try_into().unwrap()),
+        }
+    }
+}
+
+impl BoundedStorable for ChunkKey {
+    const MAX_SIZE: u32 = 8;
+    const IS_FIXED_SIZE: bool = true;
+}
+
+/// Stores the undo records of the most recent stable blocks, indexed by height.
+pub struct UndoStore {
+    chunks: StableBTreeMap<ChunkKey, Blob<CHUNK_SIZE>, Memory>,
+}
+
+// NOTE: `PartialEq` is only available in tests as it would be impractically
+// expensive in production.
+#[cfg(test)]
+impl PartialEq for UndoStore {
+    fn eq(&self, other: &Self) -> bool {
+        use crate::test_utils::is_stable_btreemap_equal;
+        is_stable_btreemap_equal(&self.chunks, &other.chunks)
+    }
+}
+
+impl UndoStore {
+    pub fn init() -> Self {
+        Self {
+            chunks: StableBTreeMap::init(crate::memory::get_undo_records_memory()),
+        }
+    }
+
+    /// Stores the undo record of the block at the given height, and discards
+    /// records that are older than `MAX_UNDO_RECORDS` blocks.
+    pub fn insert(&mut self, height: Height, record: &UndoRecord) {
+        self.remove(height);
+
+        let mut bytes = vec![];
+        ciborium::ser::into_writer(record, &mut bytes).expect("encoding a record must succeed");
+        for (index, chunk) in bytes.chunks(CHUNK_SIZE).enumerate() {
+            self.chunks.insert(
+                ChunkKey {
+                    height,
+                    index: index as u32,
+                },
+                Blob::try_from(chunk).unwrap(),
+            );
+        }
+
+        if let Some(oldest_height) = height.checked_sub(MAX_UNDO_RECORDS) {
+            self.remove(oldest_height);
+        }
+    }
+
+    /// Returns the undo record of the block at the given height, if it's stored.
+    pub fn get(&self, height: Height) -> Option<UndoRecord> {
+        let mut bytes = vec![];
+        for (_, chunk) in self.chunks.range(Self::range(height)) {
+            bytes.extend_from_slice(chunk.as_slice());
+        }
+
+        if bytes.is_empty() {
+            return None;
+        }
+
+        Some(ciborium::de::from_reader(bytes.as_slice()).expect("decoding a record must succeed"))
+    }
+
+    /// Removes the undo record of the block at the given height.
+    pub fn remove(&mut self, height: Height) {
+        let keys: Vec<_> = self
+            .chunks
+            .range(Self::range(height))
+            .map(|(key, _)| key)
+            .collect();
+        for key in keys {
+            self.chunks.remove(&key);
+        }
+    }
+
+    /// Returns the height of the oldest stored undo record.
+    pub fn oldest_height(&self) -> Option<Height> {
+        self.chunks.iter().next().map(|(key, _)| key.height)
+    }
+
+    fn range(height: Height) -> std::ops::RangeInclusive<ChunkKey> {
+        ChunkKey { height, index: 0 }..=ChunkKey {
+            height,
+            index: u32::MAX,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_utils::BlockBuilder;
+    use ic_btc_types::Txid;
+
+    fn record(num_spent: usize) -> (Block, UndoRecord) {
+        let block = BlockBuilder::genesis().build();
+        let spent = (0..num_spent)
+            .map(|i| {
+                (
+                    OutPoint::new(Txid::from(vec![(i % 256) as u8; 32]), i as u32),
+                    TxOut {
+                        value: i as u64,
+                        script_pubkey: vec![1; 100],
+                    },
+                    i as Height,
+                )
+            })
+            .collect();
+        let undo = BlockUndo {
+            spent,
+            created: vec![OutPoint::new(Txid::from(vec![0; 32]), 0)],
+        };
+        (block.clone(), UndoRecord::new(&block, undo))
+    }
+
+    #[test]
+    fn stores_records_in_chunks() {
+        let mut store = UndoStore::init();
+        // A record large enough to span multiple chunks.
+        let (block, record) = record(1_000);
+        store.insert(7, &record);
+
+        assert!(store.chunks.len() > 1);
+        assert_eq!(store.get(7), Some(record.clone()));
+        assert_eq!(store.get(7).unwrap().block(), block);
+        assert_eq!(store.get(6), None);
+        assert_eq!(store.get(8), None);
+        assert_eq!(store.oldest_height(), Some(7));
+
+        store.remove(7);
+        assert_eq!(store.get(7), None);
+        assert!(store.chunks.is_empty());
+    }
+
+    #[test]
+    fn discards_old_records() {
+        let mut store = UndoStore::init();
+        let (_, record) = record(1);
+        for height in 0..MAX_UNDO_RECORDS + 5 {
+            store.insert(height, &record);
+        }
+
+        assert_eq!(store.oldest_height(), Some(5));
+        assert_eq!(store.get(4), None);
+        assert_eq!(store.get(MAX_UNDO_RECORDS + 4), Some(record));
+    }
+}
@@ -1,13 +1,15 @@
 use crate::{
     memory::{get_utxos_medium_memory, get_utxos_small_memory, Memory},
+    multi_iter::MultiIter,
     state::{UTXO_KEY_SIZE, UTXO_VALUE_MAX_SIZE_MEDIUM, UTXO_VALUE_MAX_SIZE_SMALL},
     types::{Storable, TxOut},
 };
 use ic_btc_interface::Height;
 use ic_btc_types::OutPoint;
-#[cfg(test)]
-use ic_stable_structures::{btreemap, Memory as MemoryTrait};
-use ic_stable_structures::{storable::Blob, StableBTreeMap, Storable as StableStructuresStorable};
+use ic_stable_structures::{
+    btreemap, storable::Blob, Memory as MemoryTrait, StableBTreeMap,
+    Storable as StableStructuresStorable,
+};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::convert::TryFrom;
@@ -144,11 +146,30 @@ impl Utxos {
 
     /// Gets an iterator over the entries of the map.
     /// NOTE: The entries are not guaranteed to be sorted in any particular way.
-    #[cfg(test)]
     pub fn iter(&self) -> Iter<Memory> {
         Iter::new(self)
     }
 
+    /// Gets an iterator over the entries of the map, sorted by their txids.
+    /// NOTE: The entries of a transaction are not guaranteed to be sorted by their vouts.
+    pub fn iter_by_txid(&self) -> impl Iterator<Item = (OutPoint, (TxOut, Height))> + '_ {
+        let small_utxos = self
+            .small_utxos
+            .iter()
+            .map(|(key, value)| decode_utxo(key.as_slice(), value.as_slice()));
+        let medium_utxos = self
+            .medium_utxos
+            .iter()
+            .map(|(key, value)| decode_utxo(key.as_slice(), value.as_slice()));
+        let large_utxos = self
+            .large_utxos
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()));
+
+        // Every map is sorted by the bytes of the outpoints, which start with the txid.
+        MultiIter::new(MultiIter::new(small_utxos, medium_utxos), large_utxos)
+    }
+
     pub fn len(&self) -> u64 {
         self.large_utxos.len() as u64 + self.small_utxos.len() + self.medium_utxos.len()
     }
@@ -160,7 +181,6 @@ impl Utxos {
 }
 
 /// An iterator over the entries in [`Utxos`].
-#[cfg(test)]
 #[must_use = "iterators are lazy and do nothing unless consumed"]
 pub struct Iter<'a, M: MemoryTrait> {
     small_utxos_iter: btreemap::Iter<'a, Blob<UTXO_KEY_SIZE>, Blob<UTXO_VALUE_MAX_SIZE_SMALL>, M>,
@@ -168,7 +188,6 @@ pub struct Iter<'a, M: MemoryTrait> {
     large_utxos_iter: std::collections::btree_map::Iter<'a, OutPoint, (TxOut, Height)>,
 }
 
-#[cfg(test)]
 impl<'a> Iter<'a, Memory> {
     fn new(utxos: &'a Utxos) -> Self {
         Self {
@@ -179,25 +198,18 @@ impl<'a> Iter<'a, Memory> {
     }
 }
 
-#[cfg(test)]
 impl<M: MemoryTrait + Clone> Iterator for Iter<'_, M> {
     type Item = (OutPoint, (TxOut, Height));
 
     fn next(&mut self) -> Option<Self::Item> {
         // First, iterate over the small utxos.
         if let Some((key_bytes, value_bytes)) = self.small_utxos_iter.next() {
-            return Some((
-                OutPoint::from_bytes(std::borrow::Cow::Borrowed(key_bytes.as_slice())),
-                <(TxOut, Height)>::from_bytes(value_bytes.as_slice().to_vec()),
-            ));
+            return Some(decode_utxo(key_bytes.as_slice(), value_bytes.as_slice()));
         }
 
         // Second, iterate over the medium utxos.
         if let Some((key_bytes, value_bytes)) = self.medium_utxos_iter.next() {
-            return Some((
-                OutPoint::from_bytes(std::borrow::Cow::Borrowed(key_bytes.as_slice())),
-                <(TxOut, Height)>::from_bytes(value_bytes.as_slice().to_vec()),
-            ));
+            return Some(decode_utxo(key_bytes.as_slice(), value_bytes.as_slice()));
         }
 
         // Finally, iterate over the large utxos.
@@ -207,6 +219,13 @@ impl<M: MemoryTrait + Clone> Iterator for Iter<'_, M> {
     }
 }
 
+fn decode_utxo(key_bytes: &[u8], value_bytes: &[u8]) -> (OutPoint, (TxOut, Height)) {
+    (
+        OutPoint::from_bytes(std::borrow::Cow::Borrowed(key_bytes)),
+        <(TxOut, Height)>::from_bytes(value_bytes.to_vec()),
+    )
+}
+
 fn init_small_utxos() -> StableBTreeMap<Blob<UTXO_KEY_SIZE>, Blob<UTXO_VALUE_MAX_SIZE_SMALL>, Memory>
 {
     StableBTreeMap::init(get_utxos_small_memory())
@@ -1,6 +1,11 @@
-use crate::{blocktree::BlockDoesNotExtendTree, state::State, unstable_blocks};
-use bitcoin::BlockHeader;
-use ic_btc_validation::HeaderStore;
+use crate::{
+    blocktree::BlockDoesNotExtendTree, state::State, types::TxOut, unstable_blocks, UtxoSet,
+};
+use bitcoin::{hashes::Hash, BlockHeader};
+use ic_btc_interface::Height;
+use ic_btc_types::{Block, OutPoint, Txid};
+use ic_btc_validation::{HeaderStore, TxOutStore};
+use std::collections::{BTreeMap, BTreeSet};
 
 /// A structure passed to the validation crate to validate a specific block header.
 pub struct ValidationContext<'a> {
@@ -8,11 +13,19 @@ pub struct ValidationContext<'a> {
     // BlockHash is stored in order to avoid repeatedly calling to
     // BlockHeader::block_hash() which is expensive.
     chain: Vec<(&'a BlockHeader, ic_btc_types::BlockHash)>,
+    // The height of the main chain, passed in by the caller as computing it walks
+    // the whole tree of unstable blocks.
+    main_chain_height: Height,
 }
 
 impl<'a> ValidationContext<'a> {
     /// Initialize a `ValidationContext` for the given block header.
-    pub fn new(state: &'a State, header: &BlockHeader) -> Result<Self, BlockDoesNotExtendTree> {
+    /// `main_chain_height` is the height of the main chain of the state.
+    pub fn new(
+        state: &'a State,
+        header: &BlockHeader,
+        main_chain_height: Height,
+    ) -> Result<Self, BlockDoesNotExtendTree> {
         // Retrieve the chain that the given header extends.
         // The given header must extend one of the unstable blocks.
         let prev_block_hash = header.prev_blockhash.into();
@@ -23,7 +36,11 @@ impl<'a> ValidationContext<'a> {
             .map(|block| (block.header(), block.block_hash()))
             .collect();
 
-        Ok(Self { state, chain })
+        Ok(Self {
+            state,
+            chain,
+            main_chain_height,
+        })
     }
 
     /// Initialize a `ValidationContext` for the given block header.
@@ -31,15 +48,16 @@ impl<'a> ValidationContext<'a> {
     pub fn new_with_next_block_headers(
         state: &'a State,
         header: &BlockHeader,
+        main_chain_height: Height,
     ) -> Result<Self, BlockDoesNotExtendTree> {
         let prev_block_hash = header.prev_blockhash.into();
         let next_block_headers_chain = state
             .unstable_blocks
             .get_next_block_headers_chain_with_tip(prev_block_hash);
         if next_block_headers_chain.is_empty() {
-            Self::new(state, header)
+            Self::new(state, header, main_chain_height)
         } else {
-            let mut context = Self::new(state, next_block_headers_chain[0].0)?;
+            let mut context = Self::new(state, next_block_headers_chain[0].0, main_chain_height)?;
             for item in next_block_headers_chain.iter() {
                 context.chain.push(item.clone())
             }
@@ -48,6 +66,60 @@ impl<'a> ValidationContext<'a> {
     }
 }
 
+impl<'a> ValidationContext<'a> {
+    /// Resolves the transaction outputs spent by the given block, except for the ones
+    /// created by the block itself.
+    ///
+    /// Outputs of unstable blocks are only resolved if they're created on the chain that
+    /// the block extends, so that a block can't spend the outputs of another fork.
+    pub fn resolve_inputs(&self, block: &Block) -> BlockInputs<'a> {
+        let block_txids: BTreeSet<Txid> = block.txdata().iter().map(|tx| tx.txid()).collect();
+
+        // The heights of the blocks on the chain that the block extends.
+        let chain_heights: BTreeMap<&ic_btc_types::BlockHash, Height> = self
+            .chain
+            .iter()
+            .enumerate()
+            .map(|(i, (_, block_hash))| (block_hash, self.state.utxos.next_height() + i as u32))
+            .collect();
+
+        let mut tx_outs = BTreeMap::new();
+        for tx in block.txdata() {
+            for input in tx.input() {
+                if input.previous_output.is_null() {
+                    continue;
+                }
+
+                let outpoint: OutPoint = (&input.previous_output).into();
+                if block_txids.contains(&outpoint.txid) {
+                    continue;
+                }
+
+                if let Some(utxo) = self.state.utxos.get_utxo(&outpoint) {
+                    tx_outs.insert(outpoint, utxo);
+                } else if let Some((txout, _)) = self.state.unstable_blocks.get_tx_out(&outpoint) {
+                    // The outpoints cache is shared by all the forks, so an output is only
+                    // resolved if a block on this chain has the transaction that created it.
+                    let height = self
+                        .state
+                        .unstable_blocks
+                        .get_tx_blocks(&outpoint.txid)
+                        .iter()
+                        .find_map(|block_hash| chain_heights.get(block_hash));
+                    if let Some(height) = height {
+                        tx_outs.insert(outpoint, (txout, *height));
+                    }
+                }
+            }
+        }
+
+        BlockInputs {
+            utxos: &self.state.utxos,
+            tx_outs,
+        }
+    }
+}
+
 /// Implements the `HeaderStore` trait that's used for validating headers.
 impl<'a> HeaderStore for ValidationContext<'a> {
     fn get_with_block_hash(&self, hash: &bitcoin::BlockHash) -> Option<BlockHeader> {
@@ -83,16 +155,66 @@ impl<'a> HeaderStore for ValidationContext<'a> {
             None
         }
     }
+
+    fn main_chain_height(&self) -> u32 {
+        self.main_chain_height
+    }
+
+    fn get_extra_checkpoints(&self) -> Vec<(u32, bitcoin::BlockHash)> {
+        self.state
+            .checkpoints
+            .iter()
+            .map(|(height, block_hash)| {
+                (
+                    *height,
+                    bitcoin::BlockHash::from_slice(&block_hash.clone().to_vec())
+                        .expect("block hash must be valid"),
+                )
+            })
+            .collect()
+    }
+}
+
+/// The transaction outputs spent by a block, resolved from the chain that the block extends.
+pub struct BlockInputs<'a> {
+    utxos: &'a UtxoSet,
+    tx_outs: BTreeMap<OutPoint, (TxOut, Height)>,
+}
+
+impl<'a> BlockInputs<'a> {
+    /// Returns the resolved outputs, so that they aren't looked up again when the block
+    /// is inserted.
+    pub fn into_tx_outs(self) -> BTreeMap<OutPoint, (TxOut, Height)> {
+        self.tx_outs
+    }
+}
+
+/// Implements the `TxOutStore` trait that's used for validating the transactions of blocks.
+impl<'a> TxOutStore for BlockInputs<'a> {
+    fn get_value(&self, outpoint: &bitcoin::OutPoint) -> Option<u64> {
+        self.tx_outs
+            .get(&OutPoint::from(outpoint))
+            .map(|(txout, _)| txout.value)
+    }
+
+    fn is_unspent(&self, outpoint: &bitcoin::OutPoint) -> bool {
+        // Only the UTXO set is checked, as the outpoints cache doesn't distinguish
+        // between forks, and the same transaction can be in blocks of several forks.
+        self.utxos.get_utxo(&outpoint.into()).is_some()
+    }
 }
 
 #[cfg(test)]
 mod test {
     use super::*;
     use crate::{
-        state::{ingest_stable_blocks_into_utxoset, insert_block},
-        test_utils::{build_chain, BlockBuilder},
+        state::{
+            ingest_stable_blocks_into_utxoset, insert_block, main_chain_height, InsertBlockError,
+        },
+        test_utils::{build_chain, random_p2pkh_address, BlockBuilder, TransactionBuilder},
     };
     use ic_btc_interface::Network;
+    use ic_btc_validation::ValidateBlockError;
     use proptest::prelude::*;
     use std::str::FromStr;
 
@@ -121,7 +243,7 @@ mod test {
         let block_3 = BlockBuilder::with_prev_header(block_2.header()).build();
 
         let validation_context =
-            ValidationContext::new_with_next_block_headers(&state, block_3.header()).unwrap();
+            ValidationContext::new_with_next_block_headers(&state, block_3.header(), 0).unwrap();
 
         assert_eq!(
             validation_context.chain,
@@ -137,11 +259,105 @@ mod test {
         let not_inserted_2 = BlockBuilder::with_prev_header(not_inserted_1.header()).build();
 
         assert!(matches!(
-            ValidationContext::new_with_next_block_headers(&state, not_inserted_2.header()),
+            ValidationContext::new_with_next_block_headers(&state, not_inserted_2.header(), 0),
             Err(BlockDoesNotExtendTree(..))
         ));
     }
 
+    #[test]
+    fn blocks_cannot_spend_the_outputs_of_other_forks() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+        let genesis = BlockBuilder::genesis().build();
+        let mut state = State::new(2, network, genesis.clone());
+
+        // Two forks, where only the coinbase of fork A pays the address.
+        let fork_a = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(
+                TransactionBuilder::coinbase(1)
+                    .with_output(&address, 1000)
+                    .build(),
+            )
+            .build();
+        let fork_b = BlockBuilder::with_prev_header(genesis.header()).build();
+        insert_block(&mut state, fork_a.clone()).unwrap();
+        insert_block(&mut state, fork_b.clone()).unwrap();
+
+        let outpoint = OutPoint::new(fork_a.txdata()[0].txid(), 0);
+        let tx = TransactionBuilder::new()
+            .with_input(outpoint.clone())
+            .with_output(&address, 1000)
+            .build();
+
+        // The output of fork A isn't part of fork B's chain.
+        let block_on_b = BlockBuilder::with_prev_header(fork_b.header())
+            .with_transaction(tx.clone())
+            .build();
+        assert_eq!(
+            insert_block(&mut state, block_on_b),
+            Err(InsertBlockError::InvalidBlock(
+                ValidateBlockError::InputNotFound {
+                    outpoint: outpoint.into()
+                }
+            ))
+        );
+
+        let block_on_a = BlockBuilder::with_prev_header(fork_a.header())
+            .with_transaction(tx)
+            .build();
+        assert_eq!(insert_block(&mut state, block_on_a), Ok(()));
+    }
+
+    #[test]
+    fn blocks_can_spend_the_outputs_of_transactions_that_are_in_several_forks() {
+        let network = Network::Regtest;
+        let address = random_p2pkh_address(network);
+        let genesis = BlockBuilder::genesis().build();
+        let mut state = State::new(2, network, genesis.clone());
+
+        // Two forks that share their coinbase, where fork B also has another transaction.
+        let coinbase = TransactionBuilder::coinbase(1)
+            .with_output(&address, 1000)
+            .build();
+        let fork_a = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(coinbase.clone())
+            .build();
+        let fork_b = BlockBuilder::with_prev_header(genesis.header())
+            .with_transaction(coinbase.clone())
+            .with_transaction(
+                TransactionBuilder::new()
+                    .with_input(OutPoint::new(genesis.txdata()[0].txid(), 0))
+                    .with_output(&address, 1)
+                    .build(),
+            )
+            .build();
+        insert_block(&mut state, fork_a.clone()).unwrap();
+        insert_block(&mut state, fork_b.clone()).unwrap();
+
+        let outpoint = OutPoint::new(coinbase.txid(), 0);
+        assert_eq!(
+            state.unstable_blocks.get_tx_blocks(&coinbase.txid()).len(),
+            2
+        );
+
+        // The shared coinbase is on the chains of both forks.
+        for fork in [fork_a, fork_b] {
+            let block = BlockBuilder::with_prev_header(fork.header())
+                .with_transaction(
+                    TransactionBuilder::new()
+                        .with_input(outpoint.clone())
+                        .with_output(&address, 1000)
+                        .build(),
+                )
+                .build();
+            let context = ValidationContext::new(&state, block.header(), 1).unwrap();
+            assert_eq!(
+                context.resolve_inputs(&block).into_tx_outs().get(&outpoint),
+                Some(&(TxOut::from(&coinbase.output()[0]), 1))
+            );
+        }
+    }
+
     proptest! {
         #![proptest_config(ProptestConfig::with_cases(10))]
         #[test]
@@ -163,7 +379,11 @@ mod test {
 
             // Try validating the last block header (which wasn't inserted above).
             let validation_context =
-                ValidationContext::new(&state, blocks[blocks.len() - 1].header()).unwrap();
+                ValidationContext::new(
+                    &state,
+                    blocks[blocks.len() - 1].header(),
+                    main_chain_height(&state),
+                ).unwrap();
 
             // Assert the height is correct.
             assert_eq!(validation_context.height(), blocks.len() as u32 - 2);
@@ -0,0 +1,33 @@
+#!/usr/bin/env bash
+#
+# Generates the fixtures of states serialized by older versions of the canister.
+#
+# Each fixture is the contents of the upgrades memory as written by the `pre_upgrade`
+# hook of the canister at the last revision of its version. It's generated by building
+# `write_fixture.rs` as a binary of the canister in a worktree at that revision.
+#
+# Usage: ./generate_fixtures.sh
+set -euo pipefail
+
+SCRIPT_DIR=$(cd "$(dirname "$0")" && pwd)
+REPO_ROOT=$(git -C "$SCRIPT_DIR" rev-parse --show-toplevel)
+
+# The version of each fixture along with the last revision of that version.
+FIXTURES=(
+  "1 7f9bc87a5e8a62c5594d4941acf65977b2e904d7"
+  "2 144b30002a5727b5e79546972b667f78cf2acbfd"
+)
+
+for FIXTURE in "${FIXTURES[@]}"; do
+  read -r VERSION REVISION <<< "$FIXTURE"
+  WORKTREE=$(mktemp -d)
+
+  git -C "$REPO_ROOT" worktree add --detach "$WORKTREE" "$REVISION"
+  mkdir -p "$WORKTREE/canister/src/bin"
+  cp "$SCRIPT_DIR/write_fixture.rs" "$WORKTREE/canister/src/bin/"
+
+  (cd "$WORKTREE" && cargo run --release -p ic-btc-canister --bin write_fixture -- \
+    "$SCRIPT_DIR/state_v$VERSION.bin")
+
+  git -C "$REPO_ROOT" worktree remove --force "$WORKTREE"
+done
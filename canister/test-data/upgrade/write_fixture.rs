@@ -0,0 +1,63 @@
+//! Writes the upgrades memory of a regtest state with the following unstable blocks:
+//!
+//! ```text
+//! genesis -> block_1 -> block_2
+//! ```
+//!
+//! `block_1` pays its coinbase to one address, and `block_2` contains a transaction that
+//! spends that output and pays it to another address.
+//!
+//! This binary is built and run by `generate_fixtures.sh` against the canister at the
+//! revisions of older versions, so it only uses APIs that are common to all of them.
+use bitcoin::{consensus::Decodable, Block as BitcoinBlock};
+use ic_btc_canister::{memory::get_upgrades_memory, unstable_blocks, with_state_mut};
+use ic_btc_interface::{Config, Network};
+use ic_btc_types::Block;
+use ic_stable_structures::Memory;
+use std::convert::TryInto;
+
+const BLOCKS: &[&str] = &[
+    // block_1
+    "0000002006226e46111a0b59caaf126043eb5bbf28c34f3a5e332a1fc7b2b73cf188910f85d425649dc4a49f\
+     e16969133279213dff5eb917e3e4a79916d7e13de947c16132e8494dffff7f2000000000010100000001000000\
+     0000000000000000000000000000000000000000000000000000000000ffffffff0151ffffffff0100f2052a01\
+     0000001976a91441b517c5b1efdb4252b447b945fa10ea4bc4c46888ac00000000",
+    // block_2
+    "0000002022ad52d7df93a859a813e792946ad3921fc9891ce8fb7cb349711f911a4c65339e44c5bf7fc8b6c6\
+     51f0bc3a7ca553ff2d24b596bc6ef7f2f8465a9140ce7bba8aea494dffff7f2000000000020100000001000000\
+     0000000000000000000000000000000000000000000000000000000000ffffffff0152ffffffff0100f2052a01\
+     0000001976a914044cdf1ba255fb78e6419f146c22265f07e4466d88ac00000000010000000185d425649dc4a4\
+     9fe16969133279213dff5eb917e3e4a79916d7e13de947c1610000000000ffffffff0100111024010000001976\
+     a914044cdf1ba255fb78e6419f146c22265f07e4466d88ac00000000",
+];
+
+fn main() {
+    let path = std::env::args()
+        .nth(1)
+        .expect("usage: write_fixture <path>");
+
+    ic_btc_canister::init(Config {
+        network: Network::Regtest,
+        stability_threshold: 144,
+        ..Config::default()
+    });
+    with_state_mut(|s| {
+        for block in BLOCKS {
+            let block = Block::new(
+                BitcoinBlock::consensus_decode(hex::decode(block).unwrap().as_slice()).unwrap(),
+            );
+            unstable_blocks::push(&mut s.unstable_blocks, &s.utxos, block).unwrap();
+        }
+    });
+    ic_btc_canister::pre_upgrade();
+
+    // Write the serialized state along with its header, which is longer for versioned states.
+    let memory = get_upgrades_memory();
+    let mut header = [0; 8];
+    memory.read(0, &mut header);
+    let len = u32::from_le_bytes(header[..4].try_into().unwrap()) as usize;
+    let offset = if &header[4..8] == b"BTCS" { 12 } else { 4 };
+    let mut bytes = vec![0; offset + len];
+    memory.read(0, &mut bytes);
+    std::fs::write(path, bytes).unwrap();
+}
@@ -78,7 +78,7 @@ fn init() {
     let network = BitcoinNetwork::Regtest;
 
     // Block 1: A single transaction that gives ADDRESS_1 50 BTC split over 10k inputs.
-    let mut tx_1 = TransactionBuilder::new();
+    let mut tx_1 = TransactionBuilder::coinbase(1);
     for _ in 0..10_000 {
         tx_1 = tx_1.with_output(&Address::from_str(ADDRESS_1).unwrap(), 500_000);
     }
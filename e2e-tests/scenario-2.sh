@@ -36,6 +36,24 @@ dfx deploy --no-wallet bitcoin --argument "(record {
   api_access = variant { enabled };
   disable_api_if_not_fully_synced = variant { enabled };
   watchdog_canister = null;
+  sync_interval_ms = 1_000;
+  max_sync_interval_ms = 30_000;
+  fallback_blocks_sources = vec {};
+  blocks_source_failover_threshold = 3;
+  fee_policy = record {
+    tiers = vec {};
+    free_calls_per_period = 0;
+    period_secs = 2_592_000;
+  };
+  rate_limit = null;
+  api_access_mode = variant { unrestricted };
+  api_access_allow_list = vec {};
+  pruning_policy = record {
+    max_fork_work_deficit_in_blocks = 144;
+    max_unstable_blocks = 10_000;
+    max_unstable_tips = 100;
+  };
+  print_logs = variant { enabled };
 })"
 
 # Wait until the ingestion of stable blocks is complete.
@@ -1,6 +1,6 @@
 use bitcoin::{
     blockdata::constants::genesis_block, consensus::Encodable, Address, Block,
-    Network as BitcoinNetwork,
+    Network as BitcoinNetwork, OutPoint, Script,
 };
 use candid::CandidType;
 use ic_btc_test_utils::{BlockBuilder, TransactionBuilder};
@@ -81,14 +81,25 @@ fn init() {
 
     // Generate NUM_BLOCKS blocks, each with NUM_TRANSACTIONS transactions.
     let mut prev_header = genesis_block(network).header;
-    let mut lock_time_offset = 0;
-    for _ in 0..NUM_BLOCKS {
-        let mut block = BlockBuilder::with_prev_header(prev_header);
-        for i in lock_time_offset..lock_time_offset + TXS_PER_BLOCK {
-            // A transaction giving 1 satoshi to the address.
+    for i in 0..NUM_BLOCKS {
+        // The coinbase funds every transaction of the block with 1 satoshi. Its outputs
+        // have empty scripts to keep the block below the maximum weight. The coinbase
+        // starts with the height of the block, as its txid is needed before the block
+        // is built.
+        let mut coinbase = TransactionBuilder::coinbase(i + 1);
+        for _ in 0..TXS_PER_BLOCK {
+            coinbase = coinbase.with_output_script(Script::new(), 1);
+        }
+        let coinbase = coinbase.build();
+
+        let mut block =
+            BlockBuilder::with_prev_header(prev_header).with_transaction(coinbase.clone());
+        for vout in 0..TXS_PER_BLOCK {
+            // A transaction giving 1 satoshi to the address. It has no witness, as a
+            // witness commitment would change the txid of the coinbase it spends.
             block = block.with_transaction(
                 TransactionBuilder::new()
-                    .with_lock_time(i)
+                    .with_input(OutPoint::new(coinbase.txid(), vout), None)
                     .with_output(&Address::from_str(ADDRESS).unwrap(), 1)
                     .build(),
             );
@@ -96,7 +107,6 @@ fn init() {
         let block = block.build();
         append_block(&block);
         prev_header = block.header;
-        lock_time_offset += TXS_PER_BLOCK;
     }
 }
 
@@ -332,6 +332,10 @@ pub struct GetUtxosRequest {
 #[derive(CandidType, Debug, Deserialize, PartialEq, Eq, Clone)]
 pub struct GetUtxosResponse {
     pub utxos: Vec<Utxo>,
+    /// The tip of the main chain, i.e. the chain with the most chainwork. If several
+    /// chains have the same chainwork, the tip that was received first is used, as in
+    /// Bitcoin Core, so UTXOs with zero confirmations can come from a fork that's later
+    /// replaced by a chain with more chainwork.
     pub tip_block_hash: BlockHash,
     pub tip_height: u32,
     pub next_page: Option<Page>,
@@ -461,6 +465,56 @@ pub struct SetConfigRequest {
     /// The watchdog canister has the authority to disable the Bitcoin canister's API
     /// if it suspects that there is a problem.
     pub watchdog_canister: Option<Option<Principal>>,
+
+    /// Whether or not to also print the canister's log entries to the replica's logs.
+    pub print_logs: Option<Flag>,
+
+    /// Checkpoints to enforce in addition to the hard-coded ones of the network.
+    /// Replaces the checkpoints that were previously set.
+    pub checkpoints: Option<Vec<Checkpoint>>,
+
+    /// See `Config::sync_interval_ms`.
+    pub sync_interval_ms: Option<u64>,
+
+    /// See `Config::max_sync_interval_ms`.
+    pub max_sync_interval_ms: Option<u64>,
+
+    /// See `Config::blocks_source`.
+    pub blocks_source: Option<Principal>,
+
+    /// See `Config::fallback_blocks_sources`.
+    /// Replaces the fallback blocks sources that were previously set.
+    pub fallback_blocks_sources: Option<Vec<Principal>>,
+
+    /// See `Config::blocks_source_failover_threshold`.
+    pub blocks_source_failover_threshold: Option<u32>,
+
+    /// See `Config::fee_policy`.
+    pub fee_policy: Option<FeePolicy>,
+
+    /// See `Config::rate_limit`.
+    pub rate_limit: Option<Option<RateLimit>>,
+
+    /// See `Config::api_access_mode`.
+    /// The watchdog canister can set this mode as well.
+    pub api_access_mode: Option<ApiAccessMode>,
+
+    /// See `Config::api_access_allow_list`.
+    /// Replaces the allow-list that was previously set.
+    pub api_access_allow_list: Option<Vec<Principal>>,
+
+    /// See `Config::pruning_policy`.
+    pub pruning_policy: Option<PruningPolicy>,
+}
+
+/// A block that the header at a given height must be the header of.
+#[derive(CandidType, Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct Checkpoint {
+    pub height: Height,
+
+    /// The hash of the block, in the byte order it's serialized in (i.e. the
+    /// reverse of its hex representation).
+    pub block_hash: BlockHash,
 }
 
 #[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
@@ -472,6 +526,18 @@ pub enum Flag {
     Disabled,
 }
 
+/// Who can access the APIs provided by the canister while they're enabled.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug, Default)]
+pub enum ApiAccessMode {
+    /// Everyone can access the APIs.
+    #[serde(rename = "unrestricted")]
+    #[default]
+    Unrestricted,
+    /// Only the principals in the allow-list can access the APIs.
+    #[serde(rename = "restricted")]
+    Restricted,
+}
+
 /// The payload used to initialize the canister.
 #[derive(CandidType, Deserialize, Debug)]
 pub struct Config {
@@ -499,6 +565,44 @@ pub struct Config {
     /// The watchdog canister has the authority to disable the Bitcoin canister's API
     /// if it suspects that there is a problem.
     pub watchdog_canister: Option<Principal>,
+
+    /// The delay, in milliseconds, before syncing again when there's nothing to do
+    /// because fetching blocks returned no blocks or was rejected. The delay doubles
+    /// every time this happens consecutively, up to `max_sync_interval_ms`.
+    pub sync_interval_ms: u64,
+
+    /// The maximum delay, in milliseconds, before syncing again.
+    pub max_sync_interval_ms: u64,
+
+    /// The principals from which blocks are retrieved if `blocks_source` fails, in the
+    /// order in which they're tried.
+    pub fallback_blocks_sources: Vec<Principal>,
+
+    /// The number of consecutive rejects or stalls of a blocks source after which
+    /// blocks are retrieved from the next blocks source instead.
+    ///
+    /// A blocks source stalls when it returns no blocks even though the canister knows
+    /// of block headers beyond the tip of its main chain.
+    pub blocks_source_failover_threshold: u32,
+
+    /// The policy that determines how much of the fees each caller is charged.
+    pub fee_policy: FeePolicy,
+
+    /// The limit on the rate at which each caller can call the Bitcoin API, if any.
+    pub rate_limit: Option<RateLimit>,
+
+    /// Who can access the APIs provided by the canister while `api_access` is enabled.
+    pub api_access_mode: ApiAccessMode,
+
+    /// The principals that can access the APIs provided by the canister in the
+    /// restricted access mode.
+    pub api_access_allow_list: Vec<Principal>,
+
+    /// The policy that bounds the size of the tree of unstable blocks.
+    pub pruning_policy: PruningPolicy,
+
+    /// Whether or not to also print the canister's log entries to the replica's logs.
+    pub print_logs: Flag,
 }
 
 impl Default for Config {
@@ -512,6 +616,16 @@ impl Default for Config {
             api_access: Flag::Enabled,
             disable_api_if_not_fully_synced: Flag::Enabled,
             watchdog_canister: None,
+            sync_interval_ms: 1_000,
+            max_sync_interval_ms: 30_000,
+            fallback_blocks_sources: vec![],
+            blocks_source_failover_threshold: 3,
+            fee_policy: FeePolicy::default(),
+            rate_limit: None,
+            api_access_mode: ApiAccessMode::Unrestricted,
+            api_access_allow_list: vec![],
+            pruning_policy: PruningPolicy::default(),
+            print_logs: Flag::Enabled,
         }
     }
 }
@@ -549,6 +663,299 @@ pub struct Fees {
     pub send_transaction_per_byte: u128,
 }
 
+/// A group of callers that are charged a different share of the fees, and that can
+/// make some calls for free.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct FeeTier {
+    /// The callers in the tier.
+    pub principals: Vec<Principal>,
+
+    /// The percentage of the fees that callers in the tier are charged, e.g. 0 for
+    /// free access, or 50 for half the fees.
+    pub fee_percent: u32,
+
+    /// The number of calls that every caller in the tier can make for free in every
+    /// period of the fee policy.
+    pub free_calls_per_period: u64,
+}
+
+/// The policy that determines how much of the fees in `Fees` each caller is charged.
+///
+/// A call is free if the caller hasn't used up its free calls for the current period.
+/// Otherwise, the caller is charged the percentage of the fees of its tier, or the
+/// full fees if it isn't in any tier.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct FeePolicy {
+    /// The tiers of callers. A caller that is in several tiers belongs to the first one.
+    pub tiers: Vec<FeeTier>,
+
+    /// The number of calls that callers that aren't in any tier can make for free in
+    /// every period. Only a bounded number of such callers get free calls in a period,
+    /// as principals are free to create.
+    pub free_calls_per_period: u64,
+
+    /// The length of a period, in seconds. Free calls are replenished at the start of
+    /// every period. If zero, free calls are never replenished.
+    pub period_secs: u64,
+}
+
+impl Default for FeePolicy {
+    fn default() -> Self {
+        Self {
+            tiers: vec![],
+            free_calls_per_period: 0,
+            // 30 days.
+            period_secs: 30 * 24 * 60 * 60,
+        }
+    }
+}
+
+/// A limit on the rate at which each caller can call the update endpoints of the
+/// Bitcoin API.
+///
+/// Every caller has a bucket of up to `max_burst` calls, which is refilled at a rate of
+/// `calls_per_minute`. Calls are rejected while the caller's bucket is empty.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct RateLimit {
+    /// The number of calls per minute that each caller can make in the long run.
+    pub calls_per_minute: u64,
+
+    /// The number of calls that a caller can make at once, after not making any calls
+    /// for a while.
+    pub max_burst: u64,
+
+    /// The callers that aren't rate limited.
+    pub exempt_principals: Vec<Principal>,
+}
+
+/// The policy that bounds the size of the tree of unstable blocks by pruning its forks.
+/// The main chain is never pruned.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Debug, Clone)]
+pub struct PruningPolicy {
+    /// The number of blocks by which the chainwork of a fork can fall behind the main
+    /// chain before the fork is pruned. Blocks are counted at the difficulty of either
+    /// the anchor or the tip of the main chain, whichever is higher.
+    pub max_fork_work_deficit_in_blocks: u64,
+
+    /// The maximum number of blocks in the tree. Beyond it, the forks with the least
+    /// chainwork are pruned.
+    pub max_unstable_blocks: u64,
+
+    /// The maximum number of tips in the tree. Beyond it, the forks with the least
+    /// chainwork are pruned.
+    pub max_unstable_tips: u32,
+}
+
+impl Default for PruningPolicy {
+    fn default() -> Self {
+        Self {
+            max_fork_work_deficit_in_blocks: 144,
+            max_unstable_blocks: 10_000,
+            max_unstable_tips: 100,
+        }
+    }
+}
+
+/// The severity of a log entry.
+#[derive(
+    CandidType, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Copy, Clone, Debug,
+)]
+pub enum LogLevel {
+    #[serde(rename = "debug")]
+    Debug,
+    #[serde(rename = "info")]
+    Info,
+    #[serde(rename = "warning")]
+    Warning,
+    #[serde(rename = "error")]
+    Error,
+}
+
+impl fmt::Display for LogLevel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Debug => write!(f, "debug"),
+            Self::Info => write!(f, "info"),
+            Self::Warning => write!(f, "warning"),
+            Self::Error => write!(f, "error"),
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "debug" => Ok(Self::Debug),
+            "info" => Ok(Self::Info),
+            "warning" => Ok(Self::Warning),
+            "error" => Ok(Self::Error),
+            _ => Err("Bad log level".to_string()),
+        }
+    }
+}
+
+/// The component of the canister that emitted a log entry.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum LogComponent {
+    /// Fetching blocks and processing the responses of the adapter.
+    #[serde(rename = "heartbeat")]
+    Heartbeat,
+    /// Inserting blocks into the state and ingesting stable blocks into the UTXO set.
+    #[serde(rename = "ingestion")]
+    Ingestion,
+    /// Serving requests to the canister's endpoints.
+    #[serde(rename = "api")]
+    Api,
+    /// Auditing the consistency of the UTXO set's indexes.
+    #[serde(rename = "audit")]
+    Audit,
+    /// Persisting and migrating the state across upgrades.
+    #[serde(rename = "upgrade")]
+    Upgrade,
+}
+
+/// A structured entry of the canister's logs.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct LogEntry {
+    /// The time the entry was logged, in seconds since the UNIX epoch.
+    pub timestamp: u64,
+    pub level: LogLevel,
+    pub component: LogComponent,
+    pub message: String,
+    /// The hash of the block the entry relates to, if any.
+    pub block_hash: Option<String>,
+}
+
+/// A request for retrieving the canister's logs.
+#[derive(CandidType, Deserialize, Default, Debug)]
+pub struct GetLogsRequest {
+    /// If set, only entries with at least this level are returned.
+    pub level: Option<LogLevel>,
+
+    /// If set, only entries logged at or after this time (in seconds since
+    /// the UNIX epoch) are returned.
+    pub since: Option<u64>,
+}
+
+/// A block in the tree of unstable blocks.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct UnstableBlock {
+    pub block_hash: String,
+    /// The hash of the block's parent, or `None` if the block is the anchor.
+    pub parent_block_hash: Option<String>,
+    pub height: Height,
+    pub difficulty: u64,
+    /// The number of blocks in the longest chain starting at this block.
+    pub depth: u128,
+    /// The hex-encoded total work of the chain up to and including this block, counted
+    /// from the anchor, in the format of Bitcoin Core's `getblockheader`.
+    pub chainwork: String,
+    /// Whether or not the block is part of the main chain.
+    pub is_main_chain: bool,
+}
+
+/// The header of a block that is expected to be received, but isn't yet part
+/// of the unstable block tree.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct NextBlockHeader {
+    pub block_hash: String,
+    pub parent_block_hash: String,
+    pub height: Height,
+}
+
+/// A snapshot of the tree of unstable blocks, for diagnosing forks.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct UnstableBlockTree {
+    pub stability_threshold: u32,
+    pub num_tips: u32,
+    /// The blocks of the tree, with every block listed after its parent.
+    pub blocks: Vec<UnstableBlock>,
+    pub next_block_headers: Vec<NextBlockHeader>,
+}
+
+/// The kind of inconsistency found by an audit of the UTXO set's indexes.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Copy, Clone, Debug)]
+pub enum UtxoSetAuditMismatchKind {
+    /// An address UTXO that refers to an outpoint that isn't in the UTXO set.
+    #[serde(rename = "missing_utxo")]
+    MissingUtxo,
+    /// An address UTXO whose height or address doesn't match the UTXO it refers to.
+    #[serde(rename = "utxo_mismatch")]
+    UtxoMismatch,
+    /// An address that owns UTXOs of a non-zero value, but has no balance.
+    #[serde(rename = "missing_balance")]
+    MissingBalance,
+    /// A balance that isn't equal to the sum of the address's UTXOs.
+    #[serde(rename = "balance_mismatch")]
+    BalanceMismatch,
+    /// A balance of zero, which should have been removed.
+    #[serde(rename = "zero_balance")]
+    ZeroBalance,
+}
+
+/// An inconsistency found by an audit of the UTXO set's indexes.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct UtxoSetAuditMismatch {
+    pub kind: UtxoSetAuditMismatchKind,
+    pub address: String,
+    pub message: String,
+}
+
+/// The progress and findings of an audit of the UTXO set's indexes.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct UtxoSetAuditReport {
+    /// The time the audit started, in seconds since the UNIX epoch.
+    pub started_at: u64,
+    /// The time the audit completed, in seconds since the UNIX epoch.
+    pub completed_at: Option<u64>,
+    /// The stable height when the audit started.
+    pub start_height: Height,
+    pub address_utxos_checked: u64,
+    pub balances_checked: u64,
+    pub num_mismatches: u64,
+    /// The first mismatches found. Only a bounded number of them is kept.
+    pub mismatches: Vec<UtxoSetAuditMismatch>,
+}
+
+/// The status of the audits of the UTXO set's indexes.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct UtxoSetAuditStatus {
+    /// The audit that is currently in progress, if any.
+    pub current: Option<UtxoSetAuditReport>,
+    /// The most recently completed audit, if any.
+    pub last_completed: Option<UtxoSetAuditReport>,
+    pub runs_completed: u64,
+}
+
+/// A snapshot of the canister's stable memory that is being exported in chunks.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct StateSnapshot {
+    /// The time the snapshot was taken, in seconds since the UNIX epoch.
+    pub taken_at: u64,
+    /// The time after which the snapshot expires and syncing resumes, in seconds since
+    /// the UNIX epoch. It can be pushed back with `extend_state_snapshot`.
+    pub expires_at: u64,
+    /// The height of the latest stable block at the time the snapshot was taken.
+    pub stable_height: Height,
+    /// The size of the snapshot in Wasm pages.
+    pub size_in_pages: u64,
+    /// The size of every chunk in Wasm pages. The last chunk may be smaller.
+    pub chunk_size_in_pages: u64,
+    pub num_chunks: u64,
+}
+
+/// A chunk of a snapshot of the canister's stable memory.
+#[derive(CandidType, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
+pub struct StateSnapshotChunk {
+    pub index: u64,
+    #[serde(with = "serde_bytes")]
+    pub bytes: Vec<u8>,
+    /// The hex-encoded SHA-256 hash of the chunk's bytes.
+    pub sha256: String,
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -16,7 +16,9 @@ if [ "$(uname)" == "Darwin" ]; then
   export CC="${LLVM_PATH}/bin/clang"
 fi
 
-cargo build --bin "$CANISTER" --target "$TARGET" --release
+# Additional features can be enabled with the CARGO_FEATURES variable,
+# e.g. `CARGO_FEATURES=regtest_mining`.
+cargo build --bin "$CANISTER" --target "$TARGET" --release ${CARGO_FEATURES:+--features "$CARGO_FEATURES"}
 
 # Navigate to root directory.
 cd ..
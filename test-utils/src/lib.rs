@@ -1,8 +1,16 @@
 use bitcoin::{
-    secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1, util::uint::Uint256, Address, Block,
-    BlockHash, BlockHeader, KeyPair, Network, OutPoint, PublicKey, Script, Transaction, TxIn,
-    TxMerkleNode, TxOut, Witness, XOnlyPublicKey,
+    blockdata::script::Builder, secp256k1::rand::rngs::OsRng, secp256k1::Secp256k1,
+    util::uint::Uint256, Address, Block, BlockHash, BlockHeader, KeyPair, Network, OutPoint,
+    PublicKey, Script, Transaction, TxIn, TxMerkleNode, TxOut, Witness, XOnlyPublicKey,
 };
+use ic_btc_validation::{add_witness_commitment, block_subsidy, compute_merkle_root, solve_header};
+use std::{cell::RefCell, collections::HashMap};
+
+thread_local! {
+    // The heights of the blocks built so far, so that the blocks built on top of them
+    // know their own height.
+    static HEIGHTS: RefCell<HashMap<BlockHash, u32>> = RefCell::new(HashMap::new());
+}
 
 /// Generates a random P2PKH address.
 pub fn random_p2pkh_address(network: Network) -> Address {
@@ -30,6 +38,14 @@ fn coinbase_input() -> TxIn {
     }
 }
 
+// A coinbase input whose script starts with the given height, as required by BIP34.
+fn coinbase_input_with_height(height: u32) -> TxIn {
+    TxIn {
+        script_sig: Builder::new().push_int(height as i64).into_script(),
+        ..coinbase_input()
+    }
+}
+
 pub struct BlockBuilder {
     prev_header: Option<BlockHeader>,
     transactions: Vec<Transaction>,
@@ -56,23 +72,43 @@ impl BlockBuilder {
     }
 
     pub fn build(self) -> Block {
-        let txdata = if self.transactions.is_empty() {
-            // Create a random coinbase transaction.
-            vec![TransactionBuilder::coinbase().build()]
-        } else {
-            self.transactions
+        let height = match self.prev_header {
+            // Blocks that weren't built here, like the genesis blocks of the networks,
+            // are assumed to be genesis blocks.
+            Some(prev_header) => HEIGHTS.with(|heights| {
+                heights
+                    .borrow()
+                    .get(&prev_header.block_hash())
+                    .map_or(1, |height| height + 1)
+            }),
+            None => 0,
         };
 
-        let merkle_root =
-            bitcoin::util::hash::bitcoin_merkle_root(txdata.iter().map(|tx| tx.txid().as_hash()))
-                .unwrap();
-        let merkle_root = TxMerkleNode::from_hash(merkle_root);
+        let mut txdata = self.transactions;
+        if txdata.first().map_or(true, |tx| !tx.is_coin_base()) {
+            // Every block starts with a coinbase, so create a random one that claims
+            // the subsidy at the block's height.
+            txdata.insert(
+                0,
+                TransactionBuilder::coinbase(height)
+                    .with_output(
+                        &random_p2pkh_address(Network::Regtest),
+                        block_subsidy(&Network::Regtest, height),
+                    )
+                    .build(),
+            );
+        }
+        add_witness_commitment(&mut txdata);
+
+        let merkle_root = compute_merkle_root(&txdata).unwrap();
 
         let header = match self.prev_header {
             Some(prev_header) => header(&prev_header, merkle_root),
             None => genesis(merkle_root),
         };
 
+        HEIGHTS.with(|heights| heights.borrow_mut().insert(header.block_hash(), height));
+
         Block { header, txdata }
     }
 }
@@ -94,7 +130,7 @@ fn genesis(merkle_root: TxMerkleNode) -> BlockHeader {
         merkle_root,
         prev_blockhash: BlockHash::default(),
     };
-    solve(&mut header);
+    solve_header(&mut header);
 
     header
 }
@@ -114,16 +150,22 @@ impl TransactionBuilder {
         }
     }
 
-    pub fn coinbase() -> Self {
+    /// Creates a coinbase for a block at the given height. Its script starts with the
+    /// height, as required by BIP34.
+    pub fn coinbase(height: u32) -> Self {
         Self {
-            input: vec![coinbase_input()],
+            input: vec![coinbase_input_with_height(height)],
             output: vec![],
             lock_time: 0,
         }
     }
 
     pub fn with_input(mut self, previous_output: OutPoint, witness: Option<Witness>) -> Self {
-        if self.input == vec![coinbase_input()] {
+        if self
+            .input
+            .iter()
+            .any(|input| input.previous_output.is_null())
+        {
             panic!("A call `with_input` should not be possible if `coinbase` was called");
         }
 
@@ -146,6 +188,14 @@ impl TransactionBuilder {
         self
     }
 
+    pub fn with_output_script(mut self, script_pubkey: Script, value: u64) -> Self {
+        self.output.push(TxOut {
+            value,
+            script_pubkey,
+        });
+        self
+    }
+
     pub fn with_lock_time(mut self, time: u32) -> Self {
         self.lock_time = time;
         self
@@ -195,23 +245,16 @@ fn header(prev_header: &BlockHeader, merkle_root: TxMerkleNode) -> BlockHeader {
         merkle_root,
         prev_blockhash: prev_header.block_hash(),
     };
-    solve(&mut header);
+    solve_header(&mut header);
 
     header
 }
 
-fn solve(header: &mut BlockHeader) {
-    let target = header.target();
-    while header.validate_pow(&target).is_err() {
-        header.nonce += 1;
-    }
-}
-
 #[cfg(test)]
 mod test {
     mod transaction_builder {
         use crate::{random_p2pkh_address, TransactionBuilder};
-        use bitcoin::{Network, OutPoint};
+        use bitcoin::{blockdata::script::Builder, Network, OutPoint};
 
         #[test]
         fn new_build() {
@@ -225,10 +268,14 @@ mod test {
 
         #[test]
         fn coinbase() {
-            let tx = TransactionBuilder::coinbase().build();
+            let tx = TransactionBuilder::coinbase(5).build();
             assert!(tx.is_coin_base());
             assert_eq!(tx.input.len(), 1);
             assert_eq!(tx.input[0].previous_output, OutPoint::null());
+            assert_eq!(
+                tx.input[0].script_sig,
+                Builder::new().push_int(5).into_script()
+            );
             assert_eq!(tx.output.len(), 1);
             assert_eq!(tx.output[0].value, 50_0000_0000);
         }
@@ -239,18 +286,18 @@ mod test {
         )]
         fn with_input_panic() {
             let address = random_p2pkh_address(Network::Regtest);
-            let coinbase_tx = TransactionBuilder::coinbase()
+            let coinbase_tx = TransactionBuilder::coinbase(0)
                 .with_output(&address, 1000)
                 .build();
 
-            TransactionBuilder::coinbase()
+            TransactionBuilder::coinbase(0)
                 .with_input(bitcoin::OutPoint::new(coinbase_tx.txid(), 0), None);
         }
 
         #[test]
         fn with_output() {
             let address = random_p2pkh_address(Network::Regtest);
-            let tx = TransactionBuilder::coinbase()
+            let tx = TransactionBuilder::coinbase(0)
                 .with_output(&address, 1000)
                 .build();
 
@@ -266,7 +313,7 @@ mod test {
         fn with_output_2() {
             let address_0 = random_p2pkh_address(Network::Regtest);
             let address_1 = random_p2pkh_address(Network::Regtest);
-            let tx = TransactionBuilder::coinbase()
+            let tx = TransactionBuilder::coinbase(0)
                 .with_output(&address_0, 1000)
                 .with_output(&address_1, 2000)
                 .build();
@@ -284,7 +331,7 @@ mod test {
         #[test]
         fn with_input() {
             let address = random_p2pkh_address(Network::Regtest);
-            let coinbase_tx = TransactionBuilder::coinbase()
+            let coinbase_tx = TransactionBuilder::coinbase(0)
                 .with_output(&address, 1000)
                 .build();
 
@@ -304,10 +351,10 @@ mod test {
         #[test]
         fn with_input_2() {
             let address = random_p2pkh_address(Network::Regtest);
-            let coinbase_tx_0 = TransactionBuilder::coinbase()
+            let coinbase_tx_0 = TransactionBuilder::coinbase(0)
                 .with_output(&address, 1000)
                 .build();
-            let coinbase_tx_1 = TransactionBuilder::coinbase()
+            let coinbase_tx_1 = TransactionBuilder::coinbase(0)
                 .with_output(&address, 2000)
                 .build();
 
@@ -329,4 +376,83 @@ mod test {
             assert_eq!(tx.output[0].value, 50_0000_0000);
         }
     }
+
+    mod block_builder {
+        use crate::{BlockBuilder, TransactionBuilder};
+        use bitcoin::{
+            blockdata::{constants::genesis_block, script::Builder},
+            Network, OutPoint, Witness,
+        };
+
+        #[test]
+        fn commits_to_witnesses() {
+            let coinbase = TransactionBuilder::coinbase(0).build();
+            let block = BlockBuilder::genesis()
+                .with_transaction(coinbase.clone())
+                .build();
+            assert_eq!(block.txdata[0], coinbase);
+
+            let tx = TransactionBuilder::new()
+                .with_input(
+                    OutPoint::new(coinbase.txid(), 0),
+                    Some(Witness::from_vec(vec![vec![1]])),
+                )
+                .build();
+            let block = BlockBuilder::genesis()
+                .with_transaction(coinbase.clone())
+                .with_transaction(tx)
+                .build();
+            assert_eq!(block.txdata[0].output.len(), coinbase.output.len() + 1);
+            assert!(block.check_merkle_root());
+            assert!(block.check_witness_commitment());
+        }
+
+        #[test]
+        fn starts_coinbases_with_the_height() {
+            let genesis = BlockBuilder::genesis().build();
+            let block_1 = BlockBuilder::with_prev_header(genesis.header).build();
+            let block_2 = BlockBuilder::with_prev_header(block_1.header).build();
+            for (block, height) in [(genesis, 0), (block_1, 1), (block_2, 2)] {
+                assert_eq!(
+                    block.txdata[0].input[0].script_sig,
+                    Builder::new().push_int(height).into_script()
+                );
+            }
+
+            // The genesis blocks of the networks are assumed to be at height 0.
+            let block =
+                BlockBuilder::with_prev_header(genesis_block(Network::Regtest).header).build();
+            assert_eq!(
+                block.txdata[0].input[0].script_sig,
+                Builder::new().push_int(1).into_script()
+            );
+        }
+
+        #[test]
+        fn keeps_the_coinbase_it_is_given() {
+            let genesis = BlockBuilder::genesis().build();
+            let coinbase = TransactionBuilder::coinbase(1).build();
+            let block = BlockBuilder::with_prev_header(genesis.header)
+                .with_transaction(coinbase.clone())
+                .build();
+            assert_eq!(block.txdata[0].txid(), coinbase.txid());
+        }
+
+        #[test]
+        fn starts_blocks_with_a_coinbase() {
+            let block = BlockBuilder::genesis().build();
+            assert_eq!(block.txdata.len(), 1);
+            assert!(block.txdata[0].is_coin_base());
+
+            let coinbase = TransactionBuilder::coinbase(0).build();
+            let tx = TransactionBuilder::new()
+                .with_input(OutPoint::new(coinbase.txid(), 0), None)
+                .build();
+            let block = BlockBuilder::genesis().with_transaction(tx.clone()).build();
+            assert_eq!(block.txdata.len(), 2);
+            assert!(block.txdata[0].is_coin_base());
+            assert_eq!(block.txdata[1], tx);
+            assert!(block.check_merkle_root());
+        }
+    }
 }
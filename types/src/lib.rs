@@ -41,6 +41,11 @@ impl Block {
         &self.block.header
     }
 
+    /// Returns the underlying `bitcoin::Block`.
+    pub fn as_bitcoin_block(&self) -> &BitcoinBlock {
+        &self.block
+    }
+
     pub fn block_hash(&self) -> BlockHash {
         self.block_hash
             .borrow_mut()
@@ -61,6 +66,17 @@ impl Block {
         Self::target_difficulty(network, self.header().target())
     }
 
+    /// Returns the expected number of hashes required to mine the block, as computed
+    /// from its target by Bitcoin Core.
+    pub fn work(&self) -> Uint256 {
+        #[cfg(feature = "mock_difficulty")]
+        if let Some(difficulty) = self.mock_difficulty {
+            return Uint256::from_u64(difficulty).expect("a u64 must fit in a Uint256");
+        }
+
+        self.header().work()
+    }
+
     pub fn consensus_encode(&self, buffer: &mut Vec<u8>) -> Result<usize, std::io::Error> {
         use bitcoin::consensus::Encodable;
         self.block.consensus_encode(buffer)
@@ -0,0 +1,833 @@
+use bitcoin::{
+    blockdata::{opcodes, script::Builder},
+    hashes::{sha256d, Hash},
+    util::hash::bitcoin_merkle_root,
+    Block, Network, OutPoint, Transaction, TxMerkleNode, TxOut, Txid, Witness,
+};
+use std::collections::{HashMap, HashSet};
+
+use crate::{
+    constants::{
+        bip34_height, segwit_height, subsidy_halving_interval, DUPLICATE_TX_ID_BYTES,
+        INITIAL_SUBSIDY, MAX_BLOCK_WEIGHT,
+    },
+    BlockHeight,
+};
+
+// The prefix of the coinbase output that commits to the block's witnesses (BIP141):
+// OP_RETURN, a push of 36 bytes, and the commitment header.
+const WITNESS_COMMITMENT_PREFIX: [u8; 6] = [0x6a, 0x24, 0xaa, 0x21, 0xa9, 0xed];
+
+// The size of a witness commitment output's script, including the prefix.
+const WITNESS_COMMITMENT_SIZE: usize = 38;
+
+/// An error thrown when trying to validate the transactions of a block.
+#[derive(Debug, PartialEq)]
+pub enum ValidateBlockError {
+    /// Used when the block has no transactions.
+    NoTransactions,
+    /// Used when the first transaction in the block isn't a coinbase.
+    FirstTransactionIsNotCoinbase,
+    /// Used when a transaction other than the first one is a coinbase.
+    MultipleCoinbaseTransactions,
+    /// Used when the merkle root of the transactions doesn't match the one
+    /// in the header.
+    InvalidMerkleRoot,
+    /// Used when a transaction appears more than once in the block. Such a
+    /// block can have the same merkle root as a valid one (CVE-2012-2459).
+    DuplicateTransactions,
+    /// Used when the witness commitment in the coinbase doesn't match the
+    /// witnesses of the transactions.
+    InvalidWitnessCommitment,
+    /// Used when transactions have witnesses, but the coinbase doesn't
+    /// commit to them.
+    UnexpectedWitness,
+    /// Used when the weight of the block is above the maximum.
+    BlockWeightTooHigh { weight: usize, max_weight: usize },
+    /// Used when the coinbase doesn't start with the height of the block,
+    /// as required by BIP34.
+    InvalidCoinbaseHeight,
+    /// Used when a transaction has the same ID as a previous one whose outputs
+    /// aren't all spent (BIP30).
+    DuplicateTransaction { txid: Txid },
+    /// Used when a transaction spends an output that isn't found in the
+    /// block or in the `TxOutStore`.
+    InputNotFound { outpoint: OutPoint },
+    /// Used when the outputs of a transaction are worth more than its inputs.
+    OutputValueAboveInputValue { txid: Txid },
+    /// Used when the coinbase claims more than the subsidy and the fees of
+    /// the block.
+    CoinbaseValueTooHigh { value: u64, max_value: u64 },
+}
+
+/// A store of the transaction outputs that a block can spend.
+pub trait TxOutStore {
+    /// Returns the value of the given output, if it's available to be spent
+    /// by the block.
+    fn get_value(&self, outpoint: &OutPoint) -> Option<u64>;
+
+    /// Returns true if the given output exists and hasn't been spent.
+    /// Used to reject transactions that duplicate existing ones (BIP30).
+    fn is_unspent(&self, outpoint: &OutPoint) -> bool {
+        self.get_value(outpoint).is_some()
+    }
+}
+
+/// Validates the transactions of a block at the given height. If a failure
+/// occurs, a [ValidateBlockError](ValidateBlockError) will be returned.
+///
+/// The transactions are verified against the commitments in the header and
+/// the coinbase, and the coinbase is checked to claim no more than the
+/// subsidy and the fees. The inputs of the transactions are resolved from
+/// the block itself and from the given store.
+///
+/// The header itself is expected to be validated with `validate_header`.
+pub fn validate_block(
+    network: &Network,
+    store: &impl TxOutStore,
+    block: &Block,
+    height: BlockHeight,
+) -> Result<(), ValidateBlockError> {
+    match block.txdata.first() {
+        None => return Err(ValidateBlockError::NoTransactions),
+        Some(tx) if !tx.is_coin_base() => {
+            return Err(ValidateBlockError::FirstTransactionIsNotCoinbase)
+        }
+        Some(_) => {}
+    }
+
+    if block.txdata[1..].iter().any(|tx| tx.is_coin_base()) {
+        return Err(ValidateBlockError::MultipleCoinbaseTransactions);
+    }
+
+    let txids: Vec<Txid> = block.txdata.iter().map(|tx| tx.txid()).collect();
+
+    validate_merkle_root(block, &txids)?;
+    validate_witness_commitment(network, block, height)?;
+
+    let weight = block.weight();
+    if weight > MAX_BLOCK_WEIGHT {
+        return Err(ValidateBlockError::BlockWeightTooHigh {
+            weight,
+            max_weight: MAX_BLOCK_WEIGHT,
+        });
+    }
+
+    let bip34_active = bip34_height(network).map_or(false, |bip34_height| height >= bip34_height);
+    if bip34_active {
+        validate_coinbase_height(block, height)?;
+    } else {
+        // As in Bitcoin Core, duplicates are only looked for until BIP34 activates,
+        // as unique coinbases make them impossible afterwards.
+        validate_no_duplicate_transactions(block, &txids, store)?;
+    }
+
+    validate_coinbase_value(network, store, block, &txids, height)
+}
+
+fn validate_merkle_root(block: &Block, txids: &[Txid]) -> Result<(), ValidateBlockError> {
+    if merkle_root(txids) != Some(block.header.merkle_root) {
+        return Err(ValidateBlockError::InvalidMerkleRoot);
+    }
+
+    let mut seen = HashSet::new();
+    if !txids.iter().all(|txid| seen.insert(txid)) {
+        return Err(ValidateBlockError::DuplicateTransactions);
+    }
+
+    Ok(())
+}
+
+fn validate_witness_commitment(
+    network: &Network,
+    block: &Block,
+    height: BlockHeight,
+) -> Result<(), ValidateBlockError> {
+    let has_witness = || {
+        block
+            .txdata
+            .iter()
+            .any(|tx| tx.input.iter().any(|input| !input.witness.is_empty()))
+    };
+
+    // Before segwit, a coinbase output that looks like a commitment is just an
+    // output, but witnesses are still invalid.
+    if height < segwit_height(network) {
+        if has_witness() {
+            return Err(ValidateBlockError::UnexpectedWitness);
+        }
+        return Ok(());
+    }
+
+    let coinbase = &block.txdata[0];
+
+    // If there are multiple commitments, the last one is used.
+    let commitment = coinbase.output.iter().rev().find_map(|output| {
+        let script = output.script_pubkey.as_bytes();
+        if script.len() >= WITNESS_COMMITMENT_SIZE && script[..6] == WITNESS_COMMITMENT_PREFIX {
+            Some(&script[6..WITNESS_COMMITMENT_SIZE])
+        } else {
+            None
+        }
+    });
+
+    let commitment = match commitment {
+        Some(commitment) => commitment,
+        None => {
+            // Without a commitment, none of the transactions can have witnesses.
+            if has_witness() {
+                return Err(ValidateBlockError::UnexpectedWitness);
+            }
+            return Ok(());
+        }
+    };
+
+    // The coinbase's witness must be a single 32-byte reserved value.
+    let witness = coinbase.input[0].witness.to_vec();
+    let reserved_value = match witness.as_slice() {
+        [reserved_value] if reserved_value.len() == 32 => reserved_value,
+        _ => return Err(ValidateBlockError::InvalidWitnessCommitment),
+    };
+
+    let expected_commitment = compute_witness_commitment(&block.txdata, reserved_value)
+        .expect("a block has at least one transaction");
+    if &expected_commitment[..] != commitment {
+        return Err(ValidateBlockError::InvalidWitnessCommitment);
+    }
+
+    Ok(())
+}
+
+// Checks that the coinbase's script starts with a push of the block's height (BIP34).
+fn validate_coinbase_height(block: &Block, height: BlockHeight) -> Result<(), ValidateBlockError> {
+    let expected_prefix = Builder::new().push_int(height as i64).into_script();
+    if !block.txdata[0].input[0]
+        .script_sig
+        .as_bytes()
+        .starts_with(expected_prefix.as_bytes())
+    {
+        return Err(ValidateBlockError::InvalidCoinbaseHeight);
+    }
+
+    Ok(())
+}
+
+// Checks that none of the transactions overwrite the unspent outputs of a previous
+// transaction with the same ID (BIP30).
+fn validate_no_duplicate_transactions(
+    block: &Block,
+    txids: &[Txid],
+    store: &impl TxOutStore,
+) -> Result<(), ValidateBlockError> {
+    for (tx, txid) in block.txdata.iter().zip(txids) {
+        let is_duplicate =
+            (0..tx.output.len() as u32).any(|vout| store.is_unspent(&OutPoint::new(*txid, vout)));
+
+        if is_duplicate && !is_historical_duplicate(txid) {
+            return Err(ValidateBlockError::DuplicateTransaction { txid: *txid });
+        }
+    }
+
+    Ok(())
+}
+
+fn is_historical_duplicate(txid: &Txid) -> bool {
+    DUPLICATE_TX_ID_BYTES
+        .iter()
+        .any(|duplicate| txid.as_inner() == duplicate)
+}
+
+// Checks that the coinbase claims no more than the block's subsidy and the fees of
+// its transactions.
+fn validate_coinbase_value(
+    network: &Network,
+    store: &impl TxOutStore,
+    block: &Block,
+    txids: &[Txid],
+    height: BlockHeight,
+) -> Result<(), ValidateBlockError> {
+    // The outputs created by the transactions of the block, which the transactions
+    // that follow them can spend.
+    let mut block_outputs: HashMap<OutPoint, u64> = HashMap::new();
+    let mut fees: u64 = 0;
+
+    for (tx, txid) in block.txdata.iter().zip(txids) {
+        if !tx.is_coin_base() {
+            let mut input_value: u64 = 0;
+            for input in tx.input.iter() {
+                let outpoint = input.previous_output;
+                let value = match block_outputs.get(&outpoint) {
+                    Some(value) => *value,
+                    None => store
+                        .get_value(&outpoint)
+                        .ok_or(ValidateBlockError::InputNotFound { outpoint })?,
+                };
+                input_value = input_value.saturating_add(value);
+            }
+
+            let output_value = total_output_value(tx);
+            if output_value > input_value {
+                return Err(ValidateBlockError::OutputValueAboveInputValue { txid: *txid });
+            }
+            fees = fees.saturating_add(input_value - output_value);
+        }
+
+        for (vout, output) in tx.output.iter().enumerate() {
+            block_outputs.insert(OutPoint::new(*txid, vout as u32), output.value);
+        }
+    }
+
+    let value = total_output_value(&block.txdata[0]);
+    let max_value = block_subsidy(network, height).saturating_add(fees);
+    if value > max_value {
+        return Err(ValidateBlockError::CoinbaseValueTooHigh { value, max_value });
+    }
+
+    Ok(())
+}
+
+fn total_output_value(tx: &Transaction) -> u64 {
+    tx.output
+        .iter()
+        .fold(0, |total: u64, output| total.saturating_add(output.value))
+}
+
+/// Returns the subsidy of the block at the given height.
+pub fn block_subsidy(network: &Network, height: BlockHeight) -> u64 {
+    let halvings = height / subsidy_halving_interval(network);
+    if halvings >= 64 {
+        0
+    } else {
+        INITIAL_SUBSIDY >> halvings
+    }
+}
+
+/// Returns the merkle root of the given transactions, or `None` if there are none.
+pub fn compute_merkle_root(txdata: &[Transaction]) -> Option<TxMerkleNode> {
+    let txids: Vec<Txid> = txdata.iter().map(|tx| tx.txid()).collect();
+    merkle_root(&txids)
+}
+
+fn merkle_root(txids: &[Txid]) -> Option<TxMerkleNode> {
+    bitcoin_merkle_root(txids.iter().map(|txid| txid.as_hash())).map(TxMerkleNode::from_hash)
+}
+
+/// Returns the commitment to the witnesses of the given transactions with the given
+/// witness reserved value (BIP141), or `None` if there are no transactions.
+pub fn compute_witness_commitment(
+    txdata: &[Transaction],
+    reserved_value: &[u8],
+) -> Option<sha256d::Hash> {
+    // The coinbase's wtxid is defined to be all zeros.
+    let witness_root = bitcoin_merkle_root(txdata.iter().enumerate().map(|(i, tx)| {
+        if i == 0 {
+            sha256d::Hash::from_inner([0; 32])
+        } else {
+            tx.wtxid().as_hash()
+        }
+    }))?;
+
+    Some(sha256d::Hash::hash(
+        &[&witness_root[..], reserved_value].concat(),
+    ))
+}
+
+/// Adds a witness commitment to the coinbase, the first of the given transactions,
+/// if any of the transactions has a witness. The witness reserved value is all zeros.
+///
+/// The commitment must be added before the merkle root of the transactions is
+/// computed, as it changes the coinbase's txid.
+pub fn add_witness_commitment(txdata: &mut [Transaction]) {
+    if txdata.first().map_or(true, |tx| !tx.is_coin_base())
+        || txdata
+            .iter()
+            .all(|tx| tx.input.iter().all(|input| input.witness.is_empty()))
+    {
+        return;
+    }
+
+    let reserved_value = [0; 32];
+    let commitment = compute_witness_commitment(txdata, &reserved_value)
+        .expect("there is at least one transaction");
+
+    let coinbase = &mut txdata[0];
+    coinbase.input[0].witness = Witness::from_vec(vec![reserved_value.to_vec()]);
+    coinbase.output.push(TxOut {
+        value: 0,
+        script_pubkey: Builder::new()
+            .push_opcode(opcodes::all::OP_RETURN)
+            .push_slice(&[&WITNESS_COMMITMENT_PREFIX[2..], &commitment[..]].concat())
+            .into_script(),
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use bitcoin::{
+        blockdata::constants::genesis_block, Network, OutPoint, Script, Transaction, TxOut, Witness,
+    };
+    use ic_btc_test_utils::{random_p2pkh_address, BlockBuilder, TransactionBuilder};
+
+    #[derive(Default)]
+    struct TestTxOutStore {
+        values: HashMap<OutPoint, u64>,
+        spent: HashSet<OutPoint>,
+    }
+
+    impl TestTxOutStore {
+        fn with_outputs(tx: &Transaction) -> Self {
+            let mut store = Self::default();
+            for (vout, output) in tx.output.iter().enumerate() {
+                store
+                    .values
+                    .insert(OutPoint::new(tx.txid(), vout as u32), output.value);
+            }
+            store
+        }
+    }
+
+    impl TxOutStore for TestTxOutStore {
+        fn get_value(&self, outpoint: &OutPoint) -> Option<u64> {
+            self.values.get(outpoint).copied()
+        }
+
+        fn is_unspent(&self, outpoint: &OutPoint) -> bool {
+            self.values.contains_key(outpoint) && !self.spent.contains(outpoint)
+        }
+    }
+
+    // Validates a block on regtest at height 0, where its coinbase doesn't need a height
+    // (BIP34), with an empty store.
+    fn validate(block: &Block) -> Result<(), ValidateBlockError> {
+        validate_block(&Network::Regtest, &TestTxOutStore::default(), block, 0)
+    }
+
+    // Recomputes the merkle root of a block after its transactions were modified.
+    fn with_merkle_root(mut block: Block) -> Block {
+        block.header.merkle_root = compute_merkle_root(&block.txdata).unwrap();
+        block
+    }
+
+    fn spend(tx: &Transaction, witness: Option<Witness>) -> Transaction {
+        TransactionBuilder::new()
+            .with_input(OutPoint::new(tx.txid(), 0), witness)
+            .build()
+    }
+
+    // A block with a transaction that has a witness, spending the given coinbase of a
+    // previous block. The transaction can't spend the block's own coinbase, as the
+    // witness commitment changes the coinbase's txid.
+    fn block_with_witness(prev_coinbase: &Transaction) -> Block {
+        BlockBuilder::genesis()
+            .with_transaction(TransactionBuilder::coinbase(0).build())
+            .with_transaction(spend(
+                prev_coinbase,
+                Some(Witness::from_vec(vec![vec![1, 2, 3]])),
+            ))
+            .build()
+    }
+
+    #[test]
+    fn accepts_valid_blocks() {
+        for network in [Network::Bitcoin, Network::Testnet, Network::Regtest] {
+            assert_eq!(
+                validate_block(
+                    &network,
+                    &TestTxOutStore::default(),
+                    &genesis_block(network),
+                    0
+                ),
+                Ok(())
+            );
+        }
+
+        let coinbase = TransactionBuilder::coinbase(0).build();
+        let tx = spend(&coinbase, None);
+        let block = BlockBuilder::genesis()
+            .with_transaction(coinbase)
+            .with_transaction(tx)
+            .build();
+        assert_eq!(validate(&block), Ok(()));
+
+        let prev_coinbase = TransactionBuilder::coinbase(0).build();
+        assert_eq!(
+            validate_block(
+                &Network::Regtest,
+                &TestTxOutStore::with_outputs(&prev_coinbase),
+                &block_with_witness(&prev_coinbase),
+                0
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_blocks_without_a_coinbase_first() {
+        let mut block = BlockBuilder::genesis().build();
+        block.txdata.clear();
+        assert_eq!(validate(&block), Err(ValidateBlockError::NoTransactions));
+
+        // The builder starts blocks with a coinbase, so it's removed afterwards.
+        let coinbase = TransactionBuilder::coinbase(0).build();
+        let mut block = BlockBuilder::genesis()
+            .with_transaction(spend(&coinbase, None))
+            .build();
+        block.txdata.remove(0);
+        assert_eq!(
+            validate(&with_merkle_root(block)),
+            Err(ValidateBlockError::FirstTransactionIsNotCoinbase)
+        );
+
+        let block = BlockBuilder::genesis()
+            .with_transaction(coinbase)
+            .with_transaction(TransactionBuilder::coinbase(0).build())
+            .build();
+        assert_eq!(
+            validate(&block),
+            Err(ValidateBlockError::MultipleCoinbaseTransactions)
+        );
+    }
+
+    #[test]
+    fn rejects_transactions_that_do_not_match_the_merkle_root() {
+        let coinbase = TransactionBuilder::coinbase(0).build();
+        let mut block = BlockBuilder::genesis()
+            .with_transaction(coinbase.clone())
+            .with_transaction(spend(&coinbase, None))
+            .build();
+        block.txdata[1].output[0].value += 1;
+        assert_eq!(validate(&block), Err(ValidateBlockError::InvalidMerkleRoot));
+    }
+
+    #[test]
+    fn rejects_duplicate_transactions() {
+        // Duplicating the last of an odd number of transactions doesn't change the
+        // merkle root.
+        let coinbase = TransactionBuilder::coinbase(0).build();
+        let tx_1 = spend(&coinbase, None);
+        let tx_2 = spend(&tx_1, None);
+        let mut block = BlockBuilder::genesis()
+            .with_transaction(coinbase)
+            .with_transaction(tx_1)
+            .with_transaction(tx_2.clone())
+            .build();
+        block.txdata.push(tx_2);
+        assert!(block.check_merkle_root());
+        assert_eq!(
+            validate(&block),
+            Err(ValidateBlockError::DuplicateTransactions)
+        );
+    }
+
+    #[test]
+    fn adds_valid_witness_commitments() {
+        let prev_coinbase = TransactionBuilder::coinbase(0).build();
+        let mut txdata = vec![
+            TransactionBuilder::coinbase(0).build(),
+            spend(&prev_coinbase, Some(Witness::from_vec(vec![vec![1, 2, 3]]))),
+        ];
+        add_witness_commitment(&mut txdata);
+
+        let mut block = genesis_block(Network::Regtest);
+        block.txdata = txdata;
+        let block = with_merkle_root(block);
+        assert!(block.check_witness_commitment());
+        assert_eq!(
+            validate_block(
+                &Network::Regtest,
+                &TestTxOutStore::with_outputs(&prev_coinbase),
+                &block,
+                0
+            ),
+            Ok(())
+        );
+
+        // Nothing is added if none of the transactions has a witness.
+        let coinbase = TransactionBuilder::coinbase(0).build();
+        let mut txdata = vec![coinbase.clone()];
+        add_witness_commitment(&mut txdata);
+        assert_eq!(txdata, vec![coinbase]);
+    }
+
+    #[test]
+    fn rejects_invalid_witness_commitments() {
+        // A witness that was modified after the block was built.
+        let mut block = block_with_witness(&TransactionBuilder::coinbase(0).build());
+        block.txdata[1].input[0].witness = Witness::from_vec(vec![vec![4, 5, 6]]);
+        assert_eq!(
+            validate(&block),
+            Err(ValidateBlockError::InvalidWitnessCommitment)
+        );
+
+        // A reserved value of the wrong size.
+        let mut block = block_with_witness(&TransactionBuilder::coinbase(0).build());
+        block.txdata[0].input[0].witness = Witness::from_vec(vec![vec![0; 31]]);
+        assert_eq!(
+            validate(&block),
+            Err(ValidateBlockError::InvalidWitnessCommitment)
+        );
+
+        // A witness without a commitment.
+        let mut block = block_with_witness(&TransactionBuilder::coinbase(0).build());
+        block.txdata[0].output.pop();
+        let block = with_merkle_root(block);
+        assert_eq!(validate(&block), Err(ValidateBlockError::UnexpectedWitness));
+    }
+
+    #[test]
+    fn only_checks_witness_commitments_after_segwit() {
+        let network = Network::Bitcoin;
+        let segwit_height = segwit_height(&network);
+        let address = random_p2pkh_address(network);
+
+        // A coinbase that is valid at the given height on mainnet.
+        let coinbase = |height: BlockHeight| {
+            TransactionBuilder::coinbase(height)
+                .with_output(&address, 1000)
+                .build()
+        };
+
+        // A coinbase output that looks like a commitment, without a reserved value.
+        for (height, expected) in [
+            (segwit_height - 1, Ok(())),
+            (
+                segwit_height,
+                Err(ValidateBlockError::InvalidWitnessCommitment),
+            ),
+        ] {
+            let mut coinbase = coinbase(height);
+            coinbase.output.push(TxOut {
+                value: 0,
+                script_pubkey: Script::from(
+                    [&WITNESS_COMMITMENT_PREFIX[..], &[0; 32][..]].concat(),
+                ),
+            });
+            let block = BlockBuilder::genesis().with_transaction(coinbase).build();
+            assert_eq!(
+                validate_block(&network, &TestTxOutStore::default(), &block, height),
+                expected
+            );
+        }
+
+        // Witnesses are rejected before segwit, even with a valid commitment.
+        let prev_coinbase = TransactionBuilder::coinbase(0).build();
+        let store = TestTxOutStore::with_outputs(&prev_coinbase);
+        for (height, expected) in [
+            (
+                segwit_height - 1,
+                Err(ValidateBlockError::UnexpectedWitness),
+            ),
+            (segwit_height, Ok(())),
+        ] {
+            let block = BlockBuilder::genesis()
+                .with_transaction(coinbase(height))
+                .with_transaction(spend(
+                    &prev_coinbase,
+                    Some(Witness::from_vec(vec![vec![1]])),
+                ))
+                .build();
+            assert_eq!(validate_block(&network, &store, &block, height), expected);
+        }
+    }
+
+    #[test]
+    fn rejects_blocks_above_the_maximum_weight() {
+        let mut coinbase = TransactionBuilder::coinbase(0).build();
+        coinbase.output.push(TxOut {
+            value: 0,
+            script_pubkey: Script::from(vec![0; MAX_BLOCK_WEIGHT / 4]),
+        });
+        let block = BlockBuilder::genesis().with_transaction(coinbase).build();
+        assert_eq!(
+            validate(&block),
+            Err(ValidateBlockError::BlockWeightTooHigh {
+                weight: block.weight(),
+                max_weight: MAX_BLOCK_WEIGHT
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_coinbases_above_the_subsidy_and_fees() {
+        let address = random_p2pkh_address(Network::Regtest);
+        let coinbase = TransactionBuilder::coinbase(0)
+            .with_output(&address, INITIAL_SUBSIDY + 1)
+            .build();
+        let block = BlockBuilder::genesis().with_transaction(coinbase).build();
+        assert_eq!(
+            validate(&block),
+            Err(ValidateBlockError::CoinbaseValueTooHigh {
+                value: INITIAL_SUBSIDY + 1,
+                max_value: INITIAL_SUBSIDY
+            })
+        );
+
+        // The subsidy halves every 150 blocks on regtest.
+        let coinbase = TransactionBuilder::coinbase(150)
+            .with_output(&address, INITIAL_SUBSIDY)
+            .build();
+        let block = BlockBuilder::genesis().with_transaction(coinbase).build();
+        assert_eq!(
+            validate_block(&Network::Regtest, &TestTxOutStore::default(), &block, 150),
+            Err(ValidateBlockError::CoinbaseValueTooHigh {
+                value: INITIAL_SUBSIDY,
+                max_value: INITIAL_SUBSIDY / 2
+            })
+        );
+    }
+
+    #[test]
+    fn accepts_coinbases_that_claim_the_fees() {
+        let address = random_p2pkh_address(Network::Regtest);
+        let prev_coinbase = TransactionBuilder::coinbase(0)
+            .with_output(&address, 1000)
+            .build();
+        let store = TestTxOutStore::with_outputs(&prev_coinbase);
+        let tx = TransactionBuilder::new()
+            .with_input(OutPoint::new(prev_coinbase.txid(), 0), None)
+            .with_output(&address, 900)
+            .build();
+
+        let block = |coinbase_value| {
+            BlockBuilder::genesis()
+                .with_transaction(
+                    TransactionBuilder::coinbase(0)
+                        .with_output(&address, coinbase_value)
+                        .build(),
+                )
+                .with_transaction(tx.clone())
+                .build()
+        };
+
+        assert_eq!(
+            validate_block(&Network::Regtest, &store, &block(INITIAL_SUBSIDY + 100), 0),
+            Ok(())
+        );
+        assert_eq!(
+            validate_block(&Network::Regtest, &store, &block(INITIAL_SUBSIDY + 101), 0),
+            Err(ValidateBlockError::CoinbaseValueTooHigh {
+                value: INITIAL_SUBSIDY + 101,
+                max_value: INITIAL_SUBSIDY + 100
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_transactions_with_invalid_inputs() {
+        let address = random_p2pkh_address(Network::Regtest);
+        let prev_coinbase = TransactionBuilder::coinbase(0)
+            .with_output(&address, 1000)
+            .build();
+        let store = TestTxOutStore::with_outputs(&prev_coinbase);
+
+        // An input that isn't in the store.
+        let outpoint = OutPoint::new(prev_coinbase.txid(), 1);
+        let block = BlockBuilder::genesis()
+            .with_transaction(TransactionBuilder::coinbase(0).build())
+            .with_transaction(TransactionBuilder::new().with_input(outpoint, None).build())
+            .build();
+        assert_eq!(
+            validate_block(&Network::Regtest, &store, &block, 0),
+            Err(ValidateBlockError::InputNotFound { outpoint })
+        );
+
+        // Outputs that are worth more than the inputs.
+        let tx = TransactionBuilder::new()
+            .with_input(OutPoint::new(prev_coinbase.txid(), 0), None)
+            .with_output(&address, 1001)
+            .build();
+        let block = BlockBuilder::genesis()
+            .with_transaction(TransactionBuilder::coinbase(0).build())
+            .with_transaction(tx.clone())
+            .build();
+        assert_eq!(
+            validate_block(&Network::Regtest, &store, &block, 0),
+            Err(ValidateBlockError::OutputValueAboveInputValue { txid: tx.txid() })
+        );
+    }
+
+    #[test]
+    fn requires_the_height_in_the_coinbase_after_bip34() {
+        let height = bip34_height(&Network::Bitcoin).unwrap();
+        // A coinbase whose script starts with a height other than the block's.
+        let coinbase = TransactionBuilder::coinbase(0)
+            .with_output(
+                &random_p2pkh_address(Network::Bitcoin),
+                block_subsidy(&Network::Bitcoin, height),
+            )
+            .build();
+        let block = BlockBuilder::genesis().with_transaction(coinbase).build();
+        assert_eq!(
+            validate_block(
+                &Network::Bitcoin,
+                &TestTxOutStore::default(),
+                &block,
+                height
+            ),
+            Err(ValidateBlockError::InvalidCoinbaseHeight)
+        );
+
+        // Blocks before the activation don't need the height.
+        assert_eq!(
+            validate_block(
+                &Network::Bitcoin,
+                &TestTxOutStore::default(),
+                &block,
+                height - 1
+            ),
+            Ok(())
+        );
+
+        let mut block = block;
+        block.txdata[0].input[0].script_sig = Builder::new()
+            .push_int(height as i64)
+            .push_slice(&[1, 2, 3])
+            .into_script();
+        let block = with_merkle_root(block);
+        assert_eq!(
+            validate_block(
+                &Network::Bitcoin,
+                &TestTxOutStore::default(),
+                &block,
+                height
+            ),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn rejects_transactions_that_duplicate_unspent_ones() {
+        let coinbase = TransactionBuilder::coinbase(0).build();
+        let block = BlockBuilder::genesis()
+            .with_transaction(coinbase.clone())
+            .build();
+
+        // The same coinbase was already included in a previous block. Duplicates are
+        // only possible before BIP34.
+        let mut store = TestTxOutStore::with_outputs(&coinbase);
+        assert_eq!(
+            validate_block(&Network::Bitcoin, &store, &block, 2),
+            Err(ValidateBlockError::DuplicateTransaction {
+                txid: coinbase.txid()
+            })
+        );
+
+        // Duplicates are allowed once the outputs of the previous one are spent.
+        store.spent.insert(OutPoint::new(coinbase.txid(), 0));
+        assert_eq!(validate_block(&Network::Bitcoin, &store, &block, 2), Ok(()));
+    }
+
+    #[test]
+    fn historical_duplicates_are_allowed() {
+        use crate::constants::DUPLICATE_TX_IDS;
+        use std::str::FromStr;
+
+        for txid in DUPLICATE_TX_IDS {
+            assert!(is_historical_duplicate(&Txid::from_str(txid).unwrap()));
+        }
+        assert!(!is_historical_duplicate(
+            &TransactionBuilder::coinbase(0).build().txid()
+        ));
+    }
+}
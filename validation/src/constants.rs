@@ -8,6 +8,37 @@ pub const DIFFICULTY_ADJUSTMENT_INTERVAL: BlockHeight = 6 * 24 * 14;
 /// Needed to help test check for the 20 minute testnet/regtest rule
 pub const TEN_MINUTES: u32 = 60 * 10;
 
+/// The maximum weight of a block, as defined in BIP141.
+pub const MAX_BLOCK_WEIGHT: usize = 4_000_000;
+
+/// The subsidy of a block before any halving, in satoshis.
+pub const INITIAL_SUBSIDY: u64 = 50 * 100_000_000;
+
+/// Transactions that appear twice in the mainnet chain, as they were included
+/// again before BIP30 made duplicates invalid. The second occurrences overwrite
+/// the outputs of the first ones.
+///
+/// See: https://en.bitcoin.it/wiki/BIP_0030
+pub const DUPLICATE_TX_IDS: [&str; 2] = [
+    "d5d27987d2a3dfc724e359870c6644b40e497bdc0589a033220fe15429d88599",
+    "e3bf3d07d4b0375638d5f1db5255fe07ba2c4cb067cd81b84ee974b6585fb468",
+];
+
+/// The bytes of `DUPLICATE_TX_IDS` as they're stored in a `Txid`, so that they can be
+/// compared against without parsing the hex strings.
+pub(crate) const DUPLICATE_TX_ID_BYTES: [[u8; 32]; 2] = [
+    [
+        0x99, 0x85, 0xd8, 0x29, 0x54, 0xe1, 0x0f, 0x22, 0x33, 0xa0, 0x89, 0x05, 0xdc, 0x7b, 0x49,
+        0x0e, 0xb4, 0x44, 0x66, 0x0c, 0x87, 0x59, 0xe3, 0x24, 0xc7, 0xdf, 0xa3, 0xd2, 0x87, 0x79,
+        0xd2, 0xd5,
+    ],
+    [
+        0x68, 0xb4, 0x5f, 0x58, 0xb6, 0x74, 0xe9, 0x4e, 0xb8, 0x81, 0xcd, 0x67, 0xb0, 0x4c, 0x2c,
+        0xba, 0x07, 0xfe, 0x55, 0x52, 0xdb, 0xf1, 0xd5, 0x38, 0x56, 0x37, 0xb0, 0xd4, 0x07, 0x3d,
+        0xbf, 0xe3,
+    ],
+];
+
 /// Bitcoin mainnet maximum target value
 const BITCOIN_MAX_TARGET: Uint256 = Uint256([
     0x0000000000000000,
@@ -59,6 +90,102 @@ pub fn no_pow_retargeting(network: &Network) -> bool {
     }
 }
 
+/// Returns the number of blocks after which the block subsidy is halved.
+pub fn subsidy_halving_interval(network: &Network) -> BlockHeight {
+    match network {
+        Network::Bitcoin | Network::Testnet | Network::Signet => 210_000,
+        Network::Regtest => 150,
+    }
+}
+
+/// Returns the height from which coinbases must start with the block's height
+/// (BIP34), or `None` if it isn't enforced on the network. The heights are the
+/// same as in Bitcoin Core.
+pub fn bip34_height(network: &Network) -> Option<BlockHeight> {
+    match network {
+        Network::Bitcoin => Some(227_931),
+        Network::Testnet => Some(21_111),
+        Network::Signet | Network::Regtest => Some(1),
+    }
+}
+
+/// Returns the height from which segwit is active (BIP141). Below it, blocks
+/// can't have witnesses and their coinbases aren't checked for a commitment.
+pub fn segwit_height(network: &Network) -> BlockHeight {
+    match network {
+        Network::Bitcoin => 481_824,
+        Network::Testnet => 834_624,
+        Network::Signet => 1,
+        Network::Regtest => 0,
+    }
+}
+
+/// Returns the checkpoints of the network as (height, block hash) pairs, in
+/// ascending order of height. They're the same as in Bitcoin Core.
+pub fn checkpoints(network: &Network) -> &'static [(BlockHeight, &'static str)] {
+    match network {
+        Network::Bitcoin => &[
+            (
+                11_111,
+                "0000000069e244f73d78e8fd29ba2fd2ed618bd6fa2ee92559f542fdb26e7c1d",
+            ),
+            (
+                33_333,
+                "000000002dd5588a74784eaa7ab0507a18ad16a236e7b1ce69f00d7ddfb5d0a6",
+            ),
+            (
+                74_000,
+                "0000000000573993a3c9e41ce34471c079dcf5f52a0e824a81e7f953b8661a20",
+            ),
+            (
+                105_000,
+                "00000000000291ce28027faea320c8d2b054b2e0fe44a773f3eefb151d6bdc97",
+            ),
+            (
+                134_444,
+                "00000000000005b12ffd4cd315cd34ffd4a594f430ac814c91184a0d42d2b0fe",
+            ),
+            (
+                168_000,
+                "000000000000099e61ea72015e79632f216fe6cb33d7899acb35b75c8303b763",
+            ),
+            (
+                193_000,
+                "000000000000059f452a5f7340de6682a977387c17010ff6e6c3bd83ca8b1317",
+            ),
+            (
+                210_000,
+                "000000000000048b95347e83192f69cf0366076336c639f9b7228e9ba171342e",
+            ),
+            (
+                216_116,
+                "00000000000001b4f4b433e81ee46494af945cf96014816a4e2370f11b23df4e",
+            ),
+            (
+                225_430,
+                "00000000000001c108384350f74090433e7fcf79a606b8e797f065b130575932",
+            ),
+            (
+                250_000,
+                "000000000000003887df1f29024b06fc2200b55f8af8f35453d7be294df2d214",
+            ),
+            (
+                279_000,
+                "0000000000000001ae8c72a0b0c301f67e3afca10e819efa9041e458e9bd7e40",
+            ),
+            (
+                295_000,
+                "00000000000000004d9b4ef50f0f9d686fd69db2e03af35a100370c64632a983",
+            ),
+        ],
+        Network::Testnet => &[(
+            546,
+            "000000002a936ca763904c3c35fce2f3556c559c0214345d31b1bcebf76acb70",
+        )],
+        Network::Signet | Network::Regtest => &[],
+    }
+}
+
 /// Returns the PoW limit bits of the bitcoin network
 pub fn pow_limit_bits(network: &Network) -> u32 {
     match network {
@@ -1,8 +1,10 @@
 use bitcoin::{util::uint::Uint256, BlockHash, BlockHeader, Network};
+use std::str::FromStr;
 
 use crate::{
     constants::{
-        max_target, no_pow_retargeting, pow_limit_bits, DIFFICULTY_ADJUSTMENT_INTERVAL, TEN_MINUTES,
+        checkpoints, max_target, no_pow_retargeting, pow_limit_bits,
+        DIFFICULTY_ADJUSTMENT_INTERVAL, TEN_MINUTES,
     },
     BlockHeight,
 };
@@ -31,10 +33,50 @@ pub enum ValidateHeaderError {
     /// Used when the predecessor of the input header is not found in the
     /// HeaderStore.
     PrevHeaderNotFound,
+    /// Used when the header is at the height of a checkpoint, but its hash
+    /// is different from the checkpoint's.
+    DoesNotMatchCheckpoint { height: BlockHeight },
+    /// Used when the header forks off the main chain below a checkpoint
+    /// that the main chain has already reached.
+    ForkBelowCheckpoint {
+        height: BlockHeight,
+        checkpoint_height: BlockHeight,
+    },
+    /// Used when a header that the target of the header is computed from, such
+    /// as the header of the last difficulty adjustment, isn't in the HeaderStore.
+    AncestorNotFound { height: BlockHeight },
 }
 
 const ONE_HOUR: u64 = 3_600;
 
+lazy_static::lazy_static! {
+    static ref BITCOIN_CHECKPOINTS: Vec<(BlockHeight, BlockHash)> =
+        parse_checkpoints(&Network::Bitcoin);
+    static ref TESTNET_CHECKPOINTS: Vec<(BlockHeight, BlockHash)> =
+        parse_checkpoints(&Network::Testnet);
+}
+
+fn parse_checkpoints(network: &Network) -> Vec<(BlockHeight, BlockHash)> {
+    checkpoints(network)
+        .iter()
+        .map(|(height, hash)| {
+            (
+                *height,
+                BlockHash::from_str(hash).expect("checkpoint hash must be valid"),
+            )
+        })
+        .collect()
+}
+
+/// Returns the hard-coded checkpoints of the network, parsed only once.
+fn parsed_checkpoints(network: &Network) -> &'static [(BlockHeight, BlockHash)] {
+    match network {
+        Network::Bitcoin => BITCOIN_CHECKPOINTS.as_slice(),
+        Network::Testnet => TESTNET_CHECKPOINTS.as_slice(),
+        Network::Signet | Network::Regtest => &[],
+    }
+}
+
 pub trait HeaderStore {
     /// Returns the header with the given block hash.
     fn get_with_block_hash(&self, hash: &BlockHash) -> Option<BlockHeader>;
@@ -51,6 +93,18 @@ pub trait HeaderStore {
             .expect("genesis block header not found")
             .block_hash()
     }
+
+    /// Returns the height of the main chain, which can differ from the height
+    /// of the tip that the new header extends if the header is on a fork.
+    fn main_chain_height(&self) -> BlockHeight {
+        self.height()
+    }
+
+    /// Returns the checkpoints to enforce in addition to the hard-coded ones
+    /// of the network, as (height, block hash) pairs.
+    fn get_extra_checkpoints(&self) -> Vec<(BlockHeight, BlockHash)> {
+        vec![]
+    }
 }
 
 /// Validates a header. If a failure occurs, a
@@ -69,6 +123,8 @@ pub fn validate_header(
         }
     };
 
+    validate_checkpoints(network, store, header, prev_height + 1)?;
+
     is_timestamp_valid(store, header, current_time)?;
 
     let header_target = header.target();
@@ -80,19 +136,61 @@ pub fn validate_header(
         return Err(ValidateHeaderError::InvalidPoWForHeaderTarget);
     }
 
-    let target = get_next_target(network, store, &prev_header, prev_height, header.time);
-    if let Err(err) = header.validate_pow(&target) {
-        match err {
-            bitcoin::Error::BlockBadProofOfWork => println!("bad proof of work"),
-            bitcoin::Error::BlockBadTarget => println!("bad target"),
-            _ => {}
-        };
+    let target = get_next_target(network, store, &prev_header, prev_height, header.time)?;
+    if header.validate_pow(&target).is_err() {
         return Err(ValidateHeaderError::InvalidPoWForComputedTarget);
     }
 
     Ok(())
 }
 
+/// Increments the nonce of the header until its proof of work is valid for the
+/// target in the header. Only practical with the low targets of regtest.
+pub fn solve_header(header: &mut BlockHeader) {
+    let target = header.target();
+    while header.validate_pow(&target).is_err() {
+        header.nonce += 1;
+    }
+}
+
+/// Validates the header against the checkpoints of the network and the store.
+/// A header at the height of a checkpoint must have the checkpoint's hash, and
+/// a header can't fork off the main chain below a checkpoint the main chain has
+/// reached.
+fn validate_checkpoints(
+    network: &Network,
+    store: &impl HeaderStore,
+    header: &BlockHeader,
+    height: BlockHeight,
+) -> Result<(), ValidateHeaderError> {
+    let extra_checkpoints = store.get_extra_checkpoints();
+    let checkpoints = || {
+        parsed_checkpoints(network)
+            .iter()
+            .chain(extra_checkpoints.iter())
+    };
+
+    let mut checkpoints_at_height = checkpoints().filter(|(h, _)| *h == height).peekable();
+    if checkpoints_at_height.peek().is_some() {
+        let block_hash = header.block_hash();
+        if checkpoints_at_height.any(|(_, hash)| *hash != block_hash) {
+            return Err(ValidateHeaderError::DoesNotMatchCheckpoint { height });
+        }
+    }
+
+    // The main chain is only looked up if there's a checkpoint above the header.
+    if let Some(checkpoint_height) = checkpoints().map(|(h, _)| *h).filter(|h| *h > height).min() {
+        if checkpoint_height <= store.main_chain_height() {
+            return Err(ValidateHeaderError::ForkBelowCheckpoint {
+                height,
+                checkpoint_height,
+            });
+        }
+    }
+
+    Ok(())
+}
+
 fn timestamp_is_less_than_2h_in_future(
     block_time: u64,
     current_time: u64,
@@ -149,8 +247,8 @@ fn get_next_target(
     prev_header: &BlockHeader,
     prev_height: BlockHeight,
     timestamp: u32,
-) -> Uint256 {
-    match network {
+) -> Result<Uint256, ValidateHeaderError> {
+    let target = match network {
         Network::Testnet | Network::Regtest => {
             if (prev_height + 1) % DIFFICULTY_ADJUSTMENT_INTERVAL != 0 {
                 // This if statements is reached only for Regtest and Testnet networks
@@ -170,7 +268,7 @@ fn get_next_target(
                         store,
                         prev_header,
                         prev_height,
-                    ))
+                    )?)
                 }
             } else {
                 BlockHeader::u256_from_compact_target(compute_next_difficulty(
@@ -178,13 +276,15 @@ fn get_next_target(
                     store,
                     prev_header,
                     prev_height,
-                ))
+                )?)
             }
         }
         Network::Bitcoin | Network::Signet => BlockHeader::u256_from_compact_target(
-            compute_next_difficulty(network, store, prev_header, prev_height),
+            compute_next_difficulty(network, store, prev_header, prev_height)?,
         ),
-    }
+    };
+
+    Ok(target)
 }
 
 /// This method is only valid when used for testnet and regtest networks.
@@ -199,7 +299,7 @@ fn find_next_difficulty_in_chain(
     store: &impl HeaderStore,
     prev_header: &BlockHeader,
     prev_height: BlockHeight,
-) -> u32 {
+) -> Result<u32, ValidateHeaderError> {
     // This is the maximum difficulty target for the network
     let pow_limit_bits = pow_limit_bits(network);
     match network {
@@ -216,7 +316,7 @@ fn find_next_difficulty_in_chain(
                 if current_header.bits != pow_limit_bits
                     || current_height % DIFFICULTY_ADJUSTMENT_INTERVAL == 0
                 {
-                    return current_header.bits;
+                    return Ok(current_header.bits);
                 }
 
                 // Stop if we reach the initial header.
@@ -226,16 +326,17 @@ fn find_next_difficulty_in_chain(
 
                 // Traverse to the previous header.
                 let prev_blockhash = current_header.prev_blockhash;
-                current_header = store
-                    .get_with_block_hash(&prev_blockhash)
-                    .expect("previous header should be in the header store");
-                // Update the current height and hash.
                 current_height -= 1;
+                current_header = store.get_with_block_hash(&prev_blockhash).ok_or(
+                    ValidateHeaderError::AncestorNotFound {
+                        height: current_height,
+                    },
+                )?;
                 current_hash = prev_blockhash;
             }
-            pow_limit_bits
+            Ok(pow_limit_bits)
         }
-        Network::Bitcoin | Network::Signet => pow_limit_bits,
+        Network::Bitcoin | Network::Signet => Ok(pow_limit_bits),
     }
 }
 
@@ -246,7 +347,7 @@ fn compute_next_difficulty(
     store: &impl HeaderStore,
     prev_header: &BlockHeader,
     prev_height: BlockHeight,
-) -> u32 {
+) -> Result<u32, ValidateHeaderError> {
     // Difficulty is adjusted only once in every interval of 2 weeks (2016 blocks)
     // If an interval boundary is not reached, then previous difficulty target is
     // returned Regtest network doesn't adjust PoW difficult levels. For
@@ -254,7 +355,7 @@ fn compute_next_difficulty(
 
     let height = prev_height + 1;
     if height % DIFFICULTY_ADJUSTMENT_INTERVAL != 0 || no_pow_retargeting(network) {
-        return prev_header.bits;
+        return Ok(prev_header.bits);
     }
 
     // Computing the `last_adjustment_header`.
@@ -264,9 +365,11 @@ fn compute_next_difficulty(
     } else {
         height - DIFFICULTY_ADJUSTMENT_INTERVAL
     };
-    let last_adjustment_header = store
-        .get_with_height(last_adjustment_height)
-        .expect("Last adjustment header must exist");
+    let last_adjustment_header = store.get_with_height(last_adjustment_height).ok_or(
+        ValidateHeaderError::AncestorNotFound {
+            height: last_adjustment_height,
+        },
+    )?;
     let last_adjustment_time = last_adjustment_header.time;
 
     // Computing the time interval between the last adjustment header time and
@@ -298,11 +401,11 @@ fn compute_next_difficulty(
     target = Uint256::min(target, max_target(network));
 
     // Converting the target (Uint256) into a 32 bit representation used by Bitcoin
-    BlockHeader::compact_target_from_u256(&target)
+    Ok(BlockHeader::compact_target_from_u256(&target))
 }
 
 #[cfg(test)]
-mod test {
+pub(crate) mod test {
 
     use std::{collections::HashMap, path::PathBuf, str::FromStr};
 
@@ -329,6 +432,8 @@ mod test {
         height: BlockHeight,
         tip_hash: BlockHash,
         initial_hash: BlockHash,
+        main_chain_height: Option<BlockHeight>,
+        extra_checkpoints: Vec<(BlockHeight, BlockHash)>,
     }
 
     impl SimpleHeaderStore {
@@ -349,6 +454,8 @@ mod test {
                 height,
                 tip_hash,
                 initial_hash,
+                main_chain_height: None,
+                extra_checkpoints: vec![],
             }
         }
 
@@ -389,6 +496,14 @@ mod test {
         fn get_initial_hash(&self) -> BlockHash {
             self.initial_hash
         }
+
+        fn main_chain_height(&self) -> BlockHeight {
+            self.main_chain_height.unwrap_or(self.height)
+        }
+
+        fn get_extra_checkpoints(&self) -> Vec<(BlockHeight, BlockHash)> {
+            self.extra_checkpoints.clone()
+        }
     }
 
     fn deserialize_header(encoded_bytes: &str) -> BlockHeader {
@@ -396,9 +511,9 @@ mod test {
         deserialize(bytes.as_slice()).expect("failed to deserialize")
     }
 
-    /// This function reads `num_headers` headers from `tests/data/headers.csv`
-    /// and returns them.
-    fn get_bitcoin_headers() -> Vec<BlockHeader> {
+    /// This function reads the mainnet headers from `tests/data/headers.csv`, which
+    /// start at height 586,657, and returns them.
+    pub(crate) fn get_bitcoin_headers() -> Vec<BlockHeader> {
         let rdr = Reader::from_path(
             PathBuf::from(std::env::var("CARGO_MANIFEST_DIR").unwrap())
                 .join("tests/data/headers.csv"),
@@ -646,7 +761,8 @@ mod test {
         proptest!(|(i in 0..up_to_height)| {
             // Compute what the target of the next header should be.
             let expected_next_target =
-                get_next_target(&network, &store, &headers[i], i as u32, headers[i + 1].time);
+                get_next_target(&network, &store, &headers[i], i as u32, headers[i + 1].time)
+                    .unwrap();
 
             // Assert that the expected next target matches the next header's target.
             assert_eq!(
@@ -737,7 +853,64 @@ mod test {
                 last_header.time + TEN_MINUTES,
             );
             // Assert.
-            assert_eq!(target, BlockHeader::u256_from_compact_target(expected_pow));
+            assert_eq!(
+                target,
+                Ok(BlockHeader::u256_from_compact_target(expected_pow))
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_headers_that_do_not_match_a_checkpoint() {
+        let network = Network::Regtest;
+        let (mut store, last_header) = create_chain(&network, pow_limit_bits(&network), 3);
+        let header = next_block_header(last_header, pow_limit_bits(&network));
+
+        store.extra_checkpoints = vec![(3, BlockHash::default())];
+        assert_eq!(
+            validate_header(&network, &store, &header, MOCK_CURRENT_TIME),
+            Err(ValidateHeaderError::DoesNotMatchCheckpoint { height: 3 })
+        );
+
+        store.extra_checkpoints = vec![(3, header.block_hash())];
+        assert_eq!(validate_checkpoints(&network, &store, &header, 3), Ok(()));
+    }
+
+    #[test]
+    fn rejects_forks_below_a_reached_checkpoint() {
+        let network = Network::Regtest;
+        let (mut store, last_header) = create_chain(&network, pow_limit_bits(&network), 3);
+        let header = next_block_header(last_header, pow_limit_bits(&network));
+        store.extra_checkpoints = vec![(5, BlockHash::default())];
+
+        // The main chain hasn't reached the checkpoint yet.
+        store.main_chain_height = Some(4);
+        assert_eq!(validate_checkpoints(&network, &store, &header, 3), Ok(()));
+
+        store.main_chain_height = Some(5);
+        assert_eq!(
+            validate_header(&network, &store, &header, MOCK_CURRENT_TIME),
+            Err(ValidateHeaderError::ForkBelowCheckpoint {
+                height: 3,
+                checkpoint_height: 5
+            })
+        );
+    }
+
+    #[test]
+    fn checkpoints_are_valid() {
+        for network in [
+            Network::Bitcoin,
+            Network::Testnet,
+            Network::Signet,
+            Network::Regtest,
+        ] {
+            let checkpoints = checkpoints(&network);
+            assert!(checkpoints.windows(2).all(|w| w[0].0 < w[1].0));
+            for (_, hash) in checkpoints {
+                assert!(BlockHash::from_str(hash).is_ok());
+            }
+            assert_eq!(parsed_checkpoints(&network).len(), checkpoints.len());
         }
     }
 }
@@ -0,0 +1,255 @@
+use bitcoin::{util::uint::Uint256, BlockHash, BlockHeader, Network};
+use std::collections::BTreeMap;
+
+use crate::{
+    header::{validate_header, HeaderStore, ValidateHeaderError},
+    BlockHeight,
+};
+
+/// An error thrown when a chain of headers contains an invalid header.
+#[derive(Debug, PartialEq)]
+pub struct ValidateHeaderChainError {
+    /// The index of the first invalid header in the chain.
+    pub index: usize,
+    /// The reason the header is invalid.
+    pub error: ValidateHeaderError,
+    /// The cumulative work of the valid headers that precede it.
+    pub work: Uint256,
+}
+
+/// A `HeaderStore` that keeps a chain of headers in memory, starting from a
+/// trusted anchor.
+///
+/// Only the headers of the chain after the anchor can be validated. For the
+/// headers that are validated against older ones, such as the headers that
+/// adjust the difficulty, the store must be seeded with enough trusted headers
+/// using `push`.
+pub struct InMemoryHeaderStore {
+    headers: Vec<BlockHeader>,
+    // The index of each header in `headers`, by block hash.
+    indices: BTreeMap<BlockHash, usize>,
+    anchor_height: BlockHeight,
+}
+
+impl InMemoryHeaderStore {
+    /// Creates a store with the given anchor at the given height.
+    pub fn new(anchor_height: BlockHeight, anchor: BlockHeader) -> Self {
+        let mut indices = BTreeMap::new();
+        indices.insert(anchor.block_hash(), 0);
+        Self {
+            headers: vec![anchor],
+            indices,
+            anchor_height,
+        }
+    }
+
+    /// Adds a header on top of the tip without validating it.
+    ///
+    /// Panics if the header doesn't extend the tip.
+    pub fn push(&mut self, header: BlockHeader) {
+        assert_eq!(
+            header.prev_blockhash,
+            self.tip().block_hash(),
+            "header must extend the tip"
+        );
+        self.indices.insert(header.block_hash(), self.headers.len());
+        self.headers.push(header);
+    }
+
+    /// Returns the header at the tip of the chain.
+    pub fn tip(&self) -> &BlockHeader {
+        self.headers.last().expect("store must contain the anchor")
+    }
+}
+
+impl HeaderStore for InMemoryHeaderStore {
+    fn get_with_block_hash(&self, hash: &BlockHash) -> Option<BlockHeader> {
+        self.indices.get(hash).map(|index| self.headers[*index])
+    }
+
+    fn get_with_height(&self, height: u32) -> Option<BlockHeader> {
+        let index = height.checked_sub(self.anchor_height)?;
+        self.headers.get(index as usize).copied()
+    }
+
+    fn height(&self) -> u32 {
+        self.anchor_height + self.headers.len() as u32 - 1
+    }
+
+    fn get_initial_hash(&self) -> BlockHash {
+        self.headers[0].block_hash()
+    }
+}
+
+/// Validates a chain of headers on top of the given anchor, a trusted header
+/// and its height, and returns their cumulative work. The first header must
+/// extend the anchor, and each header must extend the one before it.
+///
+/// If a header is invalid, its index and error are returned, along with the
+/// cumulative work of the headers that precede it. As only the anchor and the
+/// headers of the chain are known, a header whose target depends on an older
+/// header, such as the header of the last difficulty adjustment, is rejected
+/// with `ValidateHeaderError::AncestorNotFound`.
+pub fn validate_header_chain(
+    network: &Network,
+    anchor: (BlockHeight, BlockHeader),
+    headers: &[BlockHeader],
+    current_time: u64,
+) -> Result<Uint256, ValidateHeaderChainError> {
+    let (anchor_height, anchor) = anchor;
+    let mut store = InMemoryHeaderStore::new(anchor_height, anchor);
+    let mut work = Uint256::default();
+    for (index, header) in headers.iter().enumerate() {
+        // `validate_header` expects the header to extend the tip of the store.
+        let result = if header.prev_blockhash == store.tip().block_hash() {
+            validate_header(network, &store, header, current_time)
+        } else {
+            Err(ValidateHeaderError::PrevHeaderNotFound)
+        };
+
+        if let Err(error) = result {
+            return Err(ValidateHeaderChainError { index, error, work });
+        }
+
+        work = work + header.work();
+        store.push(*header);
+    }
+
+    Ok(work)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        constants::test::{
+            MAINNET_HEADER_586656, MAINNET_HEADER_705600, MAINNET_HEADER_705601,
+            MAINNET_HEADER_705602,
+        },
+        header::test::get_bitcoin_headers,
+    };
+    use bitcoin::{consensus::deserialize, hashes::hex::FromHex};
+
+    const MOCK_CURRENT_TIME: u64 = 2_634_590_600;
+
+    fn deserialize_header(encoded_bytes: &str) -> BlockHeader {
+        let bytes = Vec::from_hex(encoded_bytes).expect("failed to decoded bytes");
+        deserialize(bytes.as_slice()).expect("failed to deserialize")
+    }
+
+    #[test]
+    fn validates_a_chain_of_headers() {
+        let header_705600 = deserialize_header(MAINNET_HEADER_705600);
+        let header_705601 = deserialize_header(MAINNET_HEADER_705601);
+        let header_705602 = deserialize_header(MAINNET_HEADER_705602);
+
+        assert_eq!(
+            validate_header_chain(
+                &Network::Bitcoin,
+                (705_600, header_705600),
+                &[header_705601, header_705602],
+                MOCK_CURRENT_TIME
+            ),
+            Ok(header_705601.work() + header_705602.work())
+        );
+    }
+
+    #[test]
+    fn in_memory_store_returns_the_headers_from_the_anchor() {
+        let header_705600 = deserialize_header(MAINNET_HEADER_705600);
+        let header_705601 = deserialize_header(MAINNET_HEADER_705601);
+        let mut store = InMemoryHeaderStore::new(705_600, header_705600);
+        store.push(header_705601);
+
+        assert_eq!(store.height(), 705_601);
+        assert_eq!(store.tip(), &header_705601);
+        assert_eq!(store.get_with_height(705_600), Some(header_705600));
+        assert_eq!(store.get_with_height(705_599), None);
+        assert_eq!(
+            store.get_with_block_hash(&header_705601.block_hash()),
+            Some(header_705601)
+        );
+    }
+
+    #[test]
+    fn returns_the_first_invalid_header() {
+        let header_705600 = deserialize_header(MAINNET_HEADER_705600);
+        let header_705601 = deserialize_header(MAINNET_HEADER_705601);
+        let mut header_705602 = deserialize_header(MAINNET_HEADER_705602);
+        header_705602.nonce += 1;
+
+        assert_eq!(
+            validate_header_chain(
+                &Network::Bitcoin,
+                (705_600, header_705600),
+                &[header_705601, header_705602],
+                MOCK_CURRENT_TIME
+            ),
+            Err(ValidateHeaderChainError {
+                index: 1,
+                error: ValidateHeaderError::InvalidPoWForHeaderTarget,
+                work: header_705601.work(),
+            })
+        );
+    }
+
+    #[test]
+    fn rejects_headers_that_do_not_extend_the_previous_one() {
+        let header_705600 = deserialize_header(MAINNET_HEADER_705600);
+        let header_705602 = deserialize_header(MAINNET_HEADER_705602);
+
+        assert_eq!(
+            validate_header_chain(
+                &Network::Bitcoin,
+                (705_600, header_705600),
+                &[header_705602],
+                MOCK_CURRENT_TIME
+            ),
+            Err(ValidateHeaderChainError {
+                index: 0,
+                error: ValidateHeaderError::PrevHeaderNotFound,
+                work: Uint256::default(),
+            })
+        );
+    }
+
+    #[test]
+    fn validates_difficulty_adjustments_from_the_anchor() {
+        // The headers start right after 586,656, the height of a difficulty adjustment,
+        // and contain the next adjustment at 588,672.
+        let header_586656 = deserialize_header(MAINNET_HEADER_586656);
+        let headers = get_bitcoin_headers();
+
+        assert!(validate_header_chain(
+            &Network::Bitcoin,
+            (586_656, header_586656),
+            &headers,
+            MOCK_CURRENT_TIME
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn rejects_difficulty_adjustments_below_the_anchor() {
+        // Without the header of the previous adjustment, the target of the header at
+        // 588,672 can't be computed.
+        let headers = get_bitcoin_headers();
+        let work = headers[1..2015]
+            .iter()
+            .fold(Uint256::default(), |work, header| work + header.work());
+
+        assert_eq!(
+            validate_header_chain(
+                &Network::Bitcoin,
+                (586_657, headers[0]),
+                &headers[1..],
+                MOCK_CURRENT_TIME
+            ),
+            Err(ValidateHeaderChainError {
+                index: 2014,
+                error: ValidateHeaderError::AncestorNotFound { height: 586_656 },
+                work,
+            })
+        );
+    }
+}
@@ -1,7 +1,18 @@
+//! Validation of Bitcoin headers and blocks.
+
+mod block;
 mod constants;
 mod header;
+mod header_chain;
 
-pub use crate::constants::max_target;
-pub use crate::header::{validate_header, HeaderStore, ValidateHeaderError};
+pub use crate::block::{
+    add_witness_commitment, block_subsidy, compute_merkle_root, compute_witness_commitment,
+    validate_block, TxOutStore, ValidateBlockError,
+};
+pub use crate::constants::{max_target, DUPLICATE_TX_IDS};
+pub use crate::header::{solve_header, validate_header, HeaderStore, ValidateHeaderError};
+pub use crate::header_chain::{
+    validate_header_chain, InMemoryHeaderStore, ValidateHeaderChainError,
+};
 
 type BlockHeight = u32;